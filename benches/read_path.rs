@@ -0,0 +1,148 @@
+//! Benchmarks of the read path hot spots, on images generated with `mksquashfs`.
+//!
+//! ```console
+//! $ cargo bench --features bench
+//! ```
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{Rng, SeedableRng};
+use squashfs_async::pools::LocalReadersPoolTokio;
+use squashfs_async::{Options, SquashFs};
+
+const SPECS: [(&str, &[&str]); 3] = [
+    ("nocomp", &["-noI", "-noId", "-noD", "-noF", "-noX"]),
+    ("gzip", &["-comp", "gzip", "-Xcompression-level", "1"]),
+    ("zstd", &["-comp", "zstd", "-Xcompression-level", "1"]),
+];
+const LARGE_FILE: usize = 4_000_000;
+const SMALL_FILES: usize = 200;
+const DEPTH: usize = 8;
+
+type Fs = SquashFs<LocalReadersPoolTokio>;
+
+/// Generate the contents of the images: a large file (half random, half compressible), small
+/// files stored in fragments, and a deep directory hierarchy.
+fn contents(dir: &Path) -> std::io::Result<()> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    std::fs::create_dir_all(dir)?;
+    let mut large: Vec<u8> = (0..LARGE_FILE / 2).map(|_| rng.gen()).collect();
+    large.extend((0..LARGE_FILE / 2).map(|i| (i % 251) as u8));
+    std::fs::write(dir.join("large"), large)?;
+    let small = dir.join("small");
+    std::fs::create_dir_all(&small)?;
+    for i in 0..SMALL_FILES {
+        let size = rng.gen_range(100..10_000);
+        let data: Vec<u8> = (0..size).map(|_| rng.gen_range(b'a'..=b'z')).collect();
+        std::fs::write(small.join(format!("file-{}", i)), data)?;
+    }
+    std::fs::create_dir_all(deep_path(dir))?;
+    std::fs::write(deep_path(dir).join("leaf"), b"leaf")?;
+    Ok(())
+}
+fn deep_path(root: &Path) -> PathBuf {
+    (0..DEPTH).fold(root.join("deep"), |p, i| p.join(format!("level-{}", i)))
+}
+
+fn images() -> Vec<(&'static str, PathBuf)> {
+    let tmp = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let dir = tmp.join("bench-contents");
+    let images: Vec<_> = SPECS
+        .iter()
+        .map(|(suffix, _)| (*suffix, tmp.join(format!("bench-{}.squashfs", suffix))))
+        .collect();
+    if images.iter().all(|(_, f)| f.exists()) {
+        return images;
+    }
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    contents(&dir).unwrap();
+    for ((_, options), (_, image)) in SPECS.iter().zip(&images) {
+        let _ = std::fs::remove_file(image);
+        let output = std::process::Command::new("mksquashfs")
+            .args([&dir, image])
+            .args(["-mkfs-time", "0", "-reproducible", "-quiet"])
+            .args(*options)
+            .output()
+            .expect("Failed to run mksquashfs");
+        assert!(
+            output.status.success(),
+            "mksquashfs failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+    images
+}
+
+fn bench(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let options = Options {
+        cache_mb: 0,
+        ..Default::default()
+    };
+    let filesystems: Vec<(&str, Fs)> = images()
+        .into_iter()
+        .map(|(suffix, image)| (suffix, rt.block_on(Fs::open(&image, &options)).unwrap()))
+        .collect();
+    let inode = |fs: &Fs, path: &str| rt.block_on(fs.resolve(Path::new(path))).unwrap();
+
+    let mut group = c.benchmark_group("read_data_block");
+    for (suffix, fs) in &filesystems {
+        let large = inode(fs, "large");
+        group.throughput(Throughput::Bytes(fs.superblock.block_size as u64));
+        for (name, index) in [
+            ("random", 0),
+            (
+                "compressible",
+                LARGE_FILE / 2 / fs.superblock.block_size as usize + 1,
+            ),
+        ] {
+            group.bench_with_input(BenchmarkId::new(*suffix, name), &index, |b, index| {
+                b.to_async(&rt)
+                    .iter(|| async { fs.bench_read_data_block(large, *index).await.unwrap() })
+            });
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("read_file_impl");
+    for (suffix, fs) in &filesystems {
+        for (name, path) in [("large", "large"), ("fragment", "small/file-0")] {
+            let inode = inode(fs, path);
+            group.throughput(Throughput::Bytes(fs.attr(inode).unwrap().size));
+            group.bench_with_input(BenchmarkId::new(*suffix, name), &inode, |b, inode| {
+                b.to_async(&rt)
+                    .iter(|| async { fs.bench_read_file(*inode).await.unwrap() })
+            });
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("flatten_inode_table");
+    for (suffix, fs) in &filesystems {
+        group.bench_function(*suffix, |b| {
+            b.to_async(&rt)
+                .iter(|| async { fs.bench_flatten_inode_table().await.unwrap() })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("directory_lookup");
+    let deep = deep_path(Path::new("")).join("leaf");
+    for (suffix, fs) in &filesystems {
+        group.bench_function(BenchmarkId::new(*suffix, "deep"), |b| {
+            b.to_async(&rt)
+                .iter(|| async { fs.resolve(&deep).await.unwrap() })
+        });
+        group.bench_function(BenchmarkId::new(*suffix, "wide"), |b| {
+            b.to_async(&rt)
+                .iter(|| async { fs.resolve(Path::new("small/file-199")).await.unwrap() })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use squashfs_async::Compression;
+
+fuzz_target!(|data: &[u8]| {
+    for compression in [Compression::Gzip, Compression::Xz, Compression::Zstd] {
+        let _ = squashfs_async::fuzz::metadata_block(data, compression);
+    }
+});
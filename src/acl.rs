@@ -0,0 +1,153 @@
+//! POSIX ACLs, as stored in the `system.posix_acl_access` and `system.posix_acl_default`
+//! extended attributes.
+//!
+//! The values use the format of the Linux kernel: a version header followed by `(tag, perm, id)`
+//! entries, all little-endian. See `acl(5)` for the evaluation algorithm.
+use crate::{Error, FileAttrLite};
+
+/// Extended attribute holding the access ACL of a file.
+pub const ACCESS_XATTR: &str = "system.posix_acl_access";
+/// Extended attribute holding the default ACL of a directory.
+pub const DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+const VERSION: u32 = 2;
+const ENTRY_SIZE: usize = 8;
+
+/// Qualifier of an ACL entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    /// Upper bound of the permissions granted by the named and group entries
+    Mask,
+    Other,
+}
+
+/// ACL entry, with its permission bits (`R_OK`, `W_OK` and `X_OK`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub tag: Tag,
+    pub perm: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    pub entries: Vec<Entry>,
+}
+impl Acl {
+    /// Parse the value of an ACL extended attribute.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < 4 || u32::from_le_bytes(data[..4].try_into().unwrap()) != VERSION {
+            return Err(Error::InvalidAcl);
+        }
+        let chunks = data[4..].chunks_exact(ENTRY_SIZE);
+        if !chunks.remainder().is_empty() {
+            return Err(Error::InvalidAcl);
+        }
+        let entries = chunks
+            .map(|e| {
+                let id = u32::from_le_bytes(e[4..].try_into().unwrap());
+                let tag = match u16::from_le_bytes([e[0], e[1]]) {
+                    0x01 => Tag::UserObj,
+                    0x02 => Tag::User(id),
+                    0x04 => Tag::GroupObj,
+                    0x08 => Tag::Group(id),
+                    0x10 => Tag::Mask,
+                    0x20 => Tag::Other,
+                    _ => return Err(Error::InvalidAcl),
+                };
+                let perm = u16::from_le_bytes([e[2], e[3]]);
+                Ok(Entry { tag, perm })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { entries })
+    }
+    fn perm(&self, tag: Tag) -> Option<u16> {
+        self.entries.iter().find(|e| e.tag == tag).map(|e| e.perm)
+    }
+    /// Whether the ACL of the file `attr` grants `mask` (a combination of `R_OK`, `W_OK` and
+    /// `X_OK`) to `uid`/`gid`, ignoring supplementary groups. Root is not treated specially.
+    pub fn permits(&self, attr: &FileAttrLite, uid: u32, gid: u32, mask: i32) -> bool {
+        let mask = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+        let granted = |perm: u16| perm & mask == mask;
+        let acl_mask = self.perm(Tag::Mask).unwrap_or(0o7);
+        if uid == attr.uid {
+            return self.perm(Tag::UserObj).is_some_and(granted);
+        }
+        if let Some(perm) = self.perm(Tag::User(uid)) {
+            return granted(perm & acl_mask);
+        }
+        let groups: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|e| match e.tag {
+                Tag::GroupObj => gid == attr.gid,
+                Tag::Group(g) => gid == g,
+                _ => false,
+            })
+            .collect();
+        if !groups.is_empty() {
+            return groups.iter().any(|e| granted(e.perm & acl_mask));
+        }
+        self.perm(Tag::Other).is_some_and(granted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FileKind;
+
+    fn encode(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut data = VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            data.extend(tag.to_le_bytes());
+            data.extend(perm.to_le_bytes());
+            data.extend(id.to_le_bytes());
+        }
+        data
+    }
+    #[test]
+    fn acl_test() {
+        let acl = Acl::parse(&encode(&[
+            (0x01, 0o6, u32::MAX),
+            (0x02, 0o7, 1001),
+            (0x04, 0o4, u32::MAX),
+            (0x08, 0o6, 200),
+            (0x10, 0o5, u32::MAX),
+            (0x20, 0o0, u32::MAX),
+        ]))
+        .unwrap();
+        assert_eq!(acl.entries.len(), 6);
+        assert_eq!(acl.entries[1].tag, Tag::User(1001));
+        let attr = FileAttrLite {
+            inode: 1,
+            size: 0,
+            kind: FileKind::File,
+            mode: 0o640,
+            uid: 1000,
+            gid: 100,
+            mtime: std::time::UNIX_EPOCH,
+            crtime: std::time::UNIX_EPOCH,
+            nlink: 1,
+        };
+        // Owner
+        assert!(acl.permits(&attr, 1000, 1, libc::R_OK | libc::W_OK));
+        // Named user, restricted by the mask
+        assert!(acl.permits(&attr, 1001, 1, libc::R_OK | libc::X_OK));
+        assert!(!acl.permits(&attr, 1001, 1, libc::W_OK));
+        // Owning and named groups
+        assert!(acl.permits(&attr, 1002, 100, libc::R_OK));
+        assert!(acl.permits(&attr, 1002, 200, libc::R_OK));
+        assert!(!acl.permits(&attr, 1002, 200, libc::W_OK));
+        // Others
+        assert!(!acl.permits(&attr, 1002, 1, libc::R_OK));
+        assert!(acl.permits(&attr, 1002, 1, libc::F_OK));
+
+        assert!(Acl::parse(&[]).is_err());
+        assert!(Acl::parse(&encode(&[(0x40, 0, 0)])).is_err());
+        assert!(Acl::parse(&encode(&[(0x01, 0, 0)])[..10]).is_err());
+    }
+}
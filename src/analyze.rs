@@ -0,0 +1,492 @@
+//! Statistics on the contents of an image, to help choosing the block size and compression when
+//! building it. See [`SquashFs::analyze`] and [`SquashFs::advise`].
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+use std::path::{Path, PathBuf};
+
+use async_compression::tokio::bufread::{XzEncoder, ZlibEncoder, ZstdEncoder};
+use async_compression::Level;
+use futures::{StreamExt, TryStreamExt};
+use tokio::io::AsyncReadExt;
+
+use crate::dir_stats::DiskUsage;
+use crate::inodes;
+use crate::{AsyncSeekBufRead, Compression, Error, ReadHints, SquashFs};
+
+/// Number and total size (bytes) of items.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Count {
+    pub count: u64,
+    pub size: u64,
+}
+impl Count {
+    fn add(&mut self, size: u64) {
+        self.count += 1;
+        self.size += size;
+    }
+}
+
+/// Report of [`SquashFs::analyze`]. Hard links are counted once.
+#[derive(Debug, Default, Clone)]
+pub struct Analysis {
+    /// Files and their apparent size, by extension (lowercase, empty for files without
+    /// extension)
+    pub extensions: BTreeMap<String, Count>,
+    /// Largest files (path relative to the root, apparent size), the largest first
+    pub largest: Vec<(PathBuf, u64)>,
+    /// Sizes of the files directly in each directory (path relative to the root), for the
+    /// directories containing files
+    pub directories: BTreeMap<PathBuf, DiskUsage>,
+    /// Data blocks stored compressed, with their size in the image
+    pub compressed_blocks: Count,
+    /// Data blocks stored uncompressed, as compressing them did not save space
+    pub uncompressed_blocks: Count,
+    /// Sparse (zero-filled) blocks, which take no space
+    pub sparse_blocks: u64,
+    /// Tail ends of files stored in fragments, with their uncompressed size
+    pub fragments: Count,
+}
+impl Analysis {
+    /// Directories sorted by decreasing on-disk size of their files.
+    pub fn directories_by_size(&self) -> Vec<(&Path, &DiskUsage)> {
+        let mut directories: Vec<_> = self
+            .directories
+            .iter()
+            .map(|(path, usage)| (path.as_path(), usage))
+            .collect();
+        directories.sort_by_key(|(_, usage)| Reverse(usage.on_disk));
+        directories
+    }
+}
+/// Ratio of the on-disk to the apparent size.
+fn ratio(usage: &DiskUsage) -> f64 {
+    usage.on_disk as f64 / usage.apparent.max(1) as f64
+}
+impl std::fmt::Display for Analysis {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mb = |x: u64| x as f64 / 1e6;
+        writeln!(f, "Extensions (files, MB):")?;
+        let mut extensions: Vec<_> = self.extensions.iter().collect();
+        extensions.sort_by_key(|(_, count)| Reverse(count.size));
+        for (extension, count) in extensions {
+            let extension = if extension.is_empty() {
+                "(none)"
+            } else {
+                extension
+            };
+            writeln!(
+                f,
+                "  {:<16} {:>8} {:>12.1}",
+                extension,
+                count.count,
+                mb(count.size)
+            )?;
+        }
+        writeln!(f, "Largest files (MB):")?;
+        for (path, size) in &self.largest {
+            writeln!(f, "  {:>12.1} /{}", mb(*size), path.display())?;
+        }
+        writeln!(f, "Largest directories (files, MB, MB on disk, ratio):")?;
+        for (path, usage) in self
+            .directories_by_size()
+            .into_iter()
+            .take(self.largest.len())
+        {
+            writeln!(
+                f,
+                "  {:>8} {:>12.1} {:>12.1} {:>6.2} /{}",
+                usage.files,
+                mb(usage.apparent),
+                mb(usage.on_disk),
+                ratio(usage),
+                path.display()
+            )?;
+        }
+        writeln!(
+            f,
+            "Blocks: {} compressed ({:.1} MB), {} uncompressed ({:.1} MB), {} sparse",
+            self.compressed_blocks.count,
+            mb(self.compressed_blocks.size),
+            self.uncompressed_blocks.count,
+            mb(self.uncompressed_blocks.size),
+            self.sparse_blocks
+        )?;
+        write!(
+            f,
+            "Fragments: {} tail ends ({:.1} MB)",
+            self.fragments.count,
+            mb(self.fragments.size)
+        )
+    }
+}
+
+/// Layout of the data of an image built from the same files with other `mksquashfs` options,
+/// see [`SquashFs::advise`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Simulation {
+    /// `-b` option
+    pub block_size: u32,
+    /// `-comp` option
+    pub compression: Compression,
+    /// Data blocks, including the last short blocks of the files without fragments
+    pub blocks: u64,
+    /// Tail ends of files stored in fragments
+    pub tail_ends: u64,
+    /// Fragment blocks, with the tail ends packed in the order of the directory tables
+    pub fragment_blocks: u64,
+    /// Size of the block lists of the file inodes and of the fragments table (bytes, before
+    /// compression)
+    pub metadata: u64,
+    /// Size of the data blocks and fragment blocks (bytes), estimated from the compression of a
+    /// sample of the files. `None` without sample, or for compressions that are not supported
+    /// (only gzip, xz and zstd are).
+    pub data: Option<u64>,
+}
+impl std::fmt::Display for Simulation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>8} {:>5} {:>10} {:>10} {:>10} {:>12.1}",
+            self.block_size / 1024,
+            self.compression,
+            self.blocks,
+            self.tail_ends,
+            self.fragment_blocks,
+            self.metadata as f64 / 1e6,
+        )?;
+        match self.data {
+            Some(data) => write!(f, " {:>12.1}", data as f64 / 1e6),
+            None => write!(f, " {:>12}", "-"),
+        }
+    }
+}
+
+/// Report of [`SquashFs::advise`].
+#[derive(Debug, Clone)]
+pub struct Advice {
+    /// Actual layout of the image. Its data size is the one of the data section of the image.
+    pub image: Simulation,
+    /// Simulated layouts, in the order of the requested options
+    pub simulations: Vec<Simulation>,
+    /// Size of the sample used to estimate the compression (bytes)
+    pub sample: u64,
+}
+impl std::fmt::Display for Advice {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:>8} {:>5} {:>10} {:>10} {:>10} {:>12} {:>12}",
+            "-b (KiB)", "-comp", "blocks", "tail ends", "fragments", "metadata MB", "data MB"
+        )?;
+        writeln!(f, "{} (image)", self.image)?;
+        for simulation in &self.simulations {
+            writeln!(f, "{}", simulation)?;
+        }
+        write!(
+            f,
+            "Data sizes estimated from a sample of {:.1} MB",
+            self.sample as f64 / 1e6
+        )
+    }
+}
+
+/// Size of `data` compressed as a block of `mksquashfs`, with its default level, or `None` if
+/// the compression is not supported. Blocks that do not compress are stored as is.
+async fn compressed_size(compression: Compression, data: &[u8]) -> Option<u64> {
+    let mut encoder: Box<dyn tokio::io::AsyncRead + Unpin + Send> = match compression {
+        Compression::Gzip => Box::new(ZlibEncoder::with_quality(data, Level::Precise(9))),
+        Compression::Xz => Box::new(XzEncoder::with_quality(data, Level::Precise(6))),
+        Compression::Zstd => Box::new(ZstdEncoder::with_quality(data, Level::Precise(15))),
+        _ => return None,
+    };
+    let mut compressed = vec![];
+    encoder.read_to_end(&mut compressed).await.ok()?;
+    Some((compressed.len() as u64).min(data.len() as u64))
+}
+
+impl<T, R> SquashFs<R>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+{
+    /// Statistics on the files of the image, with the `largest` largest files.
+    ///
+    /// This only uses the tables, without reading any data.
+    pub async fn analyze(&self, largest: usize) -> Result<Analysis, Error> {
+        let inode_table = self.inode_table.wait().await?;
+        let mut analysis = Analysis::default();
+        let mut seen = BTreeSet::new();
+        // Smallest of the largest files first
+        let mut files = BinaryHeap::new();
+        let mut entries = std::pin::pin!(self.walk());
+        while let Some((path, entry)) = entries.try_next().await? {
+            let Some(file) = inode_table.files.get(&entry.inode) else {
+                continue;
+            };
+            if !seen.insert(entry.inode) {
+                continue;
+            }
+            let size = file.file_size();
+            let extension = path
+                .extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            analysis.extensions.entry(extension).or_default().add(size);
+            let mut on_disk = 0;
+            for block in file.block_sizes() {
+                let compressed_size = block.compressed_size();
+                on_disk += compressed_size;
+                if compressed_size == 0 {
+                    analysis.sparse_blocks += 1;
+                } else if block.compressed() {
+                    analysis.compressed_blocks.add(compressed_size);
+                } else {
+                    analysis.uncompressed_blocks.add(compressed_size);
+                }
+            }
+            let tail = file.fragment_size(&self.superblock);
+            if file.fragment().valid() && tail > 0 {
+                analysis.fragments.add(tail);
+            }
+            let directory = analysis
+                .directories
+                .entry(path.parent().unwrap_or(Path::new("")).to_owned())
+                .or_default();
+            directory.files += 1;
+            directory.apparent += size;
+            directory.on_disk += on_disk + tail;
+            files.push(Reverse((size, path)));
+            if files.len() > largest {
+                files.pop();
+            }
+        }
+        analysis.largest = files
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((size, path))| (path, size))
+            .collect();
+        Ok(analysis)
+    }
+    /// Simulate the layout of the image built from the same files with each of the
+    /// `block_sizes` (powers of two between 4 KiB and 1 MiB) and `compressions` (`mksquashfs -b`
+    /// and `-comp`), keeping the other options (i.e. whether fragments are used).
+    ///
+    /// The number of blocks and fragments and the size of the metadata depending on them are
+    /// derived from the tables. The size of the data is extrapolated from the compression of up
+    /// to `sample` bytes, read from the start of files spread over the image. Sparse blocks and
+    /// duplicate files are not taken into account.
+    pub async fn advise(
+        &self,
+        block_sizes: &[u32],
+        compressions: &[Compression],
+        sample: u64,
+    ) -> Result<Advice, Error> {
+        let inode_table = self.inode_table.wait().await?;
+        let mut seen = BTreeSet::new();
+        // Sizes of the files, in the order of the directory tables
+        let mut files = vec![];
+        let mut fragments = false;
+        let mut image = Simulation {
+            block_size: self.superblock.block_size,
+            compression: self.superblock.compression,
+            blocks: 0,
+            tail_ends: 0,
+            fragment_blocks: self.superblock.fragment_entry_count as u64,
+            metadata: 0,
+            data: Some(self.superblock.inode_table_start.saturating_sub(96)),
+        };
+        let mut entries = std::pin::pin!(self.walk());
+        while let Some((_, entry)) = entries.try_next().await? {
+            let Some(file) = inode_table.files.get(&entry.inode) else {
+                continue;
+            };
+            if !seen.insert(entry.inode) {
+                continue;
+            }
+            fragments |= file.fragment().valid();
+            image.blocks += file.block_sizes().len() as u64;
+            if file.fragment().valid() && file.fragment_size(&self.superblock) > 0 {
+                image.tail_ends += 1;
+            }
+            files.push((entry.inode, file.file_size()));
+        }
+        image.metadata = 4 * image.blocks + 16 * image.fragment_blocks;
+
+        let sample = self.sample(&files, block_sizes, sample).await?;
+        let sample_size: u64 = sample.iter().map(|data| data.len() as u64).sum();
+        let total: u64 = files.iter().map(|(_, size)| size).sum();
+        let mut simulations = vec![];
+        for &block_size in block_sizes {
+            let mut simulation = Simulation {
+                block_size,
+                compression: self.superblock.compression,
+                blocks: 0,
+                tail_ends: 0,
+                fragment_blocks: 0,
+                metadata: 0,
+                data: None,
+            };
+            let mut packer = FragmentPacker::new(block_size as u64);
+            for (_, size) in &files {
+                let tail = inodes::fragment_size(*size, block_size as u64, fragments);
+                simulation.blocks += inodes::n_blocks(*size, block_size as u64, fragments);
+                if tail > 0 {
+                    simulation.tail_ends += 1;
+                    packer.add(tail);
+                }
+            }
+            simulation.fragment_blocks = packer.finish().len() as u64;
+            simulation.metadata = 4 * simulation.blocks + 16 * simulation.fragment_blocks;
+            for &compression in compressions {
+                let data = match sample_size {
+                    0 => None,
+                    _ => self
+                        .sample_compressed_size(&sample, block_size, compression, fragments)
+                        .await
+                        .map(|size| (size as f64 / sample_size as f64 * total as f64) as u64),
+                };
+                simulations.push(Simulation {
+                    compression,
+                    data,
+                    ..simulation.clone()
+                });
+            }
+        }
+        Ok(Advice {
+            image,
+            simulations,
+            sample: sample_size,
+        })
+    }
+    /// Read up to `max` bytes from the start of `files` (inode, size), taking at most the
+    /// largest of the `block_sizes` from each, from files spread over the list.
+    async fn sample(
+        &self,
+        files: &[(u32, u64)],
+        block_sizes: &[u32],
+        max: u64,
+    ) -> Result<Vec<bytes::Bytes>, Error> {
+        let chunk = block_sizes.iter().copied().max().unwrap_or_default() as u64;
+        let mean =
+            files.iter().map(|(_, size)| size.min(&chunk)).sum::<u64>() / files.len().max(1) as u64;
+        let step = files.len() / (max / mean.max(1)).max(1) as usize;
+        let mut selected = vec![];
+        let mut size = 0;
+        for (inode, file_size) in files.iter().step_by(step.max(1)) {
+            if size >= max {
+                break;
+            }
+            let read = (*file_size).min(chunk).min(max - size);
+            if read > 0 {
+                selected.push((*inode, read));
+                size += read;
+            }
+        }
+        let hints = ReadHints {
+            retain: false,
+            ..ReadHints::background()
+        };
+        futures::stream::iter(selected)
+            .map(|(inode, size)| {
+                self.read_file_with(
+                    inode,
+                    0,
+                    size as usize,
+                    0,
+                    self.superblock.compression,
+                    hints,
+                )
+            })
+            .buffered(self.n_readers.max(1))
+            .try_collect()
+            .await
+    }
+    /// Size of the `sample` (starts of files) compressed with `compression` in blocks of
+    /// `block_size`, or `None` if the compression is not supported.
+    async fn sample_compressed_size(
+        &self,
+        sample: &[bytes::Bytes],
+        block_size: u32,
+        compression: Compression,
+        fragments: bool,
+    ) -> Option<u64> {
+        let mut size = 0;
+        let mut packer = FragmentPacker::new(block_size as u64);
+        for data in sample {
+            for block in data.chunks(block_size as usize) {
+                if fragments && block.len() < block_size as usize {
+                    packer.add_data(block);
+                } else {
+                    size += compressed_size(compression, block).await?;
+                }
+            }
+        }
+        for fragment in packer.finish() {
+            size += compressed_size(compression, &fragment).await?;
+        }
+        Some(size)
+    }
+}
+
+/// Packing of tail ends in fragment blocks as `mksquashfs` does: a tail end goes into the
+/// current fragment block if it fits, otherwise into a new one.
+struct FragmentPacker {
+    block_size: u64,
+    /// Completed blocks and the current one, with their data if tracked
+    blocks: Vec<Vec<u8>>,
+    used: u64,
+}
+impl FragmentPacker {
+    fn new(block_size: u64) -> Self {
+        Self {
+            block_size,
+            blocks: vec![],
+            used: 0,
+        }
+    }
+    fn next_block(&mut self, size: u64) -> &mut Vec<u8> {
+        if self.blocks.is_empty() || self.used + size > self.block_size {
+            self.blocks.push(vec![]);
+            self.used = 0;
+        }
+        self.used += size;
+        self.blocks.last_mut().unwrap()
+    }
+    fn add(&mut self, size: u64) {
+        self.next_block(size);
+    }
+    fn add_data(&mut self, data: &[u8]) {
+        self.next_block(data.len() as u64).extend_from_slice(data);
+    }
+    fn finish(self) -> Vec<Vec<u8>> {
+        self.blocks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn fragment_packer_test() {
+        let mut packer = FragmentPacker::new(10);
+        for size in [4, 4, 2, 10, 1] {
+            packer.add(size);
+        }
+        assert_eq!(packer.finish().len(), 3);
+        let mut packer = FragmentPacker::new(10);
+        packer.add_data(b"abcdef");
+        packer.add_data(b"ghij");
+        packer.add_data(b"k");
+        assert_eq!(packer.finish(), [b"abcdefghij".to_vec(), b"k".to_vec()]);
+    }
+    #[tokio::test]
+    async fn compressed_size_test() {
+        let data = vec![0; 4096];
+        for compression in [Compression::Gzip, Compression::Xz, Compression::Zstd] {
+            assert!(compressed_size(compression, &data).await.unwrap() < 1024);
+        }
+        // Stored uncompressed
+        assert_eq!(compressed_size(Compression::Zstd, b"a").await, Some(1));
+        assert_eq!(compressed_size(Compression::Lz4, &data).await, None);
+    }
+}
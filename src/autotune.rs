@@ -0,0 +1,222 @@
+//! Runtime tuning of [`crate::Options::direct_limit`] and of the readahead of sequential reads,
+//! see [`crate::Options::auto_tune`].
+//!
+//! Reading a small file at once is worth it when its size is below what the backend
+//! can transfer during the fixed latency of a request: beyond that, the buffering of the backend
+//! amortizes the latency anyway. Likewise, sequential reads keep the backend busy when they are
+//! ahead by a few times what it transfers during that latency. The latency and throughput of the
+//! backend are estimated with a few probing reads when opening the image, and then refined with
+//! the reads that are served.
+use std::ops::DerefMut;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use deadpool::managed::Pool;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::*;
+
+use crate::Error;
+
+/// Reads up to this size are used to estimate the latency, larger ones the throughput.
+const LATENCY_READ: u64 = 64 * 1024;
+/// Size of the probing reads for the throughput.
+const THROUGHPUT_READ: u64 = 1024 * 1024;
+/// Number of probing reads of each kind.
+const PROBES: u64 = 4;
+/// Weight of new observations in the moving averages.
+const SMOOTHING: f64 = 0.05;
+/// Minimal number of small files cache lookups before taking the hit rate into account.
+const MIN_LOOKUPS: u64 = 100;
+/// Sequential reads are read ahead by what the backend transfers during this many latencies.
+const READAHEAD_LATENCIES: f64 = 4.0;
+
+/// Moving average, stored as the bits of a `f64` so that reads can update it without locking.
+#[derive(Debug)]
+struct Estimate(AtomicU64);
+impl Estimate {
+    fn new(value: f64) -> Self {
+        Self(AtomicU64::new(value.to_bits()))
+    }
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+    /// Move the average towards `observation`.
+    fn record(&self, observation: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let value = f64::from_bits(bits);
+                Some((value + SMOOTHING * (observation - value)).to_bits())
+            });
+    }
+}
+
+/// Estimates of the backend performance, and the resulting direct limit and readahead.
+#[derive(Debug)]
+pub(crate) struct AutoTuner {
+    /// Seconds
+    latency: Estimate,
+    /// Bytes per second
+    throughput: Estimate,
+    direct_limit: AtomicUsize,
+    readahead: AtomicU64,
+    /// Upper bound for the direct limit and the readahead, given the cache size.
+    max_limit: usize,
+    hits: AtomicU64,
+    lookups: AtomicU64,
+}
+impl AutoTuner {
+    /// Probe the backend with reads spread over the first `image_size` bytes.
+    pub async fn calibrate<R>(
+        pool: &Pool<R>,
+        image_size: u64,
+        max_limit: usize,
+    ) -> Result<Self, Error>
+    where
+        R: deadpool::managed::Manager<Error = std::io::Error>,
+        R::Type: crate::AsyncSeekBufRead,
+    {
+        let mut r = pool.get().await?;
+        let r = r.deref_mut();
+        let offset = |size: u64, i: u64| (image_size - size.min(image_size)) / PROBES * i;
+        let mut latency = Duration::MAX;
+        for i in 0..PROBES {
+            latency = latency.min(probe(&mut *r, offset(4096, i), 4096).await?.1);
+        }
+        let mut throughput: f64 = 0.0;
+        for i in 0..PROBES {
+            let (bytes, elapsed) =
+                probe(&mut *r, offset(THROUGHPUT_READ, i), THROUGHPUT_READ).await?;
+            let transfer = elapsed
+                .saturating_sub(latency)
+                .max(Duration::from_micros(1));
+            throughput = throughput.max(bytes as f64 / transfer.as_secs_f64());
+        }
+        let tuner = Self {
+            latency: Estimate::new(latency.as_secs_f64()),
+            throughput: Estimate::new(throughput),
+            direct_limit: AtomicUsize::new(0),
+            readahead: AtomicU64::new(0),
+            max_limit,
+            hits: AtomicU64::new(0),
+            lookups: AtomicU64::new(0),
+        };
+        tuner.update();
+        info!("Auto-tuning: {}", tuner);
+        Ok(tuner)
+    }
+    pub fn direct_limit(&self) -> usize {
+        self.direct_limit.load(Ordering::Relaxed)
+    }
+    /// Bytes to read ahead of sequential reads.
+    pub fn readahead(&self) -> u64 {
+        self.readahead.load(Ordering::Relaxed)
+    }
+    /// Record a lookup in the small files cache.
+    pub fn record_lookup(&self, hit: bool) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// Record a read of `bytes` from the backend, which took `elapsed`.
+    pub fn record_read(&self, bytes: u64, elapsed: Duration) {
+        if bytes <= LATENCY_READ {
+            self.latency.record(elapsed.as_secs_f64());
+        } else {
+            let transfer = (elapsed.as_secs_f64() - self.latency.get()).max(1e-6);
+            self.throughput.record(bytes as f64 / transfer);
+        }
+        self.update();
+    }
+    /// Hit rate of the small files cache, once there are enough lookups.
+    fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.lookups.load(Ordering::Relaxed);
+        (lookups >= MIN_LOOKUPS).then(|| self.hits.load(Ordering::Relaxed) as f64 / lookups as f64)
+    }
+    fn update(&self) {
+        let transferred = self.latency.get() * self.throughput.get();
+        // Files that are rarely read again gain less from being cached whole, and take space
+        // from the other entries of the cache.
+        let factor = 0.5 + 0.5 * self.hit_rate().unwrap_or(1.0);
+        let limit = (transferred * factor) as usize;
+        self.direct_limit
+            .store(limit.min(self.max_limit), Ordering::Relaxed);
+        let readahead = (transferred * READAHEAD_LATENCIES) as u64;
+        self.readahead
+            .store(readahead.min(self.max_limit as u64), Ordering::Relaxed);
+    }
+}
+impl std::fmt::Display for AutoTuner {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "latency {:.2} ms, throughput {:.1} MB/s, direct limit {} B, readahead {} B",
+            self.latency.get() * 1e3,
+            self.throughput.get() / 1e6,
+            self.direct_limit(),
+            self.readahead()
+        )?;
+        if let Some(hit_rate) = self.hit_rate() {
+            write!(f, ", small files hit rate {:.0}%", hit_rate * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read `size` bytes at `start`, returning the number of bytes read and the duration.
+async fn probe(
+    mut r: impl crate::AsyncSeekBufRead,
+    start: u64,
+    size: u64,
+) -> Result<(u64, Duration), Error> {
+    let t = Instant::now();
+    r.seek(std::io::SeekFrom::Start(start))
+        .await
+        .map_err(Error::ReadFailure)?;
+    let mut buf = Vec::with_capacity(size as usize);
+    r.take(size)
+        .read_to_end(&mut buf)
+        .await
+        .map_err(Error::ReadFailure)?;
+    Ok((buf.len() as u64, t.elapsed()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn autotune_test() {
+        // 1 ms, 100 MB/s
+        let tuner = AutoTuner {
+            latency: Estimate::new(1e-3),
+            throughput: Estimate::new(1e8),
+            direct_limit: AtomicUsize::new(0),
+            readahead: AtomicU64::new(0),
+            max_limit: 1_000_000,
+            hits: AtomicU64::new(0),
+            lookups: AtomicU64::new(0),
+        };
+        tuner.update();
+        assert_eq!(tuner.direct_limit(), 100_000);
+        assert_eq!(tuner.readahead(), 400_000);
+        // Slower reads raise the latency, and then the limits up to their bound
+        for _ in 0..1000 {
+            tuner.record_read(4096, Duration::from_millis(20));
+        }
+        assert!((tuner.latency.get() - 2e-2).abs() < 1e-4);
+        assert_eq!(tuner.direct_limit(), 1_000_000);
+        assert_eq!(tuner.readahead(), 1_000_000);
+        // Rarely hit small files
+        for _ in 0..MIN_LOOKUPS {
+            tuner.record_lookup(false);
+        }
+        for _ in 0..1000 {
+            tuner.record_read(10_000_000, Duration::from_millis(520));
+        }
+        // 20 ms, 20 MB/s, with a direct limit halved by the hit rate
+        assert_eq!(tuner.readahead(), 1_000_000);
+        assert!((190_000..210_000).contains(&tuner.direct_limit()));
+    }
+}
@@ -0,0 +1,89 @@
+//! Entry points into the read path, for the benchmarks in `benches/`.
+//!
+//! These bypass the caches, so that each iteration measures the actual reading and decoding.
+use std::ops::DerefMut;
+
+use tokio::io::AsyncSeekExt;
+
+use crate::data::{read_data_block, Priority, ReadHints};
+use crate::metadata::MetadataBlock;
+use crate::{AsyncSeekBufRead, Error, SquashFs};
+
+impl<T, R> SquashFs<R>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+{
+    /// Read and decode the `index`-th regular data block of the file `inode`.
+    pub async fn bench_read_data_block(
+        &self,
+        inode: u32,
+        index: usize,
+    ) -> Result<bytes::BytesMut, Error> {
+        let inode_table = self.inode_table.wait().await?;
+        let file = inode_table
+            .files
+            .get(&inode)
+            .ok_or(Error::FileNotFound(None))?;
+        let location = file
+            .data_locations()
+            .nth(index)
+            .ok_or(Error::InvalidOffset)?;
+        let block_size = self.superblock.block_size as u64;
+        let expected = file
+            .file_size()
+            .saturating_sub(index as u64 * block_size)
+            .min(block_size);
+        let mut buf = bytes::BytesMut::with_capacity(block_size as usize);
+        let mut r = self.get_reader(0).await?;
+        read_data_block(
+            r.deref_mut(),
+            0,
+            location.block_start,
+            location.block_size,
+            &mut buf,
+            None,
+            (None, None),
+            (&self.superblock.codec(), expected..=expected),
+            (self.decompression.as_ref(), Priority::Foreground, None),
+        )
+        .await?;
+        Ok(buf)
+    }
+    /// Read the whole file `inode` with [`SquashFs::read_file_impl`].
+    pub async fn bench_read_file(&self, inode: u32) -> Result<bytes::Bytes, Error> {
+        let inode_table = self.inode_table.wait().await?;
+        let file = inode_table
+            .files
+            .get(&inode)
+            .ok_or(Error::FileNotFound(None))?;
+        let mut r = self.get_reader(0).await?;
+        self.read_file_impl(
+            file,
+            (r.deref_mut(), 0, None),
+            inode,
+            (0, file.file_size() as usize),
+            self.superblock.compression,
+            ReadHints::default(),
+        )
+        .await
+    }
+    /// Decompress and flatten the metadata blocks of the inode table, returning the
+    /// uncompressed size.
+    pub async fn bench_flatten_inode_table(&self) -> Result<u64, Error> {
+        let mut r = self.get_reader(0).await?;
+        r.seek(std::io::SeekFrom::Start(self.superblock.inode_table_start))
+            .await
+            .map_err(Error::ReadFailure)?;
+        let r = MetadataBlock::from_reader_flatten(
+            r.deref_mut(),
+            self.superblock.directory_table_start,
+            self.superblock.codec(),
+        )
+        .await?;
+        let size = tokio::io::copy(&mut Box::pin(r), &mut tokio::io::sink())
+            .await
+            .map_err(Error::ReadFailure)?;
+        Ok(size)
+    }
+}
@@ -0,0 +1,277 @@
+//! Caches of decoded data, pluggable through [`crate::Options::caches`].
+//!
+//! An image has two caches: decoded data blocks, keyed by their offset in the image, and whole
+//! small files, keyed by inode (see [`crate::Options::direct_limit`]). By default, these are the
+//! [`IndexCache`] and [`LRUCache`] of [`fuser_async`]. Any [`DataBlockCache`] can be used
+//! instead, or any [`BlockCache`], e.g. to keep the blocks in shared memory.
+//!
+//! With [`crate::Options::compressed_cache`], the data blocks are kept recompressed in a
+//! [`CompressedCache`].
+//!
+//! As keys are offsets and inodes, these caches must not be shared between images; see
+//! [`crate::content_cache`] for that.
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use fuser_async::cache::{DataBlockCache, IndexCache, LRUCache};
+
+use crate::error::DecompressError;
+use crate::Error;
+
+/// Cache of decoded blocks.
+///
+/// Implemented for every [`DataBlockCache`].
+#[async_trait::async_trait]
+pub trait BlockCache: std::fmt::Display + Send + Sync {
+    /// Append `range` of the block `key` to `buf`. Returns `false` if the block is not cached or
+    /// is shorter than `range`.
+    async fn get(&self, key: u64, range: Range<usize>, buf: &mut bytes::BytesMut) -> bool;
+    async fn insert(&self, key: u64, data: &[u8]) -> Result<(), Error>;
+}
+
+#[async_trait::async_trait]
+impl<C: DataBlockCache<Box<Error>>> BlockCache for C {
+    async fn get(&self, key: u64, range: Range<usize>, buf: &mut bytes::BytesMut) -> bool {
+        match DataBlockCache::<Box<Error>>::get(self, key).await {
+            Some(block) if block.data.len() >= range.end => {
+                buf.extend_from_slice(&block.data[range]);
+                true
+            }
+            _ => false,
+        }
+    }
+    async fn insert(&self, key: u64, data: &[u8]) -> Result<(), Error> {
+        self.insert_lock(key, async { Ok(data) }).await?;
+        Ok(())
+    }
+}
+
+/// Sizes of a cache, as passed to [`DataBlockCache::new`].
+#[derive(Debug, Clone, Copy)]
+pub struct CacheSizes {
+    /// See [`crate::Options::cache_mb`].
+    pub capacity_mb: u64,
+    /// Maximal size of the blocks.
+    pub block_size: u64,
+    /// Total size of the cached data, e.g. the size of the image.
+    pub total_size: u64,
+}
+
+type Constructor = Arc<dyn Fn(CacheSizes) -> Result<Arc<dyn BlockCache>, Error> + Send + Sync>;
+
+fn constructor<C: DataBlockCache<Box<Error>> + 'static>() -> Constructor {
+    Arc::new(|sizes: CacheSizes| {
+        let cache = C::new(sizes.capacity_mb, sizes.block_size, sizes.total_size)?;
+        Ok(Arc::new(cache) as Arc<dyn BlockCache>)
+    })
+}
+
+/// Constructors of the caches of an image, see the [module](self) documentation.
+#[derive(Clone)]
+pub struct CacheBuilder {
+    blocks: Constructor,
+    small_files: Constructor,
+}
+impl Default for CacheBuilder {
+    fn default() -> Self {
+        Self {
+            blocks: constructor::<IndexCache>(),
+            small_files: constructor::<LRUCache>(),
+        }
+    }
+}
+impl std::fmt::Debug for CacheBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("CacheBuilder").finish_non_exhaustive()
+    }
+}
+impl CacheBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Use a `C` cache for the data blocks.
+    pub fn blocks<C: DataBlockCache<Box<Error>> + 'static>(mut self) -> Self {
+        self.blocks = constructor::<C>();
+        self
+    }
+    /// Construct the data blocks cache with `f`.
+    pub fn blocks_with(
+        mut self,
+        f: impl Fn(CacheSizes) -> Result<Arc<dyn BlockCache>, Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.blocks = Arc::new(f);
+        self
+    }
+    /// Use a `C` cache for the small files.
+    pub fn small_files<C: DataBlockCache<Box<Error>> + 'static>(mut self) -> Self {
+        self.small_files = constructor::<C>();
+        self
+    }
+    /// Construct the small files cache with `f`.
+    pub fn small_files_with(
+        mut self,
+        f: impl Fn(CacheSizes) -> Result<Arc<dyn BlockCache>, Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.small_files = Arc::new(f);
+        self
+    }
+    pub(crate) fn build_blocks(&self, sizes: CacheSizes) -> Result<Arc<dyn BlockCache>, Error> {
+        (self.blocks)(sizes)
+    }
+    pub(crate) fn build_small_files(
+        &self,
+        sizes: CacheSizes,
+    ) -> Result<Arc<dyn BlockCache>, Error> {
+        (self.small_files)(sizes)
+    }
+}
+
+/// Cache of blocks recompressed with zstd at a fast level, holding several times more blocks
+/// than a plain cache of the same capacity, at the cost of a compression on each insertion and
+/// a decompression on each hit.
+///
+/// This is worthwhile when the backend is slow (e.g. a network store) and memory is limited.
+/// Blocks are evicted in least-recently used order once the compressed data exceeds the
+/// capacity.
+pub struct CompressedCache {
+    capacity: usize,
+    level: i32,
+    state: Mutex<CompressedState>,
+}
+#[derive(Default)]
+struct CompressedState {
+    /// Compressed data, decompressed size and last access of the blocks
+    blocks: HashMap<u64, (Vec<u8>, usize, u64)>,
+    /// Blocks by last access
+    accesses: BTreeMap<u64, u64>,
+    tick: u64,
+    /// Total compressed and decompressed sizes
+    size: usize,
+    raw_size: usize,
+}
+impl CompressedState {
+    fn touch(&mut self, key: u64) {
+        self.tick += 1;
+        if let Some((_, _, access)) = self.blocks.get_mut(&key) {
+            self.accesses.remove(access);
+            *access = self.tick;
+            self.accesses.insert(self.tick, key);
+        }
+    }
+    fn remove(&mut self, key: u64) {
+        if let Some((data, raw_size, access)) = self.blocks.remove(&key) {
+            self.accesses.remove(&access);
+            self.size -= data.len();
+            self.raw_size -= raw_size;
+        }
+    }
+}
+impl std::fmt::Display for CompressedCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let state = self.state.lock().unwrap();
+        write!(
+            f,
+            "Compressed cache: {} blocks, {:.1}/{:.1} MB (ratio {:.2})",
+            state.blocks.len(),
+            state.size as f64 / 1e6,
+            self.capacity as f64 / 1e6,
+            state.size as f64 / state.raw_size.max(1) as f64
+        )
+    }
+}
+impl CompressedCache {
+    /// Cache holding up to `capacity_mb` MB of compressed data.
+    pub fn new(capacity_mb: u64) -> Self {
+        Self {
+            capacity: (capacity_mb * (1e6 as u64)) as usize,
+            level: 1,
+            state: Default::default(),
+        }
+    }
+    /// zstd compression level, 1 by default.
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+}
+#[async_trait::async_trait]
+impl BlockCache for CompressedCache {
+    async fn get(&self, key: u64, range: Range<usize>, buf: &mut bytes::BytesMut) -> bool {
+        let (data, raw_size) = {
+            let mut state = self.state.lock().unwrap();
+            state.touch(key);
+            match state.blocks.get(&key) {
+                Some((data, raw_size, _)) if *raw_size >= range.end => (data.clone(), *raw_size),
+                _ => return false,
+            }
+        };
+        match zstd::bulk::decompress(&data, raw_size) {
+            Ok(block) => {
+                buf.extend_from_slice(&block[range]);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+    async fn insert(&self, key: u64, data: &[u8]) -> Result<(), Error> {
+        let compressed = zstd::bulk::compress(data, self.level)
+            .map_err(|e| Error::Decompress(DecompressError::Io(e)))?;
+        if compressed.len() > self.capacity {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        state.remove(key);
+        while state.size + compressed.len() > self.capacity {
+            let oldest = match state.accesses.first_key_value() {
+                Some((_, oldest)) => *oldest,
+                None => break,
+            };
+            state.remove(oldest);
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.size += compressed.len();
+        state.raw_size += data.len();
+        state.accesses.insert(tick, key);
+        state.blocks.insert(key, (compressed, data.len(), tick));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[tokio::test]
+    async fn compressed_cache_test() {
+        let block: Vec<u8> = (0..100_000).map(|i| (i % 7) as u8).collect();
+        let cache = CompressedCache::new(1);
+        for key in 0..100 {
+            cache.insert(key, &block).await.unwrap();
+        }
+        // 100 blocks of 100 kB fit in 1 MB once compressed
+        let mut buf = bytes::BytesMut::new();
+        assert!(cache.get(0, 10..20, &mut buf).await);
+        assert_eq!(buf[..], block[10..20]);
+        assert!(!cache.get(0, 0..200_000, &mut buf).await);
+        assert!(!cache.get(100, 0..10, &mut buf).await);
+        // Incompressible blocks evict the least recently used ones
+        let mut x = 1u64;
+        let noise: Vec<u8> = (0..900_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        cache.insert(100, &noise).await.unwrap();
+        assert!(cache.get(0, 0..10, &mut buf).await);
+        cache.insert(101, &noise).await.unwrap();
+        assert!(cache.get(0, 0..10, &mut buf).await);
+        assert!(!cache.get(1, 0..10, &mut buf).await);
+        assert!(!cache.get(100, 0..10, &mut buf).await);
+        assert!(cache.get(101, 0..10, &mut buf).await);
+        assert!(cache.state.lock().unwrap().size <= 1_000_000);
+    }
+}
@@ -0,0 +1,191 @@
+//! File capabilities, as stored in the `security.capability` extended attribute (see
+//! `capabilities(7)` and `setcap(8)`).
+//!
+//! The value uses the format of the Linux kernel (`struct vfs_cap_data`): a revision and flags
+//! header, followed by the permitted and inheritable sets split in 32-bit words, and for
+//! revision 3 the root user id of the namespace, all little-endian.
+use crate::Error;
+
+/// Extended attribute holding the capabilities of a file.
+pub const CAPABILITY_XATTR: &str = "security.capability";
+
+const REVISION_MASK: u32 = 0xFF000000;
+const FLAGS_EFFECTIVE: u32 = 0x000001;
+
+/// Names of the capabilities, by bit.
+const NAMES: [&str; 41] = [
+    "chown",
+    "dac_override",
+    "dac_read_search",
+    "fowner",
+    "fsetid",
+    "kill",
+    "setgid",
+    "setuid",
+    "setpcap",
+    "linux_immutable",
+    "net_bind_service",
+    "net_broadcast",
+    "net_admin",
+    "net_raw",
+    "ipc_lock",
+    "ipc_owner",
+    "sys_module",
+    "sys_rawio",
+    "sys_chroot",
+    "sys_ptrace",
+    "sys_pacct",
+    "sys_admin",
+    "sys_boot",
+    "sys_nice",
+    "sys_resource",
+    "sys_time",
+    "sys_tty_config",
+    "mknod",
+    "lease",
+    "audit_write",
+    "audit_control",
+    "setfcap",
+    "mac_override",
+    "mac_admin",
+    "syslog",
+    "wake_alarm",
+    "block_suspend",
+    "audit_read",
+    "perfmon",
+    "bpf",
+    "checkpoint_restore",
+];
+
+/// Name of the capability `bit`, e.g. `cap_net_raw`.
+pub fn name(bit: u32) -> String {
+    match NAMES.get(bit as usize) {
+        Some(name) => format!("cap_{}", name),
+        None => format!("cap_{}", bit),
+    }
+}
+
+/// Capabilities of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Bitmask of the capabilities that are permitted
+    pub permitted: u64,
+    /// Bitmask of the capabilities that are inherited
+    pub inheritable: u64,
+    /// Whether the permitted capabilities are raised in the effective set on `execve`
+    pub effective: bool,
+    /// Root user id of the user namespace the capabilities apply to (revision 3 only)
+    pub root_id: Option<u32>,
+}
+impl Capabilities {
+    /// Parse the value of the capability extended attribute.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let word = |i: usize| u32::from_le_bytes(data[4 * i..4 * i + 4].try_into().unwrap());
+        if data.len() < 4 {
+            return Err(Error::InvalidCapability);
+        }
+        let magic = word(0);
+        let (words, size) = match magic & REVISION_MASK {
+            0x01000000 => (1, 12),
+            0x02000000 => (2, 20),
+            0x03000000 => (2, 24),
+            _ => return Err(Error::InvalidCapability),
+        };
+        if data.len() != size {
+            return Err(Error::InvalidCapability);
+        }
+        let mut capabilities = Self {
+            permitted: 0,
+            inheritable: 0,
+            effective: magic & FLAGS_EFFECTIVE != 0,
+            root_id: (size == 24).then(|| word(5)),
+        };
+        for i in 0..words {
+            capabilities.permitted |= (word(1 + 2 * i) as u64) << (32 * i);
+            capabilities.inheritable |= (word(2 + 2 * i) as u64) << (32 * i);
+        }
+        Ok(capabilities)
+    }
+    /// Whether the file grants no capability, e.g. after `setcap -r`.
+    pub fn is_empty(&self) -> bool {
+        self.permitted == 0 && self.inheritable == 0
+    }
+}
+/// Textual representation of `getcap`, e.g. `cap_net_admin,cap_net_raw=ep`.
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Capabilities grouped by their sets, in the order of their first bit
+        let mut groups: Vec<(String, Vec<String>)> = vec![];
+        for bit in 0..64 {
+            let (permitted, inheritable) = (
+                self.permitted & (1 << bit) != 0,
+                self.inheritable & (1 << bit) != 0,
+            );
+            if !permitted && !inheritable {
+                continue;
+            }
+            let mut sets = String::new();
+            if permitted && self.effective {
+                sets.push('e');
+            }
+            if inheritable {
+                sets.push('i');
+            }
+            if permitted {
+                sets.push('p');
+            }
+            match groups.iter_mut().find(|(s, _)| *s == sets) {
+                Some((_, names)) => names.push(name(bit)),
+                None => groups.push((sets, vec![name(bit)])),
+            }
+        }
+        let groups: Vec<_> = groups
+            .into_iter()
+            .map(|(sets, names)| format!("{}={}", names.join(","), sets))
+            .collect();
+        write!(f, "{}", groups.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(magic: u32, sets: &[(u32, u32)], root_id: Option<u32>) -> Vec<u8> {
+        let mut data = magic.to_le_bytes().to_vec();
+        for (permitted, inheritable) in sets {
+            data.extend(permitted.to_le_bytes());
+            data.extend(inheritable.to_le_bytes());
+        }
+        data.extend(root_id.iter().flat_map(|id| id.to_le_bytes()));
+        data
+    }
+    #[test]
+    fn capabilities_test() {
+        // setcap cap_net_raw,cap_net_admin+ep
+        let net = (1 << 12) | (1 << 13);
+        let capabilities =
+            Capabilities::parse(&encode(0x02000001, &[(net, 0), (0, 0)], None)).unwrap();
+        assert_eq!(capabilities.permitted, net as u64);
+        assert!(capabilities.effective);
+        assert_eq!(capabilities.root_id, None);
+        assert_eq!(capabilities.to_string(), "cap_net_admin,cap_net_raw=ep");
+        // Sets differing between capabilities, and bits in the second word
+        let capabilities =
+            Capabilities::parse(&encode(0x03000000, &[(1 | 2, 1), (1 << 7, 0)], Some(1000)))
+                .unwrap();
+        assert_eq!(capabilities.root_id, Some(1000));
+        assert_eq!(capabilities.permitted, 3 | (1 << 39));
+        assert_eq!(
+            capabilities.to_string(),
+            "cap_chown=ip cap_dac_override,cap_bpf=p"
+        );
+        // Revision 1, with a single word
+        let capabilities = Capabilities::parse(&encode(0x01000000, &[(0, 0)], None)).unwrap();
+        assert!(capabilities.is_empty());
+
+        assert!(Capabilities::parse(&[]).is_err());
+        assert!(Capabilities::parse(&encode(0x04000000, &[(0, 0), (0, 0)], None)).is_err());
+        assert!(Capabilities::parse(&encode(0x02000000, &[(0, 0)], None)).is_err());
+    }
+}
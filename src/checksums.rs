@@ -0,0 +1,195 @@
+//! Digests of the data blocks, stored in a sidecar file, as squashfs has no per-block checksums.
+//!
+//! The sidecar holds the SHA-256 digest of the decompressed contents of each data and fragment
+//! block, by position in the image. It is generated with [`SquashFs::block_checksums`] (or
+//! `squashfuse-rs checksum generate`), and the blocks are verified against it when decoded if
+//! the image is opened with [`crate::Options::block_checksums`].
+//!
+//! Format (little endian): the magic `SQFSUMS1`, the number of bytes used by the image (see
+//! [`crate::SuperBlock::bytes_used`]), the number of blocks, and the blocks, each as its start
+//! (`u64`) and digest (32 bytes), sorted by start.
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::ops::DerefMut;
+use std::path::{Path, PathBuf};
+
+use sha2::Digest;
+use tracing::*;
+
+use crate::data::{read_data_block, Priority};
+use crate::{AsyncSeekBufRead, Error, SquashFs};
+
+const MAGIC: &[u8; 8] = b"SQFSUMS1";
+
+/// SHA-256 digest of a block.
+pub type BlockDigest = [u8; 32];
+
+/// Digest of the decompressed contents of a block (zero-padded to the block size).
+pub fn digest(data: &[u8]) -> BlockDigest {
+    sha2::Sha256::digest(data).into()
+}
+
+/// Default location of the sidecar of an image, next to it.
+pub fn sidecar_path(image: &Path) -> PathBuf {
+    let mut path = image.as_os_str().to_owned();
+    path.push(".sums");
+    path.into()
+}
+
+/// Digests of the data and fragment blocks of an image, see the [module](self) documentation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockChecksums {
+    bytes_used: u64,
+    digests: HashMap<u64, BlockDigest>,
+}
+impl BlockChecksums {
+    /// Number of blocks.
+    pub fn len(&self) -> usize {
+        self.digests.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty()
+    }
+    /// Check the decompressed contents of the block starting at `start`.
+    pub fn verify(&self, start: u64, data: &[u8]) -> Result<(), Error> {
+        match self.digests.get(&start) {
+            Some(expected) if *expected == digest(data) => Ok(()),
+            _ => Err(Error::BlockChecksum { start }),
+        }
+    }
+    pub fn write_to(&self, mut w: impl Write) -> std::io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&self.bytes_used.to_le_bytes())?;
+        w.write_all(&(self.digests.len() as u64).to_le_bytes())?;
+        let mut blocks: Vec<_> = self.digests.iter().collect();
+        blocks.sort_unstable();
+        for (start, digest) in blocks {
+            w.write_all(&start.to_le_bytes())?;
+            w.write_all(digest)?;
+        }
+        w.flush()
+    }
+    /// Read a sidecar, which must be that of an image using `bytes_used` bytes.
+    pub fn read_from(mut r: impl Read, bytes_used: u64) -> Result<Self, Error> {
+        let invalid = |e: std::io::Error| Error::InvalidChecksums(e.to_string());
+        let mut u64_buf = [0; 8];
+        let mut read_u64 = |r: &mut dyn Read| {
+            r.read_exact(&mut u64_buf).map_err(invalid)?;
+            Ok::<_, Error>(u64::from_le_bytes(u64_buf))
+        };
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic).map_err(invalid)?;
+        if &magic != MAGIC {
+            return Err(Error::InvalidChecksums("Invalid magic".into()));
+        }
+        let sidecar_bytes_used = read_u64(&mut r)?;
+        if sidecar_bytes_used != bytes_used {
+            return Err(Error::InvalidChecksums(format!(
+                "Generated for an image of {} bytes, instead of {}",
+                sidecar_bytes_used, bytes_used
+            )));
+        }
+        // Blocks take at least one byte of the image
+        let count = read_u64(&mut r)?;
+        if count > bytes_used {
+            return Err(Error::InvalidChecksums(format!("Invalid count {}", count)));
+        }
+        let mut digests = HashMap::with_capacity(count as usize);
+        for _ in 0..count {
+            let start = read_u64(&mut r)?;
+            let mut digest = BlockDigest::default();
+            r.read_exact(&mut digest).map_err(invalid)?;
+            digests.insert(start, digest);
+        }
+        Ok(Self {
+            bytes_used,
+            digests,
+        })
+    }
+}
+
+impl<T, R> SquashFs<R>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+{
+    /// Digests of all the data and fragment blocks, read in on-disk order, bypassing the caches.
+    pub async fn block_checksums(&self) -> Result<BlockChecksums, Error> {
+        let inode_table = self.inode_table.wait().await?;
+        let fragments_table = self.fragments_table.wait().await?;
+        let block_size = self.superblock.block_size as u64;
+        let mut r = self.get_reader(0).await?;
+        // Blocks by start, with their compressed size and expected decompressed size
+        let mut blocks = std::collections::BTreeMap::new();
+        for file in inode_table.files.values() {
+            let file_size = file.file_size();
+            for (i, l) in file.data_locations().enumerate() {
+                let size = file_size
+                    .saturating_sub(i as u64 * block_size)
+                    .min(block_size);
+                blocks.insert(l.block_start, (l.block_size, size..=size));
+            }
+            let location = file.fragment();
+            if location.valid() && file_size % block_size != 0 {
+                let entry = fragments_table.entry(location, r.deref_mut()).await?;
+                let end = location.offset as u64 + file_size % block_size;
+                blocks.insert(entry.start, (entry.size, end..=block_size));
+            }
+        }
+        debug!(blocks = blocks.len(), "Computing the digests of the blocks");
+        let mut digests = HashMap::with_capacity(blocks.len());
+        for (start, (size, expected)) in blocks {
+            // Sparse blocks are not stored
+            if size.compressed_size() == 0 {
+                continue;
+            }
+            let mut buf = bytes::BytesMut::with_capacity(block_size as usize);
+            read_data_block(
+                r.deref_mut(),
+                0,
+                start,
+                size,
+                &mut buf,
+                None,
+                (None, None),
+                (&self.superblock.codec(), expected),
+                (self.decompression.as_ref(), Priority::Background, None),
+            )
+            .await?;
+            digests.insert(start, digest(&buf));
+        }
+        Ok(BlockChecksums {
+            bytes_used: self.superblock.bytes_used,
+            digests,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn sidecar_test() {
+        let checksums = BlockChecksums {
+            bytes_used: 4096,
+            digests: [(96, digest(b"a")), (200, digest(b"b"))].into(),
+        };
+        let mut sidecar = vec![];
+        checksums.write_to(&mut sidecar).unwrap();
+        assert_eq!(sidecar.len(), 24 + 2 * 40);
+        assert_eq!(
+            BlockChecksums::read_from(&sidecar[..], 4096).unwrap(),
+            checksums
+        );
+        assert!(BlockChecksums::read_from(&sidecar[..], 4097).is_err());
+        assert!(BlockChecksums::read_from(&sidecar[..sidecar.len() - 1], 4096).is_err());
+        // Verification
+        assert!(checksums.verify(96, b"a").is_ok());
+        assert!(checksums.verify(96, b"b").is_err());
+        assert!(checksums.verify(0, b"a").is_err());
+        assert_eq!(
+            sidecar_path(Path::new("/a/b.sqfs")),
+            Path::new("/a/b.sqfs.sums")
+        );
+    }
+}
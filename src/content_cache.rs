@@ -0,0 +1,105 @@
+//! Cache of decoded data blocks shared between images, addressed by the contents of the
+//! compressed blocks.
+//!
+//! Related images, e.g. successive versions of the same root filesystem, contain many identical
+//! blocks. When they are served from the same process (see [`crate::supervisor`]), sharing a
+//! [`ContentCache`] with [`SquashFs::with_content_cache`] decodes and stores these blocks once.
+//!
+//! Compressed blocks still have to be read to be hashed: this saves decoding and memory, not IO.
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use fuser_async::cache::{DataBlockCache, LRUCache};
+use sha2::Digest;
+
+use crate::{Compression, Error, SquashFs};
+
+/// Cache of decoded data blocks, keyed by a hash of their compressed contents.
+pub struct ContentCache {
+    cache: LRUCache,
+    block_size: u32,
+}
+impl std::fmt::Display for ContentCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Content-addressed {}", self.cache)
+    }
+}
+impl ContentCache {
+    /// Cache of `cache_mb` MB, for images with blocks of `block_size` bytes.
+    pub fn new(cache_mb: u64, block_size: u32) -> Result<Self, Error> {
+        // The blocks come from several images, hence the total size is not known.
+        let cache = <LRUCache as DataBlockCache<Box<Error>>>::new(
+            cache_mb,
+            block_size as u64,
+            cache_mb * (1e6 as u64),
+        )?;
+        Ok(Self { cache, block_size })
+    }
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+    /// Key of a compressed block.
+    pub(crate) fn key(compression: Compression, data: &[u8]) -> u64 {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update((compression as u16).to_le_bytes());
+        hasher.update(data);
+        u64::from_le_bytes(hasher.finalize()[..8].try_into().unwrap())
+    }
+    /// Fill `buf` (expected to be empty) with the decoded block `key`, if cached with a size in
+    /// `expected` (e.g. shorter than the block size at the end of a file).
+    pub(crate) async fn get(
+        &self,
+        key: u64,
+        expected: &RangeInclusive<u64>,
+        buf: &mut bytes::BytesMut,
+    ) -> bool {
+        match DataBlockCache::<Box<Error>>::get(&self.cache, key).await {
+            Some(block) if expected.contains(&(block.data.len() as u64)) => {
+                buf.extend_from_slice(&block.data);
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Insert the decoded block `key`, without the padding of blocks shorter than the block size.
+    pub(crate) async fn insert(&self, key: u64, data: &[u8]) -> Result<(), Error> {
+        self.cache.insert_lock(key, async { Ok(data) }).await?;
+        Ok(())
+    }
+}
+
+impl<R: deadpool::managed::Manager> SquashFs<R> {
+    /// Share `cache` with other images. The block sizes must match.
+    pub fn with_content_cache(mut self, cache: Arc<ContentCache>) -> Result<Self, Error> {
+        if cache.block_size != self.superblock.block_size {
+            return Err(Error::InvalidOptions(
+                "The content cache must have the block size of the image",
+            ));
+        }
+        self.content_cache = Some(cache);
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn content_cache_test() {
+        let cache = ContentCache::new(1, 1000).unwrap();
+        let key = ContentCache::key(Compression::Zstd, b"compressed");
+        assert_ne!(key, ContentCache::key(Compression::Gzip, b"compressed"));
+        // Block at the end of a file, shorter than the block size
+        cache.insert(key, &[1; 100]).await.unwrap();
+        let mut buf = bytes::BytesMut::with_capacity(1000);
+        assert!(!cache.get(key, &(1000..=1000), &mut buf).await);
+        assert!(buf.is_empty());
+        assert!(cache.get(key, &(100..=100), &mut buf).await);
+        assert_eq!(&buf[..], &[1; 100]);
+        // Fragment block, holding at least the tail end of a file
+        buf.clear();
+        assert!(cache.get(key, &(50..=1000), &mut buf).await);
+        assert!(!cache.get(key + 1, &(0..=1000), &mut buf).await);
+    }
+}
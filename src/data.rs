@@ -1,38 +1,328 @@
 //! Reading data blocks
-use std::ops::DerefMut;
+use std::collections::HashMap;
+use std::ops::{DerefMut, RangeInclusive};
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::task::{Context, Poll};
 
 use async_compression::tokio::bufread::{XzDecoder, ZlibDecoder, ZstdDecoder};
-use fuser_async::cache::DataBlockCache;
 use fuser_async::utils::OutOf;
+use futures::TryStreamExt;
 use serde::Deserialize;
-use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncSeekExt, AsyncWrite};
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tracing::*;
 
+use super::cache::BlockCache;
+use super::checksums::BlockChecksums;
+use super::content_cache::ContentCache;
+use super::decompression::DecompressionPool;
 use super::error::DecompressError;
-use super::superblock::Compression;
+use super::stats::{Operation, Stats};
+use super::superblock::{Compression, CompressionOptions, SuperBlock, XzFilters};
 use super::Error;
 use super::SquashFs;
 use crate::pools;
 
+/// Decompressor for a [`Compression`] scheme.
+///
+/// The built-in decoders (based on [`async_compression`]) are registered by default, and can be
+/// replaced with [`register_decompressor`], e.g. to use hardware-accelerated implementations.
+#[async_trait::async_trait]
+pub trait Decompressor: Send + Sync {
+    /// Decompress the whole `input` into `output`.
+    async fn decompress(
+        &self,
+        input: &mut (dyn AsyncBufRead + Unpin + Send),
+        output: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<(), DecompressError>;
+}
+
+/// Built-in decoders from [`async_compression`].
+struct AsyncCompressionDecompressor(Compression);
+#[async_trait::async_trait]
+impl Decompressor for AsyncCompressionDecompressor {
+    async fn decompress(
+        &self,
+        input: &mut (dyn AsyncBufRead + Unpin + Send),
+        output: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<(), DecompressError> {
+        let mut input: Box<dyn tokio::io::AsyncRead + Unpin + Send> = match self.0 {
+            Compression::Zstd => Box::new(ZstdDecoder::new(input)),
+            Compression::Gzip => Box::new(ZlibDecoder::new(input)),
+            Compression::Xz => Box::new(XzDecoder::new(input)),
+            compression => return Err(DecompressError::UnsupportedCompression(compression)),
+        };
+        tokio::io::copy(&mut input, output).await?;
+        Ok(())
+    }
+}
+
+/// XZ decoder with a memory limit, see [`crate::Options::xz_memlimit_mb`].
+///
+/// As with the built-in decoder, which uses the same `xz2` stream decoder without a limit, the
+/// filter chain of each block, including its BCJ filter if `mksquashfs` picked one, is recorded
+/// in its headers, and applied when decoding it.
+///
+/// `xz2` decodes synchronously, hence on the blocking threads of the runtime, or directly on the
+/// threads of the [`DecompressionPool`], which are not tokio workers.
+#[derive(Debug)]
+pub struct XzDecompressor {
+    /// Memory limit in bytes
+    pub memlimit: u64,
+}
+#[async_trait::async_trait]
+impl Decompressor for XzDecompressor {
+    async fn decompress(
+        &self,
+        input: &mut (dyn AsyncBufRead + Unpin + Send),
+        output: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<(), DecompressError> {
+        let mut compressed = vec![];
+        input.read_to_end(&mut compressed).await?;
+        let stream = xz2::stream::Stream::new_stream_decoder(self.memlimit, 0)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let mut decoder =
+            xz2::read::XzDecoder::new_stream(std::io::Cursor::new(compressed), stream);
+        // Decode in chunks, so that the output can stop decompression early.
+        const CHUNK: usize = 64 * 1024;
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            let mut chunk = vec![0; CHUNK];
+            loop {
+                let n = std::io::Read::read(&mut decoder, &mut chunk)?;
+                if n == 0 {
+                    return Ok(());
+                }
+                output.write_all(&chunk[..n]).await?;
+            }
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let decoding = runtime.spawn_blocking(move || loop {
+            let mut chunk = vec![0; CHUNK];
+            let n = std::io::Read::read(&mut decoder, &mut chunk)?;
+            chunk.truncate(n);
+            // Stops when the output failed, dropping the receiver
+            if n == 0 || tx.blocking_send(chunk).is_err() {
+                return std::io::Result::Ok(());
+            }
+        });
+        while let Some(chunk) = rx.recv().await {
+            output.write_all(&chunk).await?;
+        }
+        decoding
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))??;
+        Ok(())
+    }
+}
+/// [`XzDecompressor`] of an image, according to the options and to its compressor options.
+///
+/// It is only needed with a memory limit (the built-in decoder applies the BCJ filters as well),
+/// and replaces the decompressor registered for XZ for this image only.
+pub(crate) fn xz_decompressor(
+    superblock: &SuperBlock,
+    options: &crate::Options,
+) -> Result<Option<Arc<XzDecompressor>>, Error> {
+    if superblock.compression != Compression::Xz {
+        return Ok(None);
+    }
+    let mut memlimit = options.xz_memlimit_mb.map(|mb| mb * 1_000_000);
+    if let (
+        false,
+        Some(CompressionOptions::Xz {
+            dictionary_size,
+            filters,
+        }),
+    ) = (
+        options.xz_ignore_compressor_options,
+        &superblock.compression_options,
+    ) {
+        debug!(dictionary_size, filters, "XZ compressor options");
+        if XzFilters::from_bits(*filters).is_none() {
+            return Err(DecompressError::UnsupportedXzFilters(*filters).into());
+        }
+        // LZMA2 decoding needs the dictionary, plus a small fixed overhead
+        let needed = *dictionary_size as u64 + (1 << 20);
+        if let Some(limit) = memlimit.filter(|limit| *limit < needed) {
+            warn!(
+                "Raising the XZ memory limit from {} to {} bytes to fit the dictionary size",
+                limit, needed
+            );
+            memlimit = Some(needed);
+        }
+    }
+    Ok(memlimit.map(|memlimit| Arc::new(XzDecompressor { memlimit })))
+}
+
+/// Decompression of the blocks of an image: its compression, and the decoder configured by its
+/// options, if any (see [`crate::Options::xz_memlimit_mb`]).
+#[derive(Debug, Clone)]
+pub struct Codec {
+    pub compression: Compression,
+    xz: Option<Arc<XzDecompressor>>,
+}
+impl From<Compression> for Codec {
+    /// Decompression with the decompressor registered for `compression`.
+    fn from(compression: Compression) -> Self {
+        Self {
+            compression,
+            xz: None,
+        }
+    }
+}
+impl Codec {
+    pub(crate) fn new(compression: Compression, xz: Option<Arc<XzDecompressor>>) -> Self {
+        Self { compression, xz }
+    }
+    fn decompressor(&self) -> Result<Arc<dyn Decompressor>, DecompressError> {
+        if let (Compression::Xz, Some(xz)) = (self.compression, &self.xz) {
+            return Ok(xz.clone());
+        }
+        decompressors()
+            .read()
+            .unwrap()
+            .get(&self.compression)
+            .cloned()
+            .ok_or(DecompressError::UnsupportedCompression(self.compression))
+    }
+}
+
+type Decompressors = RwLock<HashMap<Compression, Arc<dyn Decompressor>>>;
+fn decompressors() -> &'static Decompressors {
+    static DECOMPRESSORS: OnceLock<Decompressors> = OnceLock::new();
+    DECOMPRESSORS.get_or_init(|| {
+        RwLock::new(
+            [Compression::Zstd, Compression::Gzip, Compression::Xz]
+                .into_iter()
+                .map(|c| {
+                    let d: Arc<dyn Decompressor> = Arc::new(AsyncCompressionDecompressor(c));
+                    (c, d)
+                })
+                .collect(),
+        )
+    })
+}
+/// Register the decompressor used for a compression scheme, replacing the existing one.
+///
+/// This applies to all images opened in the process, except for the XZ images whose options
+/// require an [`XzDecompressor`] (see [`crate::Options::xz_memlimit_mb`]).
+pub fn register_decompressor(compression: Compression, decompressor: Arc<dyn Decompressor>) {
+    decompressors()
+        .write()
+        .unwrap()
+        .insert(compression, decompressor);
+}
+
+/// [`AsyncWrite`] counting the bytes written, and failing as soon as they exceed a limit.
+struct LimitedWriter<W> {
+    inner: W,
+    count: u64,
+    limit: u64,
+    /// Whether a write was rejected due to the limit
+    exceeded: bool,
+}
+impl<W: AsyncWrite + Unpin> AsyncWrite for LimitedWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.count + buf.len() as u64 > self.limit {
+            self.exceeded = true;
+            self.count += buf.len() as u64;
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Decompressed data exceeds the expected size",
+            )));
+        }
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.count += n as u64;
+        }
+        res
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Decompress `compressed_size` bytes from `input` into `output`, checking that the size of the
+/// decompressed data is in the `expected` range. Returns this size.
+///
+/// Decompression stops as soon as the output exceeds the range, so that malformed blocks cannot
+/// expand past it.
 pub async fn decompress(
     mut input: impl AsyncBufRead + Unpin + Send + Sync,
     compressed_size: u64,
-    mut output: impl AsyncWrite + Unpin,
-    compression: Option<Compression>,
-) -> Result<(), DecompressError> {
+    output: impl AsyncWrite + Unpin + Send,
+    codec: Option<&Codec>,
+    expected: RangeInclusive<u64>,
+) -> Result<u64, DecompressError> {
+    #[cfg(feature = "failpoints")]
+    crate::failpoints::pass(crate::failpoints::Failpoint::Decompress).await?;
     let mut input = (&mut input).take(compressed_size);
+    let mut output = LimitedWriter {
+        inner: output,
+        count: 0,
+        limit: *expected.end(),
+        exceeded: false,
+    };
 
-    let mut input: Box<dyn crate::AsyncRead> = match compression {
-        None => Box::new(input),
-        Some(Compression::Zstd) => Box::new(ZstdDecoder::new(&mut input)),
-        Some(Compression::Gzip) => Box::new(ZlibDecoder::new(&mut input)),
-        Some(Compression::Xz) => Box::new(XzDecoder::new(&mut input)),
-        // TODO: Other schemes
-        Some(compression) => return Err(DecompressError::UnsupportedCompression(compression)),
+    let result = match codec {
+        None => tokio::io::copy(&mut input, &mut output)
+            .await
+            .map(|_| ())
+            .map_err(DecompressError::from),
+        Some(codec) => {
+            let decompressor = codec.decompressor()?;
+            // The decompressor futures are not `Sync`, as required by [`crate::AsyncRead`] for
+            // the readers that decompress metadata blocks.
+            sync_wrapper::SyncFuture::new(decompressor.decompress(&mut input, &mut output)).await
+        }
     };
-    tokio::io::copy(&mut input, &mut output).await?;
+    // When the limit was exceeded, report the size mismatch rather than the write error.
+    if !output.exceeded {
+        result?;
+    }
+    if !expected.contains(&output.count) {
+        return Err(DecompressError::SizeMismatch {
+            expected,
+            got: output.count,
+        });
+    }
+    Ok(output.count)
+}
 
-    Ok(())
+/// Copy `size` bytes stored without compression from `input` into `buf`, checking the size
+/// against `expected`.
+///
+/// This bypasses the decoders, as well as the intermediate copy of [`decompress`].
+pub(crate) async fn read_stored(
+    input: impl AsyncBufRead + Unpin,
+    size: u64,
+    buf: &mut impl bytes::BufMut,
+    expected: RangeInclusive<u64>,
+) -> Result<u64, DecompressError> {
+    if !expected.contains(&size) {
+        return Err(DecompressError::SizeMismatch {
+            expected,
+            got: size,
+        });
+    }
+    let mut input = input.take(size);
+    let mut read = 0;
+    loop {
+        match input.read_buf(buf).await? {
+            0 => break,
+            n => read += n as u64,
+        }
+    }
+    if read != size {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(read)
 }
 
 #[derive(Debug, Copy, Clone, Deserialize)]
@@ -52,22 +342,138 @@ pub struct DataLocation {
     pub block_size: BlockSize,
 }
 
+/// Hints about a read, see [`SquashFs::read_file_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadHints {
+    /// Keep the decoded data blocks in the cache. This is disabled for blocks read once, e.g. by
+    /// long sequential scans (drop-behind, see [`crate::Options::drop_behind_mb`]), so that they
+    /// do not evict the working set.
+    ///
+    /// Fragment blocks, which are shared by small files, are always kept.
+    pub retain: bool,
+    /// Priority class of the read. Foreground reads always go first: background reads only use
+    /// some of the readers, and their decompression jobs are queued behind the foreground ones
+    /// (see [`crate::Options::decompression_threads`]).
+    pub priority: Priority,
+    /// Time by which the data is needed, e.g. for media streaming (see
+    /// [`crate::Options::stream_bitrate_kbps`]). The scheduler (see
+    /// [`crate::Options::scheduler_window`]) dispatches the reads closest to their deadline first.
+    pub deadline: Option<std::time::Instant>,
+    /// Only serve the data from the caches, failing with [`Error::WouldBlock`] when a block is
+    /// missing rather than reading it, e.g. for non-blocking reads (see
+    /// [`crate::Options::nonblock_eagain`]).
+    pub cached_only: bool,
+}
+impl Default for ReadHints {
+    fn default() -> Self {
+        Self {
+            retain: true,
+            priority: Priority::Foreground,
+            deadline: None,
+            cached_only: false,
+        }
+    }
+}
+impl ReadHints {
+    /// Hints for background jobs, e.g. extraction.
+    pub fn background() -> Self {
+        Self {
+            priority: Priority::Background,
+            ..Default::default()
+        }
+    }
+}
+
+/// Priority class of a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    /// Interactive reads, e.g. from FUSE requests
+    #[default]
+    Foreground,
+    /// Background jobs, e.g. prefetching and extraction, which only use some of the readers (see
+    /// [`crate::Options::background_readers`]), so that they never starve foreground reads.
+    Background,
+}
+
 impl<
         T: crate::AsyncSeekBufRead,
         R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
     > SquashFs<R>
 {
+    /// Decompression of the blocks compressed with `compression`, with the decoder configured
+    /// for the image when it is its compression.
+    pub(crate) fn codec(&self, compression: Compression) -> Codec {
+        if compression == self.superblock.compression {
+            self.superblock.codec()
+        } else {
+            compression.into()
+        }
+    }
     /// Read from a file from the archive
     pub async fn read_file(
+        &self,
+        inode: u32,
+        offset: usize,
+        size: usize,
+        flags: pools::ReadFlags,
+        compression: Compression,
+    ) -> Result<bytes::Bytes, Error> {
+        self.read_file_with(
+            inode,
+            offset,
+            size,
+            flags,
+            compression,
+            ReadHints::default(),
+        )
+        .await
+    }
+    /// Read from a file from the archive, with [`ReadHints`].
+    ///
+    /// Failures are published to [`SquashFs::subscribe_errors`] and to the hooks (see
+    /// [`crate::hooks::FileHooks::on_read_error`]).
+    pub async fn read_file_with(
+        &self,
+        inode: u32,
+        offset: usize,
+        size: usize,
+        flags: pools::ReadFlags,
+        compression: Compression,
+        hints: ReadHints,
+    ) -> Result<bytes::Bytes, Error> {
+        self.read_file_unreported(inode, offset, size, flags, compression, hints)
+            .await
+            .inspect_err(|e| {
+                self.publish_error(e, inode, offset as u64, size as u64);
+                if !matches!(e, Error::WouldBlock) {
+                    self.run_hooks(inode, flags, |hooks, file| {
+                        hooks.on_read_error(file, offset as u64, size as u64, e)
+                    });
+                }
+            })
+    }
+    async fn read_file_unreported(
         &self,
         inode: u32,
         offset: usize,
         size: usize,
         mut flags: pools::ReadFlags,
         compression: Compression,
+        hints: ReadHints,
     ) -> Result<bytes::Bytes, Error> {
+        // Held for the whole read, including the decompression of the blocks. The semaphore is
+        // never closed.
+        let _permit = match hints.priority {
+            Priority::Foreground => None,
+            Priority::Background => self.background_slots.acquire().await.ok(),
+        };
+        if let Some(tracer) = &self.tracer {
+            tracer.record(inode, offset as u64, size as u64, flags);
+        }
         let file = self
             .inode_table
+            .wait()
+            .await?
             .files
             .get(&inode)
             .ok_or(Error::FileNotFound(None))?;
@@ -92,63 +498,301 @@ impl<
             warn!(inode, "Accessing very small file (< block) in direct mode");
             flags |= libc::O_DIRECT;
         } else if let (true, Some(cache)) = (
-            (file.file_size() as usize) < self.direct_limit
+            (file.file_size() as usize) < self.direct_limit()
                 // Skip when tailend fragments (which would require another fetch)
-                && !file.fragment().valid() && (flags & libc::O_DIRECT) != 0,
+                && !file.fragment().valid()
+                && ((flags & libc::O_DIRECT) != 0 || self.capabilities.whole_small_files()),
             &self.small_files_cache,
         ) {
             // We read the entire underlying data at once and then decode it.
             // This large read, along with the O_DIRECT flag, provides a hint to the backend
             // to skip buffering and read exactly this data.
-            let first = file.data_locations().next().unwrap();
-            let tot_size = file
-                .data_locations()
-                .map(|dl| dl.block_size.compressed_size())
-                .sum::<u64>();
             flags |= libc::O_DIRECT;
-            // Cache the entire decompressed file
-            let cached = cache
-                .insert_lock(inode as u64, async {
-                    warn!(
-                        inode,
-                        "Accessing small file (< direct limit) in direct mode"
-                    );
-                    let mut reader = self.get_reader(flags).await?;
-                    // Read the raw contents
-                    reader
-                        .seek(std::io::SeekFrom::Start(first.block_start))
-                        .await
-                        .map_err(Error::ReadFailure)?;
-                    let mut buf = bytes::BytesMut::zeroed(tot_size as usize);
-                    reader
-                        .read_exact(&mut buf)
-                        .await
-                        .map_err(Error::ReadFailure)?;
-                    let mut cursor = std::io::Cursor::new(buf.deref_mut());
-                    // Decode the contents
-                    self.read_file_impl(
+            // Cache the entire decompressed file.
+            // The IO happens outside of the cache lock, under a per-inode lock that gets released
+            // if this future is cancelled.
+            let mut buf = bytes::BytesMut::with_capacity(size);
+            let range = offset..offset + size;
+            let hit = cache.get(inode as u64, range.clone(), &mut buf).await;
+            if let Some(tuner) = &self.tuner {
+                tuner.record_lookup(hit);
+            }
+            if hit {
+                return Ok(buf.freeze());
+            }
+            if hints.cached_only {
+                // The blocks of the file might be in the cache of data blocks
+                return self
+                    .read_file_impl(
                         file,
-                        (&mut cursor, first.block_start),
+                        (std::io::Cursor::new(&[][..]), 0, None),
                         inode,
-                        // Use the decompressed size here
-                        (0, file.file_size() as usize),
+                        (offset, size),
                         compression,
+                        hints,
                     )
-                    .await
-                    .map_err(Box::new)
-                })
+                    .await;
+            }
+            let _guard = self.small_files_loads.lock(inode as u64).await;
+            if cache.get(inode as u64, range.clone(), &mut buf).await {
+                return Ok(buf.freeze());
+            }
+            let data = self
+                .read_small_file(file, inode, flags, compression)
                 .await?;
-            let mut buf = bytes::BytesMut::zeroed(size);
-            buf.copy_from_slice(&cached.data[offset..offset + size]);
-            return Ok(buf.into());
+            cache.insert(inode as u64, &data).await?;
+            return Ok(data.slice(range));
+        }
+        if hints.cached_only {
+            // Nothing is read from the image
+            return self
+                .read_file_impl(
+                    file,
+                    (std::io::Cursor::new(&[][..]), 0, None),
+                    inode,
+                    (offset, size),
+                    compression,
+                    hints,
+                )
+                .await;
         }
         let mut reader = self.get_reader(flags).await?;
         self.read_file_impl(
             file,
-            (reader.deref_mut(), 0),
+            (reader.deref_mut(), 0, self.scheduler(flags)),
             inode,
             (offset, size),
             compression,
+            hints,
+        )
+        .await
+    }
+    /// Load the blocks holding `offset..offset + size` of a file into the cache in the
+    /// background, e.g. after a read with [`ReadHints::cached_only`] failed.
+    ///
+    /// This does nothing if the image was not opened with [`crate::Options::nonblock_eagain`] or
+    /// [`crate::Options::auto_tune`].
+    pub(crate) fn fetch_in_background(
+        &self,
+        inode: u32,
+        offset: usize,
+        size: usize,
+    ) -> Result<(), Error> {
+        let Some(fetcher) = &self.fetcher else {
+            return Ok(());
+        };
+        let file = self
+            .inode_table
+            .get()?
+            .files
+            .get(&inode)
+            .ok_or(Error::FileNotFound(None))?;
+        let block_size = self.superblock.block_size as u64;
+        let file_size = file.file_size();
+        let size = (size as u64).min(file_size.saturating_sub(offset as u64));
+        if size == 0 {
+            return Ok(());
+        }
+        let first_block = offset as u64 / block_size;
+        let n_blocks = (offset as u64 % block_size + size).div_ceil(block_size);
+        let expected = |block: u64| {
+            let size = file_size.saturating_sub(block * block_size).min(block_size);
+            size..=size
+        };
+        let mut blocks: Vec<_> = file
+            .data_locations()
+            .skip(first_block as usize)
+            .take(n_blocks as usize)
+            .enumerate()
+            .map(|(i, l)| crate::fetcher::Block::Data {
+                start: l.block_start,
+                size: l.block_size,
+                expected: expected(first_block + i as u64),
+            })
+            .collect();
+        let location = file.fragment();
+        if (blocks.len() as u64) < n_blocks && location.valid() {
+            blocks.push(crate::fetcher::Block::Fragment {
+                location,
+                expected: (location.offset as u64 + file_size % block_size)..=block_size,
+            });
+        }
+        fetcher.fetch(blocks);
+        Ok(())
+    }
+    /// Copy `size` bytes of a file from `offset` to `output`, in chunks of a few blocks, returning
+    /// the number of bytes copied (less than `size` at the end of the file).
+    pub async fn copy_file(
+        &self,
+        inode: u32,
+        offset: u64,
+        size: u64,
+        flags: pools::ReadFlags,
+        output: impl AsyncWrite + Unpin,
+    ) -> Result<u64, Error> {
+        self.copy_file_with(inode, offset, size, flags, output, ReadHints::default())
+            .await
+    }
+    /// [`SquashFs::copy_file`] with [`ReadHints`].
+    pub async fn copy_file_with(
+        &self,
+        inode: u32,
+        offset: u64,
+        size: u64,
+        flags: pools::ReadFlags,
+        mut output: impl AsyncWrite + Unpin,
+        hints: ReadHints,
+    ) -> Result<u64, Error> {
+        let file_size = self
+            .inode_table
+            .wait()
+            .await?
+            .files
+            .get(&inode)
+            .ok_or(Error::FileNotFound(None))?
+            .file_size();
+        let size = size.min(file_size.saturating_sub(offset));
+        let chunk = 16 * self.superblock.block_size as u64;
+        let mut copied = 0;
+        while copied < size {
+            let data = self
+                .read_file_with(
+                    inode,
+                    (offset + copied) as usize,
+                    chunk.min(size - copied) as usize,
+                    flags,
+                    self.superblock.compression,
+                    hints,
+                )
+                .await?;
+            if data.is_empty() {
+                // The inode announces more data than its blocks hold
+                return Err(Error::ReadFailure(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            output.write_all(&data).await.map_err(Error::WriteFailure)?;
+            copied += data.len() as u64;
+        }
+        output.flush().await.map_err(Error::WriteFailure)?;
+        Ok(copied)
+    }
+    /// Load the blocks holding `range` of a file into the caches, without returning them, e.g. to
+    /// warm the caches ahead of accesses that the application can predict.
+    ///
+    /// The blocks are read with [`Priority::Background`], so that they do not delay the other
+    /// reads, and the call completes once they are cached. This does nothing if the image was
+    /// opened without caches (see [`crate::Options::cache_mb`]).
+    pub async fn prefetch(&self, inode: u32, range: std::ops::Range<u64>) -> Result<(), Error> {
+        if self.cache.is_none() && self.small_files_cache.is_none() {
+            debug!(inode, "Skipping prefetch without caches");
+            return Ok(());
+        }
+        self.copy_file_with(
+            inode,
+            range.start,
+            range.end.saturating_sub(range.start),
+            0,
+            tokio::io::sink(),
+            ReadHints::background(),
+        )
+        .await?;
+        Ok(())
+    }
+    /// Prefetch (see [`SquashFs::prefetch`]) the file at `path`, or all the files under it if it
+    /// is a directory, returning the number of bytes prefetched.
+    ///
+    /// The files are read in on-disk order, so that the backend sees sequential reads.
+    pub async fn prefetch_tree(&self, path: &std::path::Path) -> Result<u64, Error> {
+        let inode = self.resolve(path).await?;
+        if self.inode_table.wait().await?.files.contains_key(&inode) {
+            return self.prefetch_files(vec![inode]).await;
+        }
+        let entries = self.walk_from(inode, path.to_owned());
+        let inodes = entries
+            .map_ok(|(_, entry)| entry.inode)
+            .try_collect()
+            .await?;
+        self.prefetch_files(inodes).await
+    }
+    /// Same as [`SquashFs::prefetch_tree`], for the files whose path (relative to the root), or
+    /// that of a parent directory, matches `pattern`.
+    pub async fn prefetch_matching(&self, pattern: &glob::Pattern) -> Result<u64, Error> {
+        let entries = self.walk().try_filter(|(path, _)| {
+            std::future::ready(path.ancestors().any(|p| pattern.matches_path(p)))
+        });
+        let inodes = entries
+            .map_ok(|(_, entry)| entry.inode)
+            .try_collect()
+            .await?;
+        self.prefetch_files(inodes).await
+    }
+    /// Prefetch the files among `inodes`, in on-disk order, ignoring the other inodes.
+    async fn prefetch_files(&self, mut inodes: Vec<u32>) -> Result<u64, Error> {
+        if self.cache.is_none() && self.small_files_cache.is_none() {
+            debug!("Skipping prefetch without caches");
+            return Ok(0);
+        }
+        let files = &self.inode_table.wait().await?.files;
+        inodes.retain(|inode| files.contains_key(inode));
+        inodes.sort_unstable_by_key(|inode| (files[inode].blocks_start(), *inode));
+        // Hard links
+        inodes.dedup();
+        let mut prefetched = 0;
+        for inode in inodes {
+            let size = files[&inode].file_size();
+            self.prefetch(inode, 0..size).await?;
+            prefetched += size;
+        }
+        debug!(prefetched, "Prefetched files");
+        Ok(prefetched)
+    }
+    /// Read a small file at once, see [`crate::Options::direct_limit`].
+    #[allow(clippy::borrowed_box)]
+    async fn read_small_file(
+        &self,
+        file: &Box<dyn crate::inodes::FileInode + Send + Sync>,
+        inode: u32,
+        flags: pools::ReadFlags,
+        compression: Compression,
+    ) -> Result<bytes::Bytes, Error> {
+        let first = file.data_locations().next().unwrap();
+        let tot_size = file
+            .data_locations()
+            .map(|dl| dl.block_size.compressed_size())
+            .sum::<u64>();
+        warn!(
+            inode,
+            "Accessing small file (< direct limit) in direct mode"
+        );
+        let mut reader = self.get_reader(flags).await?;
+        let start = std::time::Instant::now();
+        // Read the raw contents
+        reader
+            .seek(std::io::SeekFrom::Start(first.block_start))
+            .await
+            .map_err(Error::ReadFailure)?;
+        let mut buf = Vec::with_capacity(tot_size as usize);
+        reader
+            .deref_mut()
+            .take(tot_size)
+            .read_to_end(&mut buf)
+            .await
+            .map_err(Error::ReadFailure)?;
+        if buf.len() != tot_size as usize {
+            return Err(Error::InvalidBufferSize);
+        }
+        if let Some(tuner) = &self.tuner {
+            tuner.record_read(tot_size, start.elapsed());
+        }
+        let mut cursor = std::io::Cursor::new(buf.deref_mut());
+        // Decode the contents
+        self.read_file_impl(
+            file,
+            (&mut cursor, first.block_start, None),
+            inode,
+            // Use the decompressed size here
+            (0, file.file_size() as usize),
+            compression,
+            ReadHints::default(),
         )
         .await
     }
@@ -156,23 +800,35 @@ impl<
     pub async fn read_file_impl(
         &self,
         file: &Box<dyn crate::inodes::FileInode + Send + Sync>,
-        (mut reader, reader_offset): (impl crate::AsyncSeekBufRead, u64),
+        // Reader, offset of its start in the image, and scheduler for the reads from the image
+        (mut reader, reader_offset, scheduler): (
+            impl crate::AsyncSeekBufRead,
+            u64,
+            Option<&crate::scheduler::Scheduler>,
+        ),
         inode: u32,
         (offset, size): (usize, usize),
         compression: Compression,
+        hints: ReadHints,
     ) -> Result<bytes::Bytes, Error> {
         let start = std::time::Instant::now();
+        let codec = self.codec(compression);
+        // Cache into which the regular blocks are inserted
+        let retain_cache = self.cache.as_deref().filter(|_| hints.retain);
 
         let superblock = &self.superblock;
-        let first_block = (offset as f64 / superblock.block_size as f64).floor() as usize;
-        let block_offset = offset % self.superblock.block_size as usize;
-        let n_blocks =
-            ((block_offset + size) as f64 / self.superblock.block_size as f64).ceil() as usize;
-        let mut buf = bytes::BytesMut::zeroed(superblock.block_size as usize * n_blocks);
+        let first_block = offset / superblock.block_size as usize;
+        let block_offset = offset % superblock.block_size as usize;
+        let n_blocks = (block_offset + size).div_ceil(superblock.block_size as usize);
+        // The buffer is not zero-initialized: each part is filled by `read_data_block`. As long as
+        // the parts are full, they remain contiguous and can be unsplit without copies.
+        let mut buf = bytes::BytesMut::with_capacity(superblock.block_size as usize * n_blocks);
+        // Trim any extra capacity from the allocator
+        let _ = buf.split_off(superblock.block_size as usize * n_blocks);
         let mut buf_parts: Vec<_> = (0..n_blocks)
-            .map(|_| buf.split_off(buf.len() - superblock.block_size as usize))
-            .rev()
+            .map(|_| buf.split_off(buf.capacity() - superblock.block_size as usize))
             .collect();
+        buf_parts.reverse();
 
         let data_locations: Vec<_> = file
             .data_locations()
@@ -188,37 +844,157 @@ impl<
             data_locations.len(),
             first_block
         );
+        let block_size = superblock.block_size as u64;
+        let file_size = file.file_size();
+        // Expected decompressed size of the blocks: full except at the end of the file
+        let expected = |block: usize| {
+            let size = file_size
+                .saturating_sub(block as u64 * block_size)
+                .min(block_size);
+            size..=size
+        };
         // Read from regular data blocks
-        for (l, buf_part) in data_locations.iter().zip(buf_parts.iter_mut()) {
-            read_data_block(
+        for (i, (l, buf_part)) in data_locations.iter().zip(buf_parts.iter_mut()).enumerate() {
+            if hints.cached_only {
+                if !block_from_cache(
+                    l.block_start,
+                    l.block_size,
+                    buf_part,
+                    self.cache.as_deref(),
+                    Some(&self.stats),
+                )
+                .await?
+                {
+                    return Err(Error::WouldBlock);
+                }
+                continue;
+            }
+            if let Some(scheduler) = scheduler {
+                if !block_from_cache(
+                    l.block_start,
+                    l.block_size,
+                    buf_part,
+                    self.cache.as_deref(),
+                    Some(&self.stats),
+                )
+                .await?
+                {
+                    let raw = scheduler
+                        .read(
+                            l.block_start,
+                            l.block_size.compressed_size(),
+                            hints.deadline,
+                        )
+                        .await?;
+                    decode_data_block(
+                        std::io::Cursor::new(raw),
+                        l.block_start,
+                        l.block_size,
+                        buf_part,
+                        self.checksums.as_deref(),
+                        (retain_cache, self.content_cache.as_deref()),
+                        (&codec, expected(first_block + i)),
+                        (
+                            self.decompression.as_ref(),
+                            hints.priority,
+                            Some(&self.stats),
+                        ),
+                    )
+                    .await?;
+                }
+                continue;
+            }
+            if block_from_cache(
+                l.block_start,
+                l.block_size,
+                buf_part,
+                self.cache.as_deref(),
+                Some(&self.stats),
+            )
+            .await?
+            {
+                continue;
+            }
+            reader
+                .seek(std::io::SeekFrom::Start(l.block_start - reader_offset))
+                .await
+                .map_err(Error::ReadFailure)?;
+            decode_data_block(
                 &mut reader,
-                reader_offset,
                 l.block_start,
                 l.block_size,
-                buf_part.as_mut(),
-                self.cache.as_ref(),
-                compression,
+                buf_part,
+                self.checksums.as_deref(),
+                (retain_cache, self.content_cache.as_deref()),
+                (&codec, expected(first_block + i)),
+                (
+                    self.decompression.as_ref(),
+                    hints.priority,
+                    Some(&self.stats),
+                ),
             )
             .await?;
         }
         // Read last part from fragment if necessary
         if data_locations.len() != n_blocks {
             debug!("Reading from fragment");
-            assert!(n_blocks == data_locations.len() + 1);
-            let buf = buf_parts.last_mut().unwrap();
             let fragment_location = file.fragment();
-            let entry = self.fragments_table.entry(fragment_location)?;
+            // Only the last block can be missing, and only if it is stored in a fragment.
+            if n_blocks != data_locations.len() + 1 || !fragment_location.valid() {
+                return Err(Error::InvalidInode);
+            }
+            let buf = buf_parts.last_mut().unwrap();
+            if hints.cached_only {
+                let entry = self
+                    .fragments_table
+                    .get()
+                    .ok()
+                    .and_then(|table| table.cached_entry(fragment_location))
+                    .ok_or(Error::WouldBlock)?;
+                if !block_from_cache(
+                    entry.start,
+                    entry.size,
+                    buf,
+                    self.cache.as_deref(),
+                    Some(&self.stats),
+                )
+                .await?
+                {
+                    return Err(Error::WouldBlock);
+                }
+            } else {
+                // Missing fragment table blocks are read with the same reader. This is the image
+                // itself here, as in-memory readers (`reader_offset` > 0) are only used for files
+                // without fragments.
+                let entry = self
+                    .fragments_table
+                    .wait()
+                    .await?
+                    .entry(fragment_location, &mut reader)
+                    .await?;
 
-            read_data_block(
-                reader,
-                reader_offset,
-                entry.start,
-                entry.size,
-                buf,
-                self.cache.as_ref(),
-                compression,
-            )
-            .await?;
+                read_data_block(
+                    reader,
+                    reader_offset,
+                    entry.start,
+                    entry.size,
+                    buf,
+                    self.checksums.as_deref(),
+                    (self.cache.as_deref(), self.content_cache.as_deref()),
+                    // The fragment block holds the tail end of this file after the offset, and
+                    // possibly that of others.
+                    (
+                        &codec,
+                        (fragment_location.offset as u64 + file_size % block_size)..=block_size,
+                    ),
+                    (
+                        self.decompression.as_ref(),
+                        hints.priority,
+                        Some(&self.stats),
+                    ),
+                )
+                .await?;
+            }
             let _ = buf.split_to(fragment_location.offset as usize);
         }
         for part in buf_parts {
@@ -241,20 +1017,76 @@ impl<
         Ok(buf)
     }
 }
+/// [`AsyncWrite`] appending to a [`bytes::BytesMut`], which avoids zero-initializing it.
+pub(crate) struct BytesMutWriter<'a>(pub &'a mut bytes::BytesMut);
+impl AsyncWrite for BytesMutWriter<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.0.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Read a data block, filling `buf` (which is expected to be empty) up to its capacity.
+///
+/// Sparse blocks, as well as the part after the end of a shorter block, are filled with zeros.
+#[allow(clippy::too_many_arguments)]
 pub async fn read_data_block(
     mut r: impl crate::AsyncSeekBufRead,
     reader_offset: u64,
     start: u64,
     b: BlockSize,
-    buf: &mut [u8],
-    cache: Option<&impl DataBlockCache<Box<Error>>>,
-    compression: Compression,
+    buf: &mut bytes::BytesMut,
+    checksums: Option<&BlockChecksums>,
+    (cache, content): (Option<&dyn BlockCache>, Option<&ContentCache>),
+    (codec, expected): (&Codec, RangeInclusive<u64>),
+    decompression: (Option<&DecompressionPool>, Priority, Option<&Stats>),
 ) -> Result<(), Error> {
+    if block_from_cache(start, b, buf, cache, decompression.2).await? {
+        return Ok(());
+    }
+    #[cfg(feature = "failpoints")]
+    crate::failpoints::pass(crate::failpoints::Failpoint::ReadDataBlock)
+        .await
+        .map_err(Error::ReadFailure)?;
     r.seek(std::io::SeekFrom::Start(start - reader_offset))
         .await
         .map_err(Error::ReadFailure)?;
+    decode_data_block(
+        r,
+        start,
+        b,
+        buf,
+        checksums,
+        (cache, content),
+        (codec, expected),
+        decompression,
+    )
+    .await
+}
+/// Fill `buf` from the cache, or with zeros for sparse blocks. Returns `false` if the block
+/// has to be read.
+///
+/// Cache lookups are recorded in `stats`.
+pub(crate) async fn block_from_cache(
+    start: u64,
+    b: BlockSize,
+    buf: &mut bytes::BytesMut,
+    cache: Option<&dyn BlockCache>,
+    stats: Option<&Stats>,
+) -> Result<bool, Error> {
+    let block_size = buf.capacity();
     debug!(
-        compression_ratio = b.compressed_size() as f32 / buf.len() as f32,
+        compression_ratio = b.compressed_size() as f32 / block_size as f32,
         compressed_size = b.compressed_size(),
         start,
         cache = format!("{}", cache.map(|c| c.to_string()).unwrap_or_default()),
@@ -263,37 +1095,116 @@ pub async fn read_data_block(
 
     // Crucial to not mess up the caching
     if b.compressed_size() == 0 {
-        return Ok(());
+        buf.resize(block_size, 0);
+        return Ok(true);
     }
     // Check cache
-    if let Some(cache) = cache {
-        if let Some(block) = cache.get(start).await {
-            if block.data.len() != buf.len() {
-                return Err(Error::InvalidBufferSize);
-            }
-            buf.copy_from_slice(&block.data);
-            return Ok(());
-        }
+    let Some(cache) = cache else {
+        return Ok(false);
+    };
+    let hit = cache.get(start, 0..block_size, buf).await;
+    if let Some(stats) = stats {
+        stats.record_cache_lookup(hit);
+    }
+    Ok(hit)
+}
+/// Decompress a data block from `r`, positioned at its start, into `buf` and the cache.
+///
+/// The size of the decompressed data is checked against `expected`, and its digest against
+/// `checksums`, before caching.
+///
+/// With a [`DecompressionPool`] or a [`ContentCache`], the compressed data is read first, and
+/// then decompressed on the pool, or taken from the content cache.
+///
+/// The decompression time is recorded in `stats`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn decode_data_block(
+    mut r: impl crate::AsyncSeekBufRead,
+    start: u64,
+    b: BlockSize,
+    buf: &mut bytes::BytesMut,
+    checksums: Option<&BlockChecksums>,
+    (cache, content): (Option<&dyn BlockCache>, Option<&ContentCache>),
+    (codec, expected): (&Codec, RangeInclusive<u64>),
+    (decompression, priority, stats): (Option<&DecompressionPool>, Priority, Option<&Stats>),
+) -> Result<(), Error> {
+    let block_size = buf.capacity();
+    if *expected.end() > block_size as u64 {
+        return Err(Error::InvalidBufferSize);
     }
     // Given we're reading directly into the buffer, we're not doing that in the lock insert.
     // (but we might be missing some cache hits doing so)
-    let mut cursor = std::io::Cursor::new(buf);
-
-    decompress(
-        &mut r,
-        b.compressed_size(),
-        &mut cursor,
-        b.compressed().then_some(compression),
-    )
-    .await?;
+    match (decompression, content) {
+        _ if !b.compressed() => {
+            read_stored(&mut r, b.compressed_size(), buf, expected).await?;
+        }
+        (None, None) => {
+            let start_time = std::time::Instant::now();
+            decompress(
+                &mut r,
+                b.compressed_size(),
+                BytesMutWriter(buf),
+                Some(codec),
+                expected,
+            )
+            .await?;
+            if let Some(stats) = stats {
+                stats.record(Operation::Decompress, start_time.elapsed());
+            }
+        }
+        (decompression, content) => {
+            let mut data = Vec::with_capacity(b.compressed_size() as usize);
+            (&mut r)
+                .take(b.compressed_size())
+                .read_to_end(&mut data)
+                .await
+                .map_err(Error::ReadFailure)?;
+            let key = content.map(|_| ContentCache::key(codec.compression, &data));
+            let hit = match (content, key) {
+                (Some(content), Some(key)) => content.get(key, &expected, buf).await,
+                _ => false,
+            };
+            if !hit {
+                let start_time = std::time::Instant::now();
+                match decompression {
+                    Some(decompression) => {
+                        *buf = decompression
+                            .decompress(
+                                data,
+                                codec.clone(),
+                                std::mem::take(buf),
+                                expected,
+                                priority,
+                            )
+                            .await?;
+                    }
+                    None => {
+                        decompress(
+                            &data[..],
+                            data.len() as u64,
+                            BytesMutWriter(buf),
+                            Some(codec),
+                            expected,
+                        )
+                        .await?;
+                    }
+                }
+                if let Some(stats) = stats {
+                    stats.record(Operation::Decompress, start_time.elapsed());
+                }
+                if let (Some(content), Some(key)) = (content, key) {
+                    content.insert(key, buf).await?;
+                }
+            }
+        }
+    }
+    buf.resize(block_size, 0);
+    if let Some(checksums) = checksums {
+        checksums.verify(start, buf)?;
+    }
     // Write cache
     if let Some(cache) = cache {
-        let _ = cache
-            .insert_lock(start, async {
-                let data = cursor.into_inner();
-                Ok(&*data)
-            })
-            .await?;
+        cache.insert(start, buf).await?;
     }
     Ok(())
 }
@@ -0,0 +1,129 @@
+//! Dedicated decompression threads, see [`crate::Options::decompression_threads`].
+//!
+//! Blocks are decompressed on a fixed number of threads, optionally pinned to cores, rather than
+//! on the tokio worker threads. This gives predictable CPU usage and better cache locality.
+//!
+//! Foreground jobs are processed before background ones (see [`Priority`]).
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Condvar, Mutex};
+
+use tokio::sync::oneshot;
+use tracing::*;
+
+use crate::data::{decompress, BytesMutWriter, Codec, Priority};
+use crate::error::DecompressError;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Queues {
+    foreground: VecDeque<Job>,
+    background: VecDeque<Job>,
+    stopped: bool,
+}
+impl Queues {
+    fn pop(&mut self) -> Option<Job> {
+        self.foreground
+            .pop_front()
+            .or_else(|| self.background.pop_front())
+    }
+}
+type Jobs = Arc<(Mutex<Queues>, Condvar)>;
+
+/// Pool of decompression threads, which stop when the pool is dropped.
+pub struct DecompressionPool {
+    jobs: Jobs,
+}
+impl Drop for DecompressionPool {
+    fn drop(&mut self) {
+        let (queues, available) = &*self.jobs;
+        queues.lock().unwrap().stopped = true;
+        available.notify_all();
+    }
+}
+impl DecompressionPool {
+    /// Start `threads` threads, pinning the i-th one to `cores[i % cores.len()]` if `cores` is
+    /// not empty.
+    pub fn new(threads: usize, cores: &[usize]) -> std::io::Result<Self> {
+        let jobs = Jobs::default();
+        for i in 0..threads {
+            let jobs = jobs.clone();
+            let core = (!cores.is_empty()).then(|| cores[i % cores.len()]);
+            std::thread::Builder::new()
+                .name(format!("squashfs-decompress-{}", i))
+                .spawn(move || {
+                    if let Some(core) = core {
+                        pin_to_core(core);
+                    }
+                    let (queues, available) = &*jobs;
+                    loop {
+                        let job = {
+                            let mut queues = queues.lock().unwrap();
+                            loop {
+                                // Pending jobs are dropped when stopping, which closes their
+                                // channels.
+                                if queues.stopped {
+                                    return;
+                                }
+                                if let Some(job) = queues.pop() {
+                                    break job;
+                                }
+                                queues = available.wait(queues).unwrap();
+                            }
+                        };
+                        job();
+                    }
+                })?;
+        }
+        Ok(Self { jobs })
+    }
+    /// Decompress `data`, appending to `buf`, which is returned.
+    ///
+    /// See [`decompress`] for the `expected` size.
+    pub async fn decompress(
+        &self,
+        data: Vec<u8>,
+        codec: Codec,
+        mut buf: bytes::BytesMut,
+        expected: RangeInclusive<u64>,
+        priority: Priority,
+    ) -> Result<bytes::BytesMut, DecompressError> {
+        let (tx, rx) = oneshot::channel();
+        let job = move || {
+            // The input is in memory, hence the future completes without yielding.
+            let res = futures::executor::block_on(decompress(
+                &data[..],
+                data.len() as u64,
+                BytesMutWriter(&mut buf),
+                Some(&codec),
+                expected,
+            ));
+            let _ = tx.send(res.map(|_| buf));
+        };
+        {
+            let (queues, available) = &*self.jobs;
+            let mut queues = queues.lock().unwrap();
+            match priority {
+                Priority::Foreground => queues.foreground.push_back(Box::new(job)),
+                Priority::Background => queues.background.push_back(Box::new(job)),
+            }
+            available.notify_one();
+        }
+        rx.await.map_err(|_| DecompressError::PoolStopped)?
+    }
+}
+
+fn pin_to_core(core: usize) {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            warn!(
+                "Failed to pin decompression thread to core {}: {}",
+                core,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Values computed in the background, see [`crate::Options::background_parsing`].
+use std::sync::Arc;
+
+use tokio::sync::{Notify, OnceCell};
+
+use crate::Error;
+
+struct Inner<T> {
+    value: OnceCell<Result<T, String>>,
+    notify: Notify,
+}
+
+/// A value that is either available, or being computed in the background.
+///
+/// This is cheaply cloneable, the clones sharing the same value.
+pub struct Deferred<T>(Arc<Inner<T>>);
+impl<T> Clone for Deferred<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+impl<T> Deferred<T> {
+    /// Value that will be provided later with [`Deferred::set`].
+    pub(crate) fn pending() -> Self {
+        Self(Arc::new(Inner {
+            value: OnceCell::new(),
+            notify: Notify::new(),
+        }))
+    }
+    pub(crate) fn ready(value: T) -> Self {
+        Self(Arc::new(Inner {
+            value: OnceCell::new_with(Some(Ok(value))),
+            notify: Notify::new(),
+        }))
+    }
+    /// Provide the value (or the reason why it could not be computed), waking up the waiters.
+    pub(crate) fn set(&self, value: Result<T, String>) {
+        let _ = self.0.value.set(value);
+        self.0.notify.notify_waiters();
+    }
+    fn get_set(&self) -> Option<Result<&T, Error>> {
+        self.0
+            .value
+            .get()
+            .map(|v| v.as_ref().map_err(|e| Error::BackgroundParsing(e.clone())))
+    }
+    /// Get the value, failing with [`Error::Pending`] if it is not available yet.
+    pub fn get(&self) -> Result<&T, Error> {
+        self.get_set().unwrap_or(Err(Error::Pending))
+    }
+    /// Get the value, waiting for it to be available.
+    pub async fn wait(&self) -> Result<&T, Error> {
+        loop {
+            // Register before checking, to not miss a notification in between.
+            let notified = self.0.notify.notified();
+            if let Some(value) = self.get_set() {
+                return value;
+            }
+            notified.await;
+        }
+    }
+}
+impl<T: std::fmt::Display> std::fmt::Display for Deferred<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.0.value.get() {
+            Some(Ok(value)) => write!(f, "{}", value),
+            Some(Err(e)) => write!(f, "Failed: {}", e),
+            None => write!(f, "Pending"),
+        }
+    }
+}
+impl<T: std::fmt::Debug> std::fmt::Debug for Deferred<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.0.value.get() {
+            Some(value) => write!(f, "{:?}", value),
+            None => write!(f, "Pending"),
+        }
+    }
+}
@@ -2,44 +2,177 @@
 use bincode::Options;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
-pub fn bincode_deser<T>(bytes: &[u8]) -> bincode::Result<T>
+/// Largest fixed-size structure, the superblock.
+pub(crate) const MIN_STRUCTURE: usize = 96;
+/// Largest name of a directory entry or index in the format.
+pub(crate) const MAX_NAME: usize = 256;
+
+/// Limits on the sizes read from the image, checked before allocating buffers to protect
+/// against corrupted length fields, see [`crate::Options::limits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Fixed-size structures (at least the 96 bytes of the superblock)
+    pub structure: usize,
+    /// Names of directory entries and directory indices, at most 256 bytes in the format
+    pub name: usize,
+    /// Targets of symbolic links
+    pub target: usize,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            structure: 1000,
+            name: MAX_NAME,
+            // PATH_MAX
+            target: 4096,
+        }
+    }
+}
+impl Limits {
+    /// Check that the limits allow the images that follow the format.
+    pub(crate) fn validate(&self) -> Result<(), crate::Error> {
+        if self.structure < MIN_STRUCTURE {
+            return Err(crate::Error::InvalidOptions(
+                "The structure size limit must be at least 96 bytes",
+            ));
+        }
+        if self.name > MAX_NAME {
+            return Err(crate::Error::InvalidOptions(
+                "The name size limit cannot exceed the 256 bytes of the format",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn check_size(size: usize, limit: usize) -> bincode::Result<()> {
+    if size > limit {
+        return Err(Box::new(bincode::ErrorKind::SizeLimit));
+    }
+    Ok(())
+}
+
+/// Deserialize a structure, which must not exceed `limit` (see [`Limits::structure`]).
+pub fn bincode_deser<T>(bytes: &[u8], limit: usize) -> bincode::Result<T>
 where
     T: serde::de::DeserializeOwned,
 {
     bincode::DefaultOptions::new()
         .with_fixint_encoding()
         .reject_trailing_bytes()
-        .with_limit(1000)
+        .with_limit(limit as u64)
         .deserialize(bytes)
 }
-pub async fn bincode_deser_from<T>(mut r: impl AsyncRead + Unpin, size: usize) -> bincode::Result<T>
+/// Read a structure of `size` bytes, which must not exceed `limit` (see [`Limits::structure`]).
+pub async fn bincode_deser_from<T>(
+    mut r: impl AsyncRead + Unpin,
+    size: usize,
+    limit: usize,
+) -> bincode::Result<T>
 where
     T: serde::de::DeserializeOwned,
 {
+    check_size(size, limit)?;
     let mut buf = vec![0; size];
     r.read_exact(&mut buf).await?;
-    bincode_deser(&buf)
+    bincode_deser(&buf, limit)
 }
 
+/// Read a string of `size` bytes, which must not exceed `limit` (see [`Limits`]).
 pub async fn bincode_deser_string_from(
-    mut r: impl AsyncRead + Unpin,
+    r: impl AsyncRead + Unpin,
     size: usize,
+    limit: usize,
 ) -> bincode::Result<String> {
-    let mut buf = vec![0; size];
-    r.read_exact(&mut buf).await?;
-    Ok(std::str::from_utf8(&buf)
-        .map_err(bincode::ErrorKind::InvalidUtf8Encoding)?
-        .to_string())
+    let mut out = String::new();
+    bincode_deser_string_append(r, size, limit, &mut vec![], &mut out).await?;
+    Ok(out)
+}
+/// Same as [`bincode_deser_string_from`], appending the string to `out`, and reading it through
+/// `buf`, which can be reused between calls to avoid allocations.
+pub async fn bincode_deser_string_append(
+    mut r: impl AsyncRead + Unpin,
+    size: usize,
+    limit: usize,
+    buf: &mut Vec<u8>,
+    out: &mut String,
+) -> bincode::Result<()> {
+    check_size(size, limit)?;
+    buf.resize(size, 0);
+    r.read_exact(buf).await?;
+    out.push_str(std::str::from_utf8(buf).map_err(bincode::ErrorKind::InvalidUtf8Encoding)?);
+    Ok(())
 }
 
 macro_rules! from_reader {
     ($t:ty,$size:literal) => {
         impl $t {
-            pub async fn from_reader(mut r: impl crate::AsyncRead) -> Result<Self, bincode::Error> {
-                super::deser::bincode_deser_from(&mut r, $size).await
+            pub async fn from_reader(
+                mut r: impl crate::AsyncRead,
+                limits: &crate::Limits,
+            ) -> Result<Self, bincode::Error> {
+                super::deser::bincode_deser_from(&mut r, $size, limits.structure).await
             }
         }
     };
 }
 
 pub(crate) use from_reader;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn limits_test() {
+        // Length fields beyond the limits fail before reading (or allocating)
+        let limits = Limits::default();
+        let data = b"name";
+        for size in [limits.name + 1, u32::MAX as usize + 1] {
+            assert!(matches!(
+                *bincode_deser_string_from(&data[..], size, limits.name)
+                    .await
+                    .unwrap_err(),
+                bincode::ErrorKind::SizeLimit
+            ));
+        }
+        assert!(
+            bincode_deser_from::<u32>(&data[..], limits.structure + 1, limits.structure)
+                .await
+                .is_err()
+        );
+        // Within the limits, but beyond the data
+        assert!(matches!(
+            *bincode_deser_string_from(&data[..], limits.name, limits.name)
+                .await
+                .unwrap_err(),
+            bincode::ErrorKind::Io(_)
+        ));
+        assert_eq!(
+            bincode_deser_string_from(&data[..], 4, limits.name)
+                .await
+                .unwrap(),
+            "name"
+        );
+        assert!(bincode_deser_string_from(&[0xFF][..], 1, limits.name)
+            .await
+            .is_err());
+        assert!(bincode_deser::<u64>(&data[..], limits.structure).is_err());
+    }
+    #[test]
+    fn validate_limits_test() {
+        assert!(Limits::default().validate().is_ok());
+        for limits in [
+            Limits {
+                structure: 10,
+                ..Default::default()
+            },
+            Limits {
+                name: 1000,
+                ..Default::default()
+            },
+        ] {
+            assert!(limits.validate().is_err());
+        }
+    }
+}
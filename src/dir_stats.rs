@@ -0,0 +1,142 @@
+//! Directory statistics, see [`SquashFs::dir_stats`] and [`SquashFs::disk_usage`].
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+
+use futures::{StreamExt, TryStreamExt};
+
+use crate::{AsyncSeekBufRead, Error, SquashFs};
+
+/// Statistics of a directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DirStats {
+    /// Number of entries, excluding `.` and `..`
+    pub entries: usize,
+    /// Total size of the files below the directory, recursively (bytes)
+    pub size: u64,
+    /// Number of directories between the root and this directory (0 for the root)
+    pub depth: usize,
+}
+
+/// Sizes of a subtree, see [`SquashFs::disk_usage`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Total size of the files (bytes)
+    pub apparent: u64,
+    /// Size of the data of the files in the image (bytes), i.e. of their compressed blocks and
+    /// of their tail ends stored in fragments. Since fragments are compressed together with the
+    /// tail ends of other files, the latter are counted with their uncompressed size.
+    pub on_disk: u64,
+    /// Number of files, counting hard links once
+    pub files: u64,
+    /// Number of directories, including the root of the subtree
+    pub directories: u64,
+}
+
+impl<T, R> SquashFs<R>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+{
+    /// Statistics of the directory `inode`.
+    ///
+    /// The size of the subtree is computed on first request, which parses the directory tables
+    /// below `inode` if needed (see [`crate::Options::lazy`]), and is cached for all the
+    /// directories of the subtree.
+    pub async fn dir_stats(&self, inode: u32) -> Result<DirStats, Error> {
+        let entries = self.directory_table(inode).await?.entries.len();
+        Ok(DirStats {
+            entries,
+            size: self.dir_size(inode).await?,
+            depth: self.dir_depth(inode).await?,
+        })
+    }
+    /// Apparent and on-disk sizes of the files below `path`, as `du` would report them through
+    /// the mount, but without the overhead of a syscall per file.
+    ///
+    /// Directory tables that are not parsed yet (see [`crate::Options::lazy`]) are loaded
+    /// concurrently, up to the number of readers.
+    pub async fn disk_usage(&self, path: &Path) -> Result<DiskUsage, Error> {
+        let inode = self.resolve(path).await?;
+        let inode_table = self.inode_table.wait().await?;
+        let mut usage = DiskUsage::default();
+        let mut seen = BTreeSet::new();
+        let mut add_file = |inode: u32, usage: &mut DiskUsage| {
+            let Some(file) = inode_table.files.get(&inode) else {
+                // Unsupported inodes
+                return;
+            };
+            if seen.insert(inode) {
+                let blocks: u64 = file.block_sizes().iter().map(|b| b.compressed_size()).sum();
+                usage.files += 1;
+                usage.apparent += file.file_size();
+                usage.on_disk += blocks + file.fragment_size(&self.superblock);
+            }
+        };
+        if !inode_table.directories.contains_key(&inode) {
+            add_file(inode, &mut usage);
+            return Ok(usage);
+        }
+        let mut frontier = vec![inode];
+        while !frontier.is_empty() {
+            usage.directories += frontier.len() as u64;
+            let tables: Vec<_> = futures::stream::iter(std::mem::take(&mut frontier))
+                .map(|inode| self.directory_table(inode))
+                .buffer_unordered(self.n_readers.max(1))
+                .try_collect()
+                .await?;
+            for entry in tables.iter().flat_map(|t| &t.entries) {
+                if entry.is_dir() {
+                    frontier.push(entry.inode);
+                } else {
+                    add_file(entry.inode, &mut usage);
+                }
+            }
+        }
+        Ok(usage)
+    }
+    /// See [`DirStats::size`].
+    fn dir_size(
+        &self,
+        inode: u32,
+    ) -> Pin<Box<dyn Future<Output = Result<u64, Error>> + Send + '_>> {
+        Box::pin(async move {
+            if let Some(size) = self.dir_sizes.lock().unwrap().get(&inode) {
+                return Ok(*size);
+            }
+            let table = self.directory_table(inode).await?;
+            let inode_table = self.inode_table.wait().await?;
+            let mut size = 0;
+            for entry in &table.entries {
+                size += if entry.is_dir() {
+                    self.dir_size(entry.inode).await?
+                } else {
+                    // Unsupported inodes (e.g. devices) have no size
+                    inode_table
+                        .files
+                        .get(&entry.inode)
+                        .map(|f| f.file_size())
+                        .unwrap_or_default()
+                };
+            }
+            self.dir_sizes.lock().unwrap().insert(inode, size);
+            Ok(size)
+        })
+    }
+    /// See [`DirStats::depth`].
+    async fn dir_depth(&self, mut inode: u32) -> Result<usize, Error> {
+        let directories = &self.inode_table.wait().await?.directories;
+        let mut depth = 0;
+        while inode != self.root_inode {
+            let directory = directories.get(&inode).ok_or(Error::DirectoryNotFound)?;
+            inode = directory.parent_inode_number();
+            depth += 1;
+            // Loops in corrupted images
+            if depth > directories.len() {
+                return Err(Error::InvalidInode);
+            }
+        }
+        Ok(depth)
+    }
+}
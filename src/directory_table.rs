@@ -2,23 +2,30 @@
 //!
 //! See <https://dr-emann.github.io/squashfs/squashfs.html#_directory_table>
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
 use std::hash::{Hash, Hasher};
 use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
 
 use deser::from_reader;
 use itertools::Itertools;
 use serde::Deserialize;
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::OnceCell;
 use tracing::*;
 
+use super::deferred::Deferred;
 use super::deser;
 use super::error::DirectoryTableError;
-use super::inodes::{DirectoryInode, InodeType};
+use super::inodes::{DirectoryInode, DirectoryTableLocation, InodeType};
 use super::metadata::MetadataBlock;
 use super::superblock::SuperBlock;
+use super::warnings::{ParseEvent, Warnings};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, Deserialize)]
 struct Header {
     entries: u32,
     inode_table_offset: u32,
@@ -32,8 +39,60 @@ struct EntryInternal {
     inode_offset: i16,
     r#type: InodeType,
     name_size: u16,
+    /// Range of the name in the names of the table
     #[serde(skip)]
-    name: String,
+    name: Range<u32>,
+}
+
+/// Name of a directory [`Entry`].
+///
+/// The names of the entries of a [`DirectoryTable`] are stored together, which avoids an
+/// allocation per entry; this dereferences to the name of the entry.
+#[derive(Clone)]
+pub struct EntryName {
+    names: Arc<str>,
+    range: Range<u32>,
+}
+impl std::ops::Deref for EntryName {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.names[self.range.start as usize..self.range.end as usize]
+    }
+}
+impl AsRef<str> for EntryName {
+    fn as_ref(&self) -> &str {
+        self
+    }
+}
+impl AsRef<OsStr> for EntryName {
+    fn as_ref(&self) -> &OsStr {
+        OsStr::new(&**self)
+    }
+}
+impl AsRef<Path> for EntryName {
+    fn as_ref(&self) -> &Path {
+        Path::new(&**self)
+    }
+}
+impl PartialEq<str> for EntryName {
+    fn eq(&self, other: &str) -> bool {
+        **self == *other
+    }
+}
+impl PartialEq<&str> for EntryName {
+    fn eq(&self, other: &&str) -> bool {
+        **self == **other
+    }
+}
+impl std::fmt::Display for EntryName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self)
+    }
+}
+impl std::fmt::Debug for EntryName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&**self, f)
+    }
 }
 
 /// Directory table entry
@@ -42,7 +101,7 @@ pub struct Entry {
     _inode_metadata_offset: u32,
     pub inode: u32,
     pub r#type: InodeType,
-    pub name: String,
+    pub name: EntryName,
 }
 impl Entry {
     pub fn is_dir(&self) -> bool {
@@ -60,23 +119,40 @@ impl std::fmt::Display for Entry {
     }
 }
 impl Entry {
-    fn from(header: &Header, entry: EntryInternal) -> Self {
+    fn from(header: &Header, entry: EntryInternal, names: &Arc<str>) -> Self {
         Self {
             _inode_metadata_offset: header.inode_table_offset + entry.inode_metadata_offset as u32,
-            name: entry.name,
+            name: EntryName {
+                names: names.clone(),
+                range: entry.name,
+            },
             r#type: entry.r#type,
             inode: (header.inode_number_base as i32 + entry.inode_offset as i32) as u32,
         }
     }
 }
 impl EntryInternal {
-    async fn from_reader(mut r: impl crate::AsyncRead) -> Result<Self, DirectoryTableError> {
-        let mut entry: Self = deser::bincode_deser_from(&mut r, 8)
-            .await
-            .map_err(|_| DirectoryTableError::InvalidEntry)?;
-        entry.name = deser::bincode_deser_string_from(r, entry.name_size as usize + 1)
+    /// Read an entry, appending its name to `names` through the reused `buf`.
+    pub(crate) async fn from_reader(
+        mut r: impl crate::AsyncRead,
+        buf: &mut Vec<u8>,
+        names: &mut String,
+        limits: &deser::Limits,
+    ) -> Result<Self, DirectoryTableError> {
+        let mut entry: Self = deser::bincode_deser_from(&mut r, 8, limits.structure)
             .await
-            .map_err(|_| DirectoryTableError::InvalidEntry)?;
+            .map_err(DirectoryTableError::InvalidEntry)?;
+        let start = names.len() as u32;
+        deser::bincode_deser_string_append(
+            r,
+            entry.name_size as usize + 1,
+            limits.name,
+            buf,
+            names,
+        )
+        .await
+        .map_err(DirectoryTableError::InvalidEntry)?;
+        entry.name = start..names.len() as u32;
         Ok(entry)
     }
 }
@@ -94,6 +170,24 @@ pub struct DirectoryTable {
     index: HashMap<u64, Vec<usize>>,
 }
 impl DirectoryTable {
+    /// Estimate of the memory held by the table, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.entries.capacity() * std::mem::size_of::<Entry>()
+            + self
+                .entries
+                .first()
+                .map(|e| e.name.names.len())
+                .unwrap_or_default()
+            + self
+                .index
+                .values()
+                .map(|v| {
+                    std::mem::size_of::<u64>()
+                        + std::mem::size_of::<Vec<usize>>()
+                        + v.capacity() * std::mem::size_of::<usize>()
+                })
+                .sum::<usize>()
+    }
     pub fn find(&self, name: &str) -> Option<&Entry> {
         self.index
             .get(&index_hash(name))
@@ -102,26 +196,55 @@ impl DirectoryTable {
             .map(|i| &self.entries[*i])
             .find(|e| e.name == name)
     }
-    async fn from_reader(mut r: impl crate::AsyncRead) -> Result<Self, DirectoryTableError> {
-        // Read entries
+    pub(crate) async fn from_reader(
+        mut r: impl crate::AsyncRead,
+        warnings: &Warnings,
+        limits: &deser::Limits,
+    ) -> Result<Self, DirectoryTableError> {
+        // Read entries, with their names stored together
         let mut entries = vec![];
+        let mut names = String::new();
+        let mut buf = vec![];
         let mut header = [0; 12];
         loop {
             // Read header
             let header = match r.read_exact(&mut header).await {
-                Ok(_) => Header::from_reader(&header[..])
+                Ok(_) => Header::from_reader(&header[..], limits)
                     .await
-                    .map_err(|_| DirectoryTableError::InvalidHeader)?,
+                    .map_err(DirectoryTableError::InvalidHeader)?,
                 Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
                     break;
                 }
-                Err(_) => return Err(DirectoryTableError::InvalidHeader),
+                Err(e) => return Err(DirectoryTableError::InvalidHeader(e.into())),
             };
             debug!("Directory table header {:?}", header);
             // Read entries
             for _ in 0..header.entries + 1 {
-                let entry = EntryInternal::from_reader(&mut r).await?;
-                entries.push(Entry::from(&header, entry));
+                let entry =
+                    EntryInternal::from_reader(&mut r, &mut buf, &mut names, limits).await?;
+                entries.push((header, entry));
+            }
+        }
+        let names: Arc<str> = names.into();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(header, entry)| Entry::from(&header, entry, &names))
+            .collect();
+        for entry in &entries {
+            if !matches!(
+                entry.r#type,
+                InodeType::BasicFile
+                    | InodeType::ExtendedFile
+                    | InodeType::BasicDirectory
+                    | InodeType::ExtendedDirectory
+                    | InodeType::BasicSymlink
+                    | InodeType::ExtendedSymlink
+            ) {
+                warnings.push(ParseEvent::SkippedEntry {
+                    inode: entry.inode,
+                    name: entry.name.to_string(),
+                    inode_type: entry.r#type,
+                });
             }
         }
         Ok(DirectoryTable {
@@ -137,9 +260,17 @@ impl DirectoryTable {
     pub async fn from_reader_directory(
         directory: &Box<dyn DirectoryInode + Send + Sync>,
         superblock: &SuperBlock,
+        r: impl crate::AsyncSeekBufRead,
+        warnings: &Warnings,
+    ) -> Result<Self, DirectoryTableError> {
+        Self::from_reader_location(&directory.table_location(), superblock, r, warnings).await
+    }
+    pub async fn from_reader_location(
+        loc: &DirectoryTableLocation,
+        superblock: &SuperBlock,
         mut r: impl crate::AsyncSeekBufRead,
+        warnings: &Warnings,
     ) -> Result<Self, DirectoryTableError> {
-        let loc = directory.table_location();
         r.seek(SeekFrom::Start(
             superblock.directory_table_start + loc.start,
         ))
@@ -147,8 +278,8 @@ impl DirectoryTable {
         .map_err(DirectoryTableError::ReadFailure)?;
         let r = MetadataBlock::from_reader_flatten(
             r,
-            superblock.fragment_table_start,
-            superblock.compression,
+            superblock.directory_table_end(),
+            superblock.codec(),
         )
         .await?;
         let mut r = Box::pin(r);
@@ -158,6 +289,138 @@ impl DirectoryTable {
             .await
             .map_err(DirectoryTableError::ReadFailure)?;
         let r = r.take(loc.file_size);
-        Self::from_reader(r).await
+        Self::from_reader(r, warnings, &superblock.limits).await
+    }
+}
+
+/// Tables of all directories, indexed by directory inode.
+///
+/// Depending on the [`crate::Options`], the tables are either parsed when opening the image, in
+/// the background, or on first access (see [`crate::SquashFs::directory_table`]).
+///
+/// With [`crate::Options::background_parsing`], the set of directories is only known once the
+/// inode table has been parsed, and only the root directory is available before that.
+///
+/// This is cheaply cloneable, the clones sharing the same tables.
+#[derive(Debug, Clone)]
+pub struct DirectoryTables {
+    /// Root directory table, when parsed ahead of the others.
+    root: Option<Arc<(u32, DirectoryTable)>>,
+    tables: Deferred<BTreeMap<u32, OnceCell<DirectoryTable>>>,
+}
+impl DirectoryTables {
+    pub(crate) fn new(inodes: impl Iterator<Item = u32>) -> Self {
+        Self {
+            root: None,
+            tables: Deferred::ready(Self::cells(inodes)),
+        }
+    }
+    /// Tables with only the root directory available, see [`DirectoryTables::set_directories`].
+    pub(crate) fn with_root(inode: u32, table: DirectoryTable) -> Self {
+        Self {
+            root: Some(Arc::new((inode, table))),
+            tables: Deferred::pending(),
+        }
+    }
+    fn cells(inodes: impl Iterator<Item = u32>) -> BTreeMap<u32, OnceCell<DirectoryTable>> {
+        inodes.map(|inode| (inode, OnceCell::new())).collect()
+    }
+    /// Provide the set of directories (or the reason why it could not be determined).
+    pub(crate) fn set_directories(&self, inodes: Result<Vec<u32>, String>) {
+        self.tables
+            .set(inodes.map(|inodes| Self::cells(inodes.into_iter())));
+    }
+    /// Table of a directory, if it has already been parsed.
+    pub fn get(&self, inode: u32) -> Option<&DirectoryTable> {
+        match &self.root {
+            Some(root) if root.0 == inode => Some(&root.1),
+            _ => self.tables.get().ok()?.get(&inode)?.get(),
+        }
+    }
+    /// Estimate of the memory held by the parsed tables, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        let root = self.root.as_ref().map(|r| r.1.memory_usage());
+        let tables = self.tables.get().ok().map(|tables| {
+            tables.len() * std::mem::size_of::<(u32, OnceCell<DirectoryTable>)>()
+                + tables
+                    .values()
+                    .filter_map(|c| c.get())
+                    .map(|t| t.memory_usage())
+                    .sum::<usize>()
+        });
+        root.unwrap_or_default() + tables.unwrap_or_default()
+    }
+    /// Number of directories whose table has been parsed.
+    pub fn loaded(&self) -> usize {
+        self.root.is_some() as usize
+            + self
+                .tables
+                .get()
+                .map(|tables| tables.values().filter(|c| c.initialized()).count())
+                .unwrap_or_default()
+    }
+    /// Table of a directory, calling `load` to parse it if necessary.
+    ///
+    /// Concurrent calls for the same directory only parse it once. Returns `None` if `inode` is
+    /// not a directory.
+    pub(crate) async fn get_or_load<E, F, Fut>(
+        &self,
+        inode: u32,
+        load: F,
+    ) -> Result<Option<&DirectoryTable>, crate::Error>
+    where
+        E: Into<crate::Error>,
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<DirectoryTable, E>>,
+    {
+        if let Some(root) = &self.root {
+            if root.0 == inode {
+                return Ok(Some(&root.1));
+            }
+        }
+        match self.tables.wait().await?.get(&inode) {
+            Some(cell) => Ok(Some(
+                cell.get_or_try_init(|| async { load().await.map_err(Into::into) })
+                    .await?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn directory_entry_test() {
+        // Directory entry (offset, inode offset, type, name size) with a name of 2^16 bytes
+        let mut entry = vec![0, 0, 0, 0, 2, 0];
+        entry.extend(u16::MAX.to_le_bytes());
+        entry.extend(vec![b'a'; 1 << 16]);
+        let (mut buf, mut names) = (vec![], String::new());
+        let limits = deser::Limits::default();
+        assert!(
+            EntryInternal::from_reader(&entry[..], &mut buf, &mut names, &limits)
+                .await
+                .is_err()
+        );
+        entry[6..8].copy_from_slice(&1u16.to_le_bytes());
+        names.push_str("name");
+        let parsed = EntryInternal::from_reader(&entry[..10], &mut buf, &mut names, &limits)
+            .await
+            .unwrap();
+        assert_eq!(
+            &names[parsed.name.start as usize..parsed.name.end as usize],
+            "aa"
+        );
+        assert_eq!(names, "nameaa");
+        // Beyond a lowered limit
+        let limits = deser::Limits { name: 1, ..limits };
+        assert!(
+            EntryInternal::from_reader(&entry[..10], &mut buf, &mut names, &limits)
+                .await
+                .is_err()
+        );
     }
 }
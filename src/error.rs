@@ -12,8 +12,12 @@ pub enum Error {
     InvalidBufferSize,
     #[error("Invalid superblock")]
     InvalidSuperblock,
+    #[error("Failed to decode superblock")]
+    SuperblockDecode(#[source] bincode::Error),
     #[error("Read failure")]
-    ReadFailure(std::io::Error),
+    ReadFailure(#[source] std::io::Error),
+    #[error("Write failure")]
+    WriteFailure(#[source] std::io::Error),
     #[error("File not found: {0:?}")]
     FileNotFound(Option<String>),
     #[error("Directory not found")]
@@ -35,10 +39,18 @@ pub enum Error {
         #[from]
         source: deadpool::managed::BuildError<std::io::Error>,
     },
+    #[error("Tables are still being parsed in the background")]
+    Pending,
+    #[error("Background parsing failed: {0}")]
+    BackgroundParsing(String),
     #[error("Invalid options: {0}")]
     InvalidOptions(&'static str),
     #[error("Fragments error: {0}")]
     Fragments(#[from] FragmentsError),
+    #[error("Id table error: {0}")]
+    IdTable(#[from] IdTableError),
+    #[error("Xattr table error: {0}")]
+    XattrTable(#[from] XattrError),
     #[error("Inode table error: {0}")]
     InodeTable(#[from] InodeTableError),
     #[error("Directory table error: {0}")]
@@ -51,13 +63,63 @@ pub enum Error {
     Encoding,
     #[error("Invalid inode")]
     InvalidInode,
+    #[error("Permission denied")]
+    PermissionDenied,
+    #[error("Invalid ACL")]
+    InvalidAcl,
+    #[error("Invalid file capabilities")]
+    InvalidCapability,
+    #[error("Kernel notification failed")]
+    Notify(#[source] std::io::Error),
     #[cfg(feature = "memmap")]
     #[error("Failed to memory map file")]
-    MemMap,
+    MemMap(#[source] std::io::Error),
+    #[error("Failed to preload image")]
+    Preload(#[source] std::io::Error),
+    #[error("Truncated image: expected at least {expected} bytes, got {actual}")]
+    Truncated { expected: u64, actual: u64 },
+    #[error("Image structure extends to {end}, beyond the {bytes_used} bytes used by the image")]
+    OutOfBounds { end: u64, bytes_used: u64 },
+    #[error("Image digest {actual} does not match the expected {expected}")]
+    DigestMismatch { expected: String, actual: String },
+    #[error("Data block at {start} does not match its checksum")]
+    BlockChecksum { start: u64 },
+    #[error("Invalid block checksums: {0}")]
+    InvalidChecksums(String),
+    #[error("The data is not cached")]
+    WouldBlock,
+    #[error("Read scheduler stopped")]
+    SchedulerStopped,
+    #[error("Invalid trace record {0:?}")]
+    InvalidTrace(String),
+    #[error("Failed to spawn thread")]
+    ThreadSpawn(#[source] std::io::Error),
     #[error("{0}")]
     Fuse(#[from] ErrorFuse),
 }
 
+impl Error {
+    /// `errno` of the replies to FUSE requests, see [`crate::fuse::FuseAdapter`].
+    ///
+    /// Unlike the conversion to [`fuser_async::Error`], this reports [`Error::WouldBlock`] as
+    /// `EAGAIN`, as expected by non-blocking readers.
+    pub fn errno(&self) -> libc::c_int {
+        match self {
+            Error::FileNotFound(_) | Error::DirectoryNotFound => libc::ENOENT,
+            Error::InvalidInode | Error::InvalidOffset => libc::EINVAL,
+            Error::Encoding => libc::ENOSYS,
+            Error::PermissionDenied => libc::EACCES,
+            Error::WouldBlock => libc::EAGAIN,
+            Error::Fuse(ErrorFuse::NoFileDir) => libc::ENOENT,
+            Error::Fuse(ErrorFuse::InvalidArgument) => libc::EINVAL,
+            Error::Fuse(ErrorFuse::Unimplemented) => libc::ENOSYS,
+            Error::Fuse(ErrorFuse::BadFileDescriptor) => libc::EBADF,
+            Error::Fuse(ErrorFuse::ReadOnly) => libc::EROFS,
+            _ => libc::EIO,
+        }
+    }
+}
+
 impl From<Error> for ErrorFuse {
     fn from(source: Error) -> Self {
         match source {
@@ -77,18 +139,27 @@ pub enum DecompressError {
     Io(#[from] tokio::io::Error),
     #[error("Unsupported compression {0:?}")]
     UnsupportedCompression(Compression),
+    #[error("Decompression pool stopped")]
+    PoolStopped,
+    #[error("Unsupported XZ filters {0:#x}")]
+    UnsupportedXzFilters(u32),
+    #[error("Decompressed {got} bytes, expected {expected:?}")]
+    SizeMismatch {
+        expected: std::ops::RangeInclusive<u64>,
+        got: u64,
+    },
 }
 /// Metadata parsing error.
 #[derive(thiserror::Error, Debug)]
 pub enum MetadataError {
     #[error("Invalid header")]
-    InvalidHeader,
+    InvalidHeader(#[source] std::io::Error),
     #[error("Invalid entry")]
-    InvalidEntry,
+    InvalidEntry(#[source] bincode::Error),
     #[error("Invalid data length")]
     InvalidDataLength,
     #[error("Read failure")]
-    ReadFailure(std::io::Error),
+    ReadFailure(#[source] std::io::Error),
     #[error("Decompression error: {0}")]
     Decompress(#[from] DecompressError),
 }
@@ -96,35 +167,88 @@ pub enum MetadataError {
 #[derive(thiserror::Error, Debug)]
 pub enum InodeTableError {
     #[error("Invalid header")]
-    InvalidHeader,
+    InvalidHeader(#[source] bincode::Error),
     #[error("Invalid entry")]
-    InvalidEntry,
+    InvalidEntry(#[source] bincode::Error),
+    #[error("Root inode not found")]
+    RootNotFound,
     #[error("Invalid metadata: {0}")]
     InvalidMetadata(#[from] MetadataError),
     #[error("Read failure")]
-    ReadFailure(std::io::Error),
+    ReadFailure(#[source] std::io::Error),
 }
 /// Directory table error.
 #[derive(thiserror::Error, Debug)]
 pub enum DirectoryTableError {
     #[error("Invalid header")]
-    InvalidHeader,
+    InvalidHeader(#[source] bincode::Error),
     #[error("Invalid entry")]
-    InvalidEntry,
+    InvalidEntry(#[source] bincode::Error),
     #[error("Invalid metadata: {0}")]
     InvalidMetadata(#[from] MetadataError),
     #[error("Read failure")]
-    ReadFailure(std::io::Error),
+    ReadFailure(#[source] std::io::Error),
 }
 /// Fragments error.
 #[derive(thiserror::Error, Debug)]
 pub enum FragmentsError {
     #[error("Invalid location in fragment table")]
-    InvalidLocation,
+    InvalidLocation(#[source] Option<std::io::Error>),
+    #[error("Fragment block or table beyond the bytes used by the image")]
+    OutOfBounds,
     #[error("Invalid metadata: {0}")]
     InvalidMetadata(#[from] MetadataError),
     #[error("Invalid fragment table entry")]
+    InvalidEntry(#[source] bincode::Error),
+    #[error("Read failure")]
+    ReadFailure(#[source] std::io::Error),
+}
+/// Xattr table error.
+#[derive(thiserror::Error, Debug)]
+pub enum XattrError {
+    #[error("Invalid header")]
+    InvalidHeader(#[source] bincode::Error),
+    #[error("Invalid location in xattr table")]
+    InvalidLocation,
+    #[error("Invalid xattr index {0}")]
+    InvalidIndex(u32),
+    #[error("Invalid metadata: {0}")]
+    InvalidMetadata(#[from] MetadataError),
+    #[error("Invalid xattr table entry")]
     InvalidEntry,
     #[error("Read failure")]
-    ReadFailure(std::io::Error),
+    ReadFailure(#[source] std::io::Error),
+}
+/// Id table error.
+#[derive(thiserror::Error, Debug)]
+pub enum IdTableError {
+    #[error("Invalid location in id table")]
+    InvalidLocation(#[source] std::io::Error),
+    #[error("Invalid id index {0}")]
+    InvalidIndex(u16),
+    #[error("Invalid metadata: {0}")]
+    InvalidMetadata(#[from] MetadataError),
+    #[error("Invalid id table entry")]
+    InvalidEntry(#[source] std::io::Error),
+    #[error("Read failure")]
+    ReadFailure(#[source] std::io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn errno_test() {
+        assert_eq!(Error::WouldBlock.errno(), libc::EAGAIN);
+        assert_eq!(Error::FileNotFound(None).errno(), libc::ENOENT);
+        assert_eq!(Error::InvalidOffset.errno(), libc::EINVAL);
+        assert_eq!(Error::PermissionDenied.errno(), libc::EACCES);
+        assert_eq!(Error::Fuse(ErrorFuse::ReadOnly).errno(), libc::EROFS);
+        assert_eq!(
+            Error::Fuse(ErrorFuse::BadFileDescriptor).errno(),
+            libc::EBADF
+        );
+        let e = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(Error::ReadFailure(e).errno(), libc::EIO);
+    }
 }
@@ -0,0 +1,103 @@
+//! Error events, for embedding services to alert on failing backends or corrupted images without
+//! scraping the logs.
+//!
+//! Failed file reads are published on a bounded broadcast channel, see
+//! [`SquashFs::subscribe_errors`]. Slow subscribers miss the oldest events (see
+//! [`tokio::sync::broadcast::error::RecvError::Lagged`]), and events are dropped when there are
+//! no subscribers.
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use tokio::sync::broadcast;
+
+use crate::error::{FragmentsError, MetadataError};
+use crate::{Error, SquashFs};
+
+/// Number of events buffered for each subscriber.
+pub(crate) const CAPACITY: usize = 64;
+
+/// Category of an [`ErrorEvent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// I/O error from the underlying reader.
+    Read,
+    /// Failure to obtain a reader from the pool, e.g. when the backend is unreachable.
+    Backend,
+    /// A data or metadata block failed to decompress.
+    Decompress,
+    /// Inconsistent structures, which usually denote a corrupted image.
+    Corruption,
+}
+impl ErrorKind {
+    /// Category of `error`, or `None` for errors caused by the request (e.g. an invalid offset).
+    fn of(error: &Error) -> Option<Self> {
+        match error {
+            Error::ReadFailure(_)
+            | Error::SchedulerStopped
+            | Error::Fragments(FragmentsError::ReadFailure(_))
+            | Error::Metadata(MetadataError::ReadFailure(_)) => Some(Self::Read),
+            Error::PoolError { .. } | Error::PoolBuildError { .. } => Some(Self::Backend),
+            Error::Decompress(_)
+            | Error::Fragments(FragmentsError::InvalidMetadata(MetadataError::Decompress(_)))
+            | Error::Metadata(MetadataError::Decompress(_)) => Some(Self::Decompress),
+            Error::InvalidBufferSize
+            | Error::InvalidInode
+            | Error::OutOfBounds { .. }
+            | Error::BlockChecksum { .. }
+            | Error::Fragments(_)
+            | Error::Metadata(_)
+            | Error::InodeTable(_)
+            | Error::DirectoryTable(_) => Some(Self::Corruption),
+            _ => None,
+        }
+    }
+}
+
+/// Failed operation, with its context.
+#[derive(Debug, Clone)]
+pub struct ErrorEvent {
+    pub kind: ErrorKind,
+    /// See [`SquashFs::label`].
+    pub image: String,
+    /// Squashfs inode of the file being read.
+    pub inode: u32,
+    /// Paths of the file, if the index of [`SquashFs::paths_of`] has been built, empty
+    /// otherwise.
+    pub paths: Vec<PathBuf>,
+    pub offset: u64,
+    pub size: u64,
+    /// See [`crate::pools::request_id`].
+    pub request_id: Option<u64>,
+    /// Rendered error, which is not [`Clone`].
+    pub error: String,
+    pub time: SystemTime,
+}
+
+impl<R: deadpool::managed::Manager> SquashFs<R> {
+    /// Receive the errors occurring from now on.
+    pub fn subscribe_errors(&self) -> broadcast::Receiver<ErrorEvent> {
+        self.error_events.subscribe()
+    }
+    /// Publish `error`, which occurred while reading `size` bytes at `offset` in `inode`.
+    pub(crate) fn publish_error(&self, error: &Error, inode: u32, offset: u64, size: u64) {
+        if let Some(kind) = ErrorKind::of(error) {
+            // Only fails without subscribers
+            let _ = self.error_events.send(ErrorEvent {
+                kind,
+                image: self.label.clone(),
+                inode,
+                paths: self
+                    .paths
+                    .get()
+                    .and_then(|paths| paths.get(&inode))
+                    .cloned()
+                    .unwrap_or_default(),
+                offset,
+                size,
+                request_id: crate::pools::request_id(),
+                error: error.to_string(),
+                time: SystemTime::now(),
+            });
+        }
+    }
+}
@@ -0,0 +1,619 @@
+//! Export of the directory tree, e.g. for indexing the contents of an image without mounting it,
+//! of single files, and extraction of whole images. See [`SquashFs::export_tree`],
+//! [`SquashFs::export_file`] and [`SquashFs::extract_to`].
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::os::unix::fs::{FileExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::*;
+
+use crate::inodes::InodeType;
+use crate::{AsyncSeekBufRead, Error, ReadHints, SquashFs, SuperBlock};
+
+/// Alignment of the offsets, sizes and buffers of `O_DIRECT` writes.
+const DIRECT_ALIGNMENT: usize = 4096;
+
+/// `ioctl` cloning a file into another on filesystems sharing extents (`_IOW(0x94, 9, int)`).
+const FICLONE: libc::c_ulong = 0x40049409;
+
+/// How [`SquashFs::extract_to`] writes the files with the same data as a file already extracted.
+///
+/// `mksquashfs` stores the data of duplicate files once, which these are detected from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ArgEnum)]
+pub enum DuplicatePolicy {
+    /// Write every copy
+    #[default]
+    Copy,
+    /// Hard link the copies to the first one, which then share their permissions
+    HardLink,
+    /// Clone the first copy, sharing its extents, on filesystems supporting it (e.g. btrfs or
+    /// XFS), and write the copy otherwise
+    Reflink,
+}
+
+/// Options of [`SquashFs::extract_to`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtractOptions {
+    pub duplicates: DuplicatePolicy,
+    /// Write the files with `O_DIRECT`, see [`SquashFs::export_file`].
+    pub direct: bool,
+    /// File recording the progress of the extraction, from which an interrupted extraction
+    /// (e.g. by a failing remote backend) resumes when run again with the same options. It is
+    /// removed once the extraction completes.
+    pub state: Option<PathBuf>,
+}
+
+/// Summary of [`SquashFs::extract_to`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtractStats {
+    pub directories: u64,
+    /// Files written
+    pub files: u64,
+    /// Bytes written
+    pub bytes: u64,
+    /// Hard links of the image, and duplicates with [`DuplicatePolicy::HardLink`]
+    pub hard_links: u64,
+    /// Duplicates cloned with [`DuplicatePolicy::Reflink`]
+    pub reflinks: u64,
+    pub symlinks: u64,
+    /// Devices, fifos and sockets, which are not supported (see
+    /// [`crate::warnings::ParseEvent::UnsupportedInode`])
+    pub skipped: u64,
+    /// Files already written by an interrupted extraction, see [`ExtractOptions::state`]
+    pub resumed: u64,
+}
+
+/// Line of the state file of an extraction (see [`ExtractOptions::state`]), as JSON.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StateRecord {
+    /// Image extracted, in the first line
+    Image {
+        bytes_used: u64,
+        inode_count: u32,
+        mkfs_time: u64,
+    },
+    /// The file `inode` is written up to `offset`
+    Progress { inode: u32, offset: u64 },
+    /// The file `inode` is written completely
+    Done { inode: u32 },
+}
+impl StateRecord {
+    fn image(superblock: &SuperBlock) -> Self {
+        Self::Image {
+            bytes_used: superblock.bytes_used,
+            inode_count: superblock.inode_count,
+            mkfs_time: superblock
+                .creation_time()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+/// Progress of an extraction, read from its state file, to which the new progress is appended.
+struct ExtractState {
+    done: HashSet<u32>,
+    progress: HashMap<u32, u64>,
+    log: Arc<std::fs::File>,
+}
+impl ExtractState {
+    /// Read the state at `path`, starting over if it is missing or from another image than
+    /// `image`.
+    async fn open(path: &Path, image: StateRecord) -> Result<Self, Error> {
+        let path = path.to_owned();
+        blocking(move || {
+            let records: Vec<StateRecord> = match std::fs::read(&path) {
+                // Up to the first invalid line, e.g. truncated by a crash
+                Ok(data) => data
+                    .split(|b| *b == b'\n')
+                    .map_while(|line| serde_json::from_slice(line).ok())
+                    .collect(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+                Err(e) => return Err(e),
+            };
+            let mut state = Self {
+                done: Default::default(),
+                progress: Default::default(),
+                log: Arc::new(std::fs::File::create(&path)?),
+            };
+            if records.first() != Some(&image) {
+                if !records.is_empty() {
+                    warn!(?path, "Ignoring the extraction state of another image");
+                }
+                serde_json::to_writer(&*state.log, &image)?;
+                (&*state.log).write_all(b"\n")?;
+                return Ok(state);
+            }
+            debug!(?path, records = records.len(), "Resuming extraction");
+            // Rewritten, without any invalid line
+            for record in records {
+                serde_json::to_writer(&*state.log, &record)?;
+                (&*state.log).write_all(b"\n")?;
+                match record {
+                    StateRecord::Progress { inode, offset } => {
+                        state.progress.insert(inode, offset);
+                    }
+                    StateRecord::Done { inode } => {
+                        state.done.insert(inode);
+                    }
+                    StateRecord::Image { .. } => {}
+                }
+            }
+            Ok(state)
+        })
+        .await
+    }
+    /// Offset up to which the file `inode` was written.
+    fn offset(&self, inode: u32) -> u64 {
+        self.progress.get(&inode).copied().unwrap_or(0)
+    }
+    async fn record(&self, record: StateRecord) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(&record).map_err(|e| Error::WriteFailure(e.into()))?;
+        line.push(b'\n');
+        let log = self.log.clone();
+        blocking(move || (&*log).write_all(&line)).await
+    }
+}
+
+/// Output format of [`SquashFs::export_tree`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum TreeFormat {
+    /// One path per line, with a trailing `/` for directories
+    Text,
+    /// JSON array of [`TreeEntry`]
+    Json,
+    /// One JSON [`TreeEntry`] per line
+    Ndjson,
+}
+
+/// Type of a [`TreeEntry`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Socket,
+}
+impl From<InodeType> for EntryKind {
+    fn from(inode_type: InodeType) -> Self {
+        match inode_type {
+            InodeType::BasicFile | InodeType::ExtendedFile => Self::File,
+            InodeType::BasicDirectory | InodeType::ExtendedDirectory => Self::Directory,
+            InodeType::BasicSymlink | InodeType::ExtendedSymlink => Self::Symlink,
+            InodeType::BasicBlockDevice | InodeType::ExtendedBlockDevice => Self::BlockDevice,
+            InodeType::BasicCharDevice | InodeType::ExtendedCharDevice => Self::CharDevice,
+            InodeType::BasicFifo | InodeType::ExtendedFifo => Self::Fifo,
+            InodeType::BasicSocket | InodeType::ExtendedSocket => Self::Socket,
+        }
+    }
+}
+
+/// Entry of the directory tree, serialized as a JSON object by [`SquashFs::export_tree`].
+///
+/// The attributes are `None` for the inodes that are not supported (see
+/// [`crate::warnings::ParseEvent::UnsupportedInode`]).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TreeEntry {
+    /// Absolute path in the image, with invalid UTF-8 replaced
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: EntryKind,
+    /// Size in bytes (0 for directories, and the length of the target for symlinks)
+    pub size: Option<u64>,
+    /// Permission bits
+    pub mode: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Modification time (seconds since the epoch)
+    pub mtime: Option<u32>,
+    /// Target of symbolic links, as stored (it may be relative, and dangling)
+    pub target: Option<String>,
+}
+
+impl<T, R> SquashFs<R>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+{
+    /// Write the entries of the image (excluding the root) to `writer` in `format`, in the
+    /// order of [`SquashFs::find_names`].
+    ///
+    /// Entries are written as they are visited, without building the whole listing in memory.
+    pub async fn export_tree(
+        &self,
+        writer: impl AsyncWrite + Unpin,
+        format: TreeFormat,
+    ) -> Result<(), Error> {
+        let inode_table = self.inode_table.wait().await?;
+        let id_table = self.id_table.wait().await?;
+        let mut writer = tokio::io::BufWriter::new(writer);
+        let mut entries = std::pin::pin!(self.walk());
+        let mut first = true;
+        if format == TreeFormat::Json {
+            writer.write_all(b"[").await.map_err(Error::WriteFailure)?;
+        }
+        while let Some((path, entry)) = entries.try_next().await? {
+            let mut line = vec![];
+            if format == TreeFormat::Text {
+                line.push(b'/');
+                line.extend_from_slice(path.as_os_str().as_encoded_bytes());
+                if entry.is_dir() {
+                    line.push(b'/');
+                }
+            } else {
+                let header = inode_table.headers.get(&entry.inode);
+                let link = inode_table.symlinks.get(&entry.inode);
+                let tree_entry = TreeEntry {
+                    path: format!("/{}", path.to_string_lossy()),
+                    kind: entry.r#type.into(),
+                    size: header.map(|_| {
+                        inode_table
+                            .files
+                            .get(&entry.inode)
+                            .map(|f| f.file_size())
+                            .or(link.map(|l| l.target().len() as u64))
+                            .unwrap_or_default()
+                    }),
+                    mode: header.map(|h| h.permissions),
+                    uid: header.map(|h| id_table.get(h.uid_idx)).transpose()?,
+                    gid: header.map(|h| id_table.get(h.gid_idx)).transpose()?,
+                    mtime: header.map(|h| h.modified_time),
+                    target: link.map(|l| l.target().to_owned()),
+                };
+                if format == TreeFormat::Json {
+                    line.extend_from_slice(if first { b"\n" } else { b",\n" });
+                }
+                serde_json::to_writer(&mut line, &tree_entry)
+                    .map_err(|e| Error::WriteFailure(e.into()))?;
+            }
+            if format != TreeFormat::Json {
+                line.push(b'\n');
+            }
+            first = false;
+            writer.write_all(&line).await.map_err(Error::WriteFailure)?;
+        }
+        if format == TreeFormat::Json {
+            writer
+                .write_all(b"\n]\n")
+                .await
+                .map_err(Error::WriteFailure)?;
+        }
+        writer.flush().await.map_err(Error::WriteFailure)
+    }
+    /// Write the file `inode` to `dest` (created or truncated) with its permissions, for the
+    /// fast extraction of large files such as disk images. Returns the number of bytes written.
+    ///
+    /// Sparse blocks are left as holes in `dest`, and the other extents are preallocated with
+    /// `fallocate` (when the destination filesystem supports it). With `direct`, `dest` is
+    /// written with `O_DIRECT`, bypassing the page cache.
+    ///
+    /// The file is read as a background job (see [`crate::Options::background_readers`]),
+    /// without filling the cache.
+    pub async fn export_file(&self, inode: u32, dest: &Path, direct: bool) -> Result<u64, Error> {
+        self.export_file_from(inode, dest, direct, None).await
+    }
+    /// [`SquashFs::export_file`], resuming from and recording the progress in `state`.
+    async fn export_file_from(
+        &self,
+        inode: u32,
+        dest: &Path,
+        direct: bool,
+        state: Option<&ExtractState>,
+    ) -> Result<u64, Error> {
+        let inode_table = self.inode_table.wait().await?;
+        let file = inode_table
+            .files
+            .get(&inode)
+            .ok_or(Error::FileNotFound(None))?;
+        let size = file.file_size();
+        let mode = inode_table
+            .headers
+            .get(&inode)
+            .ok_or(Error::InvalidInode)?
+            .permissions as u32;
+        // Extents (offset, size, sparse), up to 16 blocks for data
+        let block_size = self.superblock.block_size as u64;
+        let chunk = 16 * block_size;
+        let mut extents: Vec<(u64, u64, bool)> = vec![];
+        let n_blocks = file.block_sizes().len() as u64;
+        let blocks = file
+            .block_sizes()
+            .iter()
+            .map(|b| b.compressed_size() == 0)
+            // Tail end in a fragment
+            .chain((n_blocks * block_size < size).then_some(false));
+        for (i, sparse) in blocks.enumerate() {
+            let offset = i as u64 * block_size;
+            let len = block_size.min(size - offset);
+            match extents.last_mut() {
+                Some(last) if last.2 == sparse && (sparse || last.1 < chunk) => last.1 += len,
+                _ => extents.push((offset, len, sparse)),
+            }
+        }
+        // Extents left, from the block at which the previous attempt stopped
+        let start = state.map(|s| s.offset(inode)).unwrap_or(0) / block_size * block_size;
+        let extents: Vec<_> = extents
+            .into_iter()
+            .filter(|(offset, len, _)| offset + len > start)
+            .map(|(offset, len, sparse)| {
+                let from = offset.max(start);
+                (from, offset + len - from, sparse)
+            })
+            .collect();
+
+        let dest = dest.to_owned();
+        let output = blocking(move || {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(start == 0);
+            if direct {
+                options.custom_flags(libc::O_DIRECT);
+            }
+            let output = options.open(dest)?;
+            output.set_len(size)?;
+            Ok(Arc::new(output))
+        })
+        .await?;
+        for (offset, len, _) in extents.iter().filter(|(_, _, sparse)| !sparse) {
+            let output = output.clone();
+            let (offset, len) = (*offset, *len);
+            blocking(move || fallocate(&output, offset, len)).await?;
+        }
+        let hints = ReadHints {
+            retain: false,
+            ..ReadHints::background()
+        };
+        let mut written = 0;
+        for (offset, len, _) in extents.into_iter().filter(|(_, _, sparse)| !sparse) {
+            let data = self
+                .read_file_with(
+                    inode,
+                    offset as usize,
+                    len as usize,
+                    0,
+                    self.superblock.compression,
+                    hints,
+                )
+                .await?;
+            written += data.len() as u64;
+            let output = output.clone();
+            blocking(move || {
+                if direct {
+                    write_direct(&output, &data, offset)
+                } else {
+                    output.write_all_at(&data, offset)
+                }
+            })
+            .await?;
+            if let Some(state) = state {
+                let offset = offset + len;
+                state
+                    .record(StateRecord::Progress { inode, offset })
+                    .await?;
+            }
+        }
+        blocking(move || {
+            // Padding of the last direct write
+            output.set_len(size)?;
+            output.set_permissions(std::fs::Permissions::from_mode(mode))?;
+            output.sync_all()
+        })
+        .await?;
+        if let Some(state) = state {
+            state.record(StateRecord::Done { inode }).await?;
+        }
+        Ok(written)
+    }
+    /// Extract the whole image under the directory `dest` (created if needed), returning a
+    /// summary.
+    ///
+    /// The hard links and symbolic links of the image are recreated, and the duplicate files are
+    /// written according to [`ExtractOptions::duplicates`]. The files are written with [`SquashFs::export_file`],
+    /// and the permissions of the directories are set once their contents are written. Existing
+    /// files are overwritten, except those recorded as written in [`ExtractOptions::state`].
+    pub async fn extract_to(
+        &self,
+        dest: &Path,
+        options: ExtractOptions,
+    ) -> Result<ExtractStats, Error> {
+        let inode_table = self.inode_table.wait().await?;
+        tokio::fs::create_dir_all(dest)
+            .await
+            .map_err(Error::WriteFailure)?;
+        let state = match &options.state {
+            Some(path) => {
+                Some(ExtractState::open(path, StateRecord::image(&self.superblock)).await?)
+            }
+            None => None,
+        };
+        let mut stats = ExtractStats::default();
+        // First path of each file inode, and of each data (start, size, fragment)
+        let mut inodes: HashMap<u32, PathBuf> = HashMap::new();
+        let mut contents: HashMap<(u64, u64, u32, u32), PathBuf> = HashMap::new();
+        let mut directories = vec![];
+        let mut entries = std::pin::pin!(self.walk());
+        while let Some((path, entry)) = entries.try_next().await? {
+            let target = dest.join(&path);
+            if entry.is_dir() {
+                match tokio::fs::create_dir(&target).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => {
+                        return Err(Error::WriteFailure(e));
+                    }
+                    _ => {}
+                }
+                if let Some(header) = inode_table.headers.get(&entry.inode) {
+                    directories.push((target, header.permissions as u32));
+                }
+                stats.directories += 1;
+                continue;
+            }
+            if inode_table.symlinks.contains_key(&entry.inode) {
+                let original = self.readlink(self.ino_to_fuse(entry.inode)).await?;
+                symlink(original, target).await?;
+                stats.symlinks += 1;
+                continue;
+            }
+            let Some(file) = inode_table.files.get(&entry.inode) else {
+                warn!(?path, "Skipping unsupported entry");
+                stats.skipped += 1;
+                continue;
+            };
+            if let Some(first) = inodes.get(&entry.inode) {
+                hard_link(first.clone(), target).await?;
+                stats.hard_links += 1;
+                continue;
+            }
+            inodes.insert(entry.inode, target.clone());
+            if state
+                .as_ref()
+                .is_some_and(|s| s.done.contains(&entry.inode))
+            {
+                stats.resumed += 1;
+                continue;
+            }
+            let fragment = file.fragment();
+            let key = (
+                file.blocks_start(),
+                file.file_size(),
+                fragment.index,
+                fragment.offset,
+            );
+            match (options.duplicates, contents.get(&key)) {
+                (DuplicatePolicy::HardLink, Some(first)) if file.file_size() > 0 => {
+                    debug!(?path, ?first, "Linking duplicate");
+                    hard_link(first.clone(), target).await?;
+                    stats.hard_links += 1;
+                    continue;
+                }
+                (DuplicatePolicy::Reflink, Some(first)) if file.file_size() > 0 => {
+                    let mode = inode_table
+                        .headers
+                        .get(&entry.inode)
+                        .ok_or(Error::InvalidInode)?
+                        .permissions as u32;
+                    let (first, target) = (first.clone(), target.clone());
+                    if blocking(move || reflink(&first, &target, mode)).await? {
+                        debug!(?path, "Cloned duplicate");
+                        stats.reflinks += 1;
+                        continue;
+                    }
+                }
+                (_, None) => {
+                    contents.insert(key, target.clone());
+                }
+                _ => {}
+            }
+            stats.bytes += self
+                .export_file_from(entry.inode, &target, options.direct, state.as_ref())
+                .await?;
+            stats.files += 1;
+        }
+        // Deepest first, so that read-only directories are filled before
+        for (directory, mode) in directories.into_iter().rev() {
+            tokio::fs::set_permissions(directory, std::fs::Permissions::from_mode(mode))
+                .await
+                .map_err(Error::WriteFailure)?;
+        }
+        if let Some(path) = &options.state {
+            tokio::fs::remove_file(path)
+                .await
+                .map_err(Error::WriteFailure)?;
+        }
+        debug!(?stats, "Extracted image");
+        Ok(stats)
+    }
+}
+
+/// Hard link `target` to `original`, replacing any existing `target`.
+async fn hard_link(original: PathBuf, target: PathBuf) -> Result<(), Error> {
+    blocking(move || {
+        match std::fs::remove_file(&target) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        std::fs::hard_link(original, target)
+    })
+    .await
+}
+
+/// Create the symbolic link `target` pointing to `original`, replacing any existing `target`.
+async fn symlink(original: PathBuf, target: PathBuf) -> Result<(), Error> {
+    blocking(move || {
+        match std::fs::remove_file(&target) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        std::os::unix::fs::symlink(original, target)
+    })
+    .await
+}
+
+/// Clone `original` into `target` (created or truncated) with `mode`, returning whether the
+/// filesystem supports it.
+fn reflink(original: &Path, target: &Path, mode: u32) -> std::io::Result<bool> {
+    let original = std::fs::File::open(original)?;
+    let target = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(target)?;
+    if unsafe { libc::ioctl(target.as_raw_fd(), FICLONE as _, original.as_raw_fd()) } != 0 {
+        let error = std::io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY) => {
+                debug!("Cloning is not supported: {}", error);
+                Ok(false)
+            }
+            _ => Err(error),
+        };
+    }
+    target.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    Ok(true)
+}
+
+/// Run blocking file operations on the blocking threads.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+) -> Result<T, Error> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::WriteFailure(e.into()))?
+        .map_err(Error::WriteFailure)
+}
+
+/// Allocate the extent of `file`, ignoring filesystems that do not support it.
+fn fallocate(file: &std::fs::File, offset: u64, len: u64) -> std::io::Result<()> {
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, offset as i64, len as i64) } == 0 {
+        return Ok(());
+    }
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::ENOSYS) => {
+            debug!("fallocate is not supported: {}", error);
+            Ok(())
+        }
+        _ => Err(error),
+    }
+}
+
+/// Write `data` at `offset` (aligned) to a file opened with `O_DIRECT`, from an aligned copy
+/// padded with zeros.
+fn write_direct(file: &std::fs::File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    let len = data.len().next_multiple_of(DIRECT_ALIGNMENT);
+    let mut buffer = vec![0; len + DIRECT_ALIGNMENT];
+    let start = buffer.as_ptr().align_offset(DIRECT_ALIGNMENT);
+    let aligned = &mut buffer[start..start + len];
+    aligned[..data.len()].copy_from_slice(data);
+    file.write_all_at(aligned, offset)
+}
@@ -0,0 +1,147 @@
+//! Injection of errors and latency in the read path (`failpoints` feature), to test how
+//! applications built on a mount (retries, alerting...) behave when the storage misbehaves.
+//!
+//! The failpoints are configured with the `SQUASHFS_FAILPOINTS` environment variable, read on
+//! first use, or with [`set`]. The variable holds comma-separated `name=rate[:latency_ms]`
+//! entries, e.g. `read_data_block=0.01,get_reader=0:200` fails 1% of the data block reads and
+//! delays every reader acquisition by 200 ms.
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+
+use tracing::*;
+
+use crate::Error;
+
+/// Environment variable configuring the failpoints.
+pub const ENV: &str = "SQUASHFS_FAILPOINTS";
+
+/// Location of an injection point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Failpoint {
+    /// Reads of data blocks
+    ReadDataBlock,
+    /// Acquisition of a reader from the pools
+    GetReader,
+    /// Decompression of data and metadata blocks
+    Decompress,
+}
+impl Failpoint {
+    pub const ALL: [Self; 3] = [Self::ReadDataBlock, Self::GetReader, Self::Decompress];
+    /// Name in [`ENV`].
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ReadDataBlock => "read_data_block",
+            Self::GetReader => "get_reader",
+            Self::Decompress => "decompress",
+        }
+    }
+}
+
+/// Behaviour of a [`Failpoint`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Action {
+    /// Probability of failing, between 0 and 1
+    pub error_rate: f64,
+    /// Delay added before each pass, failing or not
+    pub latency: Duration,
+}
+impl std::str::FromStr for Action {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidOptions("Failpoints must be given as rate[:latency_ms]");
+        let (rate, latency) = s.split_once(':').unwrap_or((s, "0"));
+        let error_rate: f64 = rate.trim().parse().map_err(|_| invalid())?;
+        let latency: u64 = latency.trim().parse().map_err(|_| invalid())?;
+        if !(0.0..=1.0).contains(&error_rate) {
+            return Err(Error::InvalidOptions(
+                "Failpoint error rates must be between 0 and 1",
+            ));
+        }
+        Ok(Self {
+            error_rate,
+            latency: Duration::from_millis(latency),
+        })
+    }
+}
+
+/// Parse the value of [`ENV`].
+pub fn parse(spec: &str) -> Result<HashMap<Failpoint, Action>, Error> {
+    spec.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (name, action) = entry.split_once('=').ok_or(Error::InvalidOptions(
+                "Failpoints must be given as name=action",
+            ))?;
+            let failpoint = Failpoint::ALL
+                .into_iter()
+                .find(|f| f.name() == name.trim())
+                .ok_or(Error::InvalidOptions("Unknown failpoint"))?;
+            Ok((failpoint, action.parse()?))
+        })
+        .collect()
+}
+
+fn failpoints() -> &'static RwLock<HashMap<Failpoint, Action>> {
+    static FAILPOINTS: OnceLock<RwLock<HashMap<Failpoint, Action>>> = OnceLock::new();
+    FAILPOINTS.get_or_init(|| {
+        let failpoints = match std::env::var(ENV) {
+            Ok(spec) => parse(&spec).unwrap_or_else(|e| {
+                error!("Ignoring {}: {}", ENV, e);
+                Default::default()
+            }),
+            Err(_) => Default::default(),
+        };
+        if !failpoints.is_empty() {
+            warn!("Failpoints enabled: {:?}", failpoints);
+        }
+        RwLock::new(failpoints)
+    })
+}
+
+/// Set (or with `None`, clear) the behaviour of a failpoint, replacing the one from [`ENV`].
+///
+/// This applies to all images opened in the process.
+pub fn set(failpoint: Failpoint, action: Option<Action>) {
+    let mut failpoints = failpoints().write().unwrap();
+    match action {
+        Some(action) => failpoints.insert(failpoint, action),
+        None => failpoints.remove(&failpoint),
+    };
+}
+
+/// Pass through `failpoint`, sleeping and failing as configured.
+pub(crate) async fn pass(failpoint: Failpoint) -> std::io::Result<()> {
+    let Some(action) = failpoints().read().unwrap().get(&failpoint).copied() else {
+        return Ok(());
+    };
+    if !action.latency.is_zero() {
+        tokio::time::sleep(action.latency).await;
+    }
+    if rand::random::<f64>() < action.error_rate {
+        debug!(failpoint = failpoint.name(), "Injecting failure");
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("Failure injected at {}", failpoint.name()),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn parse_test() {
+        let failpoints = parse("read_data_block=0.01, get_reader=0:200,").unwrap();
+        assert_eq!(failpoints.len(), 2);
+        assert_eq!(failpoints[&Failpoint::ReadDataBlock].error_rate, 0.01);
+        assert_eq!(
+            failpoints[&Failpoint::GetReader].latency,
+            Duration::from_millis(200)
+        );
+        for invalid in ["read=0.1", "decompress=2", "decompress", "decompress=0.1:x"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
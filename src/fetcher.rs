@@ -0,0 +1,153 @@
+//! Loading of data blocks into the cache in the background, for the reads that only use the
+//! caches (see [`crate::Options::nonblock_eagain`]) and the readahead of sequential reads (see
+//! [`crate::Options::auto_tune`]).
+use std::collections::HashSet;
+use std::ops::{DerefMut, RangeInclusive};
+use std::sync::{Arc, Mutex};
+
+use deadpool::managed::Pool;
+use tokio::sync::mpsc;
+use tracing::*;
+
+use crate::cache::BlockCache;
+use crate::checksums::BlockChecksums;
+use crate::data::{BlockSize, Priority};
+use crate::deferred::Deferred;
+use crate::fragments::{FragmentLocation, FragmentsTable};
+use crate::stats::Stats;
+use crate::{Codec, Error};
+
+/// Data or fragment block to load, with its expected decompressed size.
+#[derive(Debug, Clone)]
+pub(crate) enum Block {
+    Data {
+        start: u64,
+        size: BlockSize,
+        expected: RangeInclusive<u64>,
+    },
+    Fragment {
+        location: FragmentLocation,
+        expected: RangeInclusive<u64>,
+    },
+}
+impl Block {
+    /// Identifier of the block among those being loaded.
+    fn key(&self) -> (bool, u64) {
+        match self {
+            Self::Data { start, .. } => (false, *start),
+            Self::Fragment { location, .. } => (true, location.index as u64),
+        }
+    }
+}
+
+/// Handle to a fetcher, running in the background until all handles are dropped.
+#[derive(Clone)]
+pub(crate) struct Fetcher {
+    requests: mpsc::UnboundedSender<Vec<Block>>,
+    /// Blocks requested and not loaded yet
+    pending: Arc<Mutex<HashSet<(bool, u64)>>>,
+}
+impl Fetcher {
+    /// Start a fetcher reading from `pool` into `cache`, verifying the blocks against
+    /// `checksums`.
+    pub fn new<R>(
+        pool: Pool<R>,
+        (cache, checksums): (Arc<dyn BlockCache>, Option<Arc<BlockChecksums>>),
+        fragments_table: Deferred<FragmentsTable>,
+        (codec, block_size): (Codec, u32),
+        stats: Arc<Stats>,
+    ) -> Self
+    where
+        R: deadpool::managed::Manager<Error = std::io::Error> + 'static,
+        R::Type: crate::AsyncSeekBufRead,
+    {
+        let (requests, mut rx) = mpsc::unbounded_channel::<Vec<Block>>();
+        let pending: Arc<Mutex<HashSet<(bool, u64)>>> = Default::default();
+        crate::utils::spawn_named("fetcher", {
+            let pending = pending.clone();
+            async move {
+                while let Some(blocks) = rx.recv().await {
+                    for block in blocks {
+                        let fetch = fetch(
+                            &pool,
+                            (cache.as_ref(), checksums.as_deref()),
+                            &fragments_table,
+                            &block,
+                            (codec.clone(), block_size),
+                            &stats,
+                        );
+                        if let Err(e) = fetch.await {
+                            warn!(?block, "Failed to load block in the background: {}", e);
+                        }
+                        pending.lock().unwrap().remove(&block.key());
+                    }
+                }
+            }
+        });
+        Self { requests, pending }
+    }
+    /// Load `blocks` in the background, skipping those already requested.
+    pub fn fetch(&self, blocks: impl IntoIterator<Item = Block>) {
+        let blocks: Vec<_> = {
+            let mut pending = self.pending.lock().unwrap();
+            blocks
+                .into_iter()
+                .filter(|block| pending.insert(block.key()))
+                .collect()
+        };
+        if !blocks.is_empty() {
+            debug!(blocks = blocks.len(), "Loading blocks in the background");
+            // Only fails once the fetcher has stopped, with the runtime
+            let _ = self.requests.send(blocks);
+        }
+    }
+}
+
+async fn fetch<R>(
+    pool: &Pool<R>,
+    (cache, checksums): (&dyn BlockCache, Option<&BlockChecksums>),
+    fragments_table: &Deferred<FragmentsTable>,
+    block: &Block,
+    (codec, block_size): (Codec, u32),
+    stats: &Stats,
+) -> Result<(), Error>
+where
+    R: deadpool::managed::Manager<Error = std::io::Error>,
+    R::Type: crate::AsyncSeekBufRead,
+{
+    if let Block::Data { start, .. } = block {
+        // E.g. read ahead, but reached by the reads in the meantime
+        if cache.get(*start, 0..0, &mut bytes::BytesMut::new()).await {
+            return Ok(());
+        }
+    }
+    let mut reader = pool.get().await?;
+    let (start, size, expected) = match block {
+        Block::Data {
+            start,
+            size,
+            expected,
+        } => (*start, *size, expected.clone()),
+        Block::Fragment { location, expected } => {
+            let entry = fragments_table
+                .wait()
+                .await?
+                .entry(*location, reader.deref_mut())
+                .await?;
+            (entry.start, entry.size, expected.clone())
+        }
+    };
+    let mut buf = bytes::BytesMut::with_capacity(block_size as usize);
+    crate::data::read_data_block(
+        reader.deref_mut(),
+        0,
+        start,
+        size,
+        &mut buf,
+        checksums,
+        (Some(cache), None),
+        (&codec, expected),
+        (None, Priority::Background, Some(stats)),
+    )
+    .await
+}
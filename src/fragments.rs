@@ -1,10 +1,16 @@
 //! Fragments and fragments table.
-use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use futures::stream::Stream;
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::*;
 
 use super::data;
 use super::deser;
 use super::error::FragmentsError;
+use super::locks::KeyedLocks;
 use super::metadata;
 use super::superblock::SuperBlock;
 
@@ -20,68 +26,229 @@ impl FragmentLocation {
     }
 }
 
-/// Fragments table entry
-#[derive(Debug, Deserialize)]
+/// Fragments table entry, describing a fragment block.
+///
+/// Serialized (e.g. to JSON) as its `start`, `compressed_size` and `compressed` flag.
+#[derive(Debug, Clone, Copy, Deserialize)]
 pub struct Entry {
     pub start: u64,
     pub size: data::BlockSize,
     _unused: u32,
 }
+impl Entry {
+    /// Range of the block in the archive.
+    pub fn data_range(&self) -> std::ops::Range<u64> {
+        self.start..self.start + self.size.compressed_size()
+    }
+    pub fn compressed(&self) -> bool {
+        self.size.compressed()
+    }
+    /// Size of the block in the archive.
+    pub fn compressed_size(&self) -> u64 {
+        self.size.compressed_size()
+    }
+}
+impl Serialize for Entry {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Entry", 3)?;
+        s.serialize_field("start", &self.start)?;
+        s.serialize_field("compressed_size", &self.compressed_size())?;
+        s.serialize_field("compressed", &self.compressed())?;
+        s.end()
+    }
+}
+/// Number of entries in each metadata block of the table.
+const ENTRIES_PER_BLOCK: usize = 512;
+/// Number of metadata blocks kept in memory.
+const CACHED_BLOCKS: usize = 64;
+
 /// Fragments table (a simple list of [`Entry`])
-#[derive(Default, Debug)]
+///
+/// Only the locations of the metadata blocks are read when opening the image. The blocks
+/// themselves are read on demand, and the most recently used ones are kept in memory.
+#[derive(Debug)]
 pub struct FragmentsTable {
-    pub entries: Vec<Entry>,
+    /// Locations of the metadata blocks
+    pub locations: Vec<u64>,
+    count: u32,
+    codec: data::Codec,
+    /// See [`crate::Options::limits`]
+    limits: deser::Limits,
+    /// See [`SuperBlock::bytes_used`]
+    bytes_used: u64,
+    /// Recently used metadata blocks, by index, the most recent last.
+    blocks: Mutex<VecDeque<(usize, Arc<[Entry]>)>>,
+    /// Loads of the metadata blocks in progress, so that concurrent misses read them once.
+    loads: KeyedLocks,
 }
 impl std::fmt::Display for FragmentsTable {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Fragment table with {} entries", self.entries.len())
+        write!(
+            f,
+            "Fragment table with {} entries ({}/{} blocks loaded)",
+            self.count,
+            self.blocks.lock().unwrap().len(),
+            self.locations.len()
+        )
     }
 }
 
 impl FragmentsTable {
-    /// Get an entry from its location
-    pub fn entry(&self, location: FragmentLocation) -> Result<&Entry, FragmentsError> {
-        if !location.valid() {
-            return Err(FragmentsError::InvalidLocation);
+    /// Estimate of the memory held by the table, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.locations.capacity() * std::mem::size_of::<u64>()
+            + self
+                .blocks
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(_, entries)| entries.len() * std::mem::size_of::<Entry>())
+                .sum::<usize>()
+    }
+    /// Number of entries.
+    pub fn len(&self) -> u32 {
+        self.count
+    }
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+    /// Iterate over all the entries with their index, reading the metadata blocks that are not in
+    /// memory (without keeping them).
+    pub fn entries<'a>(
+        &'a self,
+        mut r: impl crate::AsyncSeekBufRead + 'a,
+    ) -> impl Stream<Item = Result<(u32, Entry), FragmentsError>> + 'a {
+        async_stream::try_stream! {
+            for block in 0..self.locations.len() {
+                let entries = match self.cached_block(block) {
+                    Some(entries) => entries,
+                    None => self.read_block(block, &mut r).await?,
+                };
+                for (i, entry) in entries.iter().enumerate() {
+                    let index = (block * ENTRIES_PER_BLOCK + i) as u32;
+                    if index < self.count {
+                        yield (index, *entry);
+                    }
+                }
+            }
         }
-
-        self.entries
-            .get(location.index as usize)
-            .ok_or(FragmentsError::InvalidLocation)
     }
-    /// Read fragments table
+    /// Get an entry from its location, reading the metadata block containing it if necessary.
+    pub async fn entry(
+        &self,
+        location: FragmentLocation,
+        r: impl crate::AsyncSeekBufRead,
+    ) -> Result<Entry, FragmentsError> {
+        if !location.valid() || location.index >= self.count {
+            return Err(FragmentsError::InvalidLocation(None));
+        }
+        let block = location.index as usize / ENTRIES_PER_BLOCK;
+        let entries = match self.cached_block(block) {
+            Some(entries) => entries,
+            None => {
+                let _guard = self.loads.lock(block as u64).await;
+                // Loaded by another caller while we were waiting
+                match self.cached_block(block) {
+                    Some(entries) => entries,
+                    None => {
+                        let entries = self.read_block(block, r).await?;
+                        let mut blocks = self.blocks.lock().unwrap();
+                        if blocks.len() >= CACHED_BLOCKS {
+                            blocks.pop_front();
+                        }
+                        blocks.push_back((block, entries.clone()));
+                        entries
+                    }
+                }
+            }
+        };
+        let entry = entries
+            .get(location.index as usize % ENTRIES_PER_BLOCK)
+            .copied()
+            .ok_or(FragmentsError::InvalidLocation(None))?;
+        if entry.data_range().end > self.bytes_used {
+            return Err(FragmentsError::OutOfBounds);
+        }
+        Ok(entry)
+    }
+    /// Get an entry from its location, if the metadata block containing it is cached.
+    pub fn cached_entry(&self, location: FragmentLocation) -> Option<Entry> {
+        if !location.valid() || location.index >= self.count {
+            return None;
+        }
+        self.cached_block(location.index as usize / ENTRIES_PER_BLOCK)?
+            .get(location.index as usize % ENTRIES_PER_BLOCK)
+            .copied()
+            .filter(|entry| entry.data_range().end <= self.bytes_used)
+    }
+    fn cached_block(&self, block: usize) -> Option<Arc<[Entry]>> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let i = blocks.iter().position(|(b, _)| *b == block)?;
+        // Move to the back
+        let cached = blocks.remove(i).unwrap();
+        blocks.push_back(cached.clone());
+        Some(cached.1)
+    }
+    async fn read_block(
+        &self,
+        block: usize,
+        mut r: impl crate::AsyncSeekBufRead,
+    ) -> Result<Arc<[Entry]>, FragmentsError> {
+        let location = self
+            .locations
+            .get(block)
+            .ok_or(FragmentsError::InvalidLocation(None))?;
+        r.seek(std::io::SeekFrom::Start(*location))
+            .await
+            .map_err(FragmentsError::ReadFailure)?;
+        let block = metadata::MetadataBlock::from_reader(&mut r, &self.codec).await?;
+        block
+            .data
+            .chunks(16)
+            .map(|entry| deser::bincode_deser(entry, self.limits.structure))
+            .collect::<Result<Arc<[Entry]>, _>>()
+            .map_err(FragmentsError::InvalidEntry)
+    }
+    /// Read the locations of the fragments table metadata blocks
     pub async fn from_reader(
         superblock: &SuperBlock,
         mut r: impl crate::AsyncSeekBufRead,
     ) -> Result<Self, FragmentsError> {
+        if !superblock.has_fragments() {
+            debug!("No fragments table");
+            return Ok(Self {
+                locations: vec![],
+                count: 0,
+                codec: superblock.codec(),
+                limits: superblock.limits,
+                bytes_used: superblock.bytes_used,
+                blocks: Default::default(),
+                loads: Default::default(),
+            });
+        }
         r.seek(std::io::SeekFrom::Start(superblock.fragment_table_start))
             .await
             .map_err(FragmentsError::ReadFailure)?;
-        let n = (superblock.fragment_entry_count as f64 / 512.0).ceil() as usize;
+        let n = (superblock.fragment_entry_count as f64 / ENTRIES_PER_BLOCK as f64).ceil() as usize;
         let mut locations = Vec::<u64>::with_capacity(n);
         for _ in 0..n {
             locations.push(
                 r.read_u64_le()
                     .await
-                    .map_err(|_| FragmentsError::InvalidLocation)?,
+                    .map_err(|e| FragmentsError::InvalidLocation(Some(e)))?,
             )
         }
-        let mut entries = Vec::<Entry>::with_capacity(superblock.fragment_entry_count as usize);
-        for l in locations {
-            r.seek(std::io::SeekFrom::Start(l))
-                .await
-                .map_err(FragmentsError::ReadFailure)?;
-            let block =
-                metadata::MetadataBlock::from_reader(&mut r, superblock.compression).await?;
-            entries.extend(
-                block
-                    .data
-                    .chunks(16)
-                    .map(deser::bincode_deser)
-                    .collect::<Result<Vec<Entry>, _>>()
-                    .map_err(|_| FragmentsError::InvalidEntry)?,
-            );
+        if locations.iter().any(|l| *l >= superblock.bytes_used) {
+            return Err(FragmentsError::OutOfBounds);
         }
-        Ok(Self { entries })
+        Ok(Self {
+            locations,
+            count: superblock.fragment_entry_count,
+            codec: superblock.codec(),
+            limits: superblock.limits,
+            bytes_used: superblock.bytes_used,
+            blocks: Default::default(),
+            loads: Default::default(),
+        })
     }
 }
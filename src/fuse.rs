@@ -0,0 +1,410 @@
+//! FUSE adapter, implementing [`fuser::Filesystem`] on the filesystems of this crate.
+//!
+//! Contrary to [`fuser_async::FilesystemFUSE`], the requests are answered with the `errno` of the
+//! errors (see [`Error::errno`]), so that e.g. non-blocking reads of data that is not cached fail
+//! with `EAGAIN`, and the operations that [`fuser_async::Filesystem`] does not forward are served.
+//! The modifications are forwarded too, and fail with `EROFS` rather than `ENOSYS`.
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use fuser_async::{DirEntry, Filesystem};
+use tracing::*;
+
+use crate::{Error, Statfs};
+
+/// Validity of the attributes and entries given to the kernel.
+const TTL: Duration = Duration::from_secs(1);
+
+/// Operations of the filesystems served by [`FuseAdapter`], beyond [`fuser_async::Filesystem`].
+///
+/// This is implemented by [`crate::SquashFs`], [`crate::SharedSquashFs`] and
+/// [`crate::supervisor::Supervisor`].
+#[async_trait::async_trait]
+pub trait FuseFilesystem: Filesystem<Error = Error> + Send + Sync + 'static {
+    /// See [`crate::SquashFs::open_reply_flags`].
+    async fn open_reply_flags(&self, ino: u64, flags: i32) -> Result<u32, Error>;
+    /// See [`crate::SquashFs::access`].
+    async fn access(&self, ino: u64, uid: u32, gid: u32, mask: i32) -> Result<(), Error>;
+    /// Statistics of the filesystem containing `ino`, see [`crate::SquashFs::statfs`].
+    async fn statfs(&self, ino: u64) -> Result<Statfs, Error>;
+    /// See [`crate::SquashFs::readlink`].
+    async fn readlink(&self, ino: u64) -> Result<std::path::PathBuf, Error>;
+    /// See [`crate::SquashFs::opendir`].
+    async fn opendir(&self, ino: u64) -> Result<u64, Error>;
+    /// Entries of the directory `ino` opened as `fh`, following the cookie `offset`, with their
+    /// cookies, see [`crate::SquashFs::readdir_handle`].
+    async fn readdir_handle(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+    ) -> Result<Box<dyn Iterator<Item = (u64, DirEntry)> + Send + Sync + '_>, Error>;
+    /// See [`crate::SquashFs::releasedir`].
+    async fn releasedir(&self, ino: u64, fh: u64) -> Result<(), Error>;
+    /// See [`fuser_async::Filesystem::setattr`], which takes `&mut self`.
+    ///
+    /// The filesystems of this crate are read-only: this and the other modifications below fail
+    /// with `EROFS`, as [`fuser_async::Filesystem::write`].
+    async fn setattr(&self, ino: u64, size: Option<u64>) -> Result<fuser::FileAttr, Error>;
+    /// See [`fuser_async::Filesystem::create`], which takes `&mut self`.
+    async fn create(
+        &self,
+        parent: u64,
+        name: OsString,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+    ) -> Result<(fuser::FileAttr, u64), Error>;
+    /// See [`fuser_async::Filesystem::mkdir`], which takes `&mut self`.
+    async fn mkdir(&self, parent: u64, name: OsString) -> Result<fuser::FileAttr, Error>;
+    /// See [`crate::SquashFs::unlink`].
+    async fn unlink(&self, parent: u64, name: &OsStr) -> Result<(), Error>;
+    /// See [`crate::SquashFs::rmdir`].
+    async fn rmdir(&self, parent: u64, name: &OsStr) -> Result<(), Error>;
+    /// See [`crate::SquashFs::rename`].
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+    ) -> Result<(), Error>;
+    /// See [`crate::SquashFs::symlink`].
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        target: &Path,
+    ) -> Result<fuser::FileAttr, Error>;
+}
+
+/// [`fuser::Filesystem`] serving a [`FuseFilesystem`] on a tokio runtime.
+pub struct FuseAdapter<F> {
+    fs: Arc<F>,
+    runtime: tokio::runtime::Handle,
+}
+impl<F: FuseFilesystem> FuseAdapter<F> {
+    /// Serve `fs`, on the current tokio runtime.
+    ///
+    /// # Panics
+    ///
+    /// When called outside of a tokio runtime.
+    pub fn new(fs: F) -> Self {
+        Self {
+            fs: Arc::new(fs),
+            runtime: tokio::runtime::Handle::current(),
+        }
+    }
+    /// Serve a request in the background.
+    fn spawn<Fut: std::future::Future<Output = ()> + Send + 'static>(
+        &self,
+        request: impl FnOnce(Arc<F>) -> Fut,
+    ) {
+        self.runtime.spawn(request(self.fs.clone()));
+    }
+}
+
+/// `errno` of the reply to a failed request.
+fn errno(op: &str, error: Error) -> libc::c_int {
+    debug!(op, "Request failed: {}", error);
+    error.errno()
+}
+
+impl<F: FuseFilesystem> fuser::Filesystem for FuseAdapter<F> {
+    fn lookup(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        let name = name.to_owned();
+        self.spawn(|fs| async move {
+            match fs.lookup(parent, &name).await {
+                Ok(attr) => reply.entry(&TTL, &attr, 0),
+                Err(e) => reply.error(errno("lookup", e)),
+            }
+        });
+    }
+    fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
+        self.spawn(|fs| async move {
+            match fs.getattr(ino).await {
+                Ok(attr) => reply.attr(&TTL, &attr),
+                Err(e) => reply.error(errno("getattr", e)),
+            }
+        });
+    }
+    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        self.spawn(|fs| async move {
+            match fs.readlink(ino).await {
+                Ok(target) => reply.data(target.as_os_str().as_bytes()),
+                Err(e) => reply.error(errno("readlink", e)),
+            }
+        });
+    }
+    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        self.spawn(|fs| async move {
+            let opened = async {
+                let reply_flags = fs.open_reply_flags(ino, flags).await?;
+                Ok((fs.open(ino, flags).await?, reply_flags))
+            };
+            match opened.await {
+                Ok((fh, reply_flags)) => reply.opened(fh, reply_flags),
+                Err(e) => reply.error(errno("open", e)),
+            }
+        });
+    }
+    fn read(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        self.spawn(|fs| async move {
+            match fs.read(ino, fh, offset, size).await {
+                Ok(data) => reply.data(&data),
+                Err(e) => reply.error(errno("read", e)),
+            }
+        });
+    }
+    fn release(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.spawn(|fs| async move {
+            match fs.release(ino, fh).await {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(errno("release", e)),
+            }
+        });
+    }
+    fn opendir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _flags: i32,
+        reply: fuser::ReplyOpen,
+    ) {
+        self.spawn(|fs| async move {
+            match fs.opendir(ino).await {
+                Ok(fh) => reply.opened(fh, 0),
+                Err(e) => reply.error(errno("opendir", e)),
+            }
+        });
+    }
+    fn readdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
+    ) {
+        self.spawn(|fs| async move {
+            match fs.readdir_handle(ino, fh, offset as u64).await {
+                Ok(entries) => {
+                    // The kernel passes the cookie of the last entry it received to the next call
+                    for (cookie, entry) in entries {
+                        if reply.add(entry.inode, cookie as i64, entry.file_type, &entry.name) {
+                            break;
+                        }
+                    }
+                    reply.ok()
+                }
+                Err(e) => reply.error(errno("readdir", e)),
+            }
+        });
+    }
+    fn releasedir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.spawn(|fs| async move {
+            match fs.releasedir(ino, fh).await {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(errno("releasedir", e)),
+            }
+        });
+    }
+    fn statfs(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+        self.spawn(|fs| async move {
+            match fs.statfs(ino).await {
+                Ok(s) => reply.statfs(
+                    s.blocks, s.bfree, s.bavail, s.files, s.ffree, s.bsize, s.namelen, s.frsize,
+                ),
+                Err(e) => reply.error(errno("statfs", e)),
+            }
+        });
+    }
+    fn setattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<fuser::TimeOrNow>,
+        _mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        self.spawn(|fs| async move {
+            match FuseFilesystem::setattr(fs.as_ref(), ino, size).await {
+                Ok(attr) => reply.attr(&TTL, &attr),
+                Err(e) => reply.error(errno("setattr", e)),
+            }
+        });
+    }
+    fn write(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        let data = bytes::Bytes::copy_from_slice(data);
+        self.spawn(|fs| async move {
+            match fs.write(ino, fh, data, offset).await {
+                Ok(written) => reply.written(written),
+                Err(e) => reply.error(errno("write", e)),
+            }
+        });
+    }
+    fn create(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        let name = name.to_owned();
+        self.spawn(|fs| async move {
+            match FuseFilesystem::create(fs.as_ref(), parent, name, mode, umask, flags).await {
+                Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
+                Err(e) => reply.error(errno("create", e)),
+            }
+        });
+    }
+    fn mkdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        let name = name.to_owned();
+        self.spawn(|fs| async move {
+            match FuseFilesystem::mkdir(fs.as_ref(), parent, name).await {
+                Ok(attr) => reply.entry(&TTL, &attr, 0),
+                Err(e) => reply.error(errno("mkdir", e)),
+            }
+        });
+    }
+    fn unlink(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let name = name.to_owned();
+        self.spawn(|fs| async move {
+            match fs.unlink(parent, &name).await {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(errno("unlink", e)),
+            }
+        });
+    }
+    fn rmdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let name = name.to_owned();
+        self.spawn(|fs| async move {
+            match fs.rmdir(parent, &name).await {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(errno("rmdir", e)),
+            }
+        });
+    }
+    fn symlink(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        let (name, link) = (name.to_owned(), link.to_owned());
+        self.spawn(|fs| async move {
+            match fs.symlink(parent, &name, &link).await {
+                Ok(attr) => reply.entry(&TTL, &attr, 0),
+                Err(e) => reply.error(errno("symlink", e)),
+            }
+        });
+    }
+    fn rename(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        _flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let (name, new_name) = (name.to_owned(), new_name.to_owned());
+        self.spawn(|fs| async move {
+            match fs.rename(parent, &name, new_parent, &new_name).await {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(errno("rename", e)),
+            }
+        });
+    }
+    fn access(&mut self, req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        let (uid, gid) = (req.uid(), req.gid());
+        self.spawn(|fs| async move {
+            match fs.access(ino, uid, gid, mask).await {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(errno("access", e)),
+            }
+        });
+    }
+}
@@ -0,0 +1,68 @@
+//! Synchronous parsing entry points over byte slices, for fuzzing (see `fuzz/`).
+//!
+//! These are not part of the public API.
+use std::io::Cursor;
+
+use futures::executor::block_on;
+
+use crate::directory_table::DirectoryTable;
+use crate::error::{DirectoryTableError, InodeTableError, MetadataError};
+use crate::inodes::{Inode, InodeHeader, InodeTable};
+use crate::superblock::{Compression, SuperBlock};
+use crate::Error;
+
+/// Parse a superblock, followed by the compressor options if any.
+pub fn superblock(data: &[u8]) -> Result<SuperBlock, Error> {
+    block_on(SuperBlock::from_reader(
+        Cursor::new(data),
+        &Default::default(),
+    ))
+}
+
+/// Valid superblock with the given block size and compression, used to parse the other
+/// structures.
+fn fuzz_superblock(block_size: u32, compression: Compression) -> SuperBlock {
+    let mut data = [0; 96];
+    data[0..4].copy_from_slice(&0x73717368u32.to_le_bytes());
+    data[12..16].copy_from_slice(&block_size.to_le_bytes());
+    data[20..22].copy_from_slice(&(compression as u16).to_le_bytes());
+    data[28..30].copy_from_slice(&4u16.to_le_bytes());
+    superblock(&data).unwrap()
+}
+
+/// Parse an inode (header and body), with 128 KiB blocks.
+pub fn inode(data: &[u8]) -> Result<(InodeHeader, Option<Inode>), InodeTableError> {
+    let superblock = fuzz_superblock(128 * 1024, Compression::Zstd);
+    block_on(async {
+        let mut r = data;
+        let header = InodeHeader::from_reader(&mut r, &superblock.limits)
+            .await
+            .map_err(InodeTableError::InvalidHeader)?;
+        let inode =
+            InodeTable::read_inode(&header, &mut r, &superblock, &Default::default()).await?;
+        Ok((header, inode))
+    })
+}
+
+/// Parse the (uncompressed) contents of a directory table: headers and entries.
+pub fn directory_table(data: &[u8]) -> Result<DirectoryTable, DirectoryTableError> {
+    block_on(DirectoryTable::from_reader(
+        data,
+        &Default::default(),
+        &Default::default(),
+    ))
+}
+
+/// Parse a fragment table entry.
+pub fn fragment_entry(data: &[u8]) -> Result<crate::fragments::Entry, bincode::Error> {
+    crate::deser::bincode_deser(data, crate::Limits::default().structure)
+}
+
+/// Parse and decompress a metadata block, returning its data.
+pub fn metadata_block(data: &[u8], compression: Compression) -> Result<Vec<u8>, MetadataError> {
+    block_on(crate::metadata::MetadataBlock::from_reader(
+        Cursor::new(data),
+        &compression.into(),
+    ))
+    .map(|block| block.data)
+}
@@ -0,0 +1,67 @@
+//! Callbacks on the file operations, for embedding applications to implement audit logging,
+//! usage analytics or fetching policies without wrapping the FUSE implementation.
+//!
+//! The hooks are registered with [`SquashFs::with_hooks`], and run synchronously in the
+//! operations: they should return quickly, and hand off any slow work, e.g. with
+//! [`tokio::spawn`].
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::{pools, Error, SquashFs};
+
+/// File concerned by a hook.
+#[derive(Debug, Clone, Copy)]
+pub struct HookFile<'a> {
+    /// Squashfs inode.
+    pub inode: u32,
+    /// Paths of the file, if the index of [`SquashFs::paths_of`] has been built, empty
+    /// otherwise.
+    pub paths: &'a [PathBuf],
+    /// Flags of the read or of the handle, see [`crate::Options::open_flags`].
+    pub flags: pools::ReadFlags,
+}
+
+/// Callbacks on the file operations, all doing nothing by default.
+pub trait FileHooks: Send + Sync {
+    /// `file` was opened with [`fuser_async::Filesystem::open`], as handle `fh`.
+    fn on_open(&self, _file: &HookFile, _fh: u64) {}
+    /// The handle `fh` on `file` was released.
+    fn on_release(&self, _file: &HookFile, _fh: u64) {}
+    /// A read of `size` bytes at `offset` in `file` failed, either through FUSE or with the
+    /// methods of [`SquashFs`]. Non-blocking reads failing with [`Error::WouldBlock`] are not
+    /// reported.
+    fn on_read_error(&self, _file: &HookFile, _offset: u64, _size: u64, _error: &Error) {}
+}
+
+impl<R: deadpool::managed::Manager> SquashFs<R> {
+    /// Register callbacks on the file operations, replacing any previous ones.
+    pub fn with_hooks(mut self, hooks: Arc<dyn FileHooks>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+    /// Run `f` on the hooks, if any, with the file `inode` read or opened with `flags`.
+    pub(crate) fn run_hooks(
+        &self,
+        inode: u32,
+        flags: pools::ReadFlags,
+        f: impl FnOnce(&dyn FileHooks, &HookFile),
+    ) {
+        let Some(hooks) = &self.hooks else {
+            return;
+        };
+        let paths = self
+            .paths
+            .get()
+            .and_then(|paths| paths.get(&inode))
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        f(
+            hooks.as_ref(),
+            &HookFile {
+                inode,
+                paths,
+                flags,
+            },
+        );
+    }
+}
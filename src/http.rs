@@ -0,0 +1,351 @@
+//! Export of an image over HTTP, without FUSE, e.g. from containers without `/dev/fuse`.
+//!
+//! [`serve`] answers `GET` and `HEAD` requests on the paths of the image: files are sent as is,
+//! directories as plain-text listings, with one entry per line and a trailing `/` for
+//! subdirectories, and symlinks as redirections to their target (or not found, for absolute
+//! targets, which are outside of the image). Single byte ranges (`Range: bytes=start-end`) are
+//! supported, so that large files can be fetched in parts or resumed.
+//!
+//! This is a minimal HTTP/1.1 server: connections are closed after each response, and there is
+//! no TLS nor authentication, so it should only listen on trusted networks.
+//!
+//! There is no NFS export: NFS clients need stable file handles and a portmapper/mountd setup
+//! that are out of the scope of this crate, and FUSE or HTTP cover the use cases so far.
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::*;
+
+use crate::{pools, AsyncSeekBufRead, Error, FileKind, SquashFs};
+
+/// Maximal size of the request line and headers.
+const MAX_HEADER: u64 = 16 * 1024;
+/// Time to receive the request line and headers, after which the request fails, so that slow
+/// clients cannot hold connections.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve `fs` on the connections accepted by `listener`, until accepting fails.
+pub async fn serve<T, R>(fs: Arc<SquashFs<R>>, listener: TcpListener) -> Result<(), Error>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync + 'static,
+{
+    loop {
+        let (stream, peer) = listener.accept().await.map_err(Error::ReadFailure)?;
+        let fs = fs.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(&fs, stream).await {
+                debug!(%peer, "HTTP connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Parsed request.
+struct Request {
+    head: bool,
+    path: String,
+    range: Option<String>,
+}
+
+/// Response to a request: its status, headers and the start of its body, followed by the part
+/// of a file streamed once they are sent.
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+    /// Inode, offset and length of the rest of the body
+    rest: Option<(u32, u64, u64)>,
+}
+impl Response {
+    fn status(status: &'static str) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: vec![],
+            rest: None,
+        }
+    }
+}
+
+async fn handle<T, R>(fs: &SquashFs<R>, stream: TcpStream) -> Result<(), Error>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+{
+    let (read, mut write) = stream.into_split();
+    let request = match read_request(BufReader::new(read), REQUEST_TIMEOUT).await? {
+        Ok(request) => request,
+        Err(status) => return respond(&mut write, &Response::status(status)).await,
+    };
+    debug!(path = request.path, "HTTP request");
+    // Failures before the response is sent are reported to the client
+    let response = match response(fs, &request).await {
+        Ok(response) => response,
+        Err(e) => {
+            respond(&mut write, &Response::status("500 Internal Server Error")).await?;
+            return Err(e);
+        }
+    };
+    respond(&mut write, &response).await?;
+    if let (false, Some((inode, offset, size))) = (request.head, response.rest) {
+        // Past the headers, failures can only close the connection, short of `Content-Length`
+        let flags: pools::ReadFlags = 0;
+        fs.copy_file(inode, offset, size, flags, &mut write).await?;
+    }
+    write.shutdown().await.map_err(Error::WriteFailure)
+}
+
+async fn response<T, R>(fs: &SquashFs<R>, request: &Request) -> Result<Response, Error>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+{
+    let inode = match fs.resolve(Path::new(&request.path)).await {
+        Ok(inode) => inode,
+        Err(Error::FileNotFound(_) | Error::DirectoryNotFound) => {
+            return Ok(Response::status("404 Not Found"))
+        }
+        Err(e) => return Err(e),
+    };
+    let attr = fs.attr(inode)?;
+    match attr.kind {
+        FileKind::Directory => {
+            let mut listing = String::new();
+            for entry in &fs.directory_table(inode).await?.entries {
+                listing += &entry.name;
+                listing += if entry.is_dir() { "/\n" } else { "\n" };
+            }
+            return Ok(Response {
+                status: "200 OK",
+                headers: vec![
+                    ("Content-Type", "text/plain; charset=utf-8".into()),
+                    ("Content-Length", listing.len().to_string()),
+                ],
+                body: if request.head {
+                    vec![]
+                } else {
+                    listing.into_bytes()
+                },
+                rest: None,
+            });
+        }
+        FileKind::Symlink => {
+            let target = fs.readlink(fs.ino_to_fuse(inode)).await?;
+            // Absolute targets are outside of the image
+            if target.is_absolute() {
+                return Ok(Response::status("404 Not Found"));
+            }
+            let parent = Path::new(&request.path).parent().unwrap_or(Path::new("/"));
+            let location = parent.join(target);
+            let Some(location) = location.to_str().map(percent_encode) else {
+                return Ok(Response::status("404 Not Found"));
+            };
+            let mut response = Response::status("302 Found");
+            response.headers.push(("Location", location));
+            return Ok(response);
+        }
+        FileKind::File => {}
+    }
+    let (status, start, end) = match request.range.as_deref().map(|r| parse_range(r, attr.size)) {
+        None => ("200 OK", 0, attr.size),
+        Some(Some((start, end))) => ("206 Partial Content", start, end),
+        Some(None) => {
+            let mut response = Response::status("416 Range Not Satisfiable");
+            response
+                .headers
+                .push(("Content-Range", format!("bytes */{}", attr.size)));
+            return Ok(response);
+        }
+    };
+    let mut headers = vec![
+        ("Content-Type", "application/octet-stream".into()),
+        ("Content-Length", (end - start).to_string()),
+        ("Accept-Ranges", "bytes".into()),
+    ];
+    if request.range.is_some() {
+        headers.push((
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, end.max(1) - 1, attr.size),
+        ));
+    }
+    // Read the first block before answering, so that e.g. corrupted or unreadable files fail
+    // with an error status rather than a truncated body
+    let mut body = vec![];
+    if !request.head {
+        let first = (end - start).min(fs.superblock.block_size as u64);
+        let flags: pools::ReadFlags = 0;
+        fs.copy_file(inode, start, first, flags, &mut body).await?;
+    }
+    let rest = end - start - body.len() as u64;
+    Ok(Response {
+        status,
+        headers,
+        rest: (rest > 0).then_some((inode, start + body.len() as u64, rest)),
+        body,
+    })
+}
+
+/// [`parse_request`], failing if the request is not received within `timeout`.
+async fn read_request(
+    read: impl tokio::io::AsyncBufRead + Unpin,
+    timeout: Duration,
+) -> Result<Result<Request, &'static str>, Error> {
+    tokio::time::timeout(timeout, parse_request(read))
+        .await
+        .unwrap_or(Ok(Err("408 Request Timeout")))
+}
+
+/// Parse the request line and headers, or return the status of the error response.
+async fn parse_request(
+    read: impl tokio::io::AsyncBufRead + Unpin,
+) -> Result<Result<Request, &'static str>, Error> {
+    let mut lines = read.take(MAX_HEADER).lines();
+    let Some(line) = lines.next_line().await.map_err(Error::ReadFailure)? else {
+        return Ok(Err("400 Bad Request"));
+    };
+    let mut range = None;
+    loop {
+        match lines.next_line().await.map_err(Error::ReadFailure)? {
+            // Headers too long
+            None => return Ok(Err("431 Request Header Fields Too Large")),
+            Some(header) if header.is_empty() => break,
+            Some(header) => {
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("range") {
+                        range = Some(value.trim().to_string());
+                    }
+                }
+            }
+        }
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(Err("400 Bad Request"));
+    };
+    let head = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return Ok(Err("405 Method Not Allowed")),
+    };
+    let target = target.split(['?', '#']).next().unwrap_or_default();
+    let Some(path) = percent_decode(target) else {
+        return Ok(Err("400 Bad Request"));
+    };
+    Ok(Ok(Request { head, path, range }))
+}
+
+async fn respond(write: &mut (impl AsyncWrite + Unpin), response: &Response) -> Result<(), Error> {
+    let mut head = format!("HTTP/1.1 {}\r\nConnection: close\r\n", response.status);
+    if response.status.starts_with("405") {
+        head += "Allow: GET, HEAD\r\n";
+    }
+    for (name, value) in &response.headers {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    if !response
+        .headers
+        .iter()
+        .any(|(name, _)| *name == "Content-Length")
+    {
+        head += &format!("Content-Length: {}\r\n", response.body.len());
+    }
+    head += "\r\n";
+    write
+        .write_all(head.as_bytes())
+        .await
+        .map_err(Error::WriteFailure)?;
+    write
+        .write_all(&response.body)
+        .await
+        .map_err(Error::WriteFailure)
+}
+
+/// Decode the `%XX` escapes of a path.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.bytes();
+    while let Some(c) = chars.next() {
+        if c == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(c);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Escape the bytes of a path that are not allowed in URLs, e.g. for the `Location` header.
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.bytes() {
+        if c.is_ascii_alphanumeric() || b"/-._~!$&'()*+,;=:@".contains(&c) {
+            out.push(c as char);
+        } else {
+            out += &format!("%{:02X}", c);
+        }
+    }
+    out
+}
+
+/// Bounds (end excluded) of a single `bytes=` range in a file of `size` bytes, or `None` if it
+/// is invalid or not satisfiable.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // Suffix
+        ("", suffix) => (size.saturating_sub(suffix.parse().ok()?), size),
+        (start, "") => (start.parse().ok()?, size),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.saturating_add(1),
+        ),
+    };
+    let end = end.min(size);
+    (start < end).then_some((start, end))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn parse_range_test() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some((0, 10)));
+        assert_eq!(parse_range("bytes=90-", 100), Some((90, 100)));
+        assert_eq!(parse_range("bytes=-10", 100), Some((90, 100)));
+        assert_eq!(parse_range("bytes=90-200", 100), Some((90, 100)));
+        assert_eq!(parse_range("bytes=100-", 100), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
+    }
+    #[tokio::test]
+    async fn read_request_test() {
+        let timeout = Duration::from_millis(10);
+        let request = &b"GET /a%20b HTTP/1.1\r\nRange: bytes=0-9\r\n\r\n"[..];
+        let request = read_request(request, timeout).await.unwrap().ok().unwrap();
+        assert_eq!(request.path, "/a b");
+        assert_eq!(request.range.as_deref(), Some("bytes=0-9"));
+        // Headers that never end
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert!(matches!(
+            read_request(BufReader::new(server), timeout).await,
+            Ok(Err("408 Request Timeout"))
+        ));
+    }
+    #[test]
+    fn percent_decode_test() {
+        assert_eq!(percent_decode("/a%20b/c").as_deref(), Some("/a b/c"));
+        assert_eq!(percent_decode("/%e2%9c%93").as_deref(), Some("/✓"));
+        assert_eq!(percent_decode("/%2"), None);
+        assert_eq!(percent_decode("/%ff"), None);
+        for path in ["/a b/c", "/✓?#%"] {
+            assert_eq!(percent_decode(&percent_encode(path)).as_deref(), Some(path));
+        }
+        assert_eq!(percent_encode("/a b/c"), "/a%20b/c");
+    }
+}
@@ -0,0 +1,70 @@
+//! Id (uid/gid) lookup table.
+//!
+//! See <https://dr-emann.github.io/squashfs/squashfs.html#_id_table>
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::error::IdTableError;
+use super::metadata;
+use super::superblock::SuperBlock;
+
+/// Id table, mapping the uid/gid indices stored in inodes to actual ids.
+#[derive(Default, Debug)]
+pub struct IdTable {
+    pub ids: Vec<u32>,
+}
+impl std::fmt::Display for IdTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Id table with {} entries", self.ids.len())
+    }
+}
+
+impl IdTable {
+    /// Estimate of the memory held by the table, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.ids.capacity() * std::mem::size_of::<u32>()
+    }
+    /// Get an id from its index
+    pub fn get(&self, index: u16) -> Result<u32, IdTableError> {
+        self.ids
+            .get(index as usize)
+            .copied()
+            .ok_or(IdTableError::InvalidIndex(index))
+    }
+    /// Read id table
+    pub async fn from_reader(
+        superblock: &SuperBlock,
+        mut r: impl crate::AsyncSeekBufRead,
+    ) -> Result<Self, IdTableError> {
+        r.seek(std::io::SeekFrom::Start(superblock.id_table_start))
+            .await
+            .map_err(IdTableError::ReadFailure)?;
+        // Each metadata block holds 2048 ids.
+        let n = (superblock.id_count as f64 / 2048.0).ceil() as usize;
+        let mut locations = Vec::<u64>::with_capacity(n);
+        for _ in 0..n {
+            locations.push(
+                r.read_u64_le()
+                    .await
+                    .map_err(IdTableError::InvalidLocation)?,
+            )
+        }
+        let mut data = Vec::<u8>::with_capacity(4 * superblock.id_count as usize);
+        for l in locations {
+            r.seek(std::io::SeekFrom::Start(l))
+                .await
+                .map_err(IdTableError::ReadFailure)?;
+            let block = metadata::MetadataBlock::from_reader(&mut r, &superblock.codec()).await?;
+            data.extend(block.data);
+        }
+        let mut data = &data[..];
+        let mut ids = Vec::<u32>::with_capacity(superblock.id_count as usize);
+        for _ in 0..superblock.id_count {
+            ids.push(
+                data.read_u32_le()
+                    .await
+                    .map_err(IdTableError::InvalidEntry)?,
+            );
+        }
+        Ok(Self { ids })
+    }
+}
@@ -15,6 +15,14 @@ pub trait DirectoryInode: std::fmt::Debug {
     fn hard_link_count(&self) -> u32;
     fn parent_inode_number(&self) -> u32;
     fn table_location(&self) -> DirectoryTableLocation;
+    /// Index of the extended attributes in the [`crate::xattrs::XattrTable`], if any.
+    fn xattr_index(&self) -> Option<u32> {
+        None
+    }
+    /// Heap memory held by the inode, in bytes.
+    fn heap_size(&self) -> usize {
+        0
+    }
 }
 #[derive(Debug, Default, Deserialize)]
 pub struct BasicDirectory {
@@ -50,13 +58,16 @@ struct DirectoryIndex {
     name: String,
 }
 impl DirectoryIndex {
-    pub async fn from_reader(mut r: impl crate::AsyncRead) -> Result<Self, InodeTableError> {
-        let mut index: Self = deser::bincode_deser_from(&mut r, 12)
+    pub async fn from_reader(
+        mut r: impl crate::AsyncRead,
+        limits: &deser::Limits,
+    ) -> Result<Self, InodeTableError> {
+        let mut index: Self = deser::bincode_deser_from(&mut r, 12, limits.structure)
             .await
-            .map_err(|_| InodeTableError::InvalidEntry)?;
-        index.name = deser::bincode_deser_string_from(r, index.name_size as usize + 1)
+            .map_err(InodeTableError::InvalidEntry)?;
+        index.name = deser::bincode_deser_string_from(r, index.name_size as usize + 1, limits.name)
             .await
-            .map_err(|_| InodeTableError::InvalidEntry)?;
+            .map_err(InodeTableError::InvalidEntry)?;
         Ok(index)
     }
 }
@@ -69,7 +80,7 @@ pub struct ExtendedDirectory {
     parent_inode_number: u32,
     index_count: u16,
     block_offset: u16,
-    _xattr_idx: u32,
+    xattr_idx: u32,
     #[serde(skip)]
     index: Vec<DirectoryIndex>,
 }
@@ -87,14 +98,25 @@ impl DirectoryInode for ExtendedDirectory {
             file_size: self.file_size as u64,
         }
     }
+    fn xattr_index(&self) -> Option<u32> {
+        (self.xattr_idx != crate::xattrs::NO_XATTRS).then_some(self.xattr_idx)
+    }
+    fn heap_size(&self) -> usize {
+        self.index.capacity() * std::mem::size_of::<DirectoryIndex>()
+            + self.index.iter().map(|i| i.name.capacity()).sum::<usize>()
+    }
 }
 impl ExtendedDirectory {
-    pub async fn from_reader(mut r: impl crate::AsyncRead) -> Result<Self, InodeTableError> {
-        let mut dir: Self = deser::bincode_deser_from(&mut r, 24)
+    pub async fn from_reader(
+        mut r: impl crate::AsyncRead,
+        limits: &deser::Limits,
+    ) -> Result<Self, InodeTableError> {
+        let mut dir: Self = deser::bincode_deser_from(&mut r, 24, limits.structure)
             .await
-            .map_err(|_| InodeTableError::InvalidEntry)?;
+            .map_err(InodeTableError::InvalidEntry)?;
         for _ in 0..dir.index_count {
-            dir.index.push(DirectoryIndex::from_reader(&mut r).await?);
+            dir.index
+                .push(DirectoryIndex::from_reader(&mut r, limits).await?);
         }
         Ok(dir)
     }
@@ -15,13 +15,23 @@ pub trait FileInode: Send + Sync + std::fmt::Debug {
     fn add_block_size(&mut self, size: BlockSize);
     fn block_sizes(&self) -> &Vec<BlockSize>;
     fn fragment(&self) -> FragmentLocation;
+    fn hard_link_count(&self) -> u32 {
+        1
+    }
+    /// Index of the extended attributes in the [`crate::xattrs::XattrTable`], if any.
+    fn xattr_index(&self) -> Option<u32> {
+        None
+    }
+    /// Heap memory held by the inode, in bytes.
+    fn heap_size(&self) -> usize {
+        self.block_sizes().capacity() * std::mem::size_of::<BlockSize>()
+    }
     fn fragment_size(&self, superblock: &SuperBlock) -> u64 {
-        let fragment = self.fragment();
-        if !fragment.valid() {
-            0
-        } else {
-            self.file_size() % superblock.block_size as u64
-        }
+        fragment_size(
+            self.file_size(),
+            superblock.block_size as u64,
+            self.fragment().valid(),
+        )
     }
     fn data_locations(&self) -> Box<dyn Iterator<Item = data::DataLocation> + '_> {
         let mut block_start = self.blocks_start();
@@ -35,30 +45,80 @@ pub trait FileInode: Send + Sync + std::fmt::Debug {
         }))
     }
 }
+/// Number of data blocks of a file, excluding the tail end stored in a fragment if `fragment`.
+///
+/// Empty files have no blocks, and files whose size is a multiple of the block size have no
+/// tail end.
+pub(crate) fn n_blocks(file_size: u64, block_size: u64, fragment: bool) -> u64 {
+    if fragment {
+        file_size / block_size
+    } else {
+        file_size.div_ceil(block_size)
+    }
+}
+/// Size of the tail end of a file stored in a fragment.
+pub(crate) fn fragment_size(file_size: u64, block_size: u64, fragment: bool) -> u64 {
+    if fragment {
+        file_size % block_size
+    } else {
+        0
+    }
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    const BS: u64 = 131072;
+    #[test]
+    fn blocks_test() {
+        // Empty file
+        assert_eq!(n_blocks(0, BS, false), 0);
+        assert_eq!(fragment_size(0, BS, false), 0);
+        // Single fragment
+        assert_eq!(n_blocks(100, BS, true), 0);
+        assert_eq!(fragment_size(100, BS, true), 100);
+        // Single short block, without fragments
+        assert_eq!(n_blocks(100, BS, false), 1);
+        // Block-aligned
+        for fragment in [true, false] {
+            assert_eq!(n_blocks(BS, BS, fragment), 1);
+            assert_eq!(n_blocks(3 * BS, BS, fragment), 3);
+        }
+        assert_eq!(fragment_size(3 * BS, BS, false), 0);
+        // Tail end
+        assert_eq!(n_blocks(3 * BS + 1, BS, true), 3);
+        assert_eq!(fragment_size(3 * BS + 1, BS, true), 1);
+        assert_eq!(n_blocks(3 * BS + 1, BS, false), 4);
+        // No loss of precision on large files
+        let large = (1 << 46) + 1;
+        assert_eq!(n_blocks(large * BS, BS, false), large);
+        assert_eq!(n_blocks(large * BS + 1, BS, false), large + 1);
+    }
+}
+
 #[async_trait]
 pub trait FileInodeDeser: FileInode + serde::de::DeserializeOwned + Sized {
     fn encoded_size() -> usize;
     fn n_blocks(&self, superblock: &SuperBlock) -> u32 {
-        let n = self.file_size() as f64 / superblock.block_size as f64;
-        if self.fragment().valid() {
-            n.floor() as u32
-        } else {
-            n.ceil() as u32
-        }
+        n_blocks(
+            self.file_size(),
+            superblock.block_size as u64,
+            self.fragment().valid(),
+        ) as u32
     }
 
     async fn from_reader(
         mut r: impl AsyncRead + std::marker::Unpin + Send + Sync,
         superblock: &SuperBlock,
     ) -> Result<Self, InodeTableError> {
-        let mut file: Self = deser::bincode_deser_from(&mut r, Self::encoded_size())
-            .await
-            .map_err(|_| InodeTableError::InvalidEntry)?;
+        let mut file: Self =
+            deser::bincode_deser_from(&mut r, Self::encoded_size(), superblock.limits.structure)
+                .await
+                .map_err(InodeTableError::InvalidEntry)?;
         for _ in 0..file.n_blocks(superblock) {
             file.add_block_size(BlockSize(
                 r.read_u32_le()
                     .await
-                    .map_err(|_| InodeTableError::InvalidEntry)?,
+                    .map_err(|e| InodeTableError::InvalidEntry(e.into()))?,
             ));
         }
         Ok(file)
@@ -108,10 +168,10 @@ pub(crate) struct ExtendedFile {
     blocks_start: u64,
     file_size: u64,
     _sparse: u64,
-    _hard_link_count: u32,
+    hard_link_count: u32,
     fragment_index: u32,
     fragment_offset: u32,
-    _xattr_idx: u32,
+    xattr_idx: u32,
     #[serde(skip)]
     block_sizes: Vec<BlockSize>,
 }
@@ -134,6 +194,12 @@ impl FileInode for ExtendedFile {
     fn file_size(&self) -> u64 {
         self.file_size
     }
+    fn hard_link_count(&self) -> u32 {
+        self.hard_link_count
+    }
+    fn xattr_index(&self) -> Option<u32> {
+        (self.xattr_idx != crate::xattrs::NO_XATTRS).then_some(self.xattr_idx)
+    }
     fn fragment(&self) -> FragmentLocation {
         FragmentLocation {
             index: self.fragment_index,
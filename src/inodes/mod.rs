@@ -4,37 +4,61 @@
 mod file;
 pub use file::FileInode;
 use file::FileInodeDeser;
+pub(crate) use file::{fragment_size, n_blocks};
 use file::{BasicFile, ExtendedFile};
 mod directory;
 use directory::{BasicDirectory, ExtendedDirectory};
 pub use directory::{DirectoryInode, DirectoryTableLocation};
 mod symlink;
+pub use symlink::Symlink;
 
 use std::collections::BTreeMap;
 use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 
 use deser::from_reader;
 use serde::Deserialize;
 use serde_repr::Deserialize_repr;
-use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, ReadBuf};
 use tracing::*;
 
 use super::deser;
 use super::error::InodeTableError;
-use super::metadata::MetadataBlock;
+use super::metadata::{MetadataBlock, MetadataIndex};
 use super::superblock::SuperBlock;
+use super::warnings::{ParseEvent, Warnings};
 
 /// Reference to an inode, encoding block start and offset.
-#[derive(Debug, Copy, Clone, Deserialize)]
+///
+/// Directory entries and the superblock (for the root inode) refer to inodes this way, see
+/// [`InodeTable::read_inode_at`] to read them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize)]
 pub struct InodeRef(u64);
 impl InodeRef {
-    fn block_start(&self) -> u64 {
+    /// Reference from the start of the metadata block (relative to the start of the inode
+    /// table, fitting in 48 bits) and the offset in its uncompressed data.
+    pub fn new(block_start: u64, block_offset: u16) -> Self {
+        debug_assert!(block_start < 1 << 48);
+        Self((block_start << 16) | block_offset as u64)
+    }
+    /// Start of the metadata block, relative to the start of the inode table.
+    pub fn block_start(&self) -> u64 {
         self.0 >> 16
     }
-    fn block_offset(&self) -> u64 {
+    /// Offset of the inode in the uncompressed metadata block.
+    pub fn block_offset(&self) -> u64 {
         self.0 & 0xFFFF
     }
 }
+/// `block_start:block_offset`, e.g. `511:416`.
+impl std::fmt::Display for InodeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.block_start(), self.block_offset())
+    }
+}
 #[cfg(test)]
 mod test {
     use super::*;
@@ -43,10 +67,12 @@ mod test {
         let iref = InodeRef(33489312);
         assert_eq!(iref.block_start(), 511);
         assert_eq!(iref.block_offset(), 416);
+        assert_eq!(InodeRef::new(511, 416), iref);
+        assert_eq!(iref.to_string(), "511:416");
     }
 }
 
-#[derive(Debug, Deserialize_repr)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize_repr)]
 #[repr(u16)]
 pub enum InodeType {
     BasicDirectory = 1,
@@ -70,17 +96,59 @@ impl InodeType {
     }
 }
 
+/// Common inode header
 #[derive(Debug, Deserialize)]
-struct InodeHeader {
-    inode_type: InodeType,
-    _permissions: u16,
-    _uid_idx: u16,
-    _gid_idx: u16,
-    _modified_time: u32,
-    inode_number: u32,
+pub struct InodeHeader {
+    pub inode_type: InodeType,
+    /// Permission bits
+    pub permissions: u16,
+    /// Index into the [`crate::id_table::IdTable`]
+    pub uid_idx: u16,
+    /// Index into the [`crate::id_table::IdTable`]
+    pub gid_idx: u16,
+    /// Seconds since the epoch
+    pub modified_time: u32,
+    pub inode_number: u32,
 }
 from_reader!(InodeHeader, 16);
 
+/// Kind of a supported inode
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// File attributes, without depending on FUSE types.
+///
+/// See [`crate::SquashFs::attr`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FileAttrLite {
+    pub inode: u32,
+    /// Size in bytes (0 for directories, and the length of the target for symlinks)
+    pub size: u64,
+    pub kind: FileKind,
+    /// Permission bits
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    /// Modification time. SquashFS does not store access or change times.
+    pub mtime: std::time::SystemTime,
+    /// Creation time of the archive, see [`SuperBlock::creation_time`]. SquashFS does not store
+    /// birth times for files.
+    pub crtime: std::time::SystemTime,
+    pub nlink: u32,
+}
+
+/// Parsed inode of a supported type
+#[derive(Debug)]
+pub enum Inode {
+    File(Box<dyn FileInode + Send + Sync>),
+    Directory(Box<dyn DirectoryInode + Send + Sync>),
+    Symlink(Symlink),
+}
+
 /// Inode table
 #[derive(Default, Debug)]
 pub struct InodeTable {
@@ -90,107 +158,283 @@ pub struct InodeTable {
     // https://github.com/dtolnay/async-trait/issues/215
     pub directories: BTreeMap<u32, Box<dyn DirectoryInode + Send + Sync>>,
     pub files: BTreeMap<u32, Box<dyn FileInode + Send + Sync>>,
+    pub symlinks: BTreeMap<u32, Symlink>,
+    /// Headers of the files, directories and symlinks above
+    pub headers: BTreeMap<u32, InodeHeader>,
+    /// Number of the root inode (see [`SuperBlock::root_inode`])
+    pub root: u32,
+    /// Positions of the metadata blocks of the table, to read inodes from their uncompressed
+    /// offsets (e.g. with [`InodeTable::read_inode_at`])
+    pub index: MetadataIndex,
+}
+
+/// Reader counting the number of bytes read.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.count += (buf.filled().len() - before) as u64;
+        res
+    }
 }
 impl std::fmt::Display for InodeTable {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Inode table with {} directories and {} files",
+            "Inode table with {} directories, {} files and {} symlinks",
             self.directories.len(),
-            self.files.len()
+            self.files.len(),
+            self.symlinks.len()
         )
     }
 }
 impl InodeTable {
+    /// Estimate of the memory held by the table, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        let entry = std::mem::size_of::<u32>() + std::mem::size_of::<Box<dyn FileInode>>();
+        self.files
+            .values()
+            .map(|f| entry + std::mem::size_of_val(f.as_ref()) + f.heap_size())
+            .sum::<usize>()
+            + self
+                .directories
+                .values()
+                .map(|d| entry + std::mem::size_of_val(d.as_ref()) + d.heap_size())
+                .sum::<usize>()
+            + self
+                .symlinks
+                .values()
+                .map(|l| std::mem::size_of::<(u32, Symlink)>() + l.heap_size())
+                .sum::<usize>()
+            + self.headers.len() * (std::mem::size_of::<u32>() + std::mem::size_of::<InodeHeader>())
+            + self.index.memory_usage()
+    }
+    /// Reference of the inode at `offset` in the uncompressed table, see
+    /// [`InodeTable::index`].
+    pub fn inode_ref(&self, offset: u64) -> Option<InodeRef> {
+        let (position, offset) = self.index.locate(offset)?;
+        Some(InodeRef::new(position, offset as u16))
+    }
+    /// End of the data blocks of the files, which must fit in the image.
+    pub fn data_end(&self) -> u64 {
+        self.files
+            .values()
+            .filter_map(|f| f.data_locations().last())
+            .map(|l| l.block_start + l.block_size.compressed_size())
+            .max()
+            .unwrap_or(0)
+    }
     pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
-        self.directories.keys().chain(self.files.keys()).copied()
+        self.directories
+            .keys()
+            .chain(self.files.keys())
+            .chain(self.symlinks.keys())
+            .copied()
+    }
+    /// Index of the extended attributes of `inode` in the [`crate::xattrs::XattrTable`], if
+    /// it has some (only extended inodes do).
+    pub fn xattr_index(&self, inode: u32) -> Option<u32> {
+        if let Some(f) = self.files.get(&inode) {
+            f.xattr_index()
+        } else if let Some(d) = self.directories.get(&inode) {
+            d.xattr_index()
+        } else {
+            self.symlinks.get(&inode)?.xattr_index()
+        }
     }
     async fn inode_table_bytes<'a>(
         superblock: &'a SuperBlock,
         mut r: impl crate::AsyncSeekBufRead + 'a,
-        inode_ref: Option<InodeRef>,
+        inode_ref: InodeRef,
     ) -> Result<impl crate::AsyncRead + 'a, InodeTableError> {
         r.seek(SeekFrom::Start(
-            superblock.inode_table_start + inode_ref.map(|x| x.block_start()).unwrap_or_default(),
+            superblock.inode_table_start + inode_ref.block_start(),
         ))
         .await
         .map_err(InodeTableError::ReadFailure)?;
         let r = MetadataBlock::from_reader_flatten(
             r,
             superblock.directory_table_start,
-            superblock.compression,
+            superblock.codec(),
         )
         .await?;
         let mut r = Box::pin(r);
-        if let Some(inode_ref) = inode_ref {
-            let r2 = &mut r;
-            tokio::io::copy(
-                &mut r2.take(inode_ref.block_offset()),
-                &mut tokio::io::sink(),
-            )
-            .await
-            .map_err(InodeTableError::ReadFailure)?;
-        }
+        let r2 = &mut r;
+        tokio::io::copy(
+            &mut r2.take(inode_ref.block_offset()),
+            &mut tokio::io::sink(),
+        )
+        .await
+        .map_err(InodeTableError::ReadFailure)?;
         Ok(r)
     }
-    pub async fn read_root_inode(
+    /// Read the inode following `header`.
+    ///
+    /// Returns `None` for unsupported inode types, which are reported to `warnings`.
+    pub(crate) async fn read_inode(
+        header: &InodeHeader,
+        mut r: impl crate::AsyncRead,
+        superblock: &SuperBlock,
+        warnings: &Warnings,
+    ) -> Result<Option<Inode>, InodeTableError> {
+        Ok(Some(match header.inode_type {
+            InodeType::BasicFile => {
+                let file = BasicFile::from_reader(&mut r, superblock).await?;
+                check_file(header, &file, superblock, warnings);
+                Inode::File(Box::new(file))
+            }
+            InodeType::ExtendedFile => {
+                let file = ExtendedFile::from_reader(&mut r, superblock).await?;
+                check_file(header, &file, superblock, warnings);
+                Inode::File(Box::new(file))
+            }
+            InodeType::BasicDirectory => Inode::Directory(Box::new(
+                BasicDirectory::from_reader(&mut r, &superblock.limits)
+                    .await
+                    .map_err(InodeTableError::InvalidEntry)?,
+            )),
+            InodeType::ExtendedDirectory => Inode::Directory(Box::new(
+                ExtendedDirectory::from_reader(&mut r, &superblock.limits).await?,
+            )),
+            InodeType::BasicSymlink => {
+                Inode::Symlink(Symlink::from_reader(&mut r, false, &superblock.limits).await?)
+            }
+            InodeType::ExtendedSymlink => {
+                Inode::Symlink(Symlink::from_reader(&mut r, true, &superblock.limits).await?)
+            }
+            _ => {
+                warnings.push(ParseEvent::UnsupportedInode {
+                    inode: header.inode_number,
+                    inode_type: header.inode_type,
+                });
+                return Ok(None);
+            }
+        }))
+    }
+    /// Read a single inode from its reference, without parsing the whole table.
+    ///
+    /// Returns `None` for the inode types that are not supported, which are reported to
+    /// `warnings`.
+    pub async fn read_inode_at(
         inode_ref: InodeRef,
         superblock: &SuperBlock,
         mut r: impl crate::AsyncSeekBufRead,
-    ) -> Result<u32, InodeTableError> {
-        let mut r = Self::inode_table_bytes(superblock, &mut r, Some(inode_ref)).await?;
-        let header = InodeHeader::from_reader(&mut r)
+        warnings: &Warnings,
+    ) -> Result<(InodeHeader, Option<Inode>), InodeTableError> {
+        let mut r = Self::inode_table_bytes(superblock, &mut r, inode_ref).await?;
+        let header = InodeHeader::from_reader(&mut r, &superblock.limits)
             .await
-            .map_err(|_| InodeTableError::InvalidHeader)?;
-        Ok(header.inode_number)
+            .map_err(InodeTableError::InvalidHeader)?;
+        let inode = Self::read_inode(&header, &mut r, superblock, warnings).await?;
+        Ok((header, inode))
     }
     pub async fn from_reader(
         superblock: &SuperBlock,
         mut r: impl crate::AsyncSeekBufRead,
+        warnings: &Warnings,
     ) -> Result<Self, InodeTableError> {
         debug!("Reading inode table");
         let mut table = InodeTable::default();
-        let mut r = Self::inode_table_bytes(superblock, &mut r, None).await?;
+        r.seek(SeekFrom::Start(superblock.inode_table_start))
+            .await
+            .map_err(InodeTableError::ReadFailure)?;
+        // Offset of the root inode in the uncompressed table, determined once its metadata block
+        // is read. This avoids a separate pass to find the root inode.
+        let root_block = superblock.inode_table_start + superblock.root_inode.block_start();
+        let root_offset = Arc::new(AtomicU64::new(u64::MAX));
+        let index = Arc::new(Mutex::new(MetadataIndex::default()));
+        let mut uncompressed = 0;
+        let r = MetadataBlock::from_reader_flatten_inspect(
+            &mut r,
+            superblock.directory_table_start,
+            superblock.codec(),
+            {
+                let root_offset = root_offset.clone();
+                let index = index.clone();
+                move |pos, block| {
+                    index
+                        .lock()
+                        .unwrap()
+                        .push(pos - superblock.inode_table_start, uncompressed);
+                    if pos == root_block {
+                        root_offset.store(
+                            uncompressed + superblock.root_inode.block_offset(),
+                            Ordering::Relaxed,
+                        );
+                    }
+                    uncompressed += block.data.len() as u64;
+                }
+            },
+        )
+        .await?;
+        let mut r = CountingReader {
+            inner: Box::pin(r),
+            count: 0,
+        };
+        let mut root = None;
         loop {
+            let offset = r.count;
             let mut header = [0; 16];
             let header = match r.read_exact(&mut header).await {
-                Ok(_) => InodeHeader::from_reader(&header[..])
+                Ok(_) => InodeHeader::from_reader(&header[..], &superblock.limits)
                     .await
-                    .map_err(|_| InodeTableError::InvalidHeader)?,
+                    .map_err(InodeTableError::InvalidHeader)?,
                 Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
                     break;
                 }
-                Err(_) => {
-                    return Err(InodeTableError::InvalidHeader);
+                Err(e) => {
+                    return Err(InodeTableError::InvalidHeader(e.into()));
                 }
             };
-            match header.inode_type {
-                InodeType::BasicFile => {
-                    let file = BasicFile::from_reader(&mut r, superblock).await?;
-                    table.files.insert(header.inode_number, Box::new(file));
-                }
-                InodeType::ExtendedFile => {
-                    let file = ExtendedFile::from_reader(&mut r, superblock).await?;
-                    table.files.insert(header.inode_number, Box::new(file));
-                }
-                InodeType::BasicDirectory => {
-                    let dir = BasicDirectory::from_reader(&mut r)
-                        .await
-                        .map_err(|_| InodeTableError::InvalidEntry)?;
-                    table.directories.insert(header.inode_number, Box::new(dir));
-                }
-                InodeType::ExtendedDirectory => {
-                    let dir = ExtendedDirectory::from_reader(&mut r).await?;
-                    table.directories.insert(header.inode_number, Box::new(dir));
+            if offset == root_offset.load(Ordering::Relaxed) {
+                root = Some(header.inode_number);
+            }
+            match Self::read_inode(&header, &mut r, superblock, warnings).await? {
+                Some(Inode::File(file)) => {
+                    table.files.insert(header.inode_number, file);
                 }
-                InodeType::BasicSymlink => {
-                    symlink::Symlink::from_reader(&mut r).await?;
+                Some(Inode::Directory(dir)) => {
+                    table.directories.insert(header.inode_number, dir);
                 }
-                _ => {
-                    warn!("Skipping unsupposed inode of type {:?}", header.inode_type);
+                Some(Inode::Symlink(link)) => {
+                    table.symlinks.insert(header.inode_number, link);
                 }
+                None => continue,
             }
+            table.headers.insert(header.inode_number, header);
         }
+        table.root = root.ok_or(InodeTableError::RootNotFound)?;
+        table.index = std::mem::take(&mut index.lock().unwrap());
         Ok(table)
     }
 }
+
+/// Report files whose data extends beyond the end of the image.
+fn check_file(
+    header: &InodeHeader,
+    file: &impl FileInode,
+    superblock: &SuperBlock,
+    warnings: &Warnings,
+) {
+    let end = file.blocks_start()
+        + file
+            .block_sizes()
+            .iter()
+            .map(|b| b.compressed_size())
+            .sum::<u64>();
+    if end > superblock.bytes_used {
+        warnings.push(ParseEvent::SuspiciousSize {
+            inode: header.inode_number,
+            size: file.file_size(),
+            reason: "data blocks extend beyond the end of the image",
+        });
+    }
+}
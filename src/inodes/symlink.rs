@@ -3,22 +3,51 @@ use serde::Deserialize;
 use super::super::error::InodeTableError;
 use crate::deser;
 
+/// Basic or extended symbolic link.
 #[derive(Debug, Default, Deserialize)]
 pub struct Symlink {
-    #[allow(dead_code)]
     link_count: u32,
     target_size: u32,
     #[serde(skip)]
     target: String,
+    #[serde(skip)]
+    xattr_idx: Option<u32>,
 }
 impl Symlink {
-    pub async fn from_reader(mut r: impl crate::AsyncRead) -> Result<Self, InodeTableError> {
-        let mut link: Self = deser::bincode_deser_from(&mut r, 8)
-            .await
-            .map_err(|_| InodeTableError::InvalidEntry)?;
-        link.target = deser::bincode_deser_string_from(r, link.target_size as usize)
+    /// Read a symlink inode, followed by an index into the xattr table if `extended`.
+    pub async fn from_reader(
+        mut r: impl crate::AsyncRead,
+        extended: bool,
+        limits: &deser::Limits,
+    ) -> Result<Self, InodeTableError> {
+        let mut link: Self = deser::bincode_deser_from(&mut r, 8, limits.structure)
             .await
-            .map_err(|_| InodeTableError::InvalidEntry)?;
+            .map_err(InodeTableError::InvalidEntry)?;
+        link.target =
+            deser::bincode_deser_string_from(&mut r, link.target_size as usize, limits.target)
+                .await
+                .map_err(InodeTableError::InvalidEntry)?;
+        if extended {
+            let xattr_idx: u32 = deser::bincode_deser_from(&mut r, 4, limits.structure)
+                .await
+                .map_err(InodeTableError::InvalidEntry)?;
+            link.xattr_idx = (xattr_idx != crate::xattrs::NO_XATTRS).then_some(xattr_idx);
+        }
         Ok(link)
     }
+    /// Path the link points to, as stored (it may be relative, and dangling).
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+    pub fn hard_link_count(&self) -> u32 {
+        self.link_count
+    }
+    /// Index of the extended attributes in the [`crate::xattrs::XattrTable`], if any.
+    pub fn xattr_index(&self) -> Option<u32> {
+        self.xattr_idx
+    }
+    /// Heap memory held by the inode, in bytes.
+    pub fn heap_size(&self) -> usize {
+        self.target.capacity()
+    }
 }
@@ -1,30 +1,73 @@
 #![doc = include_str!("../README.md")]
 
+pub mod acl;
+pub mod analyze;
+mod autotune;
+#[cfg(feature = "bench")]
+mod bench;
+pub mod cache;
+pub mod capability;
+pub mod checksums;
+pub mod content_cache;
 mod data;
+pub mod decompression;
+pub mod deferred;
 mod deser;
+pub mod dir_stats;
 pub mod directory_table;
 pub mod error;
+pub mod events;
+pub mod export;
+#[cfg(feature = "failpoints")]
+pub mod failpoints;
+mod fetcher;
 pub mod fragments;
+pub mod fuse;
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub mod fuzz;
+pub mod hooks;
+pub mod http;
+pub mod id_table;
 pub mod inodes;
+mod locks;
+pub mod memory;
 mod metadata;
+pub mod notify;
 pub mod pools;
+pub mod scheduler;
+pub mod search;
 mod squashfuse;
+pub mod stats;
 mod superblock;
+pub mod supervisor;
+#[cfg(feature = "test-util")]
+pub mod testing;
+pub mod trace;
 #[doc(hidden)]
 pub mod utils;
-use error::CacheError;
+pub mod verify;
+pub mod warnings;
+pub mod xattrs;
+pub use data::{register_decompressor, Codec, Decompressor, Priority, ReadHints};
+use deferred::Deferred;
+pub use deser::Limits;
 pub use error::Error;
 use fragments::FragmentsTable;
-pub use superblock::{Compression, SuperBlock};
+pub use inodes::{FileAttrLite, FileKind};
+pub use metadata::MetadataIndex;
+pub use squashfuse::{HandleInfo, PageCache, PageCachePolicy, SharedSquashFs, Statfs};
+pub use superblock::{Compression, CompressionOptions, SuperBlock, XzFilters};
+pub use warnings::ParseEvent;
 
 use std::collections::BTreeMap;
 use std::fmt::Write;
 use std::ops::DerefMut;
 use std::path::Path;
+use std::sync::Arc;
 
 use clap::Parser;
 use deadpool::managed::Pool;
-use fuser_async::cache::{DataBlockCache, IndexCache, LRUCache};
 use tokio::sync::RwLock;
 use tracing::*;
 
@@ -39,8 +82,43 @@ trait_set::trait_set! {
 
 const TABLES_DIRECT_THRESHOLD: u64 = 50_000;
 
+fn build_pool<T, R>(
+    manager_factory: &dyn ManagerFactory<R>,
+    flags: pools::ReadFlags,
+    size: usize,
+    recycle: pools::RecyclePolicy,
+) -> Result<Pool<R>, Error>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error>,
+{
+    Ok(Pool::builder(manager_factory(flags)?)
+        .max_size(size)
+        .pre_recycle(recycle.hook())
+        .build()?)
+}
+
+/// Open `size` readers in each pool concurrently, returning the time taken for each pool.
+async fn warm_up<R: deadpool::managed::Manager>(
+    readers: &BTreeMap<pools::ReadFlags, Pool<R>>,
+    size: usize,
+) -> Result<BTreeMap<pools::ReadFlags, std::time::Duration>, Error>
+where
+    Error: From<deadpool::managed::PoolError<R::Error>>,
+{
+    let warmup = futures::future::try_join_all(readers.iter().map(|(flags, pool)| async move {
+        let start = std::time::Instant::now();
+        // The readers return to the pool when dropped
+        futures::future::try_join_all((0..size).map(|_| pool.get())).await?;
+        debug!(flags, "Opened {} readers in {:?}", size, start.elapsed());
+        Ok::<_, Error>((*flags, start.elapsed()))
+    }))
+    .await?;
+    Ok(warmup.into_iter().collect())
+}
+
 /// Squashfs reading options.
-#[derive(Parser)]
+#[derive(Parser, Clone, Debug)]
 pub struct Options {
     /// Cache size (MB) for decoded blocks.
     #[clap(long, default_value_t = 100)]
@@ -48,6 +126,29 @@ pub struct Options {
     /// Number of readers
     #[clap(long, default_value_t = 4)]
     pub readers: usize,
+    /// Number of concurrent background reads (e.g. extraction), by default half of the readers,
+    /// so that they never starve the foreground reads.
+    #[clap(long)]
+    pub background_readers: Option<usize>,
+    /// Parse directory tables on first access rather than when opening the image.
+    ///
+    /// This reduces the opening time and memory usage when only a few directories are accessed.
+    #[clap(long)]
+    pub lazy: bool,
+    /// Time budget (ms) for parsing the directory tables when opening the image.
+    ///
+    /// When exceeded, the remaining directory tables are parsed in the background (or on first
+    /// access, whichever comes first), which bounds the opening time for images with many
+    /// directories.
+    #[clap(long = "parse-budget-ms", value_parser = utils::parse_duration_ms)]
+    pub parse_budget: Option<std::time::Duration>,
+    /// Return as soon as the superblock and root directory are parsed, parsing the other tables
+    /// in the background.
+    ///
+    /// Accesses to parts of the image that have not been parsed yet wait for the relevant table.
+    /// This greatly reduces the time to first byte on large images.
+    #[clap(long)]
+    pub background_parsing: bool,
     /// Limit (B) for reading small files with direct access.
     ///
     /// This is useful for example when the underlying storage is networked and buffered: for
@@ -57,12 +158,344 @@ pub struct Options {
     /// This will use another `cache_mb` amount of cache.
     #[clap(long, default_value_t = 0)]
     pub direct_limit: usize,
+    /// Tune `direct_limit` at runtime, from the latency and throughput of the backend (probed
+    /// when opening the image) and the hit rate of the small files cache, and read ahead of the
+    /// sequential reads of the open files, by a few times what the backend transfers during its
+    /// latency.
+    ///
+    /// This overrides `direct_limit`, which, as the readahead, is bounded by a tenth of the cache
+    /// size. Reading ahead requires the cache of data blocks.
+    #[clap(long)]
+    pub auto_tune: bool,
+    /// Load the whole image into memory before serving, for latency-critical workloads on small
+    /// images.
+    ///
+    /// Only applies to local images opened with [`SquashFs::open`], see
+    /// [`pools::LocalReadersPool::preload`].
+    #[clap(long)]
+    pub preload: bool,
+    /// Open the image with `O_DIRECT` with the `tokio` and `async-fs` backends, bypassing the
+    /// page cache, e.g. for images on network filesystems that are read once. This is dropped
+    /// with a warning on filesystems requiring aligned reads.
+    #[clap(long)]
+    pub image_direct: bool,
+    /// Open the image with `O_NOATIME` with the `tokio` and `async-fs` backends, so that reads
+    /// do not update its access time. This is dropped with a warning when not owning the image.
+    #[clap(long)]
+    pub image_noatime: bool,
+    /// Access pattern advised to the kernel for the mapping of the image with the `memmap`
+    /// backend: `sequential` for scans, `random` for lookups of small files, or `willneed` to
+    /// read it in the background.
+    #[clap(long, arg_enum, value_name = "ADVICE")]
+    pub mmap_advice: Option<pools::MemMapAdvice>,
+    /// Lock the metadata tables of the image in memory with the `memmap` backend, so that
+    /// lookups never wait for the disk. This is best effort, see `RLIMIT_MEMLOCK`.
+    #[clap(long)]
+    pub mmap_lock_tables: bool,
+    /// Back the mapping of the image with transparent huge pages with the `memmap` backend,
+    /// when the kernel and the filesystem holding the image support it.
+    #[clap(long)]
+    pub mmap_hugepages: bool,
+    /// Open all the readers of the initial pools concurrently when opening the image, so that
+    /// the first reads do not pay the connection setup of high-latency backends.
+    ///
+    /// See [`stats::Snapshot::pools`].
+    #[clap(long)]
+    pub prewarm: bool,
+    /// Expected SHA-256 digest of the image (hex). The whole image is read once when opening
+    /// it, which fails on mismatch, e.g. for truncated or partially downloaded images.
+    #[clap(long = "verify-checksum", value_name = "SHA256", value_parser = utils::parse_sha256)]
+    pub expected_digest: Option<String>,
+    /// Sidecar file with the digests of the data blocks, generated with `checksum generate`,
+    /// against which each block is verified when decoded. This catches the corruptions of the
+    /// image in storage or in transit, without reading it all upfront. See [`checksums`].
+    #[clap(long, value_name = "SIDECAR")]
+    pub block_checksums: Option<std::path::PathBuf>,
+    /// Length (MB) of the sequential reads on a file handle after which the data blocks it reads
+    /// are not kept in the cache (drop-behind), so that large scans do not evict the working set.
+    #[clap(long)]
+    pub drop_behind_mb: Option<u64>,
+    /// Soft limit on the reads in flight per file handle. While all the readers are busy, the
+    /// reads beyond it wait for an earlier read of the same handle, so that a single aggressive
+    /// reader (e.g. a parallel grep) cannot take over the readers and starve the other handles.
+    #[clap(long)]
+    pub max_handle_reads: Option<usize>,
+    /// Fail the reads of the files opened with `O_NONBLOCK` with `EAGAIN` when their blocks are
+    /// not cached, loading them into the cache in the background, so that latency-sensitive
+    /// callers can poll rather than stall. This requires the cache of data blocks, and
+    /// `open_flags` keeping the flags of the caller.
+    #[clap(long)]
+    pub nonblock_eagain: bool,
+    /// Close the readers unused for this long (s), and the pools left without readers, so that
+    /// remote backends do not hold idle connections. See [`SquashFs::readers_reaper`].
+    #[clap(long)]
+    pub reader_ttl_secs: Option<u64>,
+    /// Rate (kbit/s) at which files are consumed, e.g. when streaming videos. Reads then carry
+    /// the time at which the stream reaches them, and the scheduler (see `scheduler_window`)
+    /// dispatches the blocks closest to their deadline first.
+    ///
+    /// This can be set per handle with [`SquashFs::set_bitrate`].
+    #[clap(long)]
+    pub stream_bitrate_kbps: Option<u64>,
+    /// Collect data block reads during this window (ms), then dispatch them sorted by offset,
+    /// merging adjacent ranges.
+    ///
+    /// This helps with random small reads over high-latency backends, which are otherwise sent
+    /// in arrival order, one at a time per reader. The scheduled reads use a separate pool of
+    /// [`Options::readers`] readers. See [`scheduler`].
+    #[clap(long = "scheduler-window-ms", value_parser = utils::parse_duration_ms)]
+    pub scheduler_window: Option<std::time::Duration>,
+    /// Number of dedicated threads for decompressing data blocks (0 to decompress on the
+    /// tokio runtime).
+    #[clap(long, default_value_t = 0)]
+    pub decompression_threads: usize,
+    /// Cores to pin the decompression threads to (comma-separated).
+    #[clap(long, value_delimiter = ',')]
+    pub decompression_cores: Vec<usize>,
+    /// Memory limit (MB) for the XZ decoder. By default, there is no limit.
+    ///
+    /// This applies to this image only, and takes precedence over a decoder registered for XZ
+    /// with [`register_decompressor`].
+    #[clap(long)]
+    pub xz_memlimit_mb: Option<u64>,
+    /// Ignore the XZ compressor options stored in the image.
+    ///
+    /// By default, the memory limit is raised to fit the dictionary size of the image, and images
+    /// using unknown filters are rejected. The BCJ filters are applied in any case, as they are
+    /// recorded in the headers of each block.
+    #[clap(long)]
+    pub xz_ignore_compressor_options: bool,
+    /// Largest fixed-size structure (B) read from the image, at least the 96 bytes of the
+    /// superblock. See [`Options::limits`].
+    #[clap(long, default_value_t = 1000)]
+    pub max_structure_size: usize,
+    /// Largest name (B) of a directory entry or index, at most the 256 bytes of the format.
+    #[clap(long, default_value_t = 256)]
+    pub max_name_size: usize,
+    /// Largest target (B) of a symbolic link, by default `PATH_MAX`.
+    #[clap(long, default_value_t = 4096)]
+    pub max_symlink_size: usize,
+    /// Do not log the non-fatal issues found while parsing the image.
+    ///
+    /// They remain available with [`SquashFs::warnings`].
+    #[clap(long)]
+    pub quiet_warnings: bool,
+    /// Record every read (timestamp, inode, offset, size, flags) to this file, for replaying
+    /// later with [`SquashFs::replay`]. See [`trace`].
+    #[clap(long)]
+    pub trace_accesses: Option<std::path::PathBuf>,
+    /// What to do with readers returned to their pool: `rewind` (seek to the start), `none`
+    /// (reuse as is), `uses:N` (reopen after N uses) or `lifetime:SECONDS` (reopen once older).
+    ///
+    /// See [`pools::RecyclePolicy`].
+    #[clap(long, default_value = "rewind")]
+    pub recycle: pools::RecyclePolicy,
+    /// Capacity (KiB) of the readers' buffers, by default that of the backend (8 KiB for local
+    /// files, 128 KiB for [`pools::FilePool`]).
+    ///
+    /// Large buffers suit sequential reads from high-latency backends (e.g. object storage),
+    /// while small ones avoid reading unneeded data from fast local disks.
+    #[clap(long)]
+    pub buffer_kb: Option<usize>,
+    /// Capacity (KiB) of the buffers of the readers for files opened with `O_DIRECT`, as well as
+    /// small files (see `direct_limit`), by default `buffer_kb`.
+    #[clap(long)]
+    pub direct_buffer_kb: Option<usize>,
+    /// How the flags passed to `open` apply to the reads: `caller` keeps the `O_DIRECT` and
+    /// `O_NONBLOCK` flags, `ignore` discards them, and `direct` reads everything with
+    /// `O_DIRECT`.
+    ///
+    /// Opening for writing always fails with `EROFS`.
+    #[clap(long, arg_enum, default_value = "caller")]
+    pub open_flags: pools::OpenFlagsPolicy,
+    /// Check accesses against the permission bits, uid and gid of the files, as with the
+    /// `default_permissions` mount option. Otherwise, all reads are allowed.
+    ///
+    /// See [`SquashFs::access`].
+    #[clap(long)]
+    pub default_permissions: bool,
+    /// Owner reported for all files and directories, instead of the one stored in the image,
+    /// e.g. when the image was built by another user than the one consuming it.
+    ///
+    /// As the other overrides, this applies to the attributes served over FUSE and to
+    /// [`SquashFs::access`], but not to [`SquashFs::attr`], which returns those of the image.
+    #[clap(long)]
+    pub uid: Option<u32>,
+    /// Group reported for all files and directories.
+    #[clap(long)]
+    pub gid: Option<u32>,
+    /// Permission bits (octal) reported for all files.
+    #[clap(long, value_parser = utils::parse_mode)]
+    pub file_mode: Option<u16>,
+    /// Permission bits (octal) reported for all directories.
+    #[clap(long, value_parser = utils::parse_mode)]
+    pub dir_mode: Option<u16>,
+    /// Permission bits (octal) cleared from the reported modes, after `file_mode` and
+    /// `dir_mode`.
+    #[clap(long, value_parser = utils::parse_mode)]
+    pub umask: Option<u16>,
+    /// Report all files and directories as readable by others (as `chmod o+rX`), so that images
+    /// built as root can be browsed by unprivileged users. This applies after `umask`.
+    #[clap(long)]
+    pub other_readable: bool,
+    /// Report the size of directories as the total size of the files they contain,
+    /// recursively, rather than 0, for tools expecting non-zero directory sizes.
+    ///
+    /// This parses all the directory tables below a directory when its attributes are first
+    /// requested. See [`SquashFs::dir_stats`].
+    #[clap(long)]
+    pub dir_sizes: bool,
+    /// Caching of the file contents by the kernel: `default`, `direct-io` (bypass the page
+    /// cache), `keep-cache` (keep it across opens), or `auto` (`direct-io` for files opened with
+    /// `O_DIRECT` or larger than `direct_io_above_mb`, `keep-cache` otherwise).
+    ///
+    /// This applies to FUSE adapters using [`SquashFs::open_reply_flags`], and can be overridden
+    /// with [`SquashFs::set_page_cache_policy`].
+    #[clap(long, arg_enum, default_value = "default")]
+    pub page_cache: PageCache,
+    /// Size (MB) above which files bypass the page cache with `--page-cache auto`.
+    #[clap(long, default_value_t = 64)]
+    pub direct_io_above_mb: u64,
+    /// Keep the cached data blocks recompressed with zstd (fast level), which fits several
+    /// times more blocks in `cache_mb`, at the cost of CPU on every cache access.
+    ///
+    /// Useful with slow backends when memory is limited. This replaces the data blocks cache of
+    /// `caches` with a [`cache::CompressedCache`].
+    #[clap(long)]
+    pub compressed_cache: bool,
+    /// Constructors of the caches, see [`cache`] (not a command line option).
+    #[clap(skip)]
+    pub caches: cache::CacheBuilder,
+    /// Capabilities of the backend (not a command line option), by default those of the pool
+    /// with [`SquashFs::open`] and [`SquashFs::open_in`], and those of local files with
+    /// [`SquashFs::from_reader`].
+    #[clap(skip)]
+    pub capabilities: Option<pools::BackendCapabilities>,
+    /// Label of the image in the logs and the error events, to tell apart the images opened in
+    /// the same process. By default, a hash of the path of the image (or of its superblock when
+    /// opened from another reader).
+    #[clap(long)]
+    pub name: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::parse_from([""])
+    }
+}
+impl Options {
+    /// See [`Options::buffer_kb`] and [`Options::direct_buffer_kb`].
+    pub fn buffer_capacity(&self) -> pools::BufferCapacity {
+        pools::BufferCapacity {
+            default: self.buffer_kb.map(|kb| kb * 1024),
+            direct: self.direct_buffer_kb.map(|kb| kb * 1024),
+        }
+    }
+    /// Limits on the sizes read from the image, checked before allocating the buffers for them,
+    /// so that corrupted length fields fail the parsing. See [`Options::max_structure_size`],
+    /// [`Options::max_name_size`] and [`Options::max_symlink_size`].
+    pub fn limits(&self) -> Limits {
+        Limits {
+            structure: self.max_structure_size,
+            name: self.max_name_size,
+            target: self.max_symlink_size,
+        }
+    }
+    /// See [`Options::image_direct`] and [`Options::image_noatime`].
+    pub fn open_options(&self) -> pools::LocalOpenOptions {
+        pools::LocalOpenOptions {
+            direct: self.image_direct,
+            noatime: self.image_noatime,
+        }
+    }
+    /// See [`Options::mmap_advice`], [`Options::mmap_lock_tables`] and
+    /// [`Options::mmap_hugepages`].
+    pub fn mmap_options(&self) -> pools::MemMapOptions {
+        pools::MemMapOptions {
+            advice: self.mmap_advice,
+            lock_tables: self.mmap_lock_tables,
+            hugepages: self.mmap_hugepages,
+        }
+    }
+    /// Options with [`Options::name`] defaulting to a hash of `path`.
+    fn labeled(&self, path: &Path) -> Self {
+        Self {
+            name: Some(
+                self.name
+                    .clone()
+                    .unwrap_or_else(|| format!("{:08x}", utils::hash(&path) as u32)),
+            ),
+            ..self.clone()
+        }
+    }
+    /// Options for opening an image to read a single file, see [`read`].
+    fn one_shot(&self) -> Self {
+        Self {
+            lazy: true,
+            cache_mb: 0,
+            direct_limit: 0,
+            preload: false,
+            ..self.clone()
+        }
+    }
+}
+
+/// Read a file from a squashfs image in a single call.
+///
+/// This opens the image with [`Options::lazy`], so that only the directories on `path` get
+/// parsed, and without caches, since each block is read once. This is the fastest way to grab a
+/// single file from an image.
+pub async fn read(image: &Path, path: &Path, options: &Options) -> Result<bytes::Bytes, Error> {
+    let fs =
+        SquashFs::<pools::LocalReadersPoolTokio>::open(image, &Options::one_shot(options)).await?;
+    let inode = fs.resolve(path).await?;
+    let attr = fs.attr(inode)?;
+    if attr.kind != FileKind::File {
+        return Err(Error::FileNotFound(Some(path.display().to_string())));
+    }
+    fs.read_file(inode, 0, attr.size as usize, 0, fs.superblock.compression)
+        .await
+}
+
+/// Extract a file from a squashfs image to `dest` in a single call.
+///
+/// Like [`read`], but the file is written in chunks rather than being held in memory, and the
+/// permissions are preserved.
+pub async fn extract(
+    image: &Path,
+    path: &Path,
+    dest: &Path,
+    options: &Options,
+) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fs =
+        SquashFs::<pools::LocalReadersPoolTokio>::open(image, &Options::one_shot(options)).await?;
+    let inode = fs.resolve(path).await?;
+    let attr = fs.attr(inode)?;
+    if attr.kind != FileKind::File {
+        return Err(Error::FileNotFound(Some(path.display().to_string())));
+    }
+    let mut output = tokio::fs::File::create(dest)
+        .await
+        .map_err(Error::WriteFailure)?;
+    fs.copy_file_with(inode, 0, attr.size, 0, &mut output, ReadHints::background())
+        .await?;
+    output
+        .set_permissions(std::fs::Permissions::from_mode(attr.mode as u32))
+        .await
+        .map_err(Error::WriteFailure)?;
+    Ok(())
 }
 
 /// Base structure representing a loaded SquashFS image.
 ///
-/// Note that the tables (inode, directory...) are fully parsed on creation and kept in memory,
-/// rather than being accessed lazily.
+/// Note that the tables (inode, directory...) are parsed on creation and kept in memory,
+/// rather than being accessed lazily. The exceptions are:
+/// - The directory tables with [`Options::lazy`] or [`Options::parse_budget`], which are parsed
+///   on first access or in the background.
+/// - All tables with [`Options::background_parsing`], which are parsed in the background (see
+///   [`Deferred`]).
 ///
 /// This implements the [`fuser_async::Filesystem`] trait.
 ///
@@ -70,29 +503,124 @@ pub struct Options {
 /// See [`crate::pools`].
 pub struct SquashFs<R: deadpool::managed::Manager> {
     pub superblock: superblock::SuperBlock,
-    pub inode_table: inodes::InodeTable,
-    pub fragments_table: FragmentsTable,
+    pub inode_table: Deferred<inodes::InodeTable>,
+    pub fragments_table: Deferred<FragmentsTable>,
+    pub id_table: Deferred<id_table::IdTable>,
+    pub xattr_table: Deferred<xattrs::XattrTable>,
     /// Table for each directory inode
-    pub directory_tables: BTreeMap<u32 /* inode */, directory_table::DirectoryTable>,
+    pub directory_tables: directory_table::DirectoryTables,
     root_inode: u32,
-    pub handles: RwLock<BTreeMap<u64, pools::ReadFlags>>,
+    /// Open file handles, see [`SquashFs::open_handles`].
+    handles: RwLock<BTreeMap<u64, squashfuse::FileHandle>>,
+    /// See [`Options::open_flags`].
+    open_flags: pools::OpenFlagsPolicy,
+    /// See [`Options::default_permissions`].
+    default_permissions: bool,
+    /// See [`Options::uid`].
+    attr_overrides: squashfuse::AttrOverrides,
+    /// See [`Options::dir_sizes`].
+    report_dir_sizes: bool,
+    /// Sizes of the subtrees, see [`SquashFs::dir_stats`].
+    dir_sizes: std::sync::Mutex<BTreeMap<u32, u64>>,
+    /// Paths of each inode, built on first use, see [`SquashFs::paths_of`].
+    paths: tokio::sync::OnceCell<BTreeMap<u32, Vec<std::path::PathBuf>>>,
+    /// See [`SquashFs::stats`].
+    stats: Arc<stats::Stats>,
+    /// See [`SquashFs::label`].
+    label: String,
+    /// Span of the events of the image, with its label.
+    span: tracing::Span,
+    /// See [`SquashFs::subscribe_errors`].
+    error_events: tokio::sync::broadcast::Sender<events::ErrorEvent>,
+    /// See [`Options::drop_behind_mb`] (bytes).
+    drop_behind: Option<u64>,
+    /// See [`Options::stream_bitrate_kbps`] (bytes/s).
+    stream_bitrate: Option<u64>,
+    /// See [`Options::max_handle_reads`].
+    max_handle_reads: Option<usize>,
+    /// See [`Options::block_checksums`].
+    checksums: Option<Arc<checksums::BlockChecksums>>,
+    /// Loads the blocks missed by the non-blocking reads (see [`Options::nonblock_eagain`]), and
+    /// those read ahead (see [`Options::auto_tune`]).
+    fetcher: Option<fetcher::Fetcher>,
+    /// See [`Options::nonblock_eagain`].
+    nonblock_eagain: bool,
+    /// See [`Options::page_cache`].
+    page_cache_policy: std::sync::RwLock<Arc<dyn PageCachePolicy>>,
+    /// Next file or directory handle.
+    next_handle: std::sync::atomic::AtomicU64,
+    /// Open directory handles, with their `readdir` cursors.
+    dir_handles: RwLock<BTreeMap<u64, squashfuse::DirHandle>>,
     manager_factory: Box<dyn ManagerFactory<R>>,
-    readers: RwLock<BTreeMap<pools::ReadFlags, Pool<R>>>,
+    /// Pools of readers by flags, created on first use.
+    readers: Arc<RwLock<BTreeMap<pools::ReadFlags, Pool<R>>>>,
+    /// See [`Options::reader_ttl_secs`].
+    reader_ttl: Option<std::time::Duration>,
+    /// Duration of the warmup of the pools, see [`Options::prewarm`].
+    warmup: BTreeMap<pools::ReadFlags, std::time::Duration>,
     n_readers: usize,
+    /// See [`SquashFs::capabilities`].
+    capabilities: pools::BackendCapabilities,
+    /// See [`Options::background_readers`].
+    background_slots: tokio::sync::Semaphore,
+    /// See [`Options::recycle`].
+    recycle: pools::RecyclePolicy,
     inode_extra: u32,
     /// Files smaller than this size will be accessed with the O_NONBLOCK, which allows triggering
     /// optimizations on the storage backend (e.g. do not pre-fetch a large block for a small file).
     /// See the documentation in [`Options`].
     direct_limit: usize,
+    /// See [`Options::auto_tune`].
+    tuner: Option<autotune::AutoTuner>,
     /// Cache for decoded blocks in the image
-    cache: Option<IndexCache>,
+    cache: Option<Arc<dyn cache::BlockCache>>,
     /// Cache for small files (< direct_limit), that are read at once.
-    small_files_cache: Option<LRUCache>,
+    small_files_cache: Option<Arc<dyn cache::BlockCache>>,
+    /// Cache shared with other images, see [`content_cache`].
+    content_cache: Option<Arc<content_cache::ContentCache>>,
+    /// In-flight loads into `small_files_cache`
+    small_files_loads: locks::KeyedLocks,
+    /// Maximal size of the caches above, in bytes.
+    caches_capacity: u64,
+    /// Schedulers for data block reads, by flags, see [`Options::scheduler_window`]. Each has
+    /// its own pool of readers, as reads wait on them while holding a reader from `readers`.
+    schedulers: BTreeMap<pools::ReadFlags, scheduler::Scheduler>,
+    /// See [`Options::decompression_threads`].
+    decompression: Option<decompression::DecompressionPool>,
+    /// Background parsing of the tables, see [`Options::parse_budget`] and
+    /// [`Options::background_parsing`].
+    background: Option<tokio::task::JoinHandle<()>>,
+    /// Non-fatal issues found while parsing, see [`SquashFs::warnings`].
+    warnings: warnings::Warnings,
+    /// See [`Options::trace_accesses`].
+    tracer: Option<trace::Tracer>,
+    /// See [`SquashFs::with_hooks`].
+    hooks: Option<Arc<dyn hooks::FileHooks>>,
+    /// See [`SquashFs::set_notifier`].
+    notifier: std::sync::RwLock<Option<Arc<dyn notify::Notifier>>>,
+}
+impl<R: deadpool::managed::Manager> Drop for SquashFs<R> {
+    fn drop(&mut self) {
+        if let Some(background) = &self.background {
+            background.abort();
+        }
+    }
 }
 impl<R: deadpool::managed::Manager> std::fmt::Debug for SquashFs<R> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         writeln!(f, "{:?}", self.superblock)?;
+        writeln!(
+            f,
+            "Created at {} (seconds since the epoch)",
+            self.superblock
+                .creation_time()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        )?;
         writeln!(f, "{}", self.fragments_table)?;
+        writeln!(f, "{}", self.id_table)?;
+        writeln!(f, "{}", self.xattr_table)?;
         writeln!(f, "{}, root inode {}", self.inode_table, self.root_inode)?;
         self.tree(0, self.root_inode, f)?;
         if let Some(cache) = &self.cache {
@@ -101,6 +629,10 @@ impl<R: deadpool::managed::Manager> std::fmt::Debug for SquashFs<R> {
         if let Some(cache) = &self.small_files_cache {
             writeln!(f, "{}", cache)?;
         }
+        if let Some(tuner) = &self.tuner {
+            writeln!(f, "Auto-tuning: {}", tuner)?;
+        }
+        writeln!(f, "Memory usage: {}", self.memory_usage())?;
         Ok(())
     }
 }
@@ -111,18 +643,85 @@ where
     P: pools::LocalReadersPool
         + deadpool::managed::Manager<Type = T, Error = tokio::io::Error>
         + Send
-        + Sync,
+        + Sync
+        + 'static,
 {
     /// Open squashfs image from a local file
     pub async fn open(file: &Path, options: &Options) -> Result<Self, Error> {
+        let options = &Options {
+            capabilities: Some(options.capabilities.unwrap_or_else(P::capabilities)),
+            ..options.labeled(file)
+        };
         let file = file.to_owned();
-        Self::from_reader(options, move |_| P::new(&file)).await
+        let preload = options.preload;
+        let capacity = options.buffer_capacity();
+        let (open_options, mmap_options) = (options.open_options(), options.mmap_options());
+        Self::from_reader(options, move |flags| {
+            let mut pool = P::new(&file)?
+                .with_open_options(open_options)
+                .with_mmap_options(mmap_options)?;
+            if let Some(capacity) = capacity.get(flags) {
+                pool = pool.with_buffer_capacity(capacity);
+            }
+            if preload {
+                pool.preload()?;
+            }
+            Ok(pool)
+        })
+        .await
+    }
+}
+
+impl<F> SquashFs<pools::FilePool<F>>
+where
+    F: fuser_async::FilesystemSSUS + Clone,
+    F::Error: Send + Sync + std::fmt::Display + Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    /// Open squashfs image stored at `path` in another filesystem, e.g. a network filesystem or
+    /// another image.
+    ///
+    /// Each reader holds a file handle on `fs`, opened with the flags of its pool.
+    pub async fn open_in(fs: F, path: &Path, options: &Options) -> Result<Self, Error> {
+        let options = &Options {
+            capabilities: Some(
+                options
+                    .capabilities
+                    .unwrap_or_else(pools::FilePool::<F>::capabilities),
+            ),
+            ..options.labeled(path)
+        };
+        let inode = pools::FilePool::resolve(&fs, path).await?;
+        debug!(?path, inode, "Opening nested image");
+        let factory = pools::FilePool::factory(fs, inode, options.buffer_capacity());
+        Self::from_reader(options, factory).await
+    }
+}
+
+impl<T, R> SquashFs<pools::FilePool<SharedSquashFs<R>>>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync + 'static,
+{
+    /// Open squashfs image stored as a file at `path` inside the image `outer`, without
+    /// extracting it.
+    ///
+    /// The reads of the nested image go through `outer`, and hence its caches.
+    pub async fn open_nested(
+        outer: &Arc<SquashFs<R>>,
+        path: &Path,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        Self::open_in(SharedSquashFs(outer.clone()), path, options).await
     }
 }
 
 impl<R: deadpool::managed::Manager> SquashFs<R> {
     fn tree<W: Write>(&self, level: usize, root_inode: u32, f: &mut W) -> std::fmt::Result {
-        for e in &self.directory_tables.get(&root_inode).unwrap().entries {
+        let Some(table) = self.directory_tables.get(root_inode) else {
+            // Not parsed yet
+            return writeln!(f, "{:level$}...", "", level = 4 * level);
+        };
+        for e in &table.entries {
             writeln!(f, "{:level$}{}", "", e, level = 4 * level)?;
             if e.is_dir() {
                 self.tree(level + 1, e.inode, f)?;
@@ -130,12 +729,79 @@ impl<R: deadpool::managed::Manager> SquashFs<R> {
         }
         Ok(())
     }
-    pub fn inodes(&self) -> impl Iterator<Item = u32> + '_ {
-        self.inode_table
+    /// Current limit for reading small files at once, see [`Options::direct_limit`] and
+    /// [`Options::auto_tune`].
+    pub fn direct_limit(&self) -> usize {
+        self.tuner
+            .as_ref()
+            .map(|t| t.direct_limit())
+            .unwrap_or(self.direct_limit)
+    }
+    /// Current readahead of sequential reads (0 if disabled), see [`Options::auto_tune`].
+    pub fn readahead(&self) -> u64 {
+        match (&self.tuner, &self.fetcher) {
+            (Some(tuner), Some(_)) => tuner.readahead(),
+            _ => 0,
+        }
+    }
+    /// Non-fatal issues found while parsing the image so far (unsupported inodes, inconsistent
+    /// sizes...), up to the first 1000.
+    ///
+    /// Tables parsed lazily or in the background can add further events later on.
+    pub fn warnings(&self) -> Vec<ParseEvent> {
+        self.warnings.events()
+    }
+    /// Capabilities of the backend, see [`Options::capabilities`].
+    pub fn capabilities(&self) -> pools::BackendCapabilities {
+        self.capabilities
+    }
+    /// Label of the image in the logs and the error events, see [`Options::name`].
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+    /// Attributes of a file, directory or symlink inode.
+    ///
+    /// Fails with [`Error::Pending`] if the tables are still being parsed in the background.
+    pub fn attr(&self, inode: u32) -> Result<FileAttrLite, Error> {
+        let inode_table = self.inode_table.get()?;
+        let id_table = self.id_table.get()?;
+        let header = inode_table.headers.get(&inode).ok_or(Error::InvalidInode)?;
+        let (kind, size, nlink) = if let Some(f) = inode_table.files.get(&inode) {
+            (FileKind::File, f.file_size(), f.hard_link_count())
+        } else if let Some(link) = inode_table.symlinks.get(&inode) {
+            let size = link.target().len() as u64;
+            (FileKind::Symlink, size, link.hard_link_count())
+        } else {
+            let directory = inode_table
+                .directories
+                .get(&inode)
+                .ok_or(Error::DirectoryNotFound)?;
+            (FileKind::Directory, 0, directory.hard_link_count())
+        };
+        Ok(FileAttrLite {
+            inode,
+            size,
+            kind,
+            mode: header.permissions,
+            uid: id_table.get(header.uid_idx)?,
+            gid: id_table.get(header.gid_idx)?,
+            mtime: std::time::UNIX_EPOCH
+                + std::time::Duration::from_secs(header.modified_time as u64),
+            crtime: self.superblock.creation_time(),
+            nlink,
+        })
+    }
+    /// Inodes of the supported files, directories and symlinks.
+    ///
+    /// Fails with [`Error::Pending`] if the tables are still being parsed in the background.
+    pub fn inodes(&self) -> Result<impl Iterator<Item = u32> + '_, Error> {
+        let inode_table = self.inode_table.get()?;
+        Ok(inode_table
             .files
             .keys()
-            .chain(self.inode_table.directories.keys())
-            .copied()
+            .chain(inode_table.directories.keys())
+            .chain(inode_table.symlinks.keys())
+            .copied())
     }
 }
 
@@ -144,126 +810,678 @@ where
     T: AsyncSeekBufRead,
     R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
 {
+    /// Scheduler for the reads with `flags`, if any.
+    fn scheduler(&self, flags: pools::ReadFlags) -> Option<&scheduler::Scheduler> {
+        self.schedulers.get(&self.capabilities.pool_flags(flags))
+    }
     async fn get_reader(
         &self,
         flags: pools::ReadFlags,
     ) -> Result<deadpool::managed::Object<R>, Error> {
-        let readers = self.readers.read().await;
-        if !readers.contains_key(&flags) {
-            drop(readers);
-            let mut readers = self.readers.write().await;
-            readers.insert(
-                flags,
-                Pool::builder((self.manager_factory)(flags)?)
-                    .max_size(self.n_readers)
-                    .build()?,
-            );
-            Ok(readers.get(&flags).unwrap().get().await?)
-        } else {
-            Ok(readers.get(&flags).unwrap().get().await?)
+        let flags = self.capabilities.pool_flags(flags);
+        // The pool is cloned out of the map, so that the lock is not held while waiting for a
+        // reader.
+        let pool = self.readers.read().await.get(&flags).cloned();
+        let pool = match pool {
+            Some(pool) => pool,
+            None => {
+                let mut readers = self.readers.write().await;
+                match readers.entry(flags) {
+                    std::collections::btree_map::Entry::Occupied(e) => e.get().clone(),
+                    std::collections::btree_map::Entry::Vacant(e) => {
+                        debug!(flags, "Creating readers pool");
+                        e.insert(build_pool(
+                            self.manager_factory.as_ref(),
+                            flags,
+                            self.n_readers,
+                            self.recycle,
+                        )?)
+                        .clone()
+                    }
+                }
+            }
+        };
+        let start = std::time::Instant::now();
+        #[cfg(feature = "failpoints")]
+        failpoints::pass(failpoints::Failpoint::GetReader)
+            .await
+            .map_err(Error::ReadFailure)?;
+        let reader = pool.get().await?;
+        self.stats.record_pool_wait(start.elapsed());
+        Ok(reader)
+    }
+    /// Bytes following the [`SuperBlock::bytes_used`] bytes of the image, up to `max`, e.g.
+    /// signatures appended by firmware formats, or another archive.
+    ///
+    /// This includes the padding added by `mksquashfs` (zeros up to a multiple of 4 KiB, unless
+    /// built with `-nopad`). These bytes are never read otherwise.
+    pub async fn trailer(&self, max: u64) -> Result<Vec<u8>, Error> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let mut r = self.get_reader(0).await?;
+        r.seek(std::io::SeekFrom::Start(self.superblock.bytes_used))
+            .await
+            .map_err(Error::ReadFailure)?;
+        let mut trailer = vec![];
+        r.deref_mut()
+            .take(max)
+            .read_to_end(&mut trailer)
+            .await
+            .map_err(Error::ReadFailure)?;
+        Ok(trailer)
+    }
+    /// Close the readers unused for [`Options::reader_ttl_secs`], and the pools left without
+    /// readers, except the default one. Returns the number of closed readers.
+    pub async fn close_idle_readers(&self) -> usize {
+        match self.reader_ttl {
+            Some(ttl) => Self::close_idle(&self.readers, ttl).await,
+            None => 0,
         }
     }
+    /// Future calling [`SquashFs::close_idle_readers`] periodically, to be spawned alongside the
+    /// mount. Completes immediately without [`Options::reader_ttl_secs`].
+    pub fn readers_reaper(&self) -> impl std::future::Future<Output = ()> + Send + 'static
+    where
+        R: 'static,
+    {
+        let readers = self.readers.clone();
+        let ttl = self.reader_ttl;
+        async move {
+            let Some(ttl) = ttl else {
+                return;
+            };
+            let mut interval =
+                tokio::time::interval((ttl / 2).max(std::time::Duration::from_secs(1)));
+            loop {
+                interval.tick().await;
+                Self::close_idle(&readers, ttl).await;
+            }
+        }
+    }
+    async fn close_idle(
+        readers: &RwLock<BTreeMap<pools::ReadFlags, Pool<R>>>,
+        ttl: std::time::Duration,
+    ) -> usize {
+        let mut readers = readers.write().await;
+        let mut closed = 0;
+        readers.retain(|flags, pool| {
+            let size = pool.status().size;
+            pool.retain(|_, metrics| metrics.last_used() < ttl);
+            let status = pool.status();
+            closed += size - status.size;
+            // The default pool is shared with the background tasks
+            *flags == 0 || status.size > 0
+        });
+        if closed > 0 {
+            debug!("Closed {} idle readers", closed);
+        }
+        closed
+    }
+    /// Flags of the reads on an open file handle.
+    pub async fn handle_flags(&self, fh: u64) -> Option<pools::ReadFlags> {
+        self.handles.read().await.get(&fh).map(|h| h.flags)
+    }
+    /// Whether files or directories are open.
     pub async fn has_handles(&self) -> bool {
-        let handles = self.handles.read().await;
-        !handles.is_empty()
+        !self.handles.read().await.is_empty() || !self.dir_handles.read().await.is_empty()
+    }
+    /// Table of a directory, parsing it if necessary (see [`Options::lazy`]).
+    pub async fn directory_table(
+        &self,
+        inode: u32,
+    ) -> Result<&directory_table::DirectoryTable, Error> {
+        if let Some(table) = self.directory_tables.get(inode) {
+            return Ok(table);
+        }
+        let dir = self
+            .inode_table
+            .wait()
+            .await?
+            .directories
+            .get(&inode)
+            .ok_or(Error::DirectoryNotFound)?;
+        self.directory_tables
+            .get_or_load(inode, || async {
+                debug!(inode, "Parsing directory table");
+                let mut r = self.get_reader(0).await?;
+                Ok::<_, Error>(
+                    directory_table::DirectoryTable::from_reader_directory(
+                        dir,
+                        &self.superblock,
+                        r.deref_mut(),
+                        &self.warnings,
+                    )
+                    .await?,
+                )
+            })
+            .await?
+            .ok_or(Error::DirectoryNotFound)
+    }
+    /// Resolve a path (relative to the root of the image) to an inode.
+    pub async fn resolve(&self, path: &Path) -> Result<u32, Error> {
+        let mut inode = self.root_inode;
+        for component in path.components() {
+            let name = match component {
+                std::path::Component::Normal(name) => name.to_str().ok_or(Error::Encoding)?,
+                std::path::Component::RootDir | std::path::Component::CurDir => continue,
+                _ => return Err(Error::FileNotFound(Some(path.display().to_string()))),
+            };
+            inode = self
+                .directory_table(inode)
+                .await?
+                .find(name)
+                .ok_or_else(|| Error::FileNotFound(Some(path.display().to_string())))?
+                .inode;
+        }
+        Ok(inode)
     }
     /// Open squashfs image from a reader factory, responsible for creating readers with the
     /// requested open flags.
     pub async fn from_reader(
         options: &Options,
         manager_factory: impl ManagerFactory<R>,
-    ) -> Result<Self, Error> {
+    ) -> Result<Self, Error>
+    where
+        R: 'static,
+    {
+        // Parent of the events of the image, including those of its background tasks
+        let span = info_span!("image", image = options.name.as_deref());
+        Self::from_reader_impl(options, manager_factory, span.clone())
+            .instrument(span)
+            .await
+    }
+    async fn from_reader_impl(
+        options: &Options,
+        manager_factory: impl ManagerFactory<R>,
+        span: tracing::Span,
+    ) -> Result<Self, Error>
+    where
+        R: 'static,
+    {
         if options.readers == 0 {
             return Err(Error::InvalidOptions("The number of readers must be >=1"));
         }
+        if options.nonblock_eagain && options.cache_mb == 0 {
+            return Err(Error::InvalidOptions(
+                "Non-blocking reads require the cache of data blocks",
+            ));
+        }
+        if options.max_handle_reads == Some(0) {
+            return Err(Error::InvalidOptions(
+                "The number of reads per handle must be >=1",
+            ));
+        }
+        options.limits().validate()?;
         if options.direct_limit as u64 * 10 > options.cache_mb * (1e6 as u64) {
             return Err(Error::InvalidOptions(
                 "The cache size must be at least 10x as large as --direct-limit.",
             ));
         }
         let manager_factory = Box::new(manager_factory);
+        let capabilities = options.capabilities.unwrap_or_default();
+        let n_readers = match capabilities.max_readers {
+            Some(max) if max < options.readers => {
+                warn!("Using {} readers, the maximum of the backend", max);
+                max
+            }
+            _ => options.readers,
+        };
 
+        // Pools are otherwise created on first use. The default one is needed to parse the
+        // tables, and the one for direct access only in direct mode.
+        let direct = options.direct_limit > 0 || options.auto_tune;
+        let direct_flags = capabilities.pool_flags(libc::O_NONBLOCK);
         let mut readers = BTreeMap::<pools::ReadFlags, Pool<R>>::default();
-        for flags in [0, libc::O_NONBLOCK] {
-            readers.insert(
-                flags,
-                Pool::builder(manager_factory(flags)?)
-                    .max_size(options.readers)
-                    .build()?,
-            );
+        for flags in [0, direct_flags] {
+            if (flags == 0 || direct) && !readers.contains_key(&flags) {
+                let pool = build_pool(manager_factory.as_ref(), flags, n_readers, options.recycle)?;
+                readers.insert(flags, pool);
+            }
         }
 
-        let mut r = readers.get(&libc::O_NONBLOCK).unwrap().get().await?;
+        let warmup = if options.prewarm {
+            warm_up(&readers, n_readers).await?
+        } else {
+            Default::default()
+        };
+
+        let mut r = readers
+            .get(&if direct { direct_flags } else { 0 })
+            .unwrap()
+            .get()
+            .await?;
 
-        let superblock = superblock::SuperBlock::from_reader(&mut r.deref_mut()).await?;
+        let mut superblock =
+            superblock::SuperBlock::from_reader(&mut r.deref_mut(), &options.limits()).await?;
+        let label = match &options.name {
+            Some(name) => name.clone(),
+            None => {
+                let label = format!(
+                    "{:08x}",
+                    utils::hash(&(
+                        superblock.bytes_used,
+                        superblock.inode_count,
+                        superblock.root_inode
+                    )) as u32
+                );
+                span.record("image", label.as_str());
+                label
+            }
+        };
+        let len = tokio::io::AsyncSeekExt::seek(r.deref_mut(), std::io::SeekFrom::End(0))
+            .await
+            .map_err(Error::ReadFailure)?;
+        superblock.check_length(len)?;
+        if let Some(expected) = &options.expected_digest {
+            debug!("Computing the image digest");
+            let actual = verify::image_digest(&mut r.deref_mut()).await?;
+            if &actual != expected {
+                return Err(Error::DigestMismatch {
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+        superblock.xz = data::xz_decompressor(&superblock, options)?;
         debug!(
             "{:?} Tables take {} bytes",
             superblock,
             superblock.tables_length()
         );
 
-        let mut r = if superblock.tables_length() < TABLES_DIRECT_THRESHOLD {
+        let mut r = if !direct || superblock.tables_length() < TABLES_DIRECT_THRESHOLD {
             r
         } else {
             // Don't use direct access if the tables are quite large
             readers.get(&0).unwrap().get().await?
         };
         let mut r = r.deref_mut();
-        let root_inode =
-            inodes::InodeTable::read_root_inode(superblock.root_inode, &superblock, &mut r).await?;
-        let inode_table = inodes::InodeTable::from_reader(&superblock, &mut r).await?;
-        let fragments_table = fragments::FragmentsTable::from_reader(&superblock, &mut r).await?;
-        let mut directory_table: BTreeMap<u32, directory_table::DirectoryTable> =
-            Default::default();
-
-        debug!("Caching directory table");
-        for (inode, dir) in &inode_table.directories {
-            directory_table.insert(
-                *inode,
-                directory_table::DirectoryTable::from_reader_directory(
-                    dir,
-                    &superblock,
-                    r.deref_mut(),
-                )
-                .await?,
+        let pool = readers.get(&0).unwrap().clone();
+        let warnings = warnings::Warnings::new(!options.quiet_warnings);
+        let (root_inode, tables, background) = if options.background_parsing {
+            // Only parse the root directory, the rest is done in the background
+            let (header, root) = inodes::InodeTable::read_inode_at(
+                superblock.root_inode,
+                &superblock,
+                &mut r,
+                &warnings,
+            )
+            .await?;
+            let Some(inodes::Inode::Directory(root)) = root else {
+                return Err(Error::DirectoryNotFound);
+            };
+            let root_table = directory_table::DirectoryTable::from_reader_directory(
+                &root,
+                &superblock,
+                &mut r,
+                &warnings,
+            )
+            .await?;
+            let tables = Tables {
+                inode_table: Deferred::pending(),
+                fragments_table: Deferred::pending(),
+                id_table: Deferred::pending(),
+                xattr_table: Deferred::pending(),
+                directory_tables: directory_table::DirectoryTables::with_root(
+                    header.inode_number,
+                    root_table,
+                ),
+            };
+            let background = utils::spawn_named(
+                "parse_tables",
+                parse_tables(
+                    tables.clone(),
+                    options.lazy,
+                    superblock.clone(),
+                    len,
+                    pool,
+                    warnings.clone(),
+                ),
             );
-        }
+            (header.inode_number, tables, Some(background))
+        } else {
+            let inode_table =
+                inodes::InodeTable::from_reader(&superblock, &mut r, &warnings).await?;
+            check_data_end(&inode_table, &superblock, len)?;
+            let fragments_table =
+                fragments::FragmentsTable::from_reader(&superblock, &mut r).await?;
+            let id_table = id_table::IdTable::from_reader(&superblock, &mut r).await?;
+            let xattr_table = xattrs::XattrTable::from_reader(&superblock, &mut r).await?;
+            let directory_tables =
+                directory_table::DirectoryTables::new(inode_table.directories.keys().copied());
 
-        let cache: Option<IndexCache> = if options.cache_mb > 0 {
-            let cache: Result<IndexCache, CacheError> = IndexCache::new(
-                options.cache_mb,
-                superblock.block_size as u64,
-                superblock.bytes_used,
-            );
-            Some(cache?)
+            let mut remaining = vec![];
+            if !options.lazy {
+                debug!("Caching directory table");
+                let start = std::time::Instant::now();
+                for (inode, dir) in &inode_table.directories {
+                    if !remaining.is_empty()
+                        || options.parse_budget.is_some_and(|b| start.elapsed() > b)
+                    {
+                        remaining.push((*inode, dir.table_location()));
+                        continue;
+                    }
+                    directory_tables
+                        .get_or_load(*inode, || {
+                            directory_table::DirectoryTable::from_reader_directory(
+                                dir,
+                                &superblock,
+                                r.deref_mut(),
+                                &warnings,
+                            )
+                        })
+                        .await?;
+                }
+            }
+
+            let background = (!remaining.is_empty()).then(|| {
+                warn!(
+                    "Parsing budget exceeded, parsing the {} remaining directory tables in the background",
+                    remaining.len()
+                );
+                utils::spawn_named(
+                    "parse_directories",
+                    parse_directories(
+                    directory_tables.clone(),
+                    remaining,
+                    superblock.clone(),
+                    pool,
+                    warnings.clone(),
+                ),
+                )
+            });
+            let tables = Tables {
+                inode_table: Deferred::ready(inode_table),
+                fragments_table: Deferred::ready(fragments_table),
+                id_table: Deferred::ready(id_table),
+                xattr_table: Deferred::ready(xattr_table),
+                directory_tables,
+            };
+            (tables.inode_table.get()?.root, tables, background)
+        };
+
+        let cache: Option<Arc<dyn cache::BlockCache>> = match options.cache_mb {
+            0 => None,
+            _ if options.compressed_cache => {
+                Some(Arc::new(cache::CompressedCache::new(options.cache_mb)))
+            }
+            _ => Some(options.caches.build_blocks(cache::CacheSizes {
+                capacity_mb: options.cache_mb,
+                block_size: superblock.block_size as u64,
+                total_size: superblock.bytes_used,
+            })?),
+        };
+
+        let tuner = if options.auto_tune {
+            Some(
+                autotune::AutoTuner::calibrate(
+                    readers.get(&0).unwrap(),
+                    superblock.bytes_used,
+                    (options.cache_mb * (1e5 as u64)) as usize,
+                )
+                .await?,
+            )
         } else {
             None
         };
-
-        let small_files_cache: Option<LRUCache> = if options.direct_limit > 0 {
-            let direct_cache: Result<LRUCache, CacheError> = LRUCache::new(
-                options.cache_mb,
-                options.direct_limit as u64,
-                (superblock.inode_count as u64) * (options.direct_limit as u64),
-            );
-            Some(direct_cache?)
+        let direct_limit = match &tuner {
+            // The limit can change up to this bound
+            Some(_) => options.cache_mb * (1e5 as u64),
+            None => options.direct_limit as u64,
+        };
+        let small_files_cache = if direct_limit > 0 {
+            Some(options.caches.build_small_files(cache::CacheSizes {
+                capacity_mb: options.cache_mb,
+                block_size: direct_limit,
+                total_size: (superblock.inode_count as u64) * direct_limit,
+            })?)
         } else {
             None
         };
+        let caches_capacity = (cache.is_some() as u64 + small_files_cache.is_some() as u64)
+            * options.cache_mb
+            * (1e6 as u64);
+        let schedulers = match options.scheduler_window {
+            Some(window) => readers
+                .keys()
+                .map(|flags| {
+                    let pool =
+                        build_pool(manager_factory.as_ref(), *flags, n_readers, options.recycle)?;
+                    Ok((*flags, scheduler::Scheduler::new(pool, window)))
+                })
+                .collect::<Result<_, Error>>()?,
+            None => Default::default(),
+        };
+        if superblock.uncompressed_data() {
+            debug!("Data stored without compression");
+        }
+        let decompression = (options.decompression_threads > 0 && !superblock.uncompressed_data())
+            .then(|| {
+                decompression::DecompressionPool::new(
+                    options.decompression_threads,
+                    &options.decompression_cores,
+                )
+            })
+            .transpose()
+            .map_err(Error::ThreadSpawn)?;
+        let checksums = match &options.block_checksums {
+            Some(path) => {
+                let sidecar = tokio::fs::read(path).await.map_err(Error::ReadFailure)?;
+                let checksums =
+                    checksums::BlockChecksums::read_from(&sidecar[..], superblock.bytes_used)?;
+                debug!(blocks = checksums.len(), "Loaded the block checksums");
+                Some(Arc::new(checksums))
+            }
+            None => None,
+        };
+        let stats: Arc<stats::Stats> = Default::default();
+        let fetcher = match (&cache, options.nonblock_eagain || options.auto_tune) {
+            (Some(cache), true) => Some(fetcher::Fetcher::new(
+                readers.get(&0).unwrap().clone(),
+                (cache.clone(), checksums.clone()),
+                tables.fragments_table.clone(),
+                (superblock.codec(), superblock.block_size),
+                stats.clone(),
+            )),
+            _ => None,
+        };
+        let tracer = options
+            .trace_accesses
+            .as_deref()
+            .map(trace::Tracer::new)
+            .transpose()?;
         Ok(Self {
+            tracer,
+            notifier: Default::default(),
+            hooks: None,
+            decompression,
+            schedulers,
             cache,
             small_files_cache,
-            inode_extra: inode_table.ids().max().unwrap() + 1,
+            content_cache: None,
+            small_files_loads: Default::default(),
+            caches_capacity,
+            background,
+            // Inode numbers range from 1 to the number of inodes
+            inode_extra: superblock.inode_count + 1,
             superblock,
-            n_readers: options.readers,
-            directory_tables: directory_table,
-            fragments_table,
-            inode_table,
+            n_readers,
+            capabilities,
+            background_slots: tokio::sync::Semaphore::new(
+                options.background_readers.unwrap_or(n_readers / 2).max(1),
+            ),
+            recycle: options.recycle,
+            directory_tables: tables.directory_tables,
+            fragments_table: tables.fragments_table,
+            id_table: tables.id_table,
+            xattr_table: tables.xattr_table,
+            inode_table: tables.inode_table,
             manager_factory,
             root_inode,
             handles: Default::default(),
-            readers: RwLock::new(readers),
+            next_handle: 1.into(),
+            open_flags: options.open_flags,
+            default_permissions: options.default_permissions,
+            attr_overrides: squashfuse::AttrOverrides::from(options),
+            report_dir_sizes: options.dir_sizes,
+            dir_sizes: Default::default(),
+            paths: Default::default(),
+            stats,
+            error_events: tokio::sync::broadcast::channel(events::CAPACITY).0,
+            drop_behind: options.drop_behind_mb.map(|mb| mb * 1_000_000),
+            stream_bitrate: options.stream_bitrate_kbps.map(|kbps| kbps * 1000 / 8),
+            max_handle_reads: options.max_handle_reads,
+            checksums,
+            fetcher,
+            nonblock_eagain: options.nonblock_eagain,
+            page_cache_policy: std::sync::RwLock::new(Arc::new(
+                squashfuse::OptionsPageCachePolicy {
+                    page_cache: options.page_cache,
+                    direct_io_above: options.direct_io_above_mb * 1_000_000,
+                },
+            )),
+            dir_handles: Default::default(),
+            readers: Arc::new(RwLock::new(readers)),
+            reader_ttl: options.reader_ttl_secs.map(std::time::Duration::from_secs),
+            warmup,
             direct_limit: options.direct_limit,
+            tuner,
+            warnings,
+            label,
+            span,
         })
     }
 }
+
+/// Tables parsed in the background.
+#[derive(Clone)]
+struct Tables {
+    inode_table: Deferred<inodes::InodeTable>,
+    fragments_table: Deferred<FragmentsTable>,
+    id_table: Deferred<id_table::IdTable>,
+    xattr_table: Deferred<xattrs::XattrTable>,
+    directory_tables: directory_table::DirectoryTables,
+}
+
+/// Check that the data blocks of the files fit in the `len` bytes of the reader, and in the
+/// bytes used by the image.
+fn check_data_end(
+    inode_table: &inodes::InodeTable,
+    superblock: &SuperBlock,
+    len: u64,
+) -> Result<(), Error> {
+    let expected = inode_table.data_end();
+    if expected > len {
+        return Err(Error::Truncated {
+            expected,
+            actual: len,
+        });
+    }
+    if expected > superblock.bytes_used {
+        return Err(Error::OutOfBounds {
+            end: expected,
+            bytes_used: superblock.bytes_used,
+        });
+    }
+    Ok(())
+}
+
+/// Parse all tables, see [`Options::background_parsing`].
+async fn parse_tables<R>(
+    tables: Tables,
+    lazy: bool,
+    superblock: SuperBlock,
+    len: u64,
+    pool: Pool<R>,
+    warnings: warnings::Warnings,
+) where
+    R: deadpool::managed::Manager<Error = tokio::io::Error>,
+    R::Type: AsyncSeekBufRead,
+{
+    let start = std::time::Instant::now();
+    let result: Result<_, Error> = async {
+        let mut r = pool.get().await?;
+        let inode_table =
+            inodes::InodeTable::from_reader(&superblock, r.deref_mut(), &warnings).await?;
+        check_data_end(&inode_table, &superblock, len)?;
+        let directories: Vec<_> = inode_table
+            .directories
+            .iter()
+            .map(|(inode, dir)| (*inode, dir.table_location()))
+            .collect();
+        tables
+            .directory_tables
+            .set_directories(Ok(directories.iter().map(|(inode, _)| *inode).collect()));
+        tables.inode_table.set(Ok(inode_table));
+        let id_table = id_table::IdTable::from_reader(&superblock, r.deref_mut()).await?;
+        tables.id_table.set(Ok(id_table));
+        let xattr_table = xattrs::XattrTable::from_reader(&superblock, r.deref_mut()).await?;
+        tables.xattr_table.set(Ok(xattr_table));
+        let fragments_table =
+            fragments::FragmentsTable::from_reader(&superblock, r.deref_mut()).await?;
+        tables.fragments_table.set(Ok(fragments_table));
+        Ok(directories)
+    }
+    .await;
+    match result {
+        Ok(directories) => {
+            debug!("Parsed tables in the background in {:?}", start.elapsed());
+            if !lazy {
+                parse_directories(
+                    tables.directory_tables,
+                    directories,
+                    superblock,
+                    pool,
+                    warnings,
+                )
+                .await;
+            }
+        }
+        Err(e) => {
+            error!("Failed to parse tables in the background: {}", e);
+            // Wake up the waiters for the tables that could not be parsed.
+            let e = e.to_string();
+            tables.inode_table.set(Err(e.clone()));
+            tables.id_table.set(Err(e.clone()));
+            tables.xattr_table.set(Err(e.clone()));
+            tables.fragments_table.set(Err(e.clone()));
+            tables.directory_tables.set_directories(Err(e));
+        }
+    }
+}
+
+/// Parse directory tables (unless they have been parsed on access in the meantime).
+async fn parse_directories<R>(
+    tables: directory_table::DirectoryTables,
+    directories: Vec<(u32, inodes::DirectoryTableLocation)>,
+    superblock: SuperBlock,
+    pool: Pool<R>,
+    warnings: warnings::Warnings,
+) where
+    R: deadpool::managed::Manager<Error = tokio::io::Error>,
+    R::Type: AsyncSeekBufRead,
+{
+    for (inode, location) in directories {
+        if let Err(e) = tables
+            .get_or_load(inode, || async {
+                let mut r = pool.get().await?;
+                Ok::<_, Error>(
+                    directory_table::DirectoryTable::from_reader_location(
+                        &location,
+                        &superblock,
+                        r.deref_mut(),
+                        &warnings,
+                    )
+                    .await?,
+                )
+            })
+            .await
+        {
+            warn!(
+                inode,
+                "Failed to parse directory table in the background: {}", e
+            );
+        }
+    }
+    debug!("Finished parsing directory tables in the background");
+}
@@ -0,0 +1,103 @@
+//! Per-key locks, used to deduplicate concurrent loads into the caches.
+//!
+//! The locks are released when the guard is dropped, including when the future holding it is
+//! cancelled (e.g. when a FUSE request is interrupted). Another waiter then takes over the load.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OwnedMutexGuard;
+
+type KeyLock = Arc<tokio::sync::Mutex<()>>;
+
+#[derive(Default, Debug)]
+pub(crate) struct KeyedLocks {
+    locks: Mutex<HashMap<u64, KeyLock>>,
+}
+impl KeyedLocks {
+    /// Wait for the lock on `key`.
+    pub async fn lock(&self, key: u64) -> KeyGuard<'_> {
+        let lock = self.locks.lock().unwrap().entry(key).or_default().clone();
+        // Created before waiting, so that the entry is cleaned up if we get cancelled.
+        let mut guard = KeyGuard {
+            locks: self,
+            key,
+            lock: lock.clone(),
+            guard: None,
+        };
+        guard.guard = Some(lock.lock_owned().await);
+        guard
+    }
+    /// Number of keys that are locked or waited upon.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+pub(crate) struct KeyGuard<'a> {
+    locks: &'a KeyedLocks,
+    key: u64,
+    lock: KeyLock,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+impl Drop for KeyGuard<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.locks.locks.lock().unwrap();
+        self.guard.take();
+        // Only the map and this guard hold the lock: nobody else is waiting.
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn cancelled_holder() {
+        let locks = Arc::new(KeyedLocks::default());
+        let holder = tokio::spawn({
+            let locks = locks.clone();
+            async move {
+                let _guard = locks.lock(1).await;
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(locks.len(), 1);
+        // Abort the holder mid-flight: the lock must be released.
+        holder.abort();
+        assert!(holder.await.unwrap_err().is_cancelled());
+        let guard = tokio::time::timeout(Duration::from_secs(1), locks.lock(1))
+            .await
+            .unwrap();
+        drop(guard);
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiter() {
+        let locks = Arc::new(KeyedLocks::default());
+        let guard = locks.lock(1).await;
+        let waiter = tokio::spawn({
+            let locks = locks.clone();
+            async move {
+                let _guard = locks.lock(1).await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        waiter.abort();
+        assert!(waiter.await.unwrap_err().is_cancelled());
+        drop(guard);
+        assert_eq!(locks.len(), 0);
+        // Other keys are independent
+        let _a = locks.lock(1).await;
+        let _b = tokio::time::timeout(Duration::from_secs(1), locks.lock(2))
+            .await
+            .unwrap();
+        assert_eq!(locks.len(), 2);
+    }
+}
@@ -0,0 +1,75 @@
+//! Memory usage reporting.
+use crate::SquashFs;
+
+/// Estimate of the memory held by a [`SquashFs`], in bytes.
+///
+/// The table sizes are estimated from their contents, while the caches are accounted for with
+/// their maximum capacity.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub inode_table: usize,
+    pub directory_tables: usize,
+    pub fragments_table: usize,
+    pub id_table: usize,
+    pub xattr_table: usize,
+    /// Capacity of the decoded blocks and small files caches.
+    pub caches: usize,
+}
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.inode_table
+            + self.directory_tables
+            + self.fragments_table
+            + self.id_table
+            + self.xattr_table
+            + self.caches
+    }
+}
+impl std::fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mb = |x: usize| x as f64 / 1e6;
+        write!(
+            f,
+            "{:.1} MB (inodes {:.1} MB, directories {:.1} MB, fragments {:.1} MB, ids {:.1} MB, xattrs {:.1} MB, caches {:.1} MB)",
+            mb(self.total()),
+            mb(self.inode_table),
+            mb(self.directory_tables),
+            mb(self.fragments_table),
+            mb(self.id_table),
+            mb(self.xattr_table),
+            mb(self.caches)
+        )
+    }
+}
+
+impl<R: deadpool::managed::Manager> SquashFs<R> {
+    /// Estimate the memory held by the tables and caches.
+    ///
+    /// Tables that are still being parsed in the background are not accounted for.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            inode_table: self
+                .inode_table
+                .get()
+                .map(|t| t.memory_usage())
+                .unwrap_or_default(),
+            directory_tables: self.directory_tables.memory_usage(),
+            fragments_table: self
+                .fragments_table
+                .get()
+                .map(|t| t.memory_usage())
+                .unwrap_or_default(),
+            id_table: self
+                .id_table
+                .get()
+                .map(|t| t.memory_usage())
+                .unwrap_or_default(),
+            xattr_table: self
+                .xattr_table
+                .get()
+                .map(|t| t.memory_usage())
+                .unwrap_or_default(),
+            caches: self.caches_capacity as usize,
+        }
+    }
+}
@@ -3,9 +3,66 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 use tracing::*;
 
-use super::data::decompress;
+use super::data::{decompress, read_stored, Codec};
 use super::error::MetadataError;
-use super::superblock::Compression;
+
+/// Uncompressed size of the metadata blocks, except the last one of each table.
+pub const METADATA_BLOCK_SIZE: u64 = 8192;
+
+/// Positions of the metadata blocks of a table, mapping offsets in the uncompressed table to
+/// the compressed blocks, to seek to a given position without reading the preceding blocks.
+///
+/// Positions are relative to the start of the table, as in [`crate::inodes::InodeRef`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MetadataIndex {
+    /// Position of each block, and uncompressed offset of its start
+    blocks: Vec<(u64, u64)>,
+}
+impl MetadataIndex {
+    /// Record the next block of the table.
+    pub(crate) fn push(&mut self, position: u64, offset: u64) {
+        debug_assert!(self
+            .blocks
+            .last()
+            .iter()
+            .all(|l| l.0 < position && l.1 <= offset));
+        self.blocks.push((position, offset));
+    }
+    /// Number of blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+    /// Position of the block holding the uncompressed `offset`, and offset in that block.
+    pub fn locate(&self, offset: u64) -> Option<(u64, u64)> {
+        // Blocks are full, except the last one, so this is usually found directly
+        let i = (offset / METADATA_BLOCK_SIZE) as usize;
+        let found = |i: usize| {
+            self.blocks.get(i).is_some_and(|b| b.1 <= offset)
+                && self.blocks.get(i + 1).iter().all(|b| b.1 > offset)
+        };
+        let i = if found(i) {
+            i
+        } else {
+            self.blocks
+                .partition_point(|b| b.1 <= offset)
+                .checked_sub(1)?
+        };
+        let (position, start) = self.blocks[i];
+        Some((position, offset - start))
+    }
+    /// Uncompressed offset of the position `offset` in the block starting at `position`.
+    pub fn offset(&self, position: u64, offset: u64) -> Option<u64> {
+        let i = self.blocks.binary_search_by_key(&position, |b| b.0).ok()?;
+        Some(self.blocks[i].1 + offset)
+    }
+    /// Estimate of the memory held by the index, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.blocks.capacity() * std::mem::size_of::<(u64, u64)>()
+    }
+}
 
 /// https://dr-emann.github.io/squashfs/squashfs.html#_packing_metadata
 #[derive(Debug)]
@@ -16,26 +73,34 @@ pub struct MetadataBlock {
 impl MetadataBlock {
     pub async fn from_reader(
         mut r: impl crate::AsyncSeekBufRead,
-        compression: Compression,
+        codec: &Codec,
     ) -> Result<Self, MetadataError> {
         let header = r
             .read_u16_le()
             .await
-            .map_err(|_| MetadataError::InvalidHeader)?;
+            .map_err(MetadataError::InvalidHeader)?;
         let compressed_size = header & 0x7FFF;
         let compressed = (header & 0x8000) == 0;
         debug!("Read metadata block of size {}", compressed_size);
         let mut data = Vec::<u8>::with_capacity(8192);
-        let mut cursor = std::io::Cursor::new(&mut data);
-        decompress(
-            &mut r,
-            compressed_size as u64,
-            &mut cursor,
-            compressed.then_some(compression),
-        )
-        .await?;
-        if data.len() > 8192 {
-            return Err(MetadataError::InvalidDataLength);
+        if compressed {
+            let mut cursor = std::io::Cursor::new(&mut data);
+            decompress(
+                &mut r,
+                compressed_size as u64,
+                &mut cursor,
+                Some(codec),
+                0..=METADATA_BLOCK_SIZE,
+            )
+            .await?;
+        } else {
+            read_stored(
+                &mut r,
+                compressed_size as u64,
+                &mut data,
+                0..=METADATA_BLOCK_SIZE,
+            )
+            .await?;
         }
         Ok(Self {
             data,
@@ -45,7 +110,7 @@ impl MetadataBlock {
     pub fn from_reader_stream<'a>(
         mut r: impl crate::AsyncSeekBufRead + 'a,
         end: u64,
-        compression: Compression,
+        codec: Codec,
     ) -> impl Stream<Item = Result<(u64, Self), MetadataError>> + 'a {
         async_stream::stream! {
             loop {
@@ -53,7 +118,7 @@ impl MetadataBlock {
                 if pos >= end {
                     break;
                 }
-                let block = MetadataBlock::from_reader(&mut r, compression).await?;
+                let block = MetadataBlock::from_reader(&mut r, &codec).await?;
                 yield Ok((pos, block));
             }
         }
@@ -61,9 +126,20 @@ impl MetadataBlock {
     pub async fn from_reader_flatten<'a>(
         r: impl crate::AsyncSeekBufRead + 'a,
         end: u64,
-        compression: Compression,
+        codec: Codec,
+    ) -> Result<impl AsyncRead + 'a, MetadataError> {
+        Self::from_reader_flatten_inspect(r, end, codec, |_, _| {}).await
+    }
+    /// Same as [`MetadataBlock::from_reader_flatten`], calling `inspect` with the position and
+    /// contents of each block as it is read.
+    pub async fn from_reader_flatten_inspect<'a>(
+        r: impl crate::AsyncSeekBufRead + 'a,
+        end: u64,
+        codec: Codec,
+        mut inspect: impl FnMut(u64, &MetadataBlock) + 'a,
     ) -> Result<impl AsyncRead + 'a, MetadataError> {
-        Ok(Self::from_reader_stream(r, end, compression)
+        Ok(Self::from_reader_stream(r, end, codec)
+            .inspect_ok(move |(pos, b)| inspect(*pos, b))
             .map_ok(|(_, b)| stream::iter(b.data).map(|x| Ok([x])))
             .try_flatten()
             .map_err(|e: MetadataError| std::io::Error::new(std::io::ErrorKind::Other, e))
@@ -71,3 +147,27 @@ impl MetadataBlock {
             .compat())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn metadata_index_test() {
+        let mut index = MetadataIndex::default();
+        for (i, position) in [0, 3000, 5500].into_iter().enumerate() {
+            index.push(position, i as u64 * METADATA_BLOCK_SIZE);
+        }
+        assert_eq!(index.locate(0), Some((0, 0)));
+        assert_eq!(index.locate(8191), Some((0, 8191)));
+        assert_eq!(index.locate(8192 + 100), Some((3000, 100)));
+        assert_eq!(index.locate(3 * 8192 + 100), Some((5500, 8192 + 100)));
+        assert_eq!(index.offset(3000, 100), Some(8192 + 100));
+        assert_eq!(index.offset(3001, 100), None);
+        // Blocks that are not full
+        let mut index = MetadataIndex::default();
+        index.push(0, 0);
+        index.push(100, 50);
+        assert_eq!(index.locate(60), Some((100, 10)));
+        assert_eq!(MetadataIndex::default().locate(0), None);
+    }
+}
@@ -0,0 +1,81 @@
+//! Invalidation of the kernel caches (entries, attributes and page cache) of a mounted image.
+//!
+//! FUSE lets the filesystem notify the kernel that some cached entries are stale, e.g. after the
+//! backing image changed. The notification channel belongs to the FUSE session, and is provided
+//! to [`SquashFs::set_notifier`] through the [`Notifier`] trait.
+use std::ffi::OsStr;
+use std::sync::Arc;
+
+use tracing::*;
+
+use crate::{AsyncSeekBufRead, Error, SquashFs};
+
+/// Notification channel to the kernel.
+///
+/// Inodes are FUSE inode numbers. Notifications about entries that are not cached fail with
+/// [`std::io::ErrorKind::NotFound`], which is ignored.
+pub trait Notifier: Send + Sync {
+    /// Invalidate the attributes of an inode, and its cached data in `offset..offset + len`
+    /// (`len` 0 for the whole file).
+    fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> std::io::Result<()>;
+    /// Invalidate the entry `name` of the directory `parent`.
+    fn inval_entry(&self, parent: u64, name: &OsStr) -> std::io::Result<()>;
+}
+
+fn ignore_not_found(result: std::io::Result<()>) -> Result<(), Error> {
+    match result {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::Notify(e)),
+        _ => Ok(()),
+    }
+}
+
+impl<R: deadpool::managed::Manager> SquashFs<R> {
+    /// Set the channel used by the `invalidate_*` methods, which are no-ops without it.
+    pub fn set_notifier(&self, notifier: Arc<dyn Notifier>) {
+        *self.notifier.write().unwrap() = Some(notifier);
+    }
+    fn notifier(&self) -> Option<Arc<dyn Notifier>> {
+        self.notifier.read().unwrap().clone()
+    }
+    /// Invalidate the attributes and the cached data of a (FUSE) inode.
+    pub fn invalidate_inode(&self, ino_fuse: u64) -> Result<(), Error> {
+        match self.notifier() {
+            Some(notifier) => ignore_not_found(notifier.inval_inode(ino_fuse, 0, 0)),
+            None => Ok(()),
+        }
+    }
+    /// Invalidate the entry `name` of a (FUSE) directory inode.
+    pub fn invalidate_entry(&self, parent_fuse: u64, name: &OsStr) -> Result<(), Error> {
+        match self.notifier() {
+            Some(notifier) => ignore_not_found(notifier.inval_entry(parent_fuse, name)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T, R> SquashFs<R>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+{
+    /// Invalidate every entry and inode of the image in the kernel caches.
+    pub async fn invalidate_all(&self) -> Result<(), Error> {
+        if self.notifier().is_none() {
+            return Ok(());
+        }
+        let inodes: Vec<_> = {
+            self.inode_table.wait().await?;
+            self.inodes()?.collect()
+        };
+        debug!("Invalidating {} inodes", inodes.len());
+        for inode in inodes {
+            if let Ok(table) = self.directory_table(inode).await {
+                for entry in &table.entries {
+                    self.invalidate_entry(self.ino_to_fuse(inode), OsStr::new(&entry.name))?;
+                }
+            }
+            self.invalidate_inode(self.ino_to_fuse(inode))?;
+        }
+        Ok(())
+    }
+}
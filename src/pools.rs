@@ -1,11 +1,15 @@
 //! Readers pools, used when reading data blocks.
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use deadpool::managed::{Hook, HookError, HookErrorCause};
 use fuser_async::{FileHandle, FilesystemSSUS};
 use tokio::io::{AsyncSeekExt, BufReader};
 #[cfg(feature = "asyncfs")]
 use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+use tracing::*;
 
 use crate::Error;
 
@@ -19,9 +23,274 @@ pub enum LocalBackend {
     MemMap,
 }
 
+/// Capacity of the buffers of the readers, see [`crate::Options::buffer_kb`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferCapacity {
+    /// Capacity (B) for regular reads, by default that of the backend.
+    pub default: Option<usize>,
+    /// Capacity (B) for the readers of files opened with `O_DIRECT` or `O_NONBLOCK` (small
+    /// files), by default `default`.
+    pub direct: Option<usize>,
+}
+impl BufferCapacity {
+    /// Capacity for readers with the given open flags, if not that of the backend.
+    pub fn get(&self, flags: ReadFlags) -> Option<usize> {
+        if flags & (libc::O_DIRECT | libc::O_NONBLOCK) != 0 {
+            self.direct.or(self.default)
+        } else {
+            self.default
+        }
+    }
+}
+
+/// Flags with which the file-based local backends open the image, see
+/// [`crate::Options::image_direct`] and [`crate::Options::image_noatime`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LocalOpenOptions {
+    /// Bypass the page cache (`O_DIRECT`).
+    pub direct: bool,
+    /// Do not update the access time of the image (`O_NOATIME`).
+    pub noatime: bool,
+}
+impl LocalOpenOptions {
+    /// Flags to pass to `open` along `O_RDONLY`, for the image at `path`.
+    ///
+    /// The flags that the permissions (`O_NOATIME` requires owning the file) or the filesystem
+    /// do not allow are dropped with a warning. This includes `O_DIRECT` on filesystems that
+    /// require reads aligned on their block size (most local ones), since the readers read at
+    /// arbitrary offsets into unaligned buffers.
+    pub fn flags(&self, path: &Path) -> ReadFlags {
+        use std::os::unix::fs::{FileExt, OpenOptionsExt};
+
+        let mut flags = 0;
+        for (enabled, flag, name) in [
+            (self.noatime, libc::O_NOATIME, "O_NOATIME"),
+            (self.direct, libc::O_DIRECT, "O_DIRECT"),
+        ] {
+            if !enabled {
+                continue;
+            }
+            let probe = std::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(flags | flag)
+                .open(path)
+                .and_then(|f| f.read_at(&mut [0; 1], 1));
+            match probe {
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::EPERM)) => {
+                    warn!(?path, "Opening the image without {}: {}", name, e);
+                }
+                // Other errors are left to the readers
+                _ => flags |= flag,
+            }
+        }
+        flags
+    }
+}
+
+/// Access pattern advised to the kernel for memory mappings (`madvise`), see
+/// [`crate::Options::mmap_advice`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum MemMapAdvice {
+    /// Default read-ahead
+    Normal,
+    /// Aggressive read-ahead, freeing the pages soon after they are read, e.g. for scans
+    Sequential,
+    /// No read-ahead, e.g. for lookups of small files in large images
+    Random,
+    /// Read the whole image in the background
+    #[clap(name = "willneed")]
+    WillNeed,
+}
+
+/// Tuning of the memory mappings of [`LocalReadersPoolMemMap`], see
+/// [`crate::Options::mmap_options`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemMapOptions {
+    pub advice: Option<MemMapAdvice>,
+    /// Lock the metadata tables (inodes, directories, fragments, ids...) in memory.
+    pub lock_tables: bool,
+    /// Back the mapping with transparent huge pages, where the filesystem supports it.
+    pub hugepages: bool,
+}
+
+/// Latency of the reads of a backend, by order of magnitude.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LatencyClass {
+    /// Data already in memory, e.g. memory mappings of cached files
+    Memory,
+    /// Local disks
+    Local,
+    /// Network storage, e.g. object stores, where each request costs milliseconds
+    Remote,
+}
+
+/// What a backend supports, and how it performs, which selects the read strategies.
+///
+/// See [`LocalReadersPool::capabilities`] and [`FilePool::capabilities`], and
+/// [`crate::Options::capabilities`] for other backends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackendCapabilities {
+    /// Seeking to arbitrary offsets is cheap, unlike with streams that have to be reopened.
+    /// Otherwise, small files are read at once rather than block by block.
+    pub positional_reads: bool,
+    /// The readers behave differently with `O_DIRECT` (e.g. with smaller buffers, or bypassing
+    /// the page cache), so that the reads with and without it use separate pools. Otherwise,
+    /// all the reads share the same pool.
+    pub direct_io: bool,
+    /// With [`LatencyClass::Remote`], small files are read at once rather than block by block.
+    pub latency: LatencyClass,
+    /// Maximal number of concurrent readers (e.g. connections), which bounds
+    /// [`crate::Options::readers`].
+    pub max_readers: Option<usize>,
+}
+impl BackendCapabilities {
+    /// Local files, also assumed for backends that do not report their capabilities.
+    pub const LOCAL: Self = Self {
+        positional_reads: true,
+        direct_io: true,
+        latency: LatencyClass::Local,
+        max_readers: None,
+    };
+    /// Flags of the pool serving reads with `flags`.
+    pub fn pool_flags(&self, flags: ReadFlags) -> ReadFlags {
+        if self.direct_io {
+            flags
+        } else {
+            0
+        }
+    }
+    /// Whether small files are better read at once, even without `O_DIRECT`.
+    pub fn whole_small_files(&self) -> bool {
+        !self.positional_reads || self.latency == LatencyClass::Remote
+    }
+}
+impl Default for BackendCapabilities {
+    fn default() -> Self {
+        Self::LOCAL
+    }
+}
+
 /// Reader pools for a local backend/filesystem.
 pub trait LocalReadersPool: Sized {
     fn new(path: &Path) -> Result<Self, Error>;
+    /// Capabilities of the backend, see [`BackendCapabilities`].
+    fn capabilities() -> BackendCapabilities {
+        BackendCapabilities::LOCAL
+    }
+    /// Set the capacity of the buffers of the readers, when they have one.
+    fn with_buffer_capacity(self, _capacity: usize) -> Self {
+        self
+    }
+    /// Set the flags with which the image is opened, for backends that open it for each reader.
+    fn with_open_options(self, _options: LocalOpenOptions) -> Self {
+        self
+    }
+    /// Tune the memory mapping of the image, for backends that have one.
+    fn with_mmap_options(self, _options: MemMapOptions) -> Result<Self, Error> {
+        Ok(self)
+    }
+    /// Load the image into memory, see [`crate::Options::preload`].
+    ///
+    /// The file-based pools ask the kernel to read the whole file into the page cache, while
+    /// [`LocalReadersPoolMemMap`] locks its mapping.
+    fn preload(&self) -> Result<(), Error>;
+}
+
+/// What happens to a reader when it is returned to its pool, see [`crate::Options::recycle`].
+///
+/// The pools of this module do nothing when recycling readers: the policy is applied by
+/// [`crate::SquashFs`] with a pool hook (see [`RecyclePolicy::hook`]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecyclePolicy {
+    /// Reuse the reader as is. Reads always seek to their start, so this saves a seek (which
+    /// may be a round trip on remote backends) and keeps the buffered data.
+    None,
+    /// Seek back to the start of the image.
+    #[default]
+    Rewind,
+    /// Replace the reader by a new one after this many uses.
+    Uses(usize),
+    /// Replace the reader by a new one once it is older than this.
+    Lifetime(Duration),
+}
+impl std::fmt::Display for RecyclePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Rewind => write!(f, "rewind"),
+            Self::Uses(uses) => write!(f, "uses:{}", uses),
+            Self::Lifetime(lifetime) => write!(f, "lifetime:{}", lifetime.as_secs()),
+        }
+    }
+}
+impl std::str::FromStr for RecyclePolicy {
+    type Err = String;
+    /// Parse `none`, `rewind`, `uses:N` or `lifetime:SECONDS`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid recycle policy {:?}", s);
+        match s.split_once(':') {
+            None if s == "none" => Ok(Self::None),
+            None if s == "rewind" => Ok(Self::Rewind),
+            Some(("uses", n)) => match n.parse().map_err(|_| invalid())? {
+                0 => Err(invalid()),
+                n => Ok(Self::Uses(n)),
+            },
+            Some(("lifetime", secs)) => Ok(Self::Lifetime(Duration::from_secs(
+                secs.parse().map_err(|_| invalid())?,
+            ))),
+            _ => Err(invalid()),
+        }
+    }
+}
+impl RecyclePolicy {
+    /// Pre-recycle hook applying the policy, to register with
+    /// [`deadpool::managed::PoolBuilder::pre_recycle`].
+    pub fn hook<M>(self) -> Hook<M>
+    where
+        M: deadpool::managed::Manager<Error = std::io::Error>,
+        M::Type: tokio::io::AsyncSeek + Send + Unpin,
+    {
+        // Readers are discarded with `HookError::Continue`, and a new one is created.
+        match self {
+            Self::None => Hook::sync_fn(|_, _| Ok(())),
+            Self::Rewind => Hook::async_fn(|r: &mut M::Type, _| {
+                Box::pin(async move {
+                    r.seek(std::io::SeekFrom::Start(0))
+                        .await
+                        .map_err(|e| HookError::Continue(Some(HookErrorCause::Backend(e))))?;
+                    Ok(())
+                })
+            }),
+            Self::Uses(uses) => Hook::sync_fn(move |_, metrics| {
+                if metrics.recycle_count + 1 >= uses {
+                    return Err(HookError::Continue(None));
+                }
+                Ok(())
+            }),
+            Self::Lifetime(lifetime) => Hook::sync_fn(move |_, metrics| {
+                if metrics.age() >= lifetime {
+                    return Err(HookError::Continue(None));
+                }
+                Ok(())
+            }),
+        }
+    }
+}
+
+/// Default buffer capacity of the local readers (that of [`BufReader::new`]).
+const DEFAULT_CAPACITY: usize = 8 * 1024;
+/// Default buffer capacity of the [`FilePool`] readers, whose backends are typically remote.
+pub const FILE_POOL_CAPACITY: usize = 128 * 1024;
+
+/// Ask the kernel to read the whole file into the page cache.
+fn fadvise_willneed(path: &Path) -> Result<(), Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::File::open(path).map_err(Error::Preload)?;
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_WILLNEED) } {
+        0 => Ok(()),
+        errno => Err(Error::Preload(std::io::Error::from_raw_os_error(errno))),
+    }
 }
 
 #[cfg(feature = "asyncfs")]
@@ -31,6 +300,10 @@ pub trait LocalReadersPool: Sized {
 /// [`LocalReadersPool`].
 pub struct LocalReadersPoolAsyncFs {
     pub path: PathBuf,
+    /// Capacity of the readers' buffers
+    pub capacity: usize,
+    /// Flags passed to `open`, along `O_RDONLY`, see [`LocalOpenOptions::flags`]
+    pub open_flags: ReadFlags,
 }
 #[async_trait::async_trait]
 #[cfg(feature = "asyncfs")]
@@ -39,25 +312,50 @@ impl deadpool::managed::Manager for LocalReadersPoolAsyncFs {
     type Error = std::io::Error;
 
     async fn create(&self) -> Result<Self::Type, Self::Error> {
-        Ok(BufReader::new(
-            async_fs::File::open(&self.path).await?.compat(),
-        ))
+        use async_fs::unix::OpenOptionsExt;
+
+        let file = async_fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(self.open_flags)
+            .open(&self.path)
+            .await?;
+        Ok(BufReader::with_capacity(self.capacity, file.compat()))
     }
-    async fn recycle(&self, f: &mut Self::Type) -> deadpool::managed::RecycleResult<Self::Error> {
-        f.seek(std::io::SeekFrom::Start(0)).await?;
+    async fn recycle(&self, _f: &mut Self::Type) -> deadpool::managed::RecycleResult<Self::Error> {
+        // See `RecyclePolicy`
         Ok(())
     }
 }
 #[cfg(feature = "asyncfs")]
 impl LocalReadersPool for LocalReadersPoolAsyncFs {
     fn new(path: &Path) -> Result<Self, Error> {
-        Ok(Self { path: path.into() })
+        Ok(Self {
+            path: path.into(),
+            capacity: DEFAULT_CAPACITY,
+            open_flags: 0,
+        })
+    }
+    fn with_buffer_capacity(self, capacity: usize) -> Self {
+        Self { capacity, ..self }
+    }
+    fn with_open_options(self, options: LocalOpenOptions) -> Self {
+        Self {
+            open_flags: options.flags(&self.path),
+            ..self
+        }
+    }
+    fn preload(&self) -> Result<(), Error> {
+        fadvise_willneed(&self.path)
     }
 }
 
 /// Local readers (backed by [`tokio::fs::File`])
 pub struct LocalReadersPoolTokio {
     pub path: PathBuf,
+    /// Capacity of the readers' buffers
+    pub capacity: usize,
+    /// Flags passed to `open`, along `O_RDONLY`, see [`LocalOpenOptions::flags`]
+    pub open_flags: ReadFlags,
 }
 #[async_trait::async_trait]
 impl deadpool::managed::Manager for LocalReadersPoolTokio {
@@ -65,16 +363,37 @@ impl deadpool::managed::Manager for LocalReadersPoolTokio {
     type Error = std::io::Error;
 
     async fn create(&self) -> Result<Self::Type, Self::Error> {
-        Ok(BufReader::new(tokio::fs::File::open(&self.path).await?))
+        let file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(self.open_flags)
+            .open(&self.path)
+            .await?;
+        Ok(BufReader::with_capacity(self.capacity, file))
     }
-    async fn recycle(&self, f: &mut Self::Type) -> deadpool::managed::RecycleResult<Self::Error> {
-        f.seek(std::io::SeekFrom::Start(0)).await?;
+    async fn recycle(&self, _f: &mut Self::Type) -> deadpool::managed::RecycleResult<Self::Error> {
+        // See `RecyclePolicy`
         Ok(())
     }
 }
 impl LocalReadersPool for LocalReadersPoolTokio {
     fn new(path: &Path) -> Result<Self, Error> {
-        Ok(Self { path: path.into() })
+        Ok(Self {
+            path: path.into(),
+            capacity: DEFAULT_CAPACITY,
+            open_flags: 0,
+        })
+    }
+    fn with_buffer_capacity(self, capacity: usize) -> Self {
+        Self { capacity, ..self }
+    }
+    fn with_open_options(self, options: LocalOpenOptions) -> Self {
+        Self {
+            open_flags: options.flags(&self.path),
+            ..self
+        }
+    }
+    fn preload(&self) -> Result<(), Error> {
+        fadvise_willneed(&self.path)
     }
 }
 
@@ -109,20 +428,188 @@ impl deadpool::managed::Manager for LocalReadersPoolMemMap {
 #[cfg(feature = "memmap")]
 impl LocalReadersPool for LocalReadersPoolMemMap {
     fn new(path: &Path) -> Result<Self, Error> {
-        let file = std::fs::File::open(path).map_err(|_| Error::MemMap)?;
-        let data = unsafe { memmap2::Mmap::map(&file).map_err(|_| Error::MemMap)? };
+        let file = std::fs::File::open(path).map_err(Error::MemMap)?;
+        let data = unsafe { memmap2::Mmap::map(&file).map_err(Error::MemMap)? };
         Ok(Self {
             path: path.into(),
             data: MemMapArc(Arc::new(data)),
         })
     }
+    /// The readers all share the mapping, without buffers, whatever the flags.
+    fn capabilities() -> BackendCapabilities {
+        BackendCapabilities {
+            direct_io: false,
+            latency: LatencyClass::Memory,
+            ..BackendCapabilities::LOCAL
+        }
+    }
+    /// Only the advice fails on error: locking the tables and huge pages are best effort.
+    fn with_mmap_options(self, options: MemMapOptions) -> Result<Self, Error> {
+        let data = &self.data.0;
+        if let Some(advice) = options.advice {
+            data.advise(match advice {
+                MemMapAdvice::Normal => memmap2::Advice::Normal,
+                MemMapAdvice::Sequential => memmap2::Advice::Sequential,
+                MemMapAdvice::Random => memmap2::Advice::Random,
+                MemMapAdvice::WillNeed => memmap2::Advice::WillNeed,
+            })
+            .map_err(Error::MemMap)?;
+        }
+        if options.hugepages {
+            if let Err(e) = data.advise(memmap2::Advice::HugePage) {
+                warn!("Huge pages are not available for the image mapping: {}", e);
+            }
+        }
+        if options.lock_tables {
+            // The tables follow the data blocks, up to the end of the image
+            let superblock: crate::SuperBlock = data
+                .get(..96)
+                .and_then(|header| {
+                    crate::deser::bincode_deser(header, crate::Limits::default().structure).ok()
+                })
+                .ok_or(Error::InvalidSuperblock)?;
+            let end = (superblock.bytes_used as usize).min(data.len());
+            let start = (superblock.inode_table_start as usize).min(end);
+            let tables = &data[start..end];
+            let locked =
+                unsafe { libc::mlock(tables.as_ptr() as *const libc::c_void, tables.len()) };
+            if locked == 0 {
+                debug!(start, len = tables.len(), "Locked the tables in memory");
+            } else {
+                warn!(
+                    "Failed to lock the tables in memory: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        Ok(self)
+    }
+    /// Lock the mapping in memory (`mlock`).
+    ///
+    /// If this is not permitted (see `RLIMIT_MEMLOCK`), fall back to faulting in every page,
+    /// which keeps them resident until the kernel needs to reclaim memory.
+    fn preload(&self) -> Result<(), Error> {
+        let data = &self.data.0;
+        if unsafe { libc::mlock(data.as_ptr() as *const libc::c_void, data.len()) } == 0 {
+            return Ok(());
+        }
+        warn!(
+            "Failed to lock the image in memory ({}), reading it instead",
+            std::io::Error::last_os_error()
+        );
+        data.advise(memmap2::Advice::WillNeed)
+            .map_err(Error::Preload)?;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let checksum = data.iter().step_by(page_size).fold(0u8, |a, b| a ^ b);
+        std::hint::black_box(checksum);
+        Ok(())
+    }
 }
 
 /// Flags for the `open` syscall
 pub type ReadFlags = i32;
 
-/// Readers from [`fuser_async::Filesystem`] file handles.
-pub struct FilePool<F: fuser_async::Filesystem>(pub F, pub u64, pub ReadFlags);
+tokio::task_local! {
+    static REQUEST_ID: u64;
+}
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifier of the FUSE read being served, if any.
+///
+/// Backends can forward it to the storage (e.g. as an HTTP header), so that slow requests can
+/// be correlated with the storage-side logs. It is also the `request_id` field of the `read`
+/// tracing span.
+pub fn request_id() -> Option<u64> {
+    REQUEST_ID.try_with(|id| *id).ok()
+}
+pub(crate) fn next_request_id() -> u64 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+/// Run `f` with [`request_id`] returning `id`.
+pub(crate) async fn with_request_id<F: std::future::Future>(id: Option<u64>, f: F) -> F::Output {
+    match id {
+        Some(id) => REQUEST_ID.scope(id, f).await,
+        None => f.await,
+    }
+}
+
+/// How the flags of `open` calls apply to the reads on the handle, see
+/// [`crate::Options::open_flags`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum OpenFlagsPolicy {
+    /// Keep the `O_DIRECT` and `O_NONBLOCK` flags of the caller.
+    Caller,
+    /// Read without flags.
+    Ignore,
+    /// Read with `O_DIRECT`, whatever the flags of the caller.
+    Direct,
+}
+impl OpenFlagsPolicy {
+    /// Flags relevant to the reads, which are passed to the readers.
+    const READ_FLAGS: ReadFlags = libc::O_DIRECT | libc::O_NONBLOCK;
+    /// Flags with which to read a file opened with `flags`.
+    pub fn apply(&self, flags: ReadFlags) -> ReadFlags {
+        match self {
+            Self::Caller => flags & Self::READ_FLAGS,
+            Self::Ignore => 0,
+            Self::Direct => libc::O_DIRECT,
+        }
+    }
+}
+
+/// Readers from [`fuser_async::Filesystem`] file handles, given the filesystem, the inode of
+/// the image, the open flags and the capacity of the readers' buffers (see
+/// [`FILE_POOL_CAPACITY`]).
+///
+/// This allows reading an image stored in another filesystem, e.g. a network filesystem or
+/// another image, see [`crate::SquashFs::open_in`].
+pub struct FilePool<F: fuser_async::Filesystem>(pub F, pub u64, pub ReadFlags, pub usize);
+impl<F: FilesystemSSUS + Clone> FilePool<F> {
+    /// Resolve a path relative to the root of `fs`, which must point to a regular file.
+    pub async fn resolve(fs: &F, path: &Path) -> Result<u64, Error> {
+        let not_found = || Error::FileNotFound(Some(path.display().to_string()));
+        let mut attr = fs
+            .getattr(fuser::FUSE_ROOT_ID)
+            .await
+            .map_err(|e| Error::Fuse(e.into()))?;
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(name) => {
+                    attr = fs
+                        .lookup(attr.ino, name)
+                        .await
+                        .map_err(|e| match e.into() {
+                            fuser_async::Error::NoFileDir => not_found(),
+                            e => Error::Fuse(e),
+                        })?;
+                }
+                Component::ParentDir | Component::Prefix(_) => return Err(not_found()),
+            }
+        }
+        if attr.kind != fuser::FileType::RegularFile {
+            return Err(not_found());
+        }
+        Ok(attr.ino)
+    }
+    /// Capabilities of the backend: the flags are passed to the `open` calls on the
+    /// filesystem.
+    pub fn capabilities() -> BackendCapabilities {
+        BackendCapabilities::LOCAL
+    }
+    /// Manager factory for [`crate::SquashFs::from_reader`], opening handles on `inode` with the
+    /// flags of each pool.
+    pub fn factory(
+        fs: F,
+        inode: u64,
+        capacity: BufferCapacity,
+    ) -> impl crate::ManagerFactory<Self> {
+        move |flags| {
+            let capacity = capacity.get(flags).unwrap_or(FILE_POOL_CAPACITY);
+            Ok(Self(fs.clone(), inode, flags, capacity))
+        }
+    }
+}
 #[async_trait::async_trait]
 impl<F: FilesystemSSUS + Clone> deadpool::managed::Manager for FilePool<F>
 where
@@ -138,11 +625,11 @@ where
                 let e: Box<dyn std::error::Error + Send + Sync> = e.into();
                 tokio::io::Error::new(tokio::io::ErrorKind::Other, e)
             })?;
-        let fh = tokio::io::BufReader::with_capacity(128 * 1024, fh);
+        let fh = tokio::io::BufReader::with_capacity(self.3, fh);
         Ok(fh)
     }
-    async fn recycle(&self, f: &mut Self::Type) -> deadpool::managed::RecycleResult<Self::Error> {
-        f.seek(std::io::SeekFrom::Start(0)).await?;
+    async fn recycle(&self, _f: &mut Self::Type) -> deadpool::managed::RecycleResult<Self::Error> {
+        // See `RecyclePolicy`
         Ok(())
     }
 }
@@ -0,0 +1,161 @@
+//! Elevator-style scheduling of backend reads, see [`crate::Options::scheduler_window`].
+//!
+//! Block reads are collected for a short window, sorted by offset, and adjacent or overlapping
+//! ranges are merged before being dispatched to the pooled readers. This trades a little latency
+//! for far fewer requests when random small reads hit a high-latency backend.
+//!
+//! Requests can carry a deadline, which shortens the collection window, and the merged reads are
+//! dispatched by earliest deadline.
+use std::time::Duration;
+
+use deadpool::managed::Pool;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{mpsc, oneshot};
+use tracing::*;
+
+use crate::{pools, Error};
+
+/// Maximal number of requests collected in a window.
+const MAX_BATCH: usize = 256;
+/// Maximal size of a merged read, in bytes.
+const MAX_MERGED: u64 = 8 * 1024 * 1024;
+
+struct Request {
+    start: u64,
+    len: u64,
+    deadline: Option<tokio::time::Instant>,
+    /// See [`crate::pools::request_id`].
+    request_id: Option<u64>,
+    response: oneshot::Sender<Result<bytes::Bytes, std::io::Error>>,
+}
+
+/// Handle to a read scheduler, running in the background until all handles are dropped.
+#[derive(Clone)]
+pub struct Scheduler {
+    requests: mpsc::UnboundedSender<Request>,
+}
+impl Scheduler {
+    /// Start a scheduler dispatching reads to `pool`, collecting requests for `window`.
+    pub fn new<R>(pool: Pool<R>, window: Duration) -> Self
+    where
+        R: deadpool::managed::Manager<Error = std::io::Error> + 'static,
+        R::Type: crate::AsyncSeekBufRead,
+    {
+        let (requests, rx) = mpsc::unbounded_channel();
+        crate::utils::spawn_named("scheduler", run(rx, pool, window));
+        Self { requests }
+    }
+    /// Read `len` bytes starting at `start`, ideally before `deadline`.
+    pub async fn read(
+        &self,
+        start: u64,
+        len: u64,
+        deadline: Option<std::time::Instant>,
+    ) -> Result<bytes::Bytes, Error> {
+        let (response, rx) = oneshot::channel();
+        self.requests
+            .send(Request {
+                start,
+                len,
+                deadline: deadline.map(tokio::time::Instant::from_std),
+                request_id: pools::request_id(),
+                response,
+            })
+            .map_err(|_| Error::SchedulerStopped)?;
+        rx.await
+            .map_err(|_| Error::SchedulerStopped)?
+            .map_err(Error::ReadFailure)
+    }
+}
+
+async fn run<R>(mut rx: mpsc::UnboundedReceiver<Request>, pool: Pool<R>, window: Duration)
+where
+    R: deadpool::managed::Manager<Error = std::io::Error> + 'static,
+    R::Type: crate::AsyncSeekBufRead,
+{
+    while let Some(first) = rx.recv().await {
+        let mut end = tokio::time::Instant::now() + window;
+        let mut batch = vec![first];
+        loop {
+            // Do not wait past the deadlines of the collected requests
+            if let Some(deadline) = batch.last().unwrap().deadline {
+                end = end.min(deadline);
+            }
+            if batch.len() >= MAX_BATCH {
+                break;
+            }
+            match tokio::time::timeout_at(end, rx.recv()).await {
+                Ok(Some(request)) => batch.push(request),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        let n_requests = batch.len();
+        let mut merged = merge(batch);
+        // Earliest deadline first, then the reads without deadline
+        merged.sort_by_key(|(_, _, requests)| {
+            let deadline = requests.iter().filter_map(|r| r.deadline).min();
+            (deadline.is_none(), deadline)
+        });
+        debug!(
+            "Dispatching {} reads merged from {} requests",
+            merged.len(),
+            n_requests
+        );
+        for (start, end, requests) in merged {
+            // Merged reads are attributed to their first request
+            let request_id = requests.iter().find_map(|r| r.request_id);
+            tokio::spawn(pools::with_request_id(
+                request_id,
+                dispatch(pool.clone(), start, end, requests)
+                    .instrument(debug_span!("dispatch", request_id)),
+            ));
+        }
+    }
+}
+
+/// Sort requests by offset and group them into contiguous ranges.
+fn merge(mut batch: Vec<Request>) -> Vec<(u64, u64, Vec<Request>)> {
+    batch.sort_by_key(|r| r.start);
+    let mut merged: Vec<(u64, u64, Vec<Request>)> = vec![];
+    for request in batch {
+        let end = request.start + request.len;
+        match merged.last_mut() {
+            Some((start, last_end, requests))
+                if request.start <= *last_end && end.max(*last_end) - *start <= MAX_MERGED =>
+            {
+                *last_end = end.max(*last_end);
+                requests.push(request);
+            }
+            _ => merged.push((request.start, end, vec![request])),
+        }
+    }
+    merged
+}
+
+async fn dispatch<R>(pool: Pool<R>, start: u64, end: u64, requests: Vec<Request>)
+where
+    R: deadpool::managed::Manager<Error = std::io::Error>,
+    R::Type: crate::AsyncSeekBufRead,
+{
+    let result: Result<bytes::Bytes, std::io::Error> = async {
+        let mut r = pool
+            .get()
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        r.seek(std::io::SeekFrom::Start(start)).await?;
+        let mut buf = Vec::with_capacity((end - start) as usize);
+        (&mut *r).take(end - start).read_to_end(&mut buf).await?;
+        if buf.len() as u64 != end - start {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buf.into())
+    }
+    .await;
+    for request in requests {
+        let offset = (request.start - start) as usize;
+        let _ = request.response.send(match &result {
+            Ok(data) => Ok(data.slice(offset..offset + request.len as usize)),
+            Err(e) => Err(std::io::Error::new(e.kind(), e.to_string())),
+        });
+    }
+}
@@ -0,0 +1,216 @@
+//! Search of the image by file names or contents, and paths of inodes, without walking it
+//! through the mount.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use futures::{Stream, StreamExt, TryStreamExt};
+
+use crate::directory_table::Entry;
+use crate::{AsyncSeekBufRead, Error, ReadHints, SquashFs};
+
+/// Pattern matched against the names of the files and directories.
+#[derive(Debug, Clone)]
+pub enum NamePattern {
+    /// Names containing this string
+    Substring(String),
+    Regex(regex::Regex),
+}
+impl NamePattern {
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Substring(s) => name.contains(s.as_str()),
+            Self::Regex(r) => r.is_match(name),
+        }
+    }
+}
+impl From<&str> for NamePattern {
+    fn from(s: &str) -> Self {
+        Self::Substring(s.into())
+    }
+}
+impl From<regex::Regex> for NamePattern {
+    fn from(r: regex::Regex) -> Self {
+        Self::Regex(r)
+    }
+}
+
+/// Line found by [`SquashFs::grep`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrepMatch {
+    /// Path of the file, relative to the root
+    pub path: PathBuf,
+    /// 1-based
+    pub line_number: usize,
+    /// Line, without the line terminator, with invalid UTF-8 replaced
+    pub line: String,
+}
+
+impl<T, R> SquashFs<R>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+{
+    /// Entries of the image with their path (relative to the root).
+    ///
+    /// Directories are visited depth-first, and the entries within a directory are sorted by
+    /// name. This uses the directory tables, parsing those that are not in memory yet (see
+    /// [`crate::Options::lazy`]).
+    pub(crate) fn walk(&self) -> impl Stream<Item = Result<(PathBuf, &Entry), Error>> + Send + '_ {
+        self.walk_from(self.root_inode, PathBuf::new())
+    }
+    /// Same as [`SquashFs::walk`], for the entries under the directory `inode` at `path`.
+    pub(crate) fn walk_from(
+        &self,
+        inode: u32,
+        path: PathBuf,
+    ) -> impl Stream<Item = Result<(PathBuf, &Entry), Error>> + Send + '_ {
+        async_stream::try_stream! {
+            let mut stack = vec![(inode, path)];
+            while let Some((inode, path)) = stack.pop() {
+                let mut subdirectories = vec![];
+                for entry in &self.directory_table(inode).await?.entries {
+                    let path = path.join(&entry.name);
+                    if entry.is_dir() {
+                        subdirectories.push((entry.inode, path.clone()));
+                    }
+                    yield (path, entry);
+                }
+                // Reversed, so that the subdirectories are visited in order
+                stack.extend(subdirectories.into_iter().rev());
+            }
+        }
+    }
+    /// Paths (relative to the root) of the files and directories whose name matches `pattern`.
+    ///
+    /// Directories are visited depth-first, and the matches within a directory are sorted by
+    /// name.
+    ///
+    /// This uses the directory tables, parsing those that are not in memory yet (see
+    /// [`crate::Options::lazy`]).
+    pub fn find_names(
+        &self,
+        pattern: impl Into<NamePattern>,
+    ) -> impl Stream<Item = Result<PathBuf, Error>> + Send + '_ {
+        let pattern = pattern.into();
+        self.walk()
+            .try_filter(move |(_, entry)| std::future::ready(pattern.matches(&entry.name)))
+            .map_ok(|(path, _)| path)
+    }
+    /// Paths (relative to the root) of `inode`, i.e. a single one except for hard links. The
+    /// path of the root is empty.
+    ///
+    /// The reverse index from inodes to paths is built on the first call, parsing all the
+    /// directory tables if needed (see [`crate::Options::lazy`]), and is then kept in memory.
+    /// Once built, the paths are also reported in the [`crate::events::ErrorEvent`]s.
+    pub async fn paths_of(&self, inode: u32) -> Result<Vec<PathBuf>, Error> {
+        let index = self
+            .paths
+            .get_or_try_init(|| async {
+                let mut index: BTreeMap<u32, Vec<PathBuf>> = BTreeMap::new();
+                index.insert(self.root_inode, vec![PathBuf::new()]);
+                let mut entries = std::pin::pin!(self.walk());
+                while let Some((path, entry)) = entries.try_next().await? {
+                    index.entry(entry.inode).or_default().push(path);
+                }
+                Ok::<_, Error>(index)
+            })
+            .await?;
+        index.get(&inode).cloned().ok_or(Error::InvalidInode)
+    }
+    /// Lines of the files matching `pattern`, restricted to the files whose path (relative to
+    /// the root) matches `path_filter`.
+    ///
+    /// Files are read as background jobs (see [`crate::Options::background_readers`]) without
+    /// filling the cache, several at a time (up to the number of readers), and the matches are
+    /// returned in the order of [`SquashFs::find_names`]. Binary files, i.e. those with a NUL
+    /// byte in their first blocks, are skipped.
+    pub fn grep(
+        &self,
+        pattern: regex::Regex,
+        path_filter: Option<NamePattern>,
+    ) -> impl Stream<Item = Result<GrepMatch, Error>> + Send + '_ {
+        self.walk()
+            .try_filter(move |(path, entry)| {
+                let selected = !entry.is_dir()
+                    && match &path_filter {
+                        Some(filter) => filter.matches(&path.to_string_lossy()),
+                        None => true,
+                    };
+                std::future::ready(selected)
+            })
+            .map_ok(move |(path, entry)| {
+                let pattern = pattern.clone();
+                async move { self.grep_file(entry.inode, path, &pattern).await }
+            })
+            .try_buffered(self.n_readers.max(1))
+            .map_ok(|matches| futures::stream::iter(matches).map(Ok))
+            .try_flatten()
+    }
+    async fn grep_file(
+        &self,
+        inode: u32,
+        path: PathBuf,
+        pattern: &regex::Regex,
+    ) -> Result<Vec<GrepMatch>, Error> {
+        let Some(size) = self
+            .inode_table
+            .wait()
+            .await?
+            .files
+            .get(&inode)
+            .map(|f| f.file_size())
+        else {
+            // Unsupported inodes
+            return Ok(vec![]);
+        };
+        let chunk = 16 * self.superblock.block_size as usize;
+        let hints = ReadHints {
+            retain: false,
+            ..ReadHints::background()
+        };
+        let mut matches = vec![];
+        let mut line_number = 0;
+        // Data from the start of the current line
+        let mut buffer = vec![];
+        let mut offset = 0;
+        while offset < size {
+            let data = self
+                .read_file_with(
+                    inode,
+                    offset as usize,
+                    chunk,
+                    0,
+                    self.superblock.compression,
+                    hints,
+                )
+                .await?;
+            if data.is_empty() || (offset == 0 && data.contains(&0)) {
+                break;
+            }
+            offset += data.len() as u64;
+            buffer.extend_from_slice(&data);
+            let complete = if offset >= size {
+                buffer.len()
+            } else {
+                match buffer.iter().rposition(|c| *c == b'\n') {
+                    Some(i) => i + 1,
+                    None => continue,
+                }
+            };
+            for line in buffer[..complete].split_inclusive(|c| *c == b'\n') {
+                line_number += 1;
+                let line = String::from_utf8_lossy(line);
+                let line = line.trim_end_matches('\n').trim_end_matches('\r');
+                if pattern.is_match(line) {
+                    matches.push(GrepMatch {
+                        path: path.clone(),
+                        line_number,
+                        line: line.to_string(),
+                    });
+                }
+            }
+            buffer.drain(..complete);
+        }
+        Ok(matches)
+    }
+}
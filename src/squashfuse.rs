@@ -1,26 +1,139 @@
 //! Implementation of `fuse_async::Filesystem` on `SquashFs`.
 use std::collections::BTreeSet;
-use std::time::UNIX_EPOCH;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use fuser_async::Error as ErrorFuse;
-use fuser_async::{utils::BLOCK_SIZE, DirEntry};
+use fuser_async::{utils::BLOCK_SIZE, DirEntry, Filesystem};
+use tracing::*;
 
-use crate::{Error, SquashFs};
+use crate::fuse::FuseFilesystem;
+use crate::inodes::InodeType;
+use crate::stats::Operation;
+use crate::{pools, Error, FileAttrLite, FileKind, ReadHints, SquashFs};
 
 impl From<&super::directory_table::Entry> for DirEntry {
     fn from(e: &super::directory_table::Entry) -> Self {
         DirEntry {
             inode: e.inode as u64,
-            name: e.name.clone(),
-            file_type: if e.is_dir() {
-                fuser::FileType::Directory
-            } else {
-                fuser::FileType::RegularFile
+            name: e.name.to_string(),
+            file_type: match e.r#type {
+                InodeType::BasicDirectory | InodeType::ExtendedDirectory => {
+                    fuser::FileType::Directory
+                }
+                InodeType::BasicSymlink | InodeType::ExtendedSymlink => fuser::FileType::Symlink,
+                _ => fuser::FileType::RegularFile,
             },
         }
     }
 }
 
+/// Open directory handle, see [`SquashFs::opendir`].
+#[derive(Debug)]
+pub(crate) struct DirHandle {
+    inode: u32,
+}
+
+/// Open file handle, see [`SquashFs::open_handles`].
+#[derive(Debug)]
+pub(crate) struct FileHandle {
+    inode: u32,
+    /// Flags of the reads (see [`crate::Options::open_flags`])
+    pub flags: pools::ReadFlags,
+    bytes_served: AtomicU64,
+    opened_at: std::time::SystemTime,
+    readahead: std::sync::Mutex<Readahead>,
+    /// Bytes/s at which the file is consumed (0 if unknown), see
+    /// [`crate::Options::stream_bitrate_kbps`].
+    bitrate: AtomicU64,
+    /// Permits for the reads in flight, see [`crate::Options::max_handle_reads`].
+    inflight: Option<Arc<tokio::sync::Semaphore>>,
+}
+impl FileHandle {
+    fn served(&self, bytes: u64) {
+        self.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+    }
+    /// Record a read, returning the hints for it given the drop-behind length (see
+    /// [`crate::Options::drop_behind_mb`]).
+    ///
+    /// With a bit rate, the deadline of the read is the time at which the stream reaches
+    /// `offset`, assuming it started when the current sequential run did.
+    fn advance(&self, offset: u64, size: u64, drop_behind: Option<u64>) -> ReadHints {
+        let mut readahead = self.readahead.lock().unwrap();
+        let sequential = offset == readahead.next;
+        readahead.window = if sequential {
+            readahead.window + size
+        } else {
+            size
+        };
+        readahead.next = offset + size;
+        if !sequential {
+            readahead.fetched = 0;
+        }
+        let retain = match drop_behind {
+            Some(limit) => readahead.window <= limit,
+            None => true,
+        };
+        let bitrate = self.bitrate.load(Ordering::Relaxed);
+        let deadline = (bitrate > 0).then(|| {
+            let position = std::time::Duration::from_secs_f64(offset as f64 / bitrate as f64);
+            let now = std::time::Instant::now();
+            let origin = match readahead.origin {
+                Some(origin) if sequential => origin,
+                _ => now.checked_sub(position).unwrap_or(now),
+            };
+            readahead.origin = Some(origin);
+            origin + position
+        });
+        ReadHints {
+            retain,
+            deadline,
+            ..Default::default()
+        }
+    }
+    /// Range to read ahead after a read of `size` bytes (see [`Self::advance`]), to keep
+    /// `window` bytes ahead of the current sequential run, excluding what was already read ahead.
+    fn read_ahead(&self, size: u64, window: u64) -> Option<std::ops::Range<u64>> {
+        let mut readahead = self.readahead.lock().unwrap();
+        // Runs of a single read may be random accesses
+        if window == 0 || readahead.window <= size {
+            return None;
+        }
+        let start = readahead.next.max(readahead.fetched);
+        let end = readahead.next + window;
+        readahead.fetched = end;
+        (start < end).then_some(start..end)
+    }
+}
+
+/// Sequential read tracking of a file handle.
+#[derive(Debug, Default)]
+struct Readahead {
+    /// Offset following the last read
+    next: u64,
+    /// Length of the current sequential run
+    window: u64,
+    /// Time at which the stream was at offset 0, for the current sequential run
+    origin: Option<std::time::Instant>,
+    /// End of the range read ahead of the current sequential run, see [`FileHandle::read_ahead`]
+    fetched: u64,
+}
+
+/// Snapshot of an open file handle, see [`SquashFs::open_handles`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandleInfo {
+    pub fh: u64,
+    /// FUSE inode
+    pub inode: u64,
+    /// Flags of the reads (see [`crate::Options::open_flags`])
+    pub flags: pools::ReadFlags,
+    /// Bytes returned by `read` and `copy_file_range` on the handle
+    pub bytes_served: u64,
+    /// Length of the current run of sequential reads
+    pub sequential: u64,
+    pub opened_at: std::time::SystemTime,
+}
+
 impl<R: deadpool::managed::Manager> SquashFs<R> {
     /// Remapping to ensure that the root inode is `fuser::FUSE_ROOT_ID`
     fn ino_from_fuse(&self, ino: u64) -> Result<u32, Error> {
@@ -33,6 +146,127 @@ impl<R: deadpool::managed::Manager> SquashFs<R> {
             ino.try_into().map_err(|_| Error::InvalidInode)
         }
     }
+    /// Filesystem statistics, as reported by `df`. The image being read-only, there are no free
+    /// blocks or inodes.
+    ///
+    /// There is no writable overlay on top of the image (modifications fail with `EROFS`), hence
+    /// no upper layer whose remaining space would be reported here, nor any write quota to enforce
+    /// with `ENOSPC`. Both would only make sense with such an overlay, which is out of scope.
+    ///
+    /// As [`SquashFs::opendir`], this is provided for [`crate::fuse::FuseAdapter`].
+    pub fn statfs(&self) -> Statfs {
+        let block_size = self.superblock.block_size;
+        Statfs {
+            blocks: self.superblock.bytes_used.div_ceil(block_size as u64),
+            bfree: 0,
+            bavail: 0,
+            files: self.superblock.inode_count as u64,
+            ffree: 0,
+            bsize: block_size,
+            namelen: 256,
+            frsize: block_size,
+        }
+    }
+    /// Remove a file, which fails with `EROFS`.
+    ///
+    /// As [`SquashFs::opendir`], this and the other mutations not forwarded by
+    /// [`fuser_async::Filesystem`] are provided for [`crate::fuse::FuseAdapter`].
+    pub async fn unlink(&self, _parent: u64, _name: &std::ffi::OsStr) -> Result<(), Error> {
+        read_only("unlink")
+    }
+    /// Remove a directory, which fails with `EROFS`.
+    pub async fn rmdir(&self, _parent: u64, _name: &std::ffi::OsStr) -> Result<(), Error> {
+        read_only("rmdir")
+    }
+    /// Rename an entry, which fails with `EROFS`.
+    pub async fn rename(
+        &self,
+        _parent: u64,
+        _name: &std::ffi::OsStr,
+        _new_parent: u64,
+        _new_name: &std::ffi::OsStr,
+    ) -> Result<(), Error> {
+        read_only("rename")
+    }
+    /// Create a symbolic link, which fails with `EROFS`.
+    pub async fn symlink(
+        &self,
+        _parent: u64,
+        _name: &std::ffi::OsStr,
+        _target: &std::path::Path,
+    ) -> Result<fuser::FileAttr, Error> {
+        read_only("symlink")
+    }
+    /// Target of the symlink `ino`, as stored in the image (it may be relative, and dangling).
+    ///
+    /// This fails with `EINVAL` for other inodes, as `readlink(2)`. Like [`SquashFs::opendir`],
+    /// this is provided for [`crate::fuse::FuseAdapter`], since [`fuser_async::Filesystem`] has
+    /// no `readlink`.
+    pub async fn readlink(&self, ino_fuse: u64) -> Result<std::path::PathBuf, Error> {
+        let ino = self.ino_from_fuse(ino_fuse)?;
+        let inode_table = self.inode_table.wait().await?;
+        let link = inode_table
+            .symlinks
+            .get(&ino)
+            .ok_or(Error::Fuse(fuser_async::Error::InvalidArgument))?;
+        Ok(link.target().into())
+    }
+    /// Open file handles, e.g. to unmount idle images.
+    pub async fn open_handles(&self) -> Vec<HandleInfo> {
+        let handles = self.handles.read().await;
+        handles
+            .iter()
+            .map(|(fh, h)| HandleInfo {
+                fh: *fh,
+                inode: self.ino_to_fuse(h.inode),
+                flags: h.flags,
+                bytes_served: h.bytes_served.load(Ordering::Relaxed),
+                sequential: h.readahead.lock().unwrap().window,
+                opened_at: h.opened_at,
+            })
+            .collect()
+    }
+    /// Set the rate (bytes/s) at which an open file is consumed, e.g. from the bit rate of a
+    /// video, overriding [`crate::Options::stream_bitrate_kbps`]. `None` removes the deadlines
+    /// of its reads.
+    pub async fn set_bitrate(&self, fh: u64, bytes_per_sec: Option<u64>) -> Result<(), Error> {
+        let handles = self.handles.read().await;
+        let handle = handles
+            .get(&fh)
+            .ok_or(Error::Fuse(fuser_async::Error::BadFileDescriptor))?;
+        handle
+            .bitrate
+            .store(bytes_per_sec.unwrap_or(0), Ordering::Relaxed);
+        Ok(())
+    }
+    /// Permit for a read on a handle limited by [`crate::Options::max_handle_reads`].
+    ///
+    /// Without a free permit, the read only waits for one if no reader of the pool for `flags`
+    /// is available, and proceeds without a permit otherwise. The permits of each handle are
+    /// granted in order, as are the readers, so that the handles share the pool fairly.
+    async fn read_permit(
+        &self,
+        inflight: Arc<tokio::sync::Semaphore>,
+        flags: pools::ReadFlags,
+    ) -> Option<tokio::sync::OwnedSemaphorePermit> {
+        if let Ok(permit) = inflight.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        let idle = self.readers.read().await.get(&flags).is_some_and(|pool| {
+            let status = pool.status();
+            status.available > 0 || status.size < status.max_size
+        });
+        if idle {
+            return None;
+        }
+        debug!(flags, "Waiting for an earlier read of the handle");
+        // The semaphore is never closed
+        inflight.acquire_owned().await.ok()
+    }
+    /// Allocate a file or directory handle, without locking the handle tables.
+    fn next_handle(&self) -> u64 {
+        self.next_handle.fetch_add(1, Ordering::Relaxed)
+    }
     /// Remapping to ensure that the root inode is `fuser::FUSE_ROOT_ID`
     pub fn ino_to_fuse(&self, ino: u32) -> u64 {
         let fuse_root: u32 = fuser::FUSE_ROOT_ID.try_into().unwrap();
@@ -44,38 +278,356 @@ impl<R: deadpool::managed::Manager> SquashFs<R> {
             ino as u64
         }
     }
-    fn getattr_inode(&self, ino: u32) -> Result<fuser::FileAttr, Error> {
-        if let Some(f) = self.inode_table.files.get(&ino) {
-            Ok(fuser_async::utils::file_attr(
-                self.ino_to_fuse(ino),
-                f.file_size(),
-                UNIX_EPOCH,
-            ))
+}
+
+/// Filesystem statistics, with the fields of the `statfs` replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Statfs {
+    /// Size of the image, in `frsize` units
+    pub blocks: u64,
+    /// Free blocks, always 0
+    pub bfree: u64,
+    /// Free blocks available to unprivileged users, always 0
+    pub bavail: u64,
+    /// Number of inodes
+    pub files: u64,
+    /// Free inodes, always 0
+    pub ffree: u64,
+    /// Preferred size of the reads, the block size of the image
+    pub bsize: u32,
+    /// Maximal length of the names
+    pub namelen: u32,
+    /// Unit of `blocks`, the block size of the image
+    pub frsize: u32,
+}
+
+/// Answer to the requests modifying the image, which is read-only: `EROFS`, rather than `ENOSYS`
+/// for unimplemented operations.
+pub(crate) fn read_only<T>(op: &str) -> Result<T, Error> {
+    debug!(op, "Rejecting modification of the read-only image");
+    Err(ErrorFuse::ReadOnly.into())
+}
+
+/// Caching of the contents of a file by the kernel, decided when it is opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum PageCache {
+    /// Use the page cache, which the kernel drops when the file is opened again.
+    Default,
+    /// Bypass the page cache (`direct_io`), e.g. for large files read once.
+    DirectIo,
+    /// Keep the page cache across opens (`keep_cache`), e.g. for small files read often. This is
+    /// safe since the image is immutable.
+    KeepCache,
+    /// `direct_io` for files opened with `O_DIRECT` or larger than
+    /// [`crate::Options::direct_io_above_mb`], `keep_cache` otherwise.
+    Auto,
+}
+/// `FOPEN_DIRECT_IO` flag of the `open` replies
+const FOPEN_DIRECT_IO: u32 = 1 << 0;
+/// `FOPEN_KEEP_CACHE` flag of the `open` replies
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// Decides how the kernel caches the contents of opened files, see
+/// [`SquashFs::set_page_cache_policy`].
+pub trait PageCachePolicy: Send + Sync {
+    /// Caching for the file `attr`, opened with `flags` (after [`crate::Options::open_flags`]).
+    /// [`PageCache::Auto`] is not a valid answer.
+    fn page_cache(&self, attr: &FileAttrLite, flags: crate::pools::ReadFlags) -> PageCache;
+}
+/// Policy from [`crate::Options::page_cache`].
+pub(crate) struct OptionsPageCachePolicy {
+    pub page_cache: PageCache,
+    /// Bytes
+    pub direct_io_above: u64,
+}
+impl PageCachePolicy for OptionsPageCachePolicy {
+    fn page_cache(&self, attr: &FileAttrLite, flags: crate::pools::ReadFlags) -> PageCache {
+        match self.page_cache {
+            PageCache::Auto if flags & libc::O_DIRECT != 0 || attr.size > self.direct_io_above => {
+                PageCache::DirectIo
+            }
+            PageCache::Auto => PageCache::KeepCache,
+            page_cache => page_cache,
+        }
+    }
+}
+
+/// Attributes reported instead of those stored in the image, see [`crate::Options::uid`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct AttrOverrides {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub file_mode: Option<u16>,
+    pub dir_mode: Option<u16>,
+    pub umask: Option<u16>,
+    pub other_readable: bool,
+}
+impl From<&crate::Options> for AttrOverrides {
+    fn from(options: &crate::Options) -> Self {
+        Self {
+            uid: options.uid,
+            gid: options.gid,
+            file_mode: options.file_mode,
+            dir_mode: options.dir_mode,
+            umask: options.umask,
+            other_readable: options.other_readable,
+        }
+    }
+}
+impl AttrOverrides {
+    pub fn apply(&self, mut attr: FileAttrLite) -> FileAttrLite {
+        attr.uid = self.uid.unwrap_or(attr.uid);
+        attr.gid = self.gid.unwrap_or(attr.gid);
+        let mode = match attr.kind {
+            FileKind::File => self.file_mode,
+            FileKind::Directory => self.dir_mode,
+            // The permissions of symlinks are not checked
+            FileKind::Symlink => None,
+        };
+        attr.mode = mode.unwrap_or(attr.mode) & !self.umask.unwrap_or(0);
+        if self.other_readable {
+            attr.mode |= 0o004;
+            // Directories must be searchable to be browsed, files keep their executability
+            if attr.kind == FileKind::Directory || attr.mode & 0o111 != 0 {
+                attr.mode |= 0o001;
+            }
+        }
+        attr
+    }
+}
+
+/// Whether the permission bits of `attr` grant `mask` (without `W_OK`) to `uid`/`gid`.
+fn permitted(attr: &FileAttrLite, uid: u32, gid: u32, mask: i32) -> bool {
+    let mask = (mask & (libc::R_OK | libc::X_OK)) as u16;
+    if uid == 0 {
+        // Root can read anything, and execute files with any execute bit set
+        return mask & libc::X_OK as u16 == 0
+            || attr.kind == FileKind::Directory
+            || attr.mode & 0o111 != 0;
+    }
+    let bits = if uid == attr.uid {
+        attr.mode >> 6
+    } else if gid == attr.gid {
+        attr.mode >> 3
+    } else {
+        attr.mode
+    };
+    bits & mask == mask
+}
+
+impl<
+        T: crate::AsyncSeekBufRead,
+        R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+    > SquashFs<R>
+{
+    async fn getattr_inode(&self, ino: u32) -> Result<fuser::FileAttr, Error> {
+        self.inode_table.wait().await?;
+        self.id_table.wait().await?;
+        let attr = self.attr_overrides.apply(self.attr(ino)?);
+        let size = if self.report_dir_sizes && attr.kind == FileKind::Directory {
+            self.dir_stats(ino).await?.size
         } else {
-            let directory = self
-                .inode_table
-                .directories
-                .get(&ino)
-                .ok_or(Error::DirectoryNotFound)?;
-            Ok(fuser::FileAttr {
-                ino: self.ino_to_fuse(ino),
-                size: 0,
-                blocks: 0,
-                // TODO: Set these.
-                atime: UNIX_EPOCH,
-                mtime: UNIX_EPOCH,
-                ctime: UNIX_EPOCH,
-                crtime: UNIX_EPOCH,
-                kind: fuser::FileType::Directory,
-                perm: 0o755,
-                nlink: directory.hard_link_count(),
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
-                blksize: BLOCK_SIZE,
-            })
+            attr.size
+        };
+        Ok(fuser::FileAttr {
+            ino: self.ino_to_fuse(ino),
+            size,
+            // In 512-byte units
+            blocks: attr.size.div_ceil(512),
+            atime: attr.mtime,
+            mtime: attr.mtime,
+            ctime: attr.mtime,
+            crtime: attr.crtime,
+            kind: match attr.kind {
+                FileKind::File => fuser::FileType::RegularFile,
+                FileKind::Directory => fuser::FileType::Directory,
+                FileKind::Symlink => fuser::FileType::Symlink,
+            },
+            perm: attr.mode,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: BLOCK_SIZE,
+        })
+    }
+    /// Generation of the directory `ino`, in the high bits of its `readdir` cookies.
+    ///
+    /// It identifies the directory in this version of the image, so that cookies obtained from
+    /// another version (e.g. when an image served by a [`crate::supervisor::Supervisor`] is
+    /// replaced) are rejected, rather than silently skipping or repeating entries.
+    fn dir_generation(&self, ino: u32) -> u64 {
+        let superblock = &self.superblock;
+        let hash = crate::utils::hash(&(superblock.creation_time(), superblock.bytes_used, ino));
+        // Non-zero, and keeping the cookies positive as signed offsets
+        hash % (u32::MAX >> 1) as u64 + 1
+    }
+    /// Entries of a directory following `cookie` (0 to start from the beginning), with their
+    /// cookies.
+    ///
+    /// The cookie of the entry at index `i` is `i + 1`, with the generation of the directory in
+    /// the high bits. Cookies without generation are plain indices, as computed by adapters
+    /// deriving them from the position in the listing (e.g. [`fuser_async::FilesystemFUSE`],
+    /// through [`fuser_async::Filesystem::readdir`]).
+    async fn dir_entries(
+        &self,
+        ino: u32,
+        cookie: u64,
+    ) -> Result<impl Iterator<Item = (u64, DirEntry)> + Send + Sync + '_, Error> {
+        let generation = self.dir_generation(ino);
+        let start = match cookie >> 32 {
+            0 => cookie,
+            g if g == generation => cookie & u32::MAX as u64,
+            _ => return Err(Error::InvalidOffset),
+        } as usize;
+        let d = self.directory_table(ino).await?;
+        Ok(d.entries
+            .get(start..)
+            .unwrap_or_default()
+            .iter()
+            .zip(start as u64 + 1..)
+            .map(move |(e, index)| {
+                let mut entry = DirEntry::from(e);
+                entry.inode = self.ino_to_fuse(e.inode);
+                ((generation << 32) | index, entry)
+            }))
+    }
+    /// Open a directory handle, to list it with [`SquashFs::readdir_handle`].
+    ///
+    /// [`fuser_async::Filesystem`] does not forward `opendir`/`releasedir`, hence these are
+    /// provided as inherent methods, for [`crate::fuse::FuseAdapter`].
+    pub async fn opendir(&self, ino_fuse: u64) -> Result<u64, Error> {
+        let inode = self.ino_from_fuse(ino_fuse)?;
+        if !self
+            .inode_table
+            .wait()
+            .await?
+            .directories
+            .contains_key(&inode)
+        {
+            return Err(Error::DirectoryNotFound);
         }
+        let fh = self.next_handle();
+        self.dir_handles
+            .write()
+            .await
+            .insert(fh, DirHandle { inode });
+        Ok(fh)
+    }
+    /// Check whether `uid`/`gid` may access a file with `mask` (`F_OK`, or a combination of
+    /// `R_OK`, `W_OK` and `X_OK`), as the `access` syscall.
+    ///
+    /// With [`crate::Options::default_permissions`], the permission bits, or the access ACL of
+    /// the file if it has one (see [`SquashFs::acl`]), are checked as the kernel would, except
+    /// for supplementary groups, which FUSE does not forward. Otherwise,
+    /// everything is allowed but writing, which fails with `EROFS`.
+    ///
+    /// As [`SquashFs::opendir`], this is provided for FUSE adapters forwarding `access`.
+    pub async fn access(&self, ino_fuse: u64, uid: u32, gid: u32, mask: i32) -> Result<(), Error> {
+        let ino = self.ino_from_fuse(ino_fuse)?;
+        self.inode_table.wait().await?;
+        self.id_table.wait().await?;
+        let attr = self.attr_overrides.apply(self.attr(ino)?);
+        if mask & libc::W_OK != 0 {
+            return read_only("access");
+        }
+        if !self.default_permissions {
+            return Ok(());
+        }
+        let permitted = match self.acl(ino).await? {
+            Some(acl) => acl.permits(&attr, uid, gid, mask),
+            None => permitted(&attr, uid, gid, mask),
+        };
+        if permitted {
+            Ok(())
+        } else {
+            Err(Error::PermissionDenied)
+        }
+    }
+    /// Copy `len` bytes of a file opened as `fh`, from `offset`, to `output`, as the
+    /// `copy_file_range` syscall. Returns the number of bytes copied.
+    ///
+    /// The data is written as it is decompressed, without going through the page cache. Since
+    /// the filesystem is read-only, FUSE adapters should answer `copy_file_range` requests (whose
+    /// destination is in the same filesystem) with `EROFS`, and use this for copies to other
+    /// files, e.g. when extracting.
+    pub async fn copy_file_range(
+        &self,
+        ino_fuse: u64,
+        fh: u64,
+        offset: u64,
+        output: impl tokio::io::AsyncWrite + Unpin,
+        len: u64,
+    ) -> Result<u64, Error> {
+        let ino = self.ino_from_fuse(ino_fuse)?;
+        let flags = self
+            .handle_flags(fh)
+            .await
+            .ok_or(Error::Fuse(fuser_async::Error::BadFileDescriptor))?;
+        let copied = self.copy_file(ino, offset, len, flags, output).await?;
+        if let Some(handle) = self.handles.read().await.get(&fh) {
+            handle.served(copied);
+        }
+        Ok(copied)
+    }
+    /// Replace the policy from [`crate::Options::page_cache`].
+    pub fn set_page_cache_policy(&self, policy: Arc<dyn PageCachePolicy>) {
+        *self.page_cache_policy.write().unwrap() = policy;
+    }
+    /// Flags (`FOPEN_DIRECT_IO`, `FOPEN_KEEP_CACHE`) of the reply to an `open` request with
+    /// `flags`, according to the [`PageCachePolicy`].
+    ///
+    /// [`fuser_async::Filesystem::open`] only returns a file handle, hence this is provided for
+    /// FUSE adapters that can set these flags.
+    pub async fn open_reply_flags(&self, ino_fuse: u64, flags: i32) -> Result<u32, Error> {
+        let ino = self.ino_from_fuse(ino_fuse)?;
+        self.inode_table.wait().await?;
+        self.id_table.wait().await?;
+        let attr = self.attr(ino)?;
+        let flags = self.open_flags.apply(flags);
+        let policy = self.page_cache_policy.read().unwrap().clone();
+        Ok(match policy.page_cache(&attr, flags) {
+            PageCache::DirectIo => FOPEN_DIRECT_IO,
+            PageCache::KeepCache => FOPEN_KEEP_CACHE,
+            PageCache::Default | PageCache::Auto => 0,
+        })
+    }
+    /// Release a directory handle obtained with [`SquashFs::opendir`].
+    pub async fn releasedir(&self, fh: u64) -> Result<(), Error> {
+        let mut handles = self.dir_handles.write().await;
+        handles
+            .remove(&fh)
+            .ok_or(Error::Fuse(fuser_async::Error::BadFileDescriptor))?;
+        Ok(())
+    }
+    /// List a directory from a handle obtained with [`SquashFs::opendir`].
+    ///
+    /// `offset` is the cookie of the last entry received (0 to start from the beginning), and
+    /// the entries are returned with their cookies, which [`crate::fuse::FuseAdapter`] passes to
+    /// the kernel.
+    /// Since cookies identify entries rather than positions in a stream, concurrent listings of
+    /// the same directory and `seekdir` are consistent. Cookies from another version of the
+    /// image fail with [`Error::InvalidOffset`]. The handle keeps no position, as the kernel
+    /// passes the offset with every request (0 after `rewinddir`).
+    pub async fn readdir_handle(
+        &self,
+        fh: u64,
+        offset: u64,
+    ) -> Result<Box<dyn Iterator<Item = (u64, DirEntry)> + Send + Sync + '_>, Error> {
+        let inode = self
+            .dir_handles
+            .read()
+            .await
+            .get(&fh)
+            .ok_or(Error::Fuse(fuser_async::Error::BadFileDescriptor))?
+            .inode;
+        let entries = self
+            .stats
+            .timed(Operation::Readdir, self.dir_entries(inode, offset))
+            .instrument(self.span.clone())
+            .await?;
+        Ok(Box::new(entries))
     }
 }
 
@@ -87,66 +639,84 @@ impl<
 {
     type Error = Error;
     async fn inodes(&self) -> Result<BTreeSet<u64>, Error> {
-        Ok(self.inodes().map(|ino| self.ino_to_fuse(ino)).collect())
+        self.inode_table.wait().await?;
+        Ok(self.inodes()?.map(|ino| self.ino_to_fuse(ino)).collect())
     }
 
-    async fn open(&self, _ino: u64, flags: i32) -> Result<u64, Self::Error> {
-        let mut handles = self.handles.write().await;
-        let fh = handles.keys().last().copied().unwrap_or_default() + 1;
-        handles.insert(fh, flags);
+    async fn open(&self, ino: u64, flags: i32) -> Result<u64, Self::Error> {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return read_only("open");
+        }
+        if flags & libc::O_DIRECT != 0 {
+            debug!(ino, "Direct IO requested");
+        }
+        let handle = FileHandle {
+            inode: self.ino_from_fuse(ino)?,
+            flags: self.open_flags.apply(flags),
+            bytes_served: Default::default(),
+            readahead: Default::default(),
+            bitrate: AtomicU64::new(self.stream_bitrate.unwrap_or(0)),
+            opened_at: std::time::SystemTime::now(),
+            inflight: self
+                .max_handle_reads
+                .map(|n| Arc::new(tokio::sync::Semaphore::new(n))),
+        };
+        let fh = self.next_handle();
+        let (inode, flags) = (handle.inode, handle.flags);
+        self.handles.write().await.insert(fh, handle);
+        self.run_hooks(inode, flags, |hooks, file| hooks.on_open(file, fh));
         Ok(fh)
     }
     async fn release(&self, _ino: u64, fh: u64) -> Result<(), Self::Error> {
-        let mut handles = self.handles.write().await;
-        handles
+        let handle = self
+            .handles
+            .write()
+            .await
             .remove(&fh)
             .ok_or(Error::Fuse(fuser_async::Error::BadFileDescriptor))?;
+        self.run_hooks(handle.inode, handle.flags, |hooks, file| {
+            hooks.on_release(file, fh)
+        });
         Ok(())
     }
 
     async fn lookup(&self, parent: u64, name: &std::ffi::OsStr) -> Result<fuser::FileAttr, Error> {
-        let ino = self.ino_from_fuse(parent)?;
-        let d = self
-            .directory_tables
-            .get(&ino)
-            .ok_or(Error::DirectoryNotFound)?;
-        let name = name.to_str().ok_or(Error::Encoding)?;
-        let f = d
-            .find(name)
-            .ok_or_else(|| Error::FileNotFound(Some(name.into())))?;
-        Ok(self.getattr_inode(f.inode)?)
+        self.stats
+            .timed(Operation::Lookup, async {
+                let ino = self.ino_from_fuse(parent)?;
+                let d = self.directory_table(ino).await?;
+                let name = name.to_str().ok_or(Error::Encoding)?;
+                let f = d
+                    .find(name)
+                    .ok_or_else(|| Error::FileNotFound(Some(name.into())))?;
+                self.getattr_inode(f.inode).await
+            })
+            .instrument(self.span.clone())
+            .await
     }
     async fn getattr(&self, ino_fuse: u64) -> Result<fuser::FileAttr, Error> {
         let ino = self.ino_from_fuse(ino_fuse)?;
-        self.getattr_inode(ino)
+        self.getattr_inode(ino).instrument(self.span.clone()).await
     }
     async fn setattr(
         &mut self,
         _ino: u64,
         _size: Option<u64>,
     ) -> Result<fuser::FileAttr, Self::Error> {
-        Err(ErrorFuse::Unimplemented.into())
+        read_only("setattr")
     }
     async fn readdir(
         &self,
         ino_fuse: u64,
         offset: u64,
     ) -> Result<Box<dyn Iterator<Item = fuser_async::DirEntry> + Send + Sync + '_>, Error> {
-        let ino = self.ino_from_fuse(ino_fuse).unwrap();
-        let d = self
-            .directory_tables
-            .get(&ino)
-            .ok_or(Error::DirectoryNotFound)?;
-        Ok(Box::new(
-            d.entries
-                .iter()
-                .skip(offset as usize)
-                .map(fuser_async::DirEntry::from)
-                .map(|mut e| {
-                    e.inode = self.ino_to_fuse(e.inode as u32);
-                    e
-                }),
-        ))
+        let ino = self.ino_from_fuse(ino_fuse)?;
+        let entries = self
+            .stats
+            .timed(Operation::Readdir, self.dir_entries(ino, offset))
+            .instrument(self.span.clone())
+            .await?;
+        Ok(Box::new(entries.map(|(_, entry)| entry)))
     }
     async fn read(
         &self,
@@ -156,21 +726,47 @@ impl<
         size: u32,
     ) -> Result<bytes::Bytes, Error> {
         let ino = self.ino_from_fuse(ino_fuse)?;
-        let flags = {
+        let (flags, hints, inflight) = {
             let handles = self.handles.read().await;
-            *handles
+            let handle = handles
                 .get(&fh)
-                .ok_or(Error::Fuse(fuser_async::Error::BadFileDescriptor))?
-        };
-        Ok(self
-            .read_file(
-                ino,
-                offset as usize,
-                size as usize,
-                flags,
-                self.superblock.compression,
-            )
-            .await?)
+                .ok_or(Error::Fuse(fuser_async::Error::BadFileDescriptor))?;
+            let mut hints = handle.advance(offset as u64, size as u64, self.drop_behind);
+            hints.cached_only = self.nonblock_eagain && handle.flags & libc::O_NONBLOCK != 0;
+            if let Some(ahead) = handle.read_ahead(size as u64, self.readahead()) {
+                let size = ahead.end - ahead.start;
+                let _ = self.fetch_in_background(ino, ahead.start as usize, size as usize);
+            }
+            (handle.flags, hints, handle.inflight.clone())
+        };
+        let _permit = match inflight {
+            Some(inflight) => self.read_permit(inflight, flags).await,
+            None => None,
+        };
+        let request_id = pools::next_request_id();
+        let read = self.read_file_with(
+            ino,
+            offset as usize,
+            size as usize,
+            flags,
+            self.superblock.compression,
+            hints,
+        );
+        let data =
+            pools::with_request_id(Some(request_id), self.stats.timed(Operation::Read, read))
+                .instrument(debug_span!(parent: &self.span, "read", request_id, ino, offset, size))
+                .await
+                .inspect_err(|e| {
+                    if matches!(e, Error::WouldBlock) {
+                        debug!(ino, offset, size, "Data not cached, loading it");
+                        let _ = self.fetch_in_background(ino, offset as usize, size as usize);
+                    }
+                })?;
+        self.stats.record_bytes(data.len() as u64);
+        if let Some(handle) = self.handles.read().await.get(&fh) {
+            handle.served(data.len() as u64);
+        }
+        Ok(data)
     }
     async fn write(
         &self,
@@ -179,7 +775,7 @@ impl<
         _data: bytes::Bytes,
         _offset: i64,
     ) -> Result<u32, Self::Error> {
-        Err(ErrorFuse::ReadOnly.into())
+        read_only("write")
     }
     async fn create(
         &mut self,
@@ -189,13 +785,434 @@ impl<
         _umask: u32,
         _flags: i32,
     ) -> Result<(fuser::FileAttr, u64), Self::Error> {
-        Err(ErrorFuse::ReadOnly.into())
+        read_only("create")
     }
     async fn mkdir(
         &mut self,
         _parent: u64,
         _name: std::ffi::OsString,
     ) -> Result<fuser::FileAttr, Self::Error> {
-        Err(ErrorFuse::ReadOnly.into())
+        read_only("mkdir")
+    }
+}
+
+/// [`SquashFs`] shared behind an [`Arc`], implementing [`fuser_async::Filesystem`] by delegation.
+///
+/// This allows reading images stored inside another image, see [`SquashFs::open_nested`].
+pub struct SharedSquashFs<R: deadpool::managed::Manager>(pub Arc<SquashFs<R>>);
+impl<R: deadpool::managed::Manager> Clone for SharedSquashFs<R> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl<
+        T: crate::AsyncSeekBufRead,
+        R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+    > fuser_async::Filesystem for SharedSquashFs<R>
+{
+    type Error = Error;
+    async fn inodes(&self) -> Result<BTreeSet<u64>, Error> {
+        Filesystem::inodes(self.0.as_ref()).await
+    }
+    async fn open(&self, ino: u64, flags: i32) -> Result<u64, Self::Error> {
+        Filesystem::open(self.0.as_ref(), ino, flags).await
+    }
+    async fn release(&self, ino: u64, fh: u64) -> Result<(), Self::Error> {
+        Filesystem::release(self.0.as_ref(), ino, fh).await
+    }
+    async fn lookup(&self, parent: u64, name: &std::ffi::OsStr) -> Result<fuser::FileAttr, Error> {
+        Filesystem::lookup(self.0.as_ref(), parent, name).await
+    }
+    async fn getattr(&self, ino_fuse: u64) -> Result<fuser::FileAttr, Error> {
+        Filesystem::getattr(self.0.as_ref(), ino_fuse).await
+    }
+    async fn setattr(
+        &mut self,
+        _ino: u64,
+        _size: Option<u64>,
+    ) -> Result<fuser::FileAttr, Self::Error> {
+        read_only("setattr")
+    }
+    async fn readdir(
+        &self,
+        ino_fuse: u64,
+        offset: u64,
+    ) -> Result<Box<dyn Iterator<Item = fuser_async::DirEntry> + Send + Sync + '_>, Error> {
+        Filesystem::readdir(self.0.as_ref(), ino_fuse, offset).await
+    }
+    async fn read(
+        &self,
+        ino_fuse: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+    ) -> Result<bytes::Bytes, Error> {
+        Filesystem::read(self.0.as_ref(), ino_fuse, fh, offset, size).await
+    }
+    async fn write(
+        &self,
+        _ino: u64,
+        _fh: u64,
+        _data: bytes::Bytes,
+        _offset: i64,
+    ) -> Result<u32, Self::Error> {
+        read_only("write")
+    }
+    async fn create(
+        &mut self,
+        _parent: u64,
+        _name: std::ffi::OsString,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+    ) -> Result<(fuser::FileAttr, u64), Self::Error> {
+        read_only("create")
+    }
+    async fn mkdir(
+        &mut self,
+        _parent: u64,
+        _name: std::ffi::OsString,
+    ) -> Result<fuser::FileAttr, Self::Error> {
+        read_only("mkdir")
+    }
+}
+
+#[async_trait::async_trait]
+impl<
+        T: crate::AsyncSeekBufRead,
+        R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync + 'static,
+    > FuseFilesystem for SquashFs<R>
+{
+    async fn open_reply_flags(&self, ino: u64, flags: i32) -> Result<u32, Error> {
+        SquashFs::open_reply_flags(self, ino, flags).await
+    }
+    async fn access(&self, ino: u64, uid: u32, gid: u32, mask: i32) -> Result<(), Error> {
+        SquashFs::access(self, ino, uid, gid, mask).await
+    }
+    async fn statfs(&self, _ino: u64) -> Result<Statfs, Error> {
+        Ok(SquashFs::statfs(self))
+    }
+    async fn readlink(&self, ino: u64) -> Result<std::path::PathBuf, Error> {
+        SquashFs::readlink(self, ino).await
+    }
+    async fn opendir(&self, ino: u64) -> Result<u64, Error> {
+        SquashFs::opendir(self, ino).await
+    }
+    async fn readdir_handle(
+        &self,
+        _ino: u64,
+        fh: u64,
+        offset: u64,
+    ) -> Result<Box<dyn Iterator<Item = (u64, DirEntry)> + Send + Sync + '_>, Error> {
+        SquashFs::readdir_handle(self, fh, offset).await
+    }
+    async fn releasedir(&self, _ino: u64, fh: u64) -> Result<(), Error> {
+        SquashFs::releasedir(self, fh).await
+    }
+    async fn setattr(&self, _ino: u64, _size: Option<u64>) -> Result<fuser::FileAttr, Error> {
+        read_only("setattr")
+    }
+    async fn create(
+        &self,
+        _parent: u64,
+        _name: std::ffi::OsString,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+    ) -> Result<(fuser::FileAttr, u64), Error> {
+        read_only("create")
+    }
+    async fn mkdir(
+        &self,
+        _parent: u64,
+        _name: std::ffi::OsString,
+    ) -> Result<fuser::FileAttr, Error> {
+        read_only("mkdir")
+    }
+    async fn unlink(&self, parent: u64, name: &std::ffi::OsStr) -> Result<(), Error> {
+        SquashFs::unlink(self, parent, name).await
+    }
+    async fn rmdir(&self, parent: u64, name: &std::ffi::OsStr) -> Result<(), Error> {
+        SquashFs::rmdir(self, parent, name).await
+    }
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        new_parent: u64,
+        new_name: &std::ffi::OsStr,
+    ) -> Result<(), Error> {
+        SquashFs::rename(self, parent, name, new_parent, new_name).await
+    }
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        target: &std::path::Path,
+    ) -> Result<fuser::FileAttr, Error> {
+        SquashFs::symlink(self, parent, name, target).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<
+        T: crate::AsyncSeekBufRead,
+        R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync + 'static,
+    > FuseFilesystem for SharedSquashFs<R>
+{
+    async fn open_reply_flags(&self, ino: u64, flags: i32) -> Result<u32, Error> {
+        self.0.open_reply_flags(ino, flags).await
+    }
+    async fn access(&self, ino: u64, uid: u32, gid: u32, mask: i32) -> Result<(), Error> {
+        self.0.access(ino, uid, gid, mask).await
+    }
+    async fn statfs(&self, _ino: u64) -> Result<Statfs, Error> {
+        Ok(self.0.statfs())
+    }
+    async fn readlink(&self, ino: u64) -> Result<std::path::PathBuf, Error> {
+        self.0.readlink(ino).await
+    }
+    async fn opendir(&self, ino: u64) -> Result<u64, Error> {
+        self.0.opendir(ino).await
+    }
+    async fn readdir_handle(
+        &self,
+        _ino: u64,
+        fh: u64,
+        offset: u64,
+    ) -> Result<Box<dyn Iterator<Item = (u64, DirEntry)> + Send + Sync + '_>, Error> {
+        self.0.readdir_handle(fh, offset).await
+    }
+    async fn releasedir(&self, _ino: u64, fh: u64) -> Result<(), Error> {
+        self.0.releasedir(fh).await
+    }
+    async fn setattr(&self, _ino: u64, _size: Option<u64>) -> Result<fuser::FileAttr, Error> {
+        read_only("setattr")
+    }
+    async fn create(
+        &self,
+        _parent: u64,
+        _name: std::ffi::OsString,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+    ) -> Result<(fuser::FileAttr, u64), Error> {
+        read_only("create")
+    }
+    async fn mkdir(
+        &self,
+        _parent: u64,
+        _name: std::ffi::OsString,
+    ) -> Result<fuser::FileAttr, Error> {
+        read_only("mkdir")
+    }
+    async fn unlink(&self, parent: u64, name: &std::ffi::OsStr) -> Result<(), Error> {
+        self.0.unlink(parent, name).await
+    }
+    async fn rmdir(&self, parent: u64, name: &std::ffi::OsStr) -> Result<(), Error> {
+        self.0.rmdir(parent, name).await
+    }
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        new_parent: u64,
+        new_name: &std::ffi::OsStr,
+    ) -> Result<(), Error> {
+        self.0.rename(parent, name, new_parent, new_name).await
+    }
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        target: &std::path::Path,
+    ) -> Result<fuser::FileAttr, Error> {
+        self.0.symlink(parent, name, target).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn permitted_test() {
+        let attr = FileAttrLite {
+            inode: 1,
+            size: 0,
+            kind: FileKind::File,
+            mode: 0o640,
+            uid: 1000,
+            gid: 100,
+            mtime: std::time::UNIX_EPOCH,
+            crtime: std::time::UNIX_EPOCH,
+            nlink: 1,
+        };
+        // Owner, group and others
+        assert!(permitted(&attr, 1000, 1, libc::R_OK));
+        assert!(!permitted(&attr, 1000, 1, libc::X_OK));
+        assert!(permitted(&attr, 1001, 100, libc::R_OK));
+        assert!(!permitted(&attr, 1001, 1, libc::R_OK));
+        assert!(permitted(&attr, 1001, 1, libc::F_OK));
+        // Owner bits take precedence over the group ones
+        let attr = FileAttrLite {
+            mode: 0o070,
+            ..attr
+        };
+        assert!(!permitted(&attr, 1000, 100, libc::R_OK));
+        // Root
+        assert!(permitted(&attr, 0, 0, libc::R_OK));
+        assert!(permitted(&attr, 0, 0, libc::X_OK));
+        let attr = FileAttrLite {
+            mode: 0o644,
+            ..attr
+        };
+        assert!(!permitted(&attr, 0, 0, libc::X_OK));
+    }
+    #[test]
+    fn attr_overrides_test() {
+        let attr = FileAttrLite {
+            inode: 1,
+            size: 0,
+            kind: FileKind::Directory,
+            mode: 0o700,
+            uid: 0,
+            gid: 0,
+            mtime: std::time::UNIX_EPOCH,
+            crtime: std::time::UNIX_EPOCH,
+            nlink: 2,
+        };
+        assert_eq!(AttrOverrides::default().apply(attr), attr);
+        let overrides = AttrOverrides {
+            uid: Some(1000),
+            file_mode: Some(0o644),
+            ..Default::default()
+        };
+        let dir = overrides.apply(attr);
+        assert_eq!((dir.uid, dir.gid, dir.mode), (1000, 0, 0o700));
+        let file = overrides.apply(FileAttrLite {
+            kind: FileKind::File,
+            ..attr
+        });
+        assert_eq!((file.uid, file.mode), (1000, 0o644));
+        // Masking, then opening to others
+        let overrides = AttrOverrides {
+            umask: Some(0o022),
+            other_readable: true,
+            ..Default::default()
+        };
+        let attr = FileAttrLite {
+            mode: 0o777,
+            ..attr
+        };
+        assert_eq!(overrides.apply(attr).mode, 0o755);
+        let attr = FileAttrLite {
+            mode: 0o700,
+            ..attr
+        };
+        assert_eq!(overrides.apply(attr).mode, 0o705);
+        let file = FileAttrLite {
+            kind: FileKind::File,
+            mode: 0o600,
+            ..attr
+        };
+        assert_eq!(overrides.apply(file).mode, 0o604);
+    }
+    #[test]
+    fn drop_behind_test() {
+        let handle = FileHandle {
+            inode: 1,
+            flags: 0,
+            bytes_served: Default::default(),
+            opened_at: std::time::UNIX_EPOCH,
+            readahead: Default::default(),
+            bitrate: Default::default(),
+            inflight: None,
+        };
+        // Sequential reads, up to the limit
+        assert!(handle.advance(0, 100, Some(250)).retain);
+        assert!(handle.advance(100, 100, Some(250)).retain);
+        assert!(!handle.advance(200, 100, Some(250)).retain);
+        // A seek starts a new run
+        assert!(handle.advance(0, 100, Some(250)).retain);
+        assert_eq!(handle.readahead.lock().unwrap().window, 100);
+        assert!(handle.advance(100, 1000, None).retain);
+    }
+    #[test]
+    fn read_ahead_test() {
+        let handle = FileHandle {
+            inode: 1,
+            flags: 0,
+            bytes_served: Default::default(),
+            opened_at: std::time::UNIX_EPOCH,
+            readahead: Default::default(),
+            bitrate: Default::default(),
+            inflight: None,
+        };
+        handle.advance(0, 100, None);
+        assert_eq!(handle.read_ahead(100, 1000), None);
+        // Sequential run, only reading ahead what was not yet
+        handle.advance(100, 100, None);
+        assert_eq!(handle.read_ahead(100, 1000), Some(200..1200));
+        handle.advance(200, 100, None);
+        assert_eq!(handle.read_ahead(100, 1000), Some(1200..1300));
+        assert_eq!(handle.read_ahead(100, 0), None);
+        // A seek starts a new run
+        handle.advance(5000, 100, None);
+        assert_eq!(handle.read_ahead(100, 1000), None);
+        handle.advance(5100, 100, None);
+        assert_eq!(handle.read_ahead(100, 1000), Some(5200..6200));
+    }
+    #[test]
+    fn deadline_test() {
+        let handle = FileHandle {
+            inode: 1,
+            flags: 0,
+            bytes_served: Default::default(),
+            opened_at: std::time::UNIX_EPOCH,
+            readahead: Default::default(),
+            bitrate: AtomicU64::new(1000),
+            inflight: None,
+        };
+        let start = std::time::Instant::now();
+        let first = handle.advance(0, 100, None).deadline.unwrap();
+        let second = handle.advance(100, 100, None).deadline.unwrap();
+        assert!(first >= start && first <= std::time::Instant::now());
+        assert_eq!(second - first, std::time::Duration::from_millis(100));
+        // A seek restarts the stream
+        let seek = handle.advance(10_000, 100, None).deadline.unwrap();
+        assert!(seek <= std::time::Instant::now());
+        handle.bitrate.store(0, Ordering::Relaxed);
+        assert!(handle.advance(10_100, 100, None).deadline.is_none());
+    }
+    #[test]
+    fn page_cache_test() {
+        let policy = OptionsPageCachePolicy {
+            page_cache: PageCache::Auto,
+            direct_io_above: 100,
+        };
+        let attr = |size| FileAttrLite {
+            inode: 1,
+            size,
+            kind: FileKind::File,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            mtime: std::time::UNIX_EPOCH,
+            crtime: std::time::UNIX_EPOCH,
+            nlink: 1,
+        };
+        assert_eq!(policy.page_cache(&attr(100), 0), PageCache::KeepCache);
+        assert_eq!(policy.page_cache(&attr(101), 0), PageCache::DirectIo);
+        assert_eq!(
+            policy.page_cache(&attr(1), libc::O_DIRECT),
+            PageCache::DirectIo
+        );
+        let policy = OptionsPageCachePolicy {
+            page_cache: PageCache::KeepCache,
+            ..policy
+        };
+        assert_eq!(policy.page_cache(&attr(101), 0), PageCache::KeepCache);
     }
 }
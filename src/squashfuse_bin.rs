@@ -1,74 +1,847 @@
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
 
-use clap::Parser;
-use fuser_async::{FilesystemFUSE, FilesystemSSUS};
+use clap::{CommandFactory, Parser};
 use tracing::*;
 
-use squashfs_async::{pools::LocalBackend, Options, SquashFs};
+use futures::{StreamExt, TryStreamExt};
+use squashfs_async::content_cache::ContentCache;
+use squashfs_async::export::{DuplicatePolicy, ExtractOptions};
+use squashfs_async::fuse::{FuseAdapter, FuseFilesystem};
+use squashfs_async::search::NamePattern;
+use squashfs_async::{
+    pools::LocalBackend, supervisor::Supervisor, Options, SharedSquashFs, SquashFs,
+};
 
 #[derive(Parser)]
-#[clap(name = "squashfuse-rs")]
+#[clap(
+    name = "squashfuse-rs",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Flags {
     /// Input squashfs image
-    input: PathBuf,
+    #[clap(required = true)]
+    input: Option<PathBuf>,
     /// Mountpoint
-    mountpoint: PathBuf,
+    #[clap(required = true)]
+    mountpoint: Option<PathBuf>,
+    /// Log a summary of the activity every this many seconds: reads/s, MB/s, hit rate of the
+    /// cache of data blocks, and 99th percentile of the waits for a reader
+    #[clap(long, value_name = "SECS")]
+    stats_interval: Option<u64>,
+    /// Load the files matching this pattern (e.g. `bin/**`, relative to the root of the image)
+    /// into the cache in the background after mounting, so that the first run of an application
+    /// stored in the image does not wait for cold reads. Can be repeated
+    #[clap(long, value_name = "GLOB")]
+    prefetch: Vec<glob::Pattern>,
+    /// Write the man pages of the command and of its subcommands (e.g.
+    /// `squashfuse-rs-extract.1`) to this directory and exit
+    #[clap(long, exclusive = true, value_name = "DIR")]
+    generate_man: Option<PathBuf>,
+    #[clap(flatten)]
+    common: CommonFlags,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Args)]
+struct CommonFlags {
     #[clap(flatten)]
     options: Options,
     #[clap(long, arg_enum, default_value_t = if cfg!(feature="memmap") {LocalBackend::MemMap} else { LocalBackend::Tokio })]
     backend: LocalBackend,
     #[clap(long, short)]
     debug: bool,
+    /// Number of worker threads serving the FUSE requests, by default the number of cores
+    ///
+    /// The kernel requests are read by a single session thread, and then processed concurrently
+    /// on the workers.
+    #[clap(long)]
+    threads: Option<usize>,
+    /// On failure, print a JSON object with the exit code, its kind and the error on stderr,
+    /// rather than logging the error
+    #[clap(long)]
+    errors_json: bool,
+}
+impl Flags {
+    fn common(&self) -> &CommonFlags {
+        match &self.command {
+            None | Some(Command::Completions(_)) => &self.common,
+            Some(Command::Replay(args)) => &args.common,
+            Some(Command::Verify(args)) => &args.common,
+            Some(Command::Checksum(args)) => match &args.command {
+                ChecksumCommand::Generate(args) => &args.common,
+            },
+            Some(Command::Supervise(args)) => &args.common,
+            Some(Command::Serve(args)) => &args.common,
+            Some(Command::Du(args)) => &args.common,
+            Some(Command::Find(args)) => &args.common,
+            Some(Command::List(args)) => &args.common,
+            Some(Command::Export(args)) => &args.common,
+            Some(Command::Extract(args)) => &args.common,
+            Some(Command::Grep(args)) => &args.common,
+            Some(Command::Analyze(args)) => &args.common,
+            Some(Command::Advise(args)) => &args.common,
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Replay a trace recorded with --trace-accesses against an image, and report the read
+    /// latencies
+    Replay(ReplayFlags),
+    /// Check that all files of images are read correctly
+    Verify(VerifyFlags),
+    /// Manage the sidecar files of digests of the data blocks, see --block-checksums
+    Checksum(ChecksumFlags),
+    /// Serve the images of a directory, each under a subdirectory of the mountpoint, opening them
+    /// on first access and closing idle ones
+    Supervise(SuperviseFlags),
+    /// Serve the contents of an image over HTTP, without FUSE
+    ///
+    /// There is no NFS mode: NFS clients need stable file handles and a portmapper/mountd setup,
+    /// which are out of the scope of this crate.
+    Serve(ServeFlags),
+    /// Print the apparent and on-disk (compressed) sizes of paths in an image, in bytes
+    Du(DuFlags),
+    /// Print the paths of the files and directories of an image whose name contains a pattern
+    Find(FindFlags),
+    /// Print the files and directories of an image, as paths or as JSON objects with their
+    /// attributes
+    List(ListFlags),
+    /// Write a file of an image to the disk, keeping its holes, e.g. for disk images
+    Export(ExportFlags),
+    /// Extract whole images to a directory, recreating their hard links and symlinks
+    Extract(ExtractFlags),
+    /// Print the lines of the files of an image matching a regular expression
+    Grep(GrepFlags),
+    /// Print statistics on the files of an image (sizes by extension, largest files and
+    /// directories, compression, fragments), to help choosing the options of mksquashfs
+    Analyze(AnalyzeFlags),
+    /// Simulate the number of blocks and fragments, the size of the metadata and the size of the
+    /// data of an image built from the same files with other block sizes and compressions
+    Advise(AdviseFlags),
+    /// Print the completions script for a shell
+    Completions(CompletionsFlags),
+}
+
+#[derive(clap::Args)]
+struct CompletionsFlags {
+    #[clap(arg_enum)]
+    shell: clap_complete::Shell,
+}
+
+#[derive(clap::Args)]
+struct ReplayFlags {
+    /// Input squashfs image
+    input: PathBuf,
+    /// Trace recorded with --trace-accesses
+    trace: PathBuf,
+    /// Issue the reads at their recorded times, rather than one after the other
+    #[clap(long)]
+    preserve_timing: bool,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
+
+#[derive(clap::Args)]
+struct VerifyFlags {
+    /// Input squashfs images
+    #[clap(required = true)]
+    input: Vec<PathBuf>,
+    /// Extract the image with `unsquashfs` and compare every file with the extraction
+    #[clap(long)]
+    against_unsquashfs: bool,
+    /// Number of images verified concurrently, each with its own readers (see --readers)
+    #[clap(long, default_value_t = 1)]
+    jobs: usize,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
+
+#[derive(clap::Args)]
+struct ChecksumFlags {
+    #[clap(subcommand)]
+    command: ChecksumCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum ChecksumCommand {
+    /// Write the digests of all the data blocks of an image to a sidecar file
+    Generate(ChecksumGenerateFlags),
+}
+
+#[derive(clap::Args)]
+struct ChecksumGenerateFlags {
+    /// Input squashfs image
+    input: PathBuf,
+    /// Sidecar file, by default the image path with a `.sums` suffix
+    #[clap(long)]
+    output: Option<PathBuf>,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
+
+#[derive(clap::Args)]
+struct SuperviseFlags {
+    /// Directory of `.squashfs`/`.sqfs` images
+    images: PathBuf,
+    /// Mountpoint
+    mountpoint: PathBuf,
+    /// Duration (s) without access after which an image without open files is closed
+    #[clap(long, default_value_t = 300)]
+    idle_secs: u64,
+    /// Size (MB) of a cache of decoded blocks shared between the images, addressed by their
+    /// contents, so that blocks common to several images are decoded and stored once
+    #[clap(long)]
+    content_cache_mb: Option<u64>,
+    /// Block size of the images sharing the content cache
+    #[clap(long, default_value_t = 131072)]
+    content_cache_block_size: u32,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
+
+/// Exit codes, stable so that wrappers can tell failures apart without parsing the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExitCode {
+    /// Any other failure, e.g. a missing image
+    Failure = 1,
+    /// Invalid command line options (also used by clap for usage errors)
+    Options = 2,
+    /// Corrupted, truncated or otherwise invalid image
+    BadImage = 3,
+    /// Image using an unsupported compression algorithm or filter
+    UnsupportedCompression = 4,
+    /// The filesystem could not be mounted, e.g. when FUSE is unavailable
+    Mount = 5,
+}
+impl ExitCode {
+    fn of(error: &anyhow::Error) -> Self {
+        use squashfs_async::error::DecompressError;
+        use squashfs_async::Error;
+        for cause in error.chain() {
+            if cause.is::<MountError>() {
+                return Self::Mount;
+            }
+            let Some(error) = cause.downcast_ref::<Error>() else {
+                continue;
+            };
+            return match error {
+                Error::Decompress(
+                    DecompressError::UnsupportedCompression(_)
+                    | DecompressError::UnsupportedXzFilters(_),
+                ) => Self::UnsupportedCompression,
+                Error::InvalidOptions(_) => Self::Options,
+                Error::InvalidSuperblock
+                | Error::SuperblockDecode(_)
+                | Error::Truncated { .. }
+                | Error::OutOfBounds { .. }
+                | Error::DigestMismatch { .. }
+                | Error::BlockChecksum { .. }
+                | Error::Fragments(_)
+                | Error::IdTable(_)
+                | Error::InodeTable(_)
+                | Error::DirectoryTable(_)
+                | Error::Metadata(_)
+                | Error::Decompress(_) => Self::BadImage,
+                _ => Self::Failure,
+            };
+        }
+        Self::Failure
+    }
+    fn name(self) -> &'static str {
+        match self {
+            Self::Failure => "failure",
+            Self::Options => "options",
+            Self::BadImage => "bad_image",
+            Self::UnsupportedCompression => "unsupported_compression",
+            Self::Mount => "mount",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to mount: {0}")]
+struct MountError(#[source] std::io::Error);
+
+/// Report `error` and exit with its [`ExitCode`].
+fn exit_with(error: &anyhow::Error, json: bool) -> ! {
+    let code = ExitCode::of(error);
+    if json {
+        let report = serde_json::json!({
+            "code": code as i32,
+            "kind": code.name(),
+            "error": error.to_string(),
+        });
+        eprintln!("{}", report);
+    } else {
+        error!("{:?}", error);
+    }
+    process::exit(code as i32)
+}
+
+#[derive(clap::Args)]
+struct ServeFlags {
+    /// Input squashfs image
+    input: PathBuf,
+    /// Address to listen on for HTTP requests
+    #[clap(long, value_name = "ADDR")]
+    http: std::net::SocketAddr,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
+
+#[derive(clap::Args)]
+struct DuFlags {
+    /// Input squashfs image
+    input: PathBuf,
+    /// Paths in the image
+    #[clap(default_value = "/")]
+    paths: Vec<PathBuf>,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
+
+#[derive(clap::Args)]
+struct FindFlags {
+    /// Input squashfs image
+    input: PathBuf,
+    /// Substring of the names
+    pattern: String,
+    /// Interpret the pattern as a regular expression
+    #[clap(long)]
+    regex: bool,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
+
+#[derive(clap::Args)]
+struct ListFlags {
+    /// Input squashfs image
+    input: PathBuf,
+    #[clap(long, arg_enum, default_value = "text")]
+    format: squashfs_async::export::TreeFormat,
+    #[clap(flatten)]
+    common: CommonFlags,
 }
 
-async fn mount<F: FilesystemSSUS + Send + Sync>(fs: F, mountpoint: &Path) -> anyhow::Result<()>
-where
-    F::Error: std::fmt::Display,
-{
-    let fuse = FilesystemFUSE::new(fs);
+#[derive(clap::Args)]
+struct ExportFlags {
+    /// Input squashfs image
+    input: PathBuf,
+    /// Path of the file in the image
+    #[clap(long)]
+    file: PathBuf,
+    /// Destination, which is created or truncated
+    #[clap(long)]
+    output: PathBuf,
+    /// Write the destination with O_DIRECT, bypassing the page cache
+    #[clap(long)]
+    direct: bool,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
+
+#[derive(clap::Args)]
+struct ExtractFlags {
+    /// Input squashfs images
+    #[clap(required = true)]
+    input: Vec<PathBuf>,
+    /// Destination directory, which is created if needed. With several images, each is extracted
+    /// to a subdirectory named after the image file, without its extension
+    output: PathBuf,
+    /// How to write the files with the same contents as a file already extracted
+    #[clap(long, arg_enum, default_value_t = DuplicatePolicy::Copy)]
+    duplicates: DuplicatePolicy,
+    /// Write the files with O_DIRECT, bypassing the page cache
+    #[clap(long)]
+    direct: bool,
+    /// Record the progress in this file, and resume from it if it exists. Only with a single
+    /// image
+    #[clap(long, value_name = "FILE")]
+    state: Option<PathBuf>,
+    /// Number of images extracted concurrently, each with its own readers (see --readers)
+    #[clap(long, default_value_t = 1)]
+    jobs: usize,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
 
-    let _mount = fuser::spawn_mount2(
-        fuse,
-        mountpoint,
-        &[fuser::MountOption::RO, fuser::MountOption::Async],
-    )?;
+#[derive(clap::Args)]
+struct GrepFlags {
+    /// Input squashfs image
+    input: PathBuf,
+    /// Regular expression
+    pattern: String,
+    /// Only search the files whose path contains this string
+    #[clap(long)]
+    path: Option<String>,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
+
+#[derive(clap::Args)]
+struct AnalyzeFlags {
+    /// Input squashfs image
+    input: PathBuf,
+    /// Number of largest files and directories to report
+    #[clap(long, default_value_t = 10)]
+    largest: usize,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
+
+#[derive(clap::Args)]
+struct AdviseFlags {
+    /// Input squashfs image
+    input: PathBuf,
+    /// Block sizes to simulate (`mksquashfs -b`), e.g. `128K`
+    #[clap(
+        long,
+        value_parser = squashfs_async::utils::parse_block_size,
+        use_value_delimiter = true,
+        default_value = "64K,128K,256K,1M"
+    )]
+    block_size: Vec<u32>,
+    /// Compressions to simulate (`mksquashfs -comp`, only gzip, xz and zstd), by default the one
+    /// of the image
+    #[clap(long, use_value_delimiter = true)]
+    compression: Vec<squashfs_async::Compression>,
+    /// Size of the sample of the files used to estimate the compressed size of the data (MB), 0
+    /// to only simulate the layout
+    #[clap(long, default_value_t = 64)]
+    sample_mb: u64,
+    #[clap(flatten)]
+    common: CommonFlags,
+}
+
+async fn mount<F: FuseFilesystem>(
+    fs: F,
+    mountpoint: &Path,
+    options: &Options,
+) -> anyhow::Result<()> {
+    let fuse = FuseAdapter::new(fs);
+
+    let mut mount_options = vec![fuser::MountOption::RO, fuser::MountOption::Async];
+    if options.default_permissions {
+        mount_options.push(fuser::MountOption::DefaultPermissions);
+    }
+    let _mount = fuser::spawn_mount2(fuse, mountpoint, &mount_options).map_err(MountError)?;
     tokio::signal::ctrl_c().await?;
     Ok(())
 }
+/// Write the man pages of `command` and of its subcommands (e.g. `squashfuse-rs-extract.1`) to
+/// `dir`.
+fn generate_man(command: clap::Command, dir: &Path) -> anyhow::Result<()> {
+    for subcommand in command.get_subcommands() {
+        let name = format!("{}-{}", command.get_name(), subcommand.get_name());
+        generate_man(subcommand.clone().name(name), dir)?;
+    }
+    let mut page = std::fs::File::create(dir.join(format!("{}.1", command.get_name())))?;
+    clap_mangen::Man::new(command).render(&mut page)?;
+    Ok(())
+}
+
+/// Run `$body` with `$t` set to the readers pool type for the selected backend.
 macro_rules! backend_variant {
-    ($t:path, $args:ident) => {
-        mount(
-            SquashFs::<$t>::open(&$args.input, &$args.options).await?,
-            &$args.mountpoint,
-        )
-        .await?
-    };
+    ($backend:expr, $t:ident, $body:expr) => {{
+        match $backend {
+            LocalBackend::Tokio => {
+                type $t = squashfs_async::pools::LocalReadersPoolTokio;
+                $body
+            }
+            #[cfg(feature = "asyncfs")]
+            LocalBackend::AsyncFs => {
+                type $t = squashfs_async::pools::LocalReadersPoolAsyncFs;
+                $body
+            }
+            #[cfg(feature = "memmap")]
+            LocalBackend::MemMap => {
+                type $t = squashfs_async::pools::LocalReadersPoolMemMap;
+                $body
+            }
+        }
+    }};
 }
 
 async fn main_impl(args: Flags) -> anyhow::Result<()> {
-    squashfs_async::utils::setup_logger(args.debug)?;
-    info!("Mounting {:?} at {:?}", args.input, args.mountpoint);
-    match args.backend {
-        LocalBackend::Tokio => backend_variant!(squashfs_async::pools::LocalReadersPoolTokio, args),
-        #[cfg(feature = "asyncfs")]
-        LocalBackend::AsyncFs => {
-            backend_variant!(squashfs_async::pools::LocalReadersPoolAsyncFs, args)
-        }
-        #[cfg(feature = "memmap")]
-        LocalBackend::MemMap => {
-            backend_variant!(squashfs_async::pools::LocalReadersPoolMemMap, args)
+    match args.command {
+        None if args.generate_man.is_some() => {
+            let dir = args.generate_man.unwrap();
+            std::fs::create_dir_all(&dir)?;
+            generate_man(Flags::command(), &dir)?;
+        }
+        None => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            // Both are required without subcommand
+            let (input, mountpoint) = (args.input.unwrap(), args.mountpoint.unwrap());
+            if args.stats_interval == Some(0) {
+                return Err(squashfs_async::Error::InvalidOptions(
+                    "The stats interval must be >=1",
+                )
+                .into());
+            }
+            info!("Mounting {:?} at {:?}", input, mountpoint);
+            backend_variant!(common.backend, Pool, {
+                let fs = Arc::new(SquashFs::<Pool>::open(&input, &common.options).await?);
+                info!("Estimated memory usage: {}", fs.memory_usage());
+                let reaper =
+                    squashfs_async::utils::spawn_named("readers_reaper", fs.readers_reaper());
+                let stats = args.stats_interval.map(|secs| {
+                    let interval = std::time::Duration::from_secs(secs);
+                    squashfs_async::utils::spawn_named("stats_logger", fs.stats_logger(interval))
+                });
+                let prefetch = squashfs_async::utils::spawn_named("prefetch", {
+                    let fs = fs.clone();
+                    async move {
+                        for pattern in &args.prefetch {
+                            match fs.prefetch_matching(pattern).await {
+                                Ok(bytes) => info!("Prefetched {} B matching {}", bytes, pattern),
+                                Err(e) => warn!("Failed to prefetch {}: {}", pattern, e),
+                            }
+                        }
+                    }
+                });
+                mount(SharedSquashFs(fs), &mountpoint, &common.options).await?;
+                reaper.abort();
+                prefetch.abort();
+                if let Some(stats) = stats {
+                    stats.abort();
+                }
+            })
+        }
+        Some(Command::Replay(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            let trace = squashfs_async::trace::read_trace(&args.trace)?;
+            info!("Replaying {} reads on {:?}", trace.len(), args.input);
+            backend_variant!(common.backend, Pool, {
+                let fs = SquashFs::<Pool>::open(&args.input, &common.options).await?;
+                print!("{}", fs.replay(&trace, args.preserve_timing).await);
+            })
+        }
+        Some(Command::Verify(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            anyhow::ensure!(
+                args.against_unsquashfs,
+                "Only verification against unsquashfs (--against-unsquashfs) is supported"
+            );
+            if args.jobs == 0 {
+                return Err(squashfs_async::Error::InvalidOptions(
+                    "The number of jobs must be >=1",
+                )
+                .into());
+            }
+            let (backend, options) = (&common.backend, &common.options);
+            let verify = |i: usize, input: PathBuf| async move {
+                let reference = std::env::temp_dir().join(format!(
+                    "squashfuse-rs-verify-{}-{}",
+                    std::process::id(),
+                    i
+                ));
+                info!("Extracting {:?} to {:?} with unsquashfs", input, reference);
+                let extraction = {
+                    let (input, reference) = (input.clone(), reference.clone());
+                    tokio::task::spawn_blocking(move || {
+                        squashfs_async::verify::unsquashfs(&input, &reference)
+                    })
+                    .await?
+                };
+                let verification = match extraction {
+                    Ok(()) => backend_variant!(backend, Pool, {
+                        match SquashFs::<Pool>::open(&input, options).await {
+                            Ok(fs) => fs.compare_with_dir(&reference).await,
+                            Err(e) => Err(e),
+                        }
+                    }),
+                    Err(e) => Err(e),
+                };
+                if reference.exists() {
+                    std::fs::remove_dir_all(&reference)?;
+                }
+                anyhow::Ok((input, verification))
+            };
+            // Reported in the order of the inputs
+            let results: Vec<_> = futures::stream::iter(args.input.into_iter().enumerate())
+                .map(|(i, input)| verify(i, input))
+                .buffered(args.jobs)
+                .try_collect()
+                .await?;
+            let (n_images, single) = (results.len(), results.len() == 1);
+            let (mut failure, mut divergent) = (None, 0);
+            for (input, verification) in results {
+                match verification {
+                    Ok(verification) => {
+                        if verification.divergence.is_some() {
+                            divergent += 1;
+                        }
+                        if single {
+                            println!("{}", verification);
+                        } else {
+                            println!("{}: {}", input.display(), verification);
+                        }
+                    }
+                    Err(e) => {
+                        if !single {
+                            println!("{}: {}", input.display(), e);
+                        }
+                        failure.get_or_insert(e);
+                    }
+                }
+            }
+            // Errors take precedence, for their exit code
+            if let Some(e) = failure {
+                return Err(e.into());
+            }
+            anyhow::ensure!(
+                divergent == 0,
+                "Verification failed for {} of {} images",
+                divergent,
+                n_images
+            );
+        }
+        Some(Command::Checksum(ChecksumFlags {
+            command: ChecksumCommand::Generate(args),
+        })) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            let output = args
+                .output
+                .unwrap_or_else(|| squashfs_async::checksums::sidecar_path(&args.input));
+            backend_variant!(common.backend, Pool, {
+                let fs = SquashFs::<Pool>::open(&args.input, &common.options).await?;
+                let checksums = fs.block_checksums().await?;
+                let file = std::fs::File::create(&output)?;
+                checksums.write_to(std::io::BufWriter::new(file))?;
+                info!(
+                    "Wrote the digests of {} blocks to {:?}",
+                    checksums.len(),
+                    output
+                );
+            })
+        }
+        Some(Command::Supervise(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            info!("Serving {:?} at {:?}", args.images, args.mountpoint);
+            backend_variant!(common.backend, Pool, {
+                let mut fs = Supervisor::<Pool>::new(
+                    &args.images,
+                    &common.options,
+                    std::time::Duration::from_secs(args.idle_secs),
+                )
+                .await?;
+                if let Some(mb) = args.content_cache_mb {
+                    let cache = ContentCache::new(mb, args.content_cache_block_size)?;
+                    fs = fs.with_content_cache(Arc::new(cache));
+                }
+                let reaper = squashfs_async::utils::spawn_named("reaper", fs.reaper());
+                mount(fs, &args.mountpoint, &common.options).await?;
+                reaper.abort();
+            })
+        }
+        Some(Command::Serve(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            let listener = tokio::net::TcpListener::bind(args.http).await?;
+            info!("Serving {:?} on http://{}", args.input, args.http);
+            backend_variant!(common.backend, Pool, {
+                let fs = Arc::new(SquashFs::<Pool>::open(&args.input, &common.options).await?);
+                let reaper =
+                    squashfs_async::utils::spawn_named("readers_reaper", fs.readers_reaper());
+                tokio::select! {
+                    served = squashfs_async::http::serve(fs, listener) => served?,
+                    interrupted = tokio::signal::ctrl_c() => interrupted?,
+                }
+                reaper.abort();
+            })
+        }
+        Some(Command::Du(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            backend_variant!(common.backend, Pool, {
+                let fs = SquashFs::<Pool>::open(&args.input, &common.options).await?;
+                for path in &args.paths {
+                    let usage = fs.disk_usage(path).await?;
+                    println!("{}\t{}\t{}", usage.apparent, usage.on_disk, path.display());
+                }
+            })
+        }
+        Some(Command::Find(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            let pattern: NamePattern = if args.regex {
+                regex::Regex::new(&args.pattern)?.into()
+            } else {
+                args.pattern.as_str().into()
+            };
+            backend_variant!(common.backend, Pool, {
+                let fs = SquashFs::<Pool>::open(&args.input, &common.options).await?;
+                let mut paths = std::pin::pin!(fs.find_names(pattern));
+                while let Some(path) = paths.try_next().await? {
+                    println!("/{}", path.display());
+                }
+            })
+        }
+        Some(Command::List(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            backend_variant!(common.backend, Pool, {
+                let fs = SquashFs::<Pool>::open(&args.input, &common.options).await?;
+                fs.export_tree(tokio::io::stdout(), args.format).await?;
+            })
+        }
+        Some(Command::Export(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            backend_variant!(common.backend, Pool, {
+                let fs = SquashFs::<Pool>::open(&args.input, &common.options).await?;
+                let inode = fs.resolve(&args.file).await?;
+                let written = fs.export_file(inode, &args.output, args.direct).await?;
+                info!("Wrote {} bytes to {:?}", written, args.output);
+            })
+        }
+        Some(Command::Extract(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            if args.jobs == 0 {
+                return Err(squashfs_async::Error::InvalidOptions(
+                    "The number of jobs must be >=1",
+                )
+                .into());
+            }
+            let single = args.input.len() == 1;
+            if !single && args.state.is_some() {
+                return Err(squashfs_async::Error::InvalidOptions(
+                    "--state requires a single image",
+                )
+                .into());
+            }
+            let outputs: Vec<PathBuf> = if single {
+                vec![args.output.clone()]
+            } else {
+                args.input
+                    .iter()
+                    .map(|input| {
+                        let stem = input.file_stem().ok_or_else(|| {
+                            anyhow::anyhow!("{:?} is not the path of an image file", input)
+                        })?;
+                        anyhow::Ok(args.output.join(stem))
+                    })
+                    .collect::<Result<_, _>>()?
+            };
+            let unique: std::collections::HashSet<_> = outputs.iter().collect();
+            anyhow::ensure!(
+                unique.len() == outputs.len(),
+                "Several images have the same file name, and would be extracted to the same \
+                 directory"
+            );
+            let options = ExtractOptions {
+                duplicates: args.duplicates,
+                direct: args.direct,
+                state: args.state,
+            };
+            let (backend, open_options, options) = (&common.backend, &common.options, &options);
+            let extract = |input: PathBuf, output: PathBuf| async move {
+                backend_variant!(backend, Pool, {
+                    let fs = SquashFs::<Pool>::open(&input, open_options).await?;
+                    let stats = fs.extract_to(&output, options.clone()).await?;
+                    info!(
+                        "Extracted {} files ({} bytes) and {} directories from {:?} to {:?}, \
+                         with {} hard links and {} clones, {} symlinks, {} files being already \
+                         extracted",
+                        stats.files,
+                        stats.bytes,
+                        stats.directories,
+                        input,
+                        output,
+                        stats.hard_links,
+                        stats.reflinks,
+                        stats.symlinks,
+                        stats.resumed
+                    );
+                });
+                anyhow::Ok(())
+            };
+            // Stops at the first failing image
+            futures::stream::iter(args.input.into_iter().zip(outputs))
+                .map(|(input, output)| extract(input, output))
+                .buffered(args.jobs)
+                .try_collect::<()>()
+                .await?;
+        }
+        Some(Command::Grep(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            let pattern = regex::Regex::new(&args.pattern)?;
+            let path_filter = args.path.as_deref().map(NamePattern::from);
+            backend_variant!(common.backend, Pool, {
+                let fs = SquashFs::<Pool>::open(&args.input, &common.options).await?;
+                let mut matches = std::pin::pin!(fs.grep(pattern, path_filter));
+                while let Some(m) = matches.try_next().await? {
+                    println!("/{}:{}:{}", m.path.display(), m.line_number, m.line);
+                }
+            })
+        }
+        Some(Command::Analyze(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            backend_variant!(common.backend, Pool, {
+                let fs = SquashFs::<Pool>::open(&args.input, &common.options).await?;
+                println!("{}", fs.analyze(args.largest).await?);
+            })
+        }
+        Some(Command::Advise(args)) => {
+            let common = args.common;
+            squashfs_async::utils::setup_logger(common.debug)?;
+            backend_variant!(common.backend, Pool, {
+                let fs = SquashFs::<Pool>::open(&args.input, &common.options).await?;
+                let compressions = if args.compression.is_empty() {
+                    vec![fs.superblock.compression]
+                } else {
+                    args.compression
+                };
+                let advice = fs
+                    .advise(&args.block_size, &compressions, args.sample_mb * 1_000_000)
+                    .await?;
+                println!("{}", advice);
+            })
+        }
+        Some(Command::Completions(args)) => {
+            let mut command = Flags::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(args.shell, &mut command, name, &mut std::io::stdout());
         }
     }
 
     Ok(())
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let args = Flags::parse();
-    if let Err(e) = main_impl(args).await {
-        error!("{:?}", e);
-        process::exit(1)
+    let json = args.common().errors_json;
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    match args.common().threads {
+        Some(0) => {
+            let error = squashfs_async::Error::InvalidOptions("The number of threads must be >=1");
+            exit_with(&error.into(), json)
+        }
+        Some(threads) => {
+            runtime.worker_threads(threads);
+        }
+        None => {}
+    }
+    let runtime = match runtime.enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => exit_with(&anyhow::anyhow!("Failed to start the runtime: {}", e), json),
+    };
+    if let Err(e) = runtime.block_on(main_impl(args)) {
+        exit_with(&e, json)
     }
 }
@@ -0,0 +1,429 @@
+//! Performance counters: latency distributions of the filesystem operations, see
+//! [`SquashFs::stats`], and periodic summaries of the activity, see [`SquashFs::stats_logger`].
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use crate::SquashFs;
+
+/// Operation whose latency is recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Lookup,
+    Readdir,
+    Read,
+    /// Decompression of a data block, including reading it from a buffered reader when it is
+    /// decompressed as it is read.
+    Decompress,
+}
+
+/// Latency counters, populated by the FUSE operations.
+#[derive(Default)]
+pub struct Stats {
+    latencies: [Mutex<MeanStd>; 4],
+    /// Bytes returned by the reads
+    bytes_read: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_lookups: AtomicU64,
+    /// Time waiting for a reader from the pools
+    pool_wait: Mutex<Histogram>,
+}
+impl Stats {
+    /// Record the bytes returned by a read.
+    pub(crate) fn record_bytes(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+    /// Record a lookup in the cache of data blocks.
+    pub(crate) fn record_cache_lookup(&self, hit: bool) {
+        self.cache_lookups.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    pub(crate) fn record_pool_wait(&self, duration: Duration) {
+        self.pool_wait.lock().unwrap().push(duration);
+    }
+    /// Cumulative counters, see [`Activity`].
+    pub fn counters(&self) -> Counters {
+        Counters {
+            reads: self.latencies[Operation::Read as usize]
+                .lock()
+                .unwrap()
+                .count as u64,
+            bytes: self.bytes_read.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_lookups: self.cache_lookups.load(Ordering::Relaxed),
+            pool_wait: self.pool_wait.lock().unwrap().clone(),
+        }
+    }
+    pub fn record(&self, operation: Operation, duration: Duration) {
+        self.latencies[operation as usize]
+            .lock()
+            .unwrap()
+            .push(duration.as_secs_f64() * 1e3);
+    }
+    /// Run `f`, recording its duration.
+    pub(crate) async fn timed<T>(&self, operation: Operation, f: impl Future<Output = T>) -> T {
+        let start = std::time::Instant::now();
+        let out = f.await;
+        self.record(operation, start.elapsed());
+        out
+    }
+    pub fn snapshot(&self) -> Snapshot {
+        let get = |operation: Operation| self.latencies[operation as usize].lock().unwrap().clone();
+        Snapshot {
+            lookup_ms: get(Operation::Lookup),
+            readdir_ms: get(Operation::Readdir),
+            read_ms: get(Operation::Read),
+            decompress_ms: get(Operation::Decompress),
+            pools: vec![],
+        }
+    }
+}
+
+/// Latencies (ms) of the operations, see [`Stats`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Snapshot {
+    pub lookup_ms: MeanStd,
+    pub readdir_ms: MeanStd,
+    pub read_ms: MeanStd,
+    pub decompress_ms: MeanStd,
+    pub pools: Vec<PoolStatus>,
+}
+
+/// State of a pool of readers.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PoolStatus {
+    /// Open flags of the readers.
+    pub flags: crate::pools::ReadFlags,
+    /// Open readers, in use or not.
+    pub readers: usize,
+    pub idle: usize,
+    /// Time taken to open the readers when opening the image, see
+    /// [`crate::Options::prewarm`].
+    pub warmup_ms: Option<f64>,
+}
+impl std::fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "lookup: {} ms ({})",
+            self.lookup_ms, self.lookup_ms.count
+        )?;
+        writeln!(
+            f,
+            "readdir: {} ms ({})",
+            self.readdir_ms, self.readdir_ms.count
+        )?;
+        writeln!(f, "read: {} ms ({})", self.read_ms, self.read_ms.count)?;
+        write!(
+            f,
+            "decompress: {} ms ({})",
+            self.decompress_ms, self.decompress_ms.count
+        )?;
+        for pool in &self.pools {
+            write!(
+                f,
+                "\npool {:#x}: {} readers, {} idle",
+                pool.flags, pool.readers, pool.idle
+            )?;
+            if let Some(warmup) = pool.warmup_ms {
+                write!(f, ", warmed up in {:.1} ms", warmup)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Histogram of durations, with buckets of powers of two microseconds.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// Bucket `i` counts the durations below `2^i` µs (and above the previous bucket)
+    buckets: [u64; 32],
+}
+impl Histogram {
+    pub fn push(&mut self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(self.buckets.len() - 1)] += 1;
+    }
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+    /// Upper bound of the duration at the percentile `p` (between 0 and 100).
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0 * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        let bucket = self.buckets.iter().position(|b| {
+            seen += b;
+            seen >= rank
+        })?;
+        Some(Duration::from_micros(1 << bucket))
+    }
+    /// Durations recorded since `earlier`, a previous state of this histogram.
+    pub fn since(&self, earlier: &Histogram) -> Histogram {
+        let mut histogram = self.clone();
+        for (bucket, earlier) in histogram.buckets.iter_mut().zip(earlier.buckets) {
+            *bucket = bucket.saturating_sub(earlier);
+        }
+        histogram
+    }
+}
+
+/// Cumulative counters of the reads, see [`Stats::counters`].
+#[derive(Clone, Debug, Default)]
+pub struct Counters {
+    pub reads: u64,
+    pub bytes: u64,
+    pub cache_hits: u64,
+    pub cache_lookups: u64,
+    pub pool_wait: Histogram,
+}
+
+/// Activity between two [`Counters`], logged by [`SquashFs::stats_logger`].
+#[derive(Clone, Debug)]
+pub struct Activity {
+    pub reads_per_sec: f64,
+    pub bytes_per_sec: f64,
+    /// Hit rate of the cache of data blocks, if it was used
+    pub cache_hit_rate: Option<f64>,
+    /// 99th percentile of the waits for a reader
+    pub pool_wait_p99: Option<Duration>,
+}
+impl Activity {
+    pub fn between(earlier: &Counters, later: &Counters, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64().max(1e-3);
+        let lookups = later.cache_lookups.saturating_sub(earlier.cache_lookups);
+        let hits = later.cache_hits.saturating_sub(earlier.cache_hits);
+        Self {
+            reads_per_sec: later.reads.saturating_sub(earlier.reads) as f64 / secs,
+            bytes_per_sec: later.bytes.saturating_sub(earlier.bytes) as f64 / secs,
+            cache_hit_rate: (lookups > 0).then(|| hits as f64 / lookups as f64),
+            pool_wait_p99: later.pool_wait.since(&earlier.pool_wait).percentile(99.0),
+        }
+    }
+}
+impl std::fmt::Display for Activity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.1} reads/s, {:.2} MB/s, cache hits ",
+            self.reads_per_sec,
+            self.bytes_per_sec / 1e6
+        )?;
+        match self.cache_hit_rate {
+            Some(rate) => write!(f, "{:.1}%", 100.0 * rate)?,
+            None => write!(f, "-")?,
+        }
+        write!(f, ", pool wait p99 ")?;
+        match self.pool_wait_p99 {
+            Some(wait) => write!(f, "<{:?}", wait),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+impl<R: deadpool::managed::Manager> SquashFs<R> {
+    /// Future logging the [`Activity`] over each `interval`, to be spawned alongside the mount,
+    /// where metrics cannot be scraped.
+    pub fn stats_logger(&self, interval: Duration) -> impl Future<Output = ()> + Send + 'static {
+        let stats = self.stats.clone();
+        async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            let (mut counters, mut last) = (stats.counters(), std::time::Instant::now());
+            loop {
+                ticks.tick().await;
+                let now = std::time::Instant::now();
+                let new = stats.counters();
+                info!("{}", Activity::between(&counters, &new, now - last));
+                (counters, last) = (new, now);
+            }
+        }
+        .instrument(self.span.clone())
+    }
+    /// Latencies of the operations since the image was opened, and state of the readers.
+    pub async fn stats(&self) -> Snapshot {
+        let mut snapshot = self.stats.snapshot();
+        snapshot.pools = self
+            .readers
+            .read()
+            .await
+            .iter()
+            .map(|(flags, pool)| {
+                let status = pool.status();
+                PoolStatus {
+                    flags: *flags,
+                    readers: status.size,
+                    idle: status.available.max(0) as usize,
+                    warmup_ms: self.warmup.get(flags).map(|d| d.as_secs_f64() * 1e3),
+                }
+            })
+            .collect();
+        snapshot
+    }
+}
+
+static DEFAULT_DISPLAY_PRECISION: usize = 3;
+
+/// Summary statistics of a series.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MeanStd {
+    pub mean: f64,
+    pub std: f64,
+    pub min: f64,
+    pub max: f64,
+    pub count: usize,
+}
+impl Default for MeanStd {
+    fn default() -> Self {
+        Self {
+            mean: f64::NAN,
+            std: f64::NAN,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            count: 0,
+        }
+    }
+}
+impl MeanStd {
+    /// Add a value, with Welford's online algorithm.
+    pub fn push(&mut self, x: f64) {
+        if x.is_nan() {
+            return;
+        }
+        let (mean, m) = if self.is_empty() {
+            (0.0, 0.0)
+        } else {
+            (self.mean, self.std * self.std * self.count as f64)
+        };
+        self.count += 1;
+        let d = x - mean;
+        self.mean = mean + d / self.count as f64;
+        self.std = ((m + d * (x - self.mean)) / self.count as f64).sqrt();
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+    }
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+    pub fn range(&self, precision: usize) -> String {
+        format!("{:.*} - {:.*}", precision, self.min, precision, self.max)
+    }
+}
+
+impl std::fmt::Display for MeanStd {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.is_empty() {
+            write!(f, "-")
+        } else {
+            let prec = f.precision().unwrap_or(DEFAULT_DISPLAY_PRECISION);
+            write!(f, "{:.*} ± {:.*}", prec, self.mean, prec, self.std)
+        }
+    }
+}
+impl std::fmt::Debug for MeanStd {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+impl std::ops::Mul<&MeanStd> for f64 {
+    type Output = MeanStd;
+
+    fn mul(self, rhs: &MeanStd) -> Self::Output {
+        let minmax = [self * rhs.min, self * rhs.max];
+        MeanStd {
+            mean: self * rhs.mean,
+            std: self * self * rhs.std,
+            min: minmax[0].min(minmax[1]),
+            max: minmax[0].max(minmax[1]),
+            count: rhs.count,
+        }
+    }
+}
+macro_rules! meanstd_from_it {
+    ($t:ty,$filter:expr) => {
+        impl FromIterator<$t> for MeanStd {
+            fn from_iter<T>(it: T) -> MeanStd
+            where
+                T: IntoIterator<Item = $t>,
+            {
+                // Welford's algorithm
+                let mut max: f64 = f64::NEG_INFINITY;
+                let mut min: f64 = f64::INFINITY;
+                let mut mean: f64 = 0.0;
+                let mut m: f64 = 0.0;
+                let mut i: f64 = 0.0;
+                for x in it.into_iter().filter($filter) {
+                    let x = x as f64;
+                    max = max.max(x);
+                    min = min.min(x);
+                    i += 1.0;
+                    let d = x - mean;
+                    mean += d / i;
+                    m += d * (x - mean);
+                }
+                if i == 0.0 {
+                    return Default::default();
+                }
+                MeanStd {
+                    mean,
+                    min,
+                    max,
+                    std: (m / i).sqrt(),
+                    count: i as usize,
+                }
+            }
+        }
+    };
+}
+meanstd_from_it!(f32, |x| !x.is_nan());
+meanstd_from_it!(f64, |x| !x.is_nan());
+meanstd_from_it!(u64, |_| true);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn meanstd_test() {
+        let values = [1.0, 4.0, 2.5, 10.0, 3.0];
+        let batch: MeanStd = values.into_iter().collect();
+        let mut online = MeanStd::default();
+        for x in values {
+            online.push(x);
+        }
+        assert_eq!(online.count, batch.count);
+        assert!((online.mean - batch.mean).abs() < 1e-9);
+        assert!((online.std - batch.std).abs() < 1e-9);
+        assert_eq!((online.min, online.max), (1.0, 10.0));
+    }
+    #[test]
+    fn histogram_test() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile(99.0), None);
+        for _ in 0..98 {
+            histogram.push(Duration::from_micros(3));
+        }
+        let earlier = histogram.clone();
+        histogram.push(Duration::from_micros(100));
+        histogram.push(Duration::from_secs(10_000_000));
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_micros(4)));
+        assert_eq!(histogram.percentile(99.0), Some(Duration::from_micros(128)));
+        assert_eq!(
+            histogram.percentile(100.0),
+            Some(Duration::from_micros(1 << 31))
+        );
+        let recent = histogram.since(&earlier);
+        assert_eq!(recent.count(), 2);
+        assert_eq!(recent.percentile(0.0), Some(Duration::from_micros(128)));
+    }
+}
@@ -2,13 +2,15 @@ use serde::Deserialize;
 use serde_repr::Deserialize_repr;
 use tracing::*;
 
+use super::data::{Codec, XzDecompressor};
+use super::deser::Limits;
 use super::error::DecompressError;
 use super::inodes::InodeRef;
 use super::metadata::MetadataBlock;
 use super::Error;
 
 /// Compression algorithm
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize_repr)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize_repr)]
 #[repr(u16)]
 pub enum Compression {
     Gzip = 1,
@@ -18,6 +20,41 @@ pub enum Compression {
     Lz4,
     Zstd,
 }
+impl Compression {
+    pub const ALL: [Self; 6] = [
+        Self::Gzip,
+        Self::Lzma,
+        Self::Lzd,
+        Self::Xz,
+        Self::Lz4,
+        Self::Zstd,
+    ];
+    /// Name used by `mksquashfs -comp`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Lzma => "lzma",
+            Self::Lzd => "lzo",
+            Self::Xz => "xz",
+            Self::Lz4 => "lz4",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+impl std::str::FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|c| c.name() == s)
+            .ok_or_else(|| format!("Unknown compression {}", s))
+    }
+}
 
 bitflags::bitflags! {
     #[derive(Deserialize)]
@@ -36,11 +73,26 @@ bitflags::bitflags! {
         const UNCOMPRESSED_IDS = 0x0800;
     }
 }
-#[derive(Debug)]
+bitflags::bitflags! {
+    /// BCJ filters tried by the compressor, see [`CompressionOptions::Xz`].
+    pub struct XzFilters: u32 {
+        const X86 = 0x0001;
+        const POWERPC = 0x0002;
+        const IA64 = 0x0004;
+        const ARM = 0x0008;
+        const ARMTHUMB = 0x0010;
+        const SPARC = 0x0020;
+    }
+}
+#[derive(Debug, Clone)]
 pub enum CompressionOptions {
     Zstd,
     Gzip,
-    Xz,
+    Xz {
+        dictionary_size: u32,
+        /// Bitmask of [`XzFilters`]
+        filters: u32,
+    },
 }
 impl CompressionOptions {
     fn from_metadata(compression: Compression, block: MetadataBlock) -> Result<Self, Error> {
@@ -58,50 +110,69 @@ impl CompressionOptions {
                 Ok(Self::Gzip)
             }
             Compression::Xz => {
-                if block.compressed_size != 8 {
+                if block.compressed_size != 8 || block.data.len() != 8 {
                     return Err(Error::InvalidBufferSize);
                 }
-                Ok(Self::Xz)
+                let field = |i: usize| u32::from_le_bytes(block.data[i..i + 4].try_into().unwrap());
+                Ok(Self::Xz {
+                    dictionary_size: field(0),
+                    filters: field(4),
+                })
             }
             // TODO: Other compression schemes
             _ => Err(DecompressError::UnsupportedCompression(compression).into()),
         }
     }
 }
+/// Start of the tables that are absent from the archive.
+const NO_TABLE: u64 = u64::MAX;
+
 /// Superblock, containing archive metadata.
 ///
 /// See <https://dr-emann.github.io/squashfs/squashfs.html#_the_superblock>
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct SuperBlock {
     magic: u32,
     pub inode_count: u32,
-    _modification_time: u32,
+    /// Seconds since the epoch, see [`SuperBlock::creation_time`]
+    modification_time: u32,
     pub block_size: u32,
     pub fragment_entry_count: u32,
     pub compression: Compression,
     _block_log: u16,
     flags: SuperBlockFlags,
-    _id_lookupcount: u16,
+    pub id_count: u16,
     version_major: u16,
     version_minor: u16,
     pub root_inode: InodeRef,
     /// Without padding
     pub bytes_used: u64,
-    _id_table_start: u64,
-    _xattr_id_table_start: u64,
+    pub id_table_start: u64,
+    xattr_id_table_start: u64,
     pub inode_table_start: u64,
     pub directory_table_start: u64,
     pub fragment_table_start: u64,
-    _export_table_start: u64,
+    export_table_start: u64,
     #[serde(skip)]
     pub compression_options: Option<CompressionOptions>,
+    /// XZ decoder configured by the options, see [`SuperBlock::codec`]
+    #[serde(skip)]
+    pub(crate) xz: Option<std::sync::Arc<XzDecompressor>>,
+    /// Limits on the sizes read from the image, see [`crate::Options::limits`]
+    #[serde(skip)]
+    pub(crate) limits: Limits,
 }
 impl SuperBlock {
-    pub async fn from_reader(mut r: impl crate::AsyncSeekBufRead) -> Result<Self, Error> {
+    /// Read the superblock, checking the sizes read from the image then against `limits`.
+    pub async fn from_reader(
+        mut r: impl crate::AsyncSeekBufRead,
+        limits: &Limits,
+    ) -> Result<Self, Error> {
         debug!("Reading superblock");
-        let mut superblock: Self = super::deser::bincode_deser_from(&mut r, 96)
+        let mut superblock: Self = super::deser::bincode_deser_from(&mut r, 96, limits.structure)
             .await
-            .map_err(|_| Error::InvalidSuperblock)?;
+            .map_err(Error::SuperblockDecode)?;
+        superblock.limits = *limits;
 
         if superblock.magic != 0x73717368
             || superblock.version_major != 4
@@ -113,7 +184,7 @@ impl SuperBlock {
             .flags
             .contains(SuperBlockFlags::COMPRESSOR_OPTIONS)
         {
-            let block = MetadataBlock::from_reader(&mut r, superblock.compression).await?;
+            let block = MetadataBlock::from_reader(&mut r, &superblock.compression.into()).await?;
             superblock.compression_options = Some(CompressionOptions::from_metadata(
                 superblock.compression,
                 block,
@@ -122,6 +193,87 @@ impl SuperBlock {
         debug!("{:?}", superblock);
         Ok(superblock)
     }
+    /// Decompression of the blocks of the image, with the decoder configured by the options it
+    /// was opened with.
+    pub fn codec(&self) -> Codec {
+        Codec::new(self.compression, self.xz.clone())
+    }
+    /// Time at which the archive was created (which SquashFS calls its modification time).
+    ///
+    /// This is set by `mksquashfs` (see its `-mkfs-time` option), and is `UNIX_EPOCH` for
+    /// reproducible images.
+    pub fn creation_time(&self) -> std::time::SystemTime {
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(self.modification_time as u64)
+    }
+    /// Whether the archive has a fragments table, which is not the case for archives created
+    /// with `mksquashfs -no-fragments`.
+    pub fn has_fragments(&self) -> bool {
+        !self.flags.contains(SuperBlockFlags::NO_FRAGMENTS)
+            && self.fragment_table_start != NO_TABLE
+            && self.fragment_entry_count > 0
+    }
+    /// Whether all data blocks, including fragments, are stored without compression
+    /// (`mksquashfs -noD -noF`), in which case no decompression takes place when reading files.
+    pub fn uncompressed_data(&self) -> bool {
+        self.flags.contains(SuperBlockFlags::UNCOMPRESSED_DATA)
+            && (self.flags.contains(SuperBlockFlags::UNCOMPRESSED_FRAGMENTS)
+                || !self.has_fragments())
+    }
+    /// Start of the export table, if the archive is exportable.
+    pub fn export_table_start(&self) -> Option<u64> {
+        (self.export_table_start != NO_TABLE).then_some(self.export_table_start)
+    }
+    /// Start of the xattr id table, if the archive has extended attributes.
+    pub fn xattr_id_table_start(&self) -> Option<u64> {
+        (self.xattr_id_table_start != NO_TABLE && !self.flags.contains(SuperBlockFlags::NO_XATTRS))
+            .then_some(self.xattr_id_table_start)
+    }
+    /// Check that the image fits in the `len` bytes of the reader, and that its tables start
+    /// within the [`SuperBlock::bytes_used`] bytes of the image. Bytes beyond are ignored (see
+    /// [`crate::SquashFs::trailer`]).
+    pub fn check_length(&self, len: u64) -> Result<(), Error> {
+        if self.bytes_used > len {
+            return Err(Error::Truncated {
+                expected: self.bytes_used,
+                actual: len,
+            });
+        }
+        let end = [
+            self.id_table_start,
+            self.inode_table_start,
+            self.directory_table_start,
+        ]
+        .into_iter()
+        .chain(self.has_fragments().then_some(self.fragment_table_start))
+        .chain(self.export_table_start())
+        .chain(self.xattr_id_table_start())
+        .max()
+        .unwrap();
+        if end > self.bytes_used {
+            return Err(Error::OutOfBounds {
+                end,
+                bytes_used: self.bytes_used,
+            });
+        }
+        Ok(())
+    }
+    /// End of the directory table, i.e. the start of the table following it.
+    ///
+    /// `mksquashfs` writes the fragments table next, but other tools (e.g. `gensquashfs` from
+    /// squashfs-tools-ng) write the tables in another order, and omit those they do not need.
+    pub fn directory_table_end(&self) -> u64 {
+        [
+            self.fragment_table_start,
+            self.export_table_start,
+            self.id_table_start,
+            self.xattr_id_table_start,
+        ]
+        .into_iter()
+        .filter(|start| *start != NO_TABLE && *start > self.directory_table_start)
+        .min()
+        .unwrap_or(self.bytes_used)
+        .min(self.bytes_used)
+    }
     pub fn tables_length(&self) -> u64 {
         self.bytes_used - self.inode_table_start
     }
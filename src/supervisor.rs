@@ -0,0 +1,501 @@
+//! Mount-on-demand of a directory of images, multiplexed in a single filesystem.
+//!
+//! Each `<name>.squashfs` (or `.sqfs`) image of the directory appears as the directory `<name>`
+//! at the root of the filesystem. Images are opened on first access, and closed by
+//! [`Supervisor::unmount_idle`] once they have no open handles and have not been accessed for a
+//! while.
+//!
+//! The FUSE inodes of image `i` are those of its [`SquashFs`], offset by `(i + 1) << 32`. Indices
+//! are assigned when images are first seen, and are stable for the lifetime of the supervisor, so
+//! that inodes cached by the kernel remain valid after an image is closed and reopened.
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use fuser_async::{DirEntry, Filesystem};
+use tokio::sync::{OnceCell, RwLock};
+use tracing::*;
+
+use crate::content_cache::ContentCache;
+use crate::fuse::FuseFilesystem;
+use crate::squashfuse::read_only;
+use crate::{pools, AsyncSeekBufRead, Error, Options, SquashFs, Statfs};
+
+/// Extensions of the image files.
+const EXTENSIONS: [&str; 2] = ["squashfs", "sqfs"];
+/// Unit of the sizes reported by `statfs` on the root, which spans images of any block size.
+const ROOT_BLOCK_SIZE: u32 = 4096;
+
+struct Image<P: deadpool::managed::Manager> {
+    name: String,
+    path: PathBuf,
+    /// Opened image, if it is open. Accesses hold the read lock while getting (or opening) it,
+    /// and closing takes the write lock.
+    fs: RwLock<OnceCell<Arc<SquashFs<P>>>>,
+    last_access: std::sync::Mutex<Instant>,
+}
+
+/// Filesystem serving the images of a directory, see the [module](self) documentation.
+pub struct Supervisor<P: deadpool::managed::Manager> {
+    dir: PathBuf,
+    options: Options,
+    idle: Duration,
+    images: Arc<RwLock<Vec<Arc<Image<P>>>>>,
+    created: SystemTime,
+    content_cache: Option<Arc<ContentCache>>,
+}
+
+fn encode(image: usize, ino: u64) -> u64 {
+    ((image as u64 + 1) << 32) | ino
+}
+fn decode(ino: u64) -> Option<(usize, u64)> {
+    let image = (ino >> 32).checked_sub(1)?;
+    Some((image as usize, ino & u32::MAX as u64))
+}
+
+impl<T, P> Supervisor<P>
+where
+    T: AsyncSeekBufRead,
+    P: pools::LocalReadersPool
+        + deadpool::managed::Manager<Type = T, Error = tokio::io::Error>
+        + Send
+        + Sync
+        + 'static,
+{
+    /// Serve the images in `dir`, opened with `options`, and closed after `idle` without access.
+    pub async fn new(dir: &Path, options: &Options, idle: Duration) -> Result<Self, Error> {
+        let supervisor = Self {
+            dir: dir.to_owned(),
+            options: options.clone(),
+            idle,
+            images: Default::default(),
+            created: SystemTime::now(),
+            content_cache: None,
+        };
+        supervisor.scan().await?;
+        Ok(supervisor)
+    }
+    /// Share `cache` between the images with its block size.
+    pub fn with_content_cache(mut self, cache: Arc<ContentCache>) -> Self {
+        self.content_cache = Some(cache);
+        self
+    }
+    /// Register the images added to the directory since the last scan.
+    pub async fn scan(&self) -> Result<(), Error> {
+        let mut found = vec![];
+        let mut entries = tokio::fs::read_dir(&self.dir)
+            .await
+            .map_err(Error::ReadFailure)?;
+        while let Some(entry) = entries.next_entry().await.map_err(Error::ReadFailure)? {
+            let path = entry.path();
+            if !path
+                .extension()
+                .and_then(OsStr::to_str)
+                .is_some_and(|e| EXTENSIONS.contains(&e))
+            {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(OsStr::to_str) {
+                found.push((name.to_string(), path));
+            }
+        }
+        found.sort();
+        let mut images = self.images.write().await;
+        for (name, path) in found {
+            if images.iter().all(|i| i.name != name) {
+                debug!(name, "Registering image");
+                images.push(Arc::new(Image {
+                    name,
+                    path,
+                    fs: Default::default(),
+                    last_access: std::sync::Mutex::new(Instant::now()),
+                }));
+            }
+        }
+        Ok(())
+    }
+    /// Names of the registered images, and whether they are currently open.
+    pub async fn images(&self) -> Vec<(String, bool)> {
+        let images = self.images.read().await.clone();
+        let mut out = vec![];
+        for image in images {
+            out.push((image.name.clone(), image.fs.read().await.initialized()));
+        }
+        out
+    }
+    /// Image `index`, opening it if needed.
+    ///
+    /// The image is opened without holding the lock on the list of images, so that the accesses
+    /// to the other images and the scans proceed meanwhile, and concurrent accesses to the image
+    /// wait for the same opening.
+    async fn image(&self, index: usize) -> Result<Arc<SquashFs<P>>, Error> {
+        let image = self
+            .images
+            .read()
+            .await
+            .get(index)
+            .cloned()
+            .ok_or(Error::InvalidInode)?;
+        *image.last_access.lock().unwrap() = Instant::now();
+        let fs = image.fs.read().await;
+        let fs = fs.get_or_try_init(|| self.open_image(&image)).await?;
+        Ok(fs.clone())
+    }
+    async fn open_image(&self, image: &Image<P>) -> Result<Arc<SquashFs<P>>, Error> {
+        info!(name = image.name, "Opening image {:?}", image.path);
+        let options = Options {
+            name: Some(image.name.clone()),
+            ..self.options.clone()
+        };
+        let mut opened = SquashFs::open(&image.path, &options).await?;
+        if let Some(cache) = &self.content_cache {
+            if cache.block_size() == opened.superblock.block_size {
+                opened = opened.with_content_cache(cache.clone())?;
+            } else {
+                warn!(
+                    name = image.name,
+                    "Not sharing the content cache, which has a different block size"
+                );
+            }
+        }
+        Ok(Arc::new(opened))
+    }
+    /// Close the images without open handles that were not accessed for the idle duration.
+    /// Returns the number of closed images.
+    pub async fn unmount_idle(&self) -> usize {
+        Self::close_idle(&self.images, self.idle).await
+    }
+    /// Future calling [`Supervisor::unmount_idle`] periodically, to be spawned alongside the
+    /// mount.
+    pub fn reaper(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let images = self.images.clone();
+        let idle = self.idle;
+        async move {
+            let mut interval = tokio::time::interval((idle / 2).max(Duration::from_secs(1)));
+            loop {
+                interval.tick().await;
+                Self::close_idle(&images, idle).await;
+            }
+        }
+    }
+    async fn close_idle(images: &RwLock<Vec<Arc<Image<P>>>>, idle: Duration) -> usize {
+        let images = images.read().await.clone();
+        let mut closed = 0;
+        for image in images {
+            if image.last_access.lock().unwrap().elapsed() < idle {
+                continue;
+            }
+            // Images being opened or accessed are not idle
+            let Ok(mut fs) = image.fs.try_write() else {
+                continue;
+            };
+            let busy = match fs.get() {
+                Some(fs) => fs.has_handles().await || Arc::strong_count(fs) > 1,
+                None => continue,
+            };
+            if !busy {
+                info!(name = image.name, "Closing idle image");
+                fs.take();
+                closed += 1;
+            }
+        }
+        closed
+    }
+    fn root_attr(&self) -> fuser::FileAttr {
+        let mut attr = fuser_async::utils::file_attr(fuser::FUSE_ROOT_ID, 0, self.created);
+        attr.kind = fuser::FileType::Directory;
+        attr.perm = 0o555;
+        attr.nlink = 2;
+        attr
+    }
+    /// Images following the cookie `offset`, with their cookies (their index plus one, as
+    /// indices are stable). The directory is scanned again when listing from the start.
+    async fn root_entries(&self, offset: u64) -> Result<Vec<(u64, DirEntry)>, Error> {
+        if offset == 0 {
+            self.scan().await?;
+        }
+        let images = self.images.read().await;
+        Ok(images
+            .iter()
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(index, image)| {
+                let entry = DirEntry {
+                    inode: encode(index, fuser::FUSE_ROOT_ID),
+                    name: image.name.clone(),
+                    file_type: fuser::FileType::Directory,
+                };
+                (index as u64 + 1, entry)
+            })
+            .collect())
+    }
+    /// Inner inode of `ino`, and its image.
+    async fn route(&self, ino: u64) -> Result<(Arc<SquashFs<P>>, usize, u64), Error> {
+        let (index, inner) = decode(ino).ok_or(Error::InvalidInode)?;
+        Ok((self.image(index).await?, index, inner))
+    }
+}
+
+#[async_trait::async_trait]
+impl<T, P> Filesystem for Supervisor<P>
+where
+    T: AsyncSeekBufRead,
+    P: pools::LocalReadersPool
+        + deadpool::managed::Manager<Type = T, Error = tokio::io::Error>
+        + Send
+        + Sync
+        + 'static,
+{
+    type Error = Error;
+    async fn inodes(&self) -> Result<BTreeSet<u64>, Error> {
+        let mut inodes = BTreeSet::from([fuser::FUSE_ROOT_ID]);
+        let n = self.images.read().await.len();
+        for index in 0..n {
+            inodes.insert(encode(index, fuser::FUSE_ROOT_ID));
+        }
+        Ok(inodes)
+    }
+    async fn open(&self, ino: u64, flags: i32) -> Result<u64, Error> {
+        let (fs, _, ino) = self.route(ino).await?;
+        Filesystem::open(fs.as_ref(), ino, flags).await
+    }
+    async fn release(&self, ino: u64, fh: u64) -> Result<(), Error> {
+        let (fs, _, ino) = self.route(ino).await?;
+        Filesystem::release(fs.as_ref(), ino, fh).await
+    }
+    async fn lookup(&self, parent: u64, name: &OsStr) -> Result<fuser::FileAttr, Error> {
+        if parent == fuser::FUSE_ROOT_ID {
+            let name = name.to_str().ok_or(Error::Encoding)?;
+            let find = || async {
+                let images = self.images.read().await;
+                images.iter().position(|i| i.name == name)
+            };
+            let index = match find().await {
+                Some(index) => Some(index),
+                None => {
+                    self.scan().await?;
+                    find().await
+                }
+            }
+            .ok_or_else(|| Error::FileNotFound(Some(name.into())))?;
+            return self.getattr(encode(index, fuser::FUSE_ROOT_ID)).await;
+        }
+        let (fs, index, parent) = self.route(parent).await?;
+        let mut attr = Filesystem::lookup(fs.as_ref(), parent, name).await?;
+        attr.ino = encode(index, attr.ino);
+        Ok(attr)
+    }
+    async fn getattr(&self, ino: u64) -> Result<fuser::FileAttr, Error> {
+        if ino == fuser::FUSE_ROOT_ID {
+            return Ok(self.root_attr());
+        }
+        let (fs, index, ino) = self.route(ino).await?;
+        let mut attr = Filesystem::getattr(fs.as_ref(), ino).await?;
+        attr.ino = encode(index, attr.ino);
+        Ok(attr)
+    }
+    async fn setattr(&mut self, _ino: u64, _size: Option<u64>) -> Result<fuser::FileAttr, Error> {
+        read_only("setattr")
+    }
+    async fn readdir(
+        &self,
+        ino: u64,
+        offset: u64,
+    ) -> Result<Box<dyn Iterator<Item = DirEntry> + Send + Sync + '_>, Error> {
+        let entries: Vec<_> = if ino == fuser::FUSE_ROOT_ID {
+            self.root_entries(offset)
+                .await?
+                .into_iter()
+                .map(|(_, entry)| entry)
+                .collect()
+        } else {
+            let (fs, index, ino) = self.route(ino).await?;
+            let entries = Filesystem::readdir(fs.as_ref(), ino, offset).await?;
+            entries
+                .map(|mut e| {
+                    e.inode = encode(index, e.inode);
+                    e
+                })
+                .collect()
+        };
+        Ok(Box::new(entries.into_iter()))
+    }
+    async fn read(&self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<bytes::Bytes, Error> {
+        let (fs, _, ino) = self.route(ino).await?;
+        Filesystem::read(fs.as_ref(), ino, fh, offset, size).await
+    }
+    async fn write(
+        &self,
+        _ino: u64,
+        _fh: u64,
+        _data: bytes::Bytes,
+        _offset: i64,
+    ) -> Result<u32, Error> {
+        read_only("write")
+    }
+    async fn create(
+        &mut self,
+        _parent: u64,
+        _name: std::ffi::OsString,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+    ) -> Result<(fuser::FileAttr, u64), Error> {
+        read_only("create")
+    }
+    async fn mkdir(
+        &mut self,
+        _parent: u64,
+        _name: std::ffi::OsString,
+    ) -> Result<fuser::FileAttr, Error> {
+        read_only("mkdir")
+    }
+}
+
+#[async_trait::async_trait]
+impl<T, P> FuseFilesystem for Supervisor<P>
+where
+    T: AsyncSeekBufRead,
+    P: pools::LocalReadersPool
+        + deadpool::managed::Manager<Type = T, Error = tokio::io::Error>
+        + Send
+        + Sync
+        + 'static,
+{
+    async fn open_reply_flags(&self, ino: u64, flags: i32) -> Result<u32, Error> {
+        let (fs, _, ino) = self.route(ino).await?;
+        fs.open_reply_flags(ino, flags).await
+    }
+    async fn access(&self, ino: u64, uid: u32, gid: u32, mask: i32) -> Result<(), Error> {
+        if ino == fuser::FUSE_ROOT_ID {
+            // The listing of the images is readable by anyone
+            return match mask & libc::W_OK {
+                0 => Ok(()),
+                _ => read_only("access"),
+            };
+        }
+        let (fs, _, ino) = self.route(ino).await?;
+        fs.access(ino, uid, gid, mask).await
+    }
+    async fn statfs(&self, ino: u64) -> Result<Statfs, Error> {
+        if ino != fuser::FUSE_ROOT_ID {
+            let (fs, _, _) = self.route(ino).await?;
+            return Ok(fs.statfs());
+        }
+        // The images, without opening them
+        let images = self.images.read().await;
+        let mut bytes = 0;
+        for image in images.iter() {
+            bytes += tokio::fs::metadata(&image.path)
+                .await
+                .map_err(Error::ReadFailure)?
+                .len();
+        }
+        Ok(Statfs {
+            blocks: bytes.div_ceil(ROOT_BLOCK_SIZE as u64),
+            bfree: 0,
+            bavail: 0,
+            files: images.len() as u64 + 1,
+            ffree: 0,
+            bsize: ROOT_BLOCK_SIZE,
+            namelen: 255,
+            frsize: ROOT_BLOCK_SIZE,
+        })
+    }
+    async fn readlink(&self, ino: u64) -> Result<PathBuf, Error> {
+        let (fs, _, ino) = self.route(ino).await?;
+        fs.readlink(ino).await
+    }
+    async fn opendir(&self, ino: u64) -> Result<u64, Error> {
+        if ino == fuser::FUSE_ROOT_ID {
+            // The listing of the images needs no handle
+            return Ok(0);
+        }
+        let (fs, _, ino) = self.route(ino).await?;
+        fs.opendir(ino).await
+    }
+    async fn readdir_handle(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+    ) -> Result<Box<dyn Iterator<Item = (u64, DirEntry)> + Send + Sync + '_>, Error> {
+        let entries = if ino == fuser::FUSE_ROOT_ID {
+            self.root_entries(offset).await?
+        } else {
+            let (fs, index, _) = self.route(ino).await?;
+            let entries = fs.readdir_handle(fh, offset).await?;
+            entries
+                .map(|(cookie, mut e)| {
+                    e.inode = encode(index, e.inode);
+                    (cookie, e)
+                })
+                .collect()
+        };
+        Ok(Box::new(entries.into_iter()))
+    }
+    async fn releasedir(&self, ino: u64, fh: u64) -> Result<(), Error> {
+        if ino == fuser::FUSE_ROOT_ID {
+            return Ok(());
+        }
+        let (fs, _, _) = self.route(ino).await?;
+        fs.releasedir(fh).await
+    }
+    async fn setattr(&self, _ino: u64, _size: Option<u64>) -> Result<fuser::FileAttr, Error> {
+        read_only("setattr")
+    }
+    async fn create(
+        &self,
+        _parent: u64,
+        _name: std::ffi::OsString,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+    ) -> Result<(fuser::FileAttr, u64), Error> {
+        read_only("create")
+    }
+    async fn mkdir(
+        &self,
+        _parent: u64,
+        _name: std::ffi::OsString,
+    ) -> Result<fuser::FileAttr, Error> {
+        read_only("mkdir")
+    }
+    async fn unlink(&self, _parent: u64, _name: &std::ffi::OsStr) -> Result<(), Error> {
+        read_only("unlink")
+    }
+    async fn rmdir(&self, _parent: u64, _name: &std::ffi::OsStr) -> Result<(), Error> {
+        read_only("rmdir")
+    }
+    async fn rename(
+        &self,
+        _parent: u64,
+        _name: &std::ffi::OsStr,
+        _new_parent: u64,
+        _new_name: &std::ffi::OsStr,
+    ) -> Result<(), Error> {
+        read_only("rename")
+    }
+    async fn symlink(
+        &self,
+        _parent: u64,
+        _name: &std::ffi::OsStr,
+        _target: &std::path::Path,
+    ) -> Result<fuser::FileAttr, Error> {
+        read_only("symlink")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn inodes_test() {
+        assert_eq!(decode(fuser::FUSE_ROOT_ID), None);
+        for (image, ino) in [(0, 1), (3, 42), (1000, u32::MAX as u64)] {
+            assert_eq!(decode(encode(image, ino)), Some((image, ino)));
+        }
+    }
+}
@@ -0,0 +1,506 @@
+//! Utilities for integration tests of filesystems built on this crate (`test-util` feature).
+//!
+//! These mount images with FUSE, either in-process with [`MountHarness`] or by spawning a binary
+//! (see [`Mount`]), and create test images with [`ImageSpec`], [`mksquashfs`] or
+//! [`gensquashfs`].
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::time::Duration;
+
+use clap::ArgEnum;
+use rand::{Rng, SeedableRng};
+
+use crate::fuse::{FuseAdapter, FuseFilesystem};
+use crate::pools::LocalBackend;
+use crate::Compression;
+
+/// Default timeout for [`wait_mounted`] and [`wait_unmounted`].
+pub const MOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Mount with an external process.
+pub trait Mount {
+    fn name(&self) -> String;
+    fn mount(&mut self, source: &Path, dest: &Path) -> anyhow::Result<()>;
+    fn handle(&mut self) -> &mut Child;
+    fn unmount(&mut self) -> anyhow::Result<()> {
+        let handle = self.handle();
+        let mut kill = Command::new("kill")
+            .args(["-s", "INT", &handle.id().to_string()])
+            .spawn()?;
+        kill.wait()?;
+        let status = handle.wait()?;
+        anyhow::ensure!(status.success());
+        Ok(())
+    }
+}
+/// Mount with the C `squashfuse` (or `squashfuse_ll`).
+#[derive(Default)]
+pub struct Squashfuse {
+    command: String,
+    handle: Option<std::process::Child>,
+}
+impl Squashfuse {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.into(),
+            handle: None,
+        }
+    }
+}
+impl Mount for Squashfuse {
+    fn name(&self) -> String {
+        self.command.clone()
+    }
+    fn mount(&mut self, source: &Path, dest: &Path) -> anyhow::Result<()> {
+        self.handle = Some(
+            Command::new(&self.command)
+                .arg("-f")
+                .args([&source, &dest])
+                .spawn()?,
+        );
+        Ok(())
+    }
+    fn handle(&mut self) -> &mut Child {
+        self.handle.as_mut().unwrap()
+    }
+}
+/// Mount with the `squashfuse-rs` binary of this crate.
+pub struct SquashfuseRs {
+    binary: PathBuf,
+    handle: Option<std::process::Child>,
+    backend: LocalBackend,
+}
+impl SquashfuseRs {
+    /// `binary` is typically `env!("CARGO_BIN_EXE_squashfuse-rs")` in the tests of this crate.
+    pub fn new(binary: impl Into<PathBuf>, backend: LocalBackend) -> Self {
+        Self {
+            binary: binary.into(),
+            handle: None,
+            backend,
+        }
+    }
+}
+impl Mount for SquashfuseRs {
+    fn name(&self) -> String {
+        format!("squashfuse-rs-{:?}", self.backend)
+    }
+    fn mount(&mut self, source: &Path, dest: &Path) -> anyhow::Result<()> {
+        self.handle = Some(
+            Command::new(&self.binary)
+                .args([&source, &dest])
+                .args(["--cache-mb", "100"])
+                .args(if cfg!(feature = "memmap") {
+                    vec![
+                        "--backend",
+                        self.backend.to_possible_value().unwrap().get_name(),
+                    ]
+                } else {
+                    vec![]
+                })
+                .stdout(std::process::Stdio::null())
+                .spawn()?,
+        );
+        Ok(())
+    }
+    fn handle(&mut self) -> &mut Child {
+        self.handle.as_mut().unwrap()
+    }
+}
+
+/// Create an image from the folder `input` with `mksquashfs`, with reproducible timestamps.
+pub fn mksquashfs<I, S>(input: &Path, dest: &Path, options: I) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let cmd = Command::new("mksquashfs")
+        .args([input, dest])
+        .args(["-mkfs-time", "0", "-reproducible"])
+        .args(options)
+        .output()?;
+    if !cmd.status.success() {
+        anyhow::bail!(
+            "Failed to run mksquashfs: {}",
+            std::str::from_utf8(&cmd.stderr)?
+        );
+    }
+    Ok(())
+}
+
+/// Create an image from the directory `input` with `gensquashfs` from squashfs-tools-ng, whose
+/// layout differs from that of [`mksquashfs`] (order of the tables, packing of the fragments...).
+///
+/// All timestamps are set to 0.
+pub fn gensquashfs<I, S>(input: &Path, dest: &Path, options: I) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let cmd = Command::new("gensquashfs")
+        .arg("--pack-dir")
+        .arg(input)
+        .args(["--defaults", "mtime=0", "--force", "--quiet"])
+        .args(options)
+        .arg(dest)
+        .output()?;
+    if !cmd.status.success() {
+        anyhow::bail!(
+            "Failed to run gensquashfs: {}",
+            std::str::from_utf8(&cmd.stderr)?
+        );
+    }
+    Ok(())
+}
+
+/// Tool building the images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Builder {
+    /// [`mksquashfs`] from squashfs-tools
+    Mksquashfs,
+    /// [`gensquashfs`] from squashfs-tools-ng
+    Gensquashfs,
+}
+
+/// Image created with [`mksquashfs`] in a temporary directory, deleted when dropped.
+pub struct TempImage {
+    _dir: tempfile::TempDir,
+    pub path: PathBuf,
+}
+impl TempImage {
+    pub fn new<I, S>(input: &Path, options: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        Self::with_builder(Builder::Mksquashfs, input, options)
+    }
+    /// Same as [`TempImage::new`], with another [`Builder`], taking its options.
+    pub fn with_builder<I, S>(builder: Builder, input: &Path, options: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.squashfs");
+        match builder {
+            Builder::Mksquashfs => mksquashfs(input, &path, options)?,
+            Builder::Gensquashfs => gensquashfs(input, &path, options)?,
+        }
+        Ok(Self { _dir: dir, path })
+    }
+}
+
+/// Contents of a file generated by [`ImageSpec`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileData {
+    /// Random bytes, which do not compress.
+    Random,
+    /// Repeated pattern, which compresses well.
+    Compressible,
+}
+
+/// Description of a generated test image, built with `mksquashfs` (or `gensquashfs`, see
+/// [`ImageSpec::builder`]).
+///
+/// The tree has `files` files at the root, and `dirs` subdirectories `0`, `1`... with the same
+/// structure, up to `depth` levels. The contents are reproducible for a given seed.
+///
+/// ```no_run
+/// # fn example() -> anyhow::Result<()> {
+/// use squashfs_async::{testing::ImageSpec, Compression};
+///
+/// let image = ImageSpec::new()
+///     .tree(2, 3, 10)
+///     .file_sizes(0..200_000)
+///     .compression(Some(Compression::Zstd))
+///     .fragments(false)
+///     .symlinks(true)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ImageSpec {
+    seed: u64,
+    depth: usize,
+    dirs: usize,
+    files: usize,
+    file_sizes: std::ops::Range<usize>,
+    data: FileData,
+    compression: Option<Compression>,
+    block_size: Option<u32>,
+    fragments: bool,
+    symlinks: bool,
+    devices: bool,
+    builder: Builder,
+}
+impl Default for ImageSpec {
+    fn default() -> Self {
+        Self {
+            seed: 42,
+            depth: 1,
+            dirs: 1,
+            files: 8,
+            file_sizes: 0..1_000_000,
+            data: FileData::Random,
+            compression: Some(Compression::Gzip),
+            block_size: None,
+            fragments: true,
+            symlinks: false,
+            devices: false,
+            builder: Builder::Mksquashfs,
+        }
+    }
+}
+impl ImageSpec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+    /// Shape of the tree: `depth` levels of `dirs` directories, each with `files` files.
+    pub fn tree(mut self, depth: usize, dirs: usize, files: usize) -> Self {
+        self.depth = depth;
+        self.dirs = dirs;
+        self.files = files;
+        self
+    }
+    /// Range of file sizes, in bytes. Use `n..n + 1` for a fixed size.
+    pub fn file_sizes(mut self, sizes: std::ops::Range<usize>) -> Self {
+        self.file_sizes = sizes;
+        self
+    }
+    pub fn data(mut self, data: FileData) -> Self {
+        self.data = data;
+        self
+    }
+    /// Compression of the image, `None` for an uncompressed image (including the tables).
+    pub fn compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+    /// Whether to store the tail end of files in fragments.
+    pub fn fragments(mut self, fragments: bool) -> Self {
+        self.fragments = fragments;
+        self
+    }
+    /// Add a symlink next to each file, pointing to it.
+    pub fn symlinks(mut self, symlinks: bool) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+    /// Add a character device, a block device, a FIFO and a socket at the root.
+    ///
+    /// These are created with `mksquashfs` pseudo-definitions, which does not require root.
+    pub fn devices(mut self, devices: bool) -> Self {
+        self.devices = devices;
+        self
+    }
+    /// Tool building the image.
+    ///
+    /// `gensquashfs` does not support uncompressed images, images without fragments, nor
+    /// devices, for which building fails.
+    pub fn builder(mut self, builder: Builder) -> Self {
+        self.builder = builder;
+        self
+    }
+    /// Write the files and directories of the image to `dir`.
+    pub fn write_contents(&self, dir: &Path) -> anyhow::Result<()> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(self.seed);
+        self.write_level(dir, self.depth, &mut rng)
+    }
+    fn write_level(&self, dir: &Path, depth: usize, rng: &mut impl Rng) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        for i in 0..self.files {
+            let name = format!("file-{}.random", i);
+            let size = if self.file_sizes.len() > 1 {
+                rng.gen_range(self.file_sizes.clone())
+            } else {
+                self.file_sizes.start
+            };
+            let data: Vec<u8> = match self.data {
+                FileData::Random => (&mut *rng)
+                    .sample_iter(rand::distributions::Standard)
+                    .take(size)
+                    .collect(),
+                FileData::Compressible => (0..size).map(|i| (i % 251) as u8).collect(),
+            };
+            std::fs::write(dir.join(&name), data)?;
+            if self.symlinks {
+                std::os::unix::fs::symlink(&name, dir.join(format!("link-{}", i)))?;
+            }
+        }
+        if depth > 0 {
+            for i in 0..self.dirs {
+                self.write_level(&dir.join(i.to_string()), depth - 1, rng)?;
+            }
+        }
+        Ok(())
+    }
+    /// Options passed to `mksquashfs`.
+    pub fn mksquashfs_options(&self) -> Vec<String> {
+        let mut options: Vec<String> = match self.compression {
+            None => ["-noI", "-noId", "-noD", "-noF", "-noX"]
+                .map(String::from)
+                .to_vec(),
+            Some(compression) => vec!["-comp".into(), compression.name().into()],
+        };
+        if matches!(
+            self.compression,
+            Some(Compression::Gzip | Compression::Zstd)
+        ) {
+            options.extend(["-Xcompression-level".into(), "1".into()]);
+        }
+        if let Some(block_size) = self.block_size {
+            options.extend(["-b".into(), block_size.to_string()]);
+        }
+        if !self.fragments {
+            options.push("-no-fragments".into());
+        }
+        if self.devices {
+            for pseudo in [
+                "char c 644 0 0 1 3",
+                "block b 644 0 0 7 0",
+                "fifo i 644 0 0 p",
+                "socket i 644 0 0 s",
+            ] {
+                options.extend(["-p".into(), pseudo.into()]);
+            }
+        }
+        options
+    }
+    /// Options passed to `gensquashfs`, see [`ImageSpec::builder`].
+    pub fn gensquashfs_options(&self) -> anyhow::Result<Vec<String>> {
+        let Some(compression) = self.compression else {
+            anyhow::bail!("gensquashfs does not build uncompressed images");
+        };
+        anyhow::ensure!(
+            self.fragments && !self.devices,
+            "gensquashfs images always have fragments, and cannot have devices"
+        );
+        let mut options = vec!["--compressor".into(), compression.name().into()];
+        if matches!(compression, Compression::Gzip | Compression::Zstd) {
+            options.extend(["--comp-extra".into(), "level=1".into()]);
+        }
+        if let Some(block_size) = self.block_size {
+            options.extend(["--block-size".into(), block_size.to_string()]);
+        }
+        Ok(options)
+    }
+    /// Build the image at `dest`.
+    pub fn build_to(&self, dest: &Path) -> anyhow::Result<()> {
+        let contents = tempfile::tempdir()?;
+        self.write_contents(contents.path())?;
+        match self.builder {
+            Builder::Mksquashfs => mksquashfs(contents.path(), dest, self.mksquashfs_options()),
+            Builder::Gensquashfs => gensquashfs(contents.path(), dest, self.gensquashfs_options()?),
+        }
+    }
+    /// Build the image in a temporary directory.
+    pub fn build(&self) -> anyhow::Result<TempImage> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.squashfs");
+        self.build_to(&path)?;
+        Ok(TempImage { _dir: dir, path })
+    }
+}
+
+/// Whether `path` is a mountpoint, i.e. is on a different device than its parent.
+pub async fn is_mounted(path: &Path) -> std::io::Result<bool> {
+    let parent = path.parent().unwrap_or(path);
+    Ok(tokio::fs::metadata(path).await?.dev() != tokio::fs::metadata(parent).await?.dev())
+}
+async fn wait_for(path: &Path, mounted: bool, timeout: Duration) -> anyhow::Result<()> {
+    let start = std::time::Instant::now();
+    while is_mounted(path).await.unwrap_or(false) != mounted {
+        anyhow::ensure!(
+            start.elapsed() < timeout,
+            "Timeout waiting for {:?} to be {}",
+            path,
+            if mounted { "mounted" } else { "unmounted" }
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    Ok(())
+}
+/// Wait until `path` is a mountpoint.
+pub async fn wait_mounted(path: &Path, timeout: Duration) -> anyhow::Result<()> {
+    wait_for(path, true, timeout).await
+}
+/// Wait until `path` is not a mountpoint anymore.
+pub async fn wait_unmounted(path: &Path, timeout: Duration) -> anyhow::Result<()> {
+    wait_for(path, false, timeout).await
+}
+
+/// Filesystem mounted in-process, in the background. It is unmounted when dropped, or with
+/// [`MountHarness::unmount`], which waits for the unmount to complete.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use squashfs_async::{pools::LocalReadersPoolTokio, testing::MountHarness, SquashFs};
+///
+/// let fs = SquashFs::<LocalReadersPoolTokio>::open("image.squashfs".as_ref(), &Default::default()).await?;
+/// let mount = MountHarness::mount_temp(fs).await?;
+/// let contents = tokio::fs::read(mount.mountpoint().join("file")).await?;
+/// mount.unmount().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct MountHarness {
+    session: Option<fuser::BackgroundSession>,
+    mountpoint: PathBuf,
+    _tempdir: Option<tempfile::TempDir>,
+}
+impl MountHarness {
+    /// Mount `fs` read-only at `mountpoint`, and wait until the mount is ready.
+    pub async fn mount<F: FuseFilesystem>(fs: F, mountpoint: &Path) -> anyhow::Result<Self> {
+        Self::mount_impl(fs, mountpoint.into(), None, true).await
+    }
+    /// Same as [`MountHarness::mount`], on a temporary mountpoint.
+    pub async fn mount_temp<F: FuseFilesystem>(fs: F) -> anyhow::Result<Self> {
+        let tempdir = tempfile::tempdir()?;
+        Self::mount_impl(fs, tempdir.path().into(), Some(tempdir), true).await
+    }
+    /// Same as [`MountHarness::mount_temp`], without the `ro` mount option, so that the kernel
+    /// forwards the modifications to `fs` instead of rejecting them itself.
+    pub async fn mount_temp_writable<F: FuseFilesystem>(fs: F) -> anyhow::Result<Self> {
+        let tempdir = tempfile::tempdir()?;
+        Self::mount_impl(fs, tempdir.path().into(), Some(tempdir), false).await
+    }
+    async fn mount_impl<F: FuseFilesystem>(
+        fs: F,
+        mountpoint: PathBuf,
+        tempdir: Option<tempfile::TempDir>,
+        read_only: bool,
+    ) -> anyhow::Result<Self> {
+        let mut options = vec![fuser::MountOption::Async];
+        if read_only {
+            options.push(fuser::MountOption::RO);
+        }
+        let session = fuser::spawn_mount2(FuseAdapter::new(fs), &mountpoint, &options)?;
+        let harness = Self {
+            session: Some(session),
+            mountpoint,
+            _tempdir: tempdir,
+        };
+        wait_mounted(&harness.mountpoint, MOUNT_TIMEOUT).await?;
+        Ok(harness)
+    }
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+    /// Unmount, and wait until the mountpoint is released.
+    pub async fn unmount(mut self) -> anyhow::Result<()> {
+        if let Some(session) = self.session.take() {
+            tokio::task::spawn_blocking(move || session.join()).await?;
+        }
+        wait_unmounted(&self.mountpoint, MOUNT_TIMEOUT).await
+    }
+}
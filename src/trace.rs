@@ -0,0 +1,192 @@
+//! Recording and replay of read access patterns, see [`crate::Options::trace_accesses`].
+//!
+//! Traces are text files with one read per line, as tab-separated
+//! `timestamp_us inode offset size flags`, where the timestamp is relative to the opening of the
+//! image. Replaying a trace against an image (possibly with different options or backends)
+//! allows tuning the caches and prefetching on real workloads.
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use tracing::*;
+
+use crate::{pools, AsyncSeekBufRead, Error, SquashFs};
+
+/// A single read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Time of the read since the image was opened.
+    pub timestamp: Duration,
+    pub inode: u32,
+    pub offset: u64,
+    pub size: u64,
+    pub flags: pools::ReadFlags,
+}
+impl std::fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            self.timestamp.as_micros(),
+            self.inode,
+            self.offset,
+            self.size,
+            self.flags
+        )
+    }
+}
+impl std::str::FromStr for TraceRecord {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidTrace(s.into());
+        let fields: Vec<_> = s.split('\t').collect();
+        let [timestamp, inode, offset, size, flags] = fields[..] else {
+            return Err(invalid());
+        };
+        Ok(Self {
+            timestamp: Duration::from_micros(timestamp.parse().map_err(|_| invalid())?),
+            inode: inode.parse().map_err(|_| invalid())?,
+            offset: offset.parse().map_err(|_| invalid())?,
+            size: size.parse().map_err(|_| invalid())?,
+            flags: flags.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Read a trace written by [`Tracer`].
+pub fn read_trace(path: &Path) -> Result<Vec<TraceRecord>, Error> {
+    let file = std::fs::File::open(path).map_err(Error::ReadFailure)?;
+    std::io::BufReader::new(file)
+        .lines()
+        .map(|l| l.map_err(Error::ReadFailure)?.parse())
+        .collect()
+}
+
+/// Records the reads to a file, flushed when dropped.
+pub struct Tracer {
+    start: Instant,
+    output: Mutex<std::io::BufWriter<std::fs::File>>,
+}
+impl Tracer {
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        let file = std::fs::File::create(path).map_err(Error::WriteFailure)?;
+        Ok(Self {
+            start: Instant::now(),
+            output: Mutex::new(std::io::BufWriter::new(file)),
+        })
+    }
+    pub fn record(&self, inode: u32, offset: u64, size: u64, flags: pools::ReadFlags) {
+        let record = TraceRecord {
+            timestamp: self.start.elapsed(),
+            inode,
+            offset,
+            size,
+            flags,
+        };
+        if let Err(e) = writeln!(self.output.lock().unwrap(), "{}", record) {
+            warn!("Failed to write access trace: {}", e);
+        }
+    }
+}
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        if let Err(e) = self.output.lock().unwrap().flush() {
+            warn!("Failed to write access trace: {}", e);
+        }
+    }
+}
+
+/// Latencies of a replayed trace, see [`SquashFs::replay`].
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    /// Latencies of the successful reads, sorted.
+    pub latencies: Vec<Duration>,
+    pub bytes: u64,
+    pub errors: usize,
+    pub duration: Duration,
+}
+impl ReplayReport {
+    /// Latency at the given percentile (between 0 and 100).
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let i = ((p / 100.0) * (self.latencies.len() - 1) as f64).round() as usize;
+        self.latencies.get(i.min(self.latencies.len() - 1)).copied()
+    }
+}
+impl std::fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} reads ({} errors), {} MB in {:?} ({:.1} MB/s)",
+            self.latencies.len() + self.errors,
+            self.errors,
+            self.bytes / 1_000_000,
+            self.duration,
+            self.bytes as f64 / 1e6 / self.duration.as_secs_f64()
+        )?;
+        for p in [50.0, 90.0, 99.0, 100.0] {
+            if let Some(latency) = self.percentile(p) {
+                writeln!(f, "p{:<3} {:?}", p, latency)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T, R> SquashFs<R>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+{
+    /// Replay a trace of reads against this image.
+    ///
+    /// With `preserve_timing`, the reads are issued at the times they were recorded, possibly
+    /// concurrently. Otherwise, they are issued one after the other, as fast as possible.
+    pub async fn replay(&self, trace: &[TraceRecord], preserve_timing: bool) -> ReplayReport {
+        let start = Instant::now();
+        let read = |record: TraceRecord| async move {
+            if preserve_timing {
+                tokio::time::sleep_until((start + record.timestamp).into()).await;
+            }
+            let read_start = Instant::now();
+            let result = self
+                .read_file(
+                    record.inode,
+                    record.offset as usize,
+                    record.size as usize,
+                    record.flags,
+                    self.superblock.compression,
+                )
+                .await;
+            (result, read_start.elapsed())
+        };
+        let mut report = ReplayReport::default();
+        let mut add = |(result, latency): (Result<bytes::Bytes, Error>, Duration)| match result {
+            Ok(data) => {
+                report.bytes += data.len() as u64;
+                report.latencies.push(latency);
+            }
+            Err(e) => {
+                debug!("Replayed read failed: {}", e);
+                report.errors += 1;
+            }
+        };
+        if preserve_timing {
+            let mut reads: FuturesUnordered<_> = trace.iter().map(|r| read(*r)).collect();
+            while let Some(result) = reads.next().await {
+                add(result);
+            }
+        } else {
+            for record in trace {
+                add(read(*record).await);
+            }
+        }
+        report.latencies.sort();
+        report.duration = start.elapsed();
+        report
+    }
+}
@@ -1,136 +1,96 @@
-pub use meanstd::*;
+pub use crate::stats::MeanStd;
 
 use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
 use std::hash::{Hash, Hasher};
 
+use tracing::{debug_span, Instrument};
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::prelude::*;
 
 pub fn setup_logger(debug: bool) -> anyhow::Result<()> {
-    tracing_subscriber::registry()
-        .with(Some(tracing_subscriber::fmt::layer().with_filter(
-            if debug {
-                LevelFilter::DEBUG
-            } else {
-                LevelFilter::INFO
-            },
-        )))
-        .init();
+    let registry = tracing_subscriber::registry().with(Some(
+        tracing_subscriber::fmt::layer().with_filter(if debug {
+            LevelFilter::DEBUG
+        } else {
+            LevelFilter::INFO
+        }),
+    ));
+    // Served on 127.0.0.1:6669, see `console_subscriber::ConsoleLayer::builder`
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry.init();
 
     Ok(())
 }
+/// Spawn a long-lived task, named after `name` in tokio-console (with `--cfg tokio_unstable`)
+/// and in the logs.
+pub fn spawn_named<F>(name: &str, future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let future = future.instrument(debug_span!("task", name));
+    #[cfg(tokio_unstable)]
+    return tokio::task::Builder::new()
+        .name(name)
+        .spawn(future)
+        .expect("Failed to spawn task");
+    #[cfg(not(tokio_unstable))]
+    tokio::spawn(future)
+}
+
 pub fn hash<T: Hash>(t: &T) -> u64 {
     let mut s = DefaultHasher::new();
     t.hash(&mut s);
     s.finish()
 }
 
-pub fn unix_timestamp() -> u64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
+/// Parse a duration in milliseconds, e.g. for command line arguments.
+pub fn parse_duration_ms(s: &str) -> Result<std::time::Duration, std::num::ParseIntError> {
+    Ok(std::time::Duration::from_millis(s.parse()?))
 }
 
-pub mod meanstd {
-    use serde::{Deserialize, Serialize};
-
-    static DEFAULT_DISPLAY_PRECISION: usize = 3;
-
-    #[derive(Serialize, Deserialize, Clone)]
-    pub struct MeanStd {
-        pub mean: f64,
-        pub std: f64,
-        pub min: f64,
-        pub max: f64,
-        pub count: usize,
-    }
-    impl Default for MeanStd {
-        fn default() -> Self {
-            Self {
-                mean: f64::NAN,
-                std: f64::NAN,
-                min: f64::INFINITY,
-                max: f64::NEG_INFINITY,
-                count: 0,
-            }
-        }
-    }
-    impl MeanStd {
-        pub fn is_empty(&self) -> bool {
-            self.count == 0
-        }
-        pub fn range(&self, precision: usize) -> String {
-            format!("{:.*} - {:.*}", precision, self.min, precision, self.max)
-        }
+/// Parse octal permission bits (e.g. `644` or `0o644`), for command line arguments.
+pub fn parse_mode(s: &str) -> Result<u16, String> {
+    let mode = u16::from_str_radix(s.trim_start_matches("0o"), 8).map_err(|e| e.to_string())?;
+    if mode > 0o7777 {
+        return Err("Expected at most 0o7777".into());
     }
+    Ok(mode)
+}
 
-    impl std::fmt::Display for MeanStd {
-        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-            if self.is_empty() {
-                write!(f, "-")
-            } else {
-                let prec = f.precision().unwrap_or(DEFAULT_DISPLAY_PRECISION);
-                write!(f, "{:.*} ± {:.*}", prec, self.mean, prec, self.std)
-            }
-        }
-    }
-    impl std::fmt::Debug for MeanStd {
-        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-            write!(f, "{}", self)
-        }
+/// Parse a block size as accepted by `mksquashfs -b` (e.g. `131072`, `128K` or `1M`): a power of
+/// two between 4 KiB and 1 MiB.
+pub fn parse_block_size(s: &str) -> Result<u32, String> {
+    let (digits, unit) = match s.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 1024),
+        None => match s.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 1024 * 1024),
+            None => (s, 1),
+        },
+    };
+    let size = digits
+        .parse::<u32>()
+        .map_err(|e| e.to_string())?
+        .saturating_mul(unit);
+    if !size.is_power_of_two() || !(4096..=1024 * 1024).contains(&size) {
+        return Err("Expected a power of two between 4K and 1M".into());
     }
-    impl std::ops::Mul<&MeanStd> for f64 {
-        type Output = MeanStd;
+    Ok(size)
+}
 
-        fn mul(self, rhs: &MeanStd) -> Self::Output {
-            let minmax = [self * rhs.min, self * rhs.max];
-            MeanStd {
-                mean: self * rhs.mean,
-                std: self * self * rhs.std,
-                min: minmax[0].min(minmax[1]),
-                max: minmax[0].max(minmax[1]),
-                count: rhs.count,
-            }
-        }
+/// Parse a hex-encoded SHA-256 digest, e.g. for command line arguments.
+pub fn parse_sha256(s: &str) -> Result<String, String> {
+    if s.len() != 64 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("Expected 64 hexadecimal characters".into());
     }
-    macro_rules! meanstd_from_it {
-        ($t:ty,$filter:expr) => {
-            impl FromIterator<$t> for MeanStd {
-                fn from_iter<T>(it: T) -> MeanStd
-                where
-                    T: IntoIterator<Item = $t>,
-                {
-                    // Welford's algorithm
-                    let mut max: f64 = f64::NEG_INFINITY;
-                    let mut min: f64 = f64::INFINITY;
-                    let mut mean: f64 = 0.0;
-                    let mut m: f64 = 0.0;
-                    let mut i: f64 = 0.0;
-                    for x in it.into_iter().filter($filter) {
-                        let x = x as f64;
-                        max = max.max(x);
-                        min = min.min(x);
-                        i += 1.0;
-                        let d = x - mean;
-                        mean += d / i;
-                        m += d * (x - mean);
-                    }
-                    if i == 0.0 {
-                        return Default::default();
-                    }
-                    MeanStd {
-                        mean,
-                        min,
-                        max,
-                        std: (m / i).sqrt(),
-                        count: i as usize,
-                    }
-                }
-            }
-        };
-    }
-    meanstd_from_it!(f32, |x| !x.is_nan());
-    meanstd_from_it!(f64, |x| !x.is_nan());
-    meanstd_from_it!(u64, |_| true);
+    Ok(s.to_ascii_lowercase())
+}
+
+pub fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
 }
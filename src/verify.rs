@@ -0,0 +1,198 @@
+//! Differential verification against a reference extraction, e.g. by `unsquashfs`.
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use tokio::io::AsyncReadExt;
+use tracing::*;
+
+use crate::{AsyncSeekBufRead, Error, FileKind, SquashFs};
+
+/// Hex-encoded SHA-256 digest of a whole image, see [`crate::Options::expected_digest`].
+pub async fn image_digest(mut r: impl AsyncSeekBufRead) -> Result<String, Error> {
+    use sha2::Digest;
+    use tokio::io::{AsyncBufReadExt, AsyncSeekExt};
+    r.seek(std::io::SeekFrom::Start(0))
+        .await
+        .map_err(Error::ReadFailure)?;
+    let mut hasher = sha2::Sha256::new();
+    loop {
+        let buf = r.fill_buf().await.map_err(Error::ReadFailure)?;
+        if buf.is_empty() {
+            break;
+        }
+        let n = buf.len();
+        hasher.update(buf);
+        r.consume(n);
+    }
+    Ok(hasher.finalize().iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    }))
+}
+
+/// First difference found by [`SquashFs::compare_with_dir`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// The file is absent from the reference, or is not a regular file there.
+    Missing { path: PathBuf },
+    SizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        got: u64,
+    },
+    /// The contents differ, starting at `offset`.
+    Contents { path: PathBuf, offset: u64 },
+}
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Missing { path } => write!(f, "{:?} is missing from the reference", path),
+            Self::SizeMismatch {
+                path,
+                expected,
+                got,
+            } => write!(
+                f,
+                "{:?} has size {} in the reference, but {} in the image",
+                path, expected, got
+            ),
+            Self::Contents { path, offset } => {
+                write!(
+                    f,
+                    "{:?} differs from the reference at offset {}",
+                    path, offset
+                )
+            }
+        }
+    }
+}
+
+/// Result of [`SquashFs::compare_with_dir`].
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub files: usize,
+    pub bytes: u64,
+    pub divergence: Option<Divergence>,
+}
+impl std::fmt::Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.divergence {
+            None => write!(
+                f,
+                "{} files ({} MB) identical to the reference",
+                self.files,
+                self.bytes / 1_000_000
+            ),
+            Some(divergence) => write!(f, "{} (after {} identical files)", divergence, self.files),
+        }
+    }
+}
+
+/// Extract `image` with `unsquashfs` into `dest`, which must not exist.
+pub fn unsquashfs(image: &Path, dest: &Path) -> Result<(), Error> {
+    let output = std::process::Command::new("unsquashfs")
+        .args(["-no-xattrs", "-d"])
+        .args([dest, image])
+        .output()
+        .map_err(|e| {
+            Error::ReadFailure(std::io::Error::new(
+                e.kind(),
+                format!("Failed to run unsquashfs: {}", e),
+            ))
+        })?;
+    if !output.status.success() {
+        return Err(Error::ReadFailure(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "unsquashfs failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        )));
+    }
+    Ok(())
+}
+
+impl<T, R> SquashFs<R>
+where
+    T: AsyncSeekBufRead,
+    R: deadpool::managed::Manager<Type = T, Error = tokio::io::Error> + Send + Sync,
+{
+    /// Compare every regular file of the image with the file at the same path under `reference`,
+    /// stopping at the first difference.
+    pub async fn compare_with_dir(&self, reference: &Path) -> Result<Verification, Error> {
+        let mut verification = Verification::default();
+        let mut stack = vec![(PathBuf::new(), self.root_inode)];
+        while let Some((dir, inode)) = stack.pop() {
+            for entry in &self.directory_table(inode).await?.entries {
+                let path = dir.join(&entry.name);
+                if entry.is_dir() {
+                    stack.push((path, entry.inode));
+                    continue;
+                }
+                match self.attr(entry.inode) {
+                    Ok(attr) if attr.kind == FileKind::File => {}
+                    // Unsupported inode types
+                    _ => continue,
+                }
+                if let Some(divergence) = self
+                    .compare_file(entry.inode, &path, &reference.join(&path))
+                    .await?
+                {
+                    verification.divergence = Some(divergence);
+                    return Ok(verification);
+                }
+                verification.files += 1;
+                verification.bytes += self.attr(entry.inode)?.size;
+            }
+        }
+        Ok(verification)
+    }
+    async fn compare_file(
+        &self,
+        inode: u32,
+        path: &Path,
+        reference: &Path,
+    ) -> Result<Option<Divergence>, Error> {
+        debug!(?path, "Verifying");
+        let size = self.attr(inode)?.size;
+        let mut file = match tokio::fs::File::open(reference).await {
+            Ok(file) if reference.is_file() => file,
+            _ => return Ok(Some(Divergence::Missing { path: path.into() })),
+        };
+        let expected = file.metadata().await.map_err(Error::ReadFailure)?.len();
+        if expected != size {
+            return Ok(Some(Divergence::SizeMismatch {
+                path: path.into(),
+                expected,
+                got: size,
+            }));
+        }
+        let chunk = 16 * self.superblock.block_size as usize;
+        let mut offset = 0;
+        let mut buf = vec![0; chunk];
+        while offset < size {
+            let data = self
+                .read_file(
+                    inode,
+                    offset as usize,
+                    chunk,
+                    0,
+                    self.superblock.compression,
+                )
+                .await?;
+            if data.is_empty() {
+                return Err(Error::ReadFailure(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            let buf = &mut buf[..data.len()];
+            file.read_exact(buf).await.map_err(Error::ReadFailure)?;
+            if let Some(i) = data.iter().zip(buf.iter()).position(|(a, b)| a != b) {
+                return Ok(Some(Divergence::Contents {
+                    path: path.into(),
+                    offset: offset + i as u64,
+                }));
+            }
+            offset += data.len() as u64;
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,105 @@
+//! Non-fatal issues encountered while parsing an image, see [`crate::SquashFs::warnings`].
+use std::sync::{Arc, Mutex};
+
+use tracing::*;
+
+use crate::inodes::InodeType;
+
+/// Maximal number of events kept; further events are only counted.
+const MAX_EVENTS: usize = 1000;
+
+/// Parsing event that does not prevent using the image, but that callers may want to know about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseEvent {
+    /// Inode of an unsupported type, which is skipped.
+    UnsupportedInode { inode: u32, inode_type: InodeType },
+    /// Directory entry pointing to an inode of an unsupported type, which can be listed but not
+    /// accessed.
+    SkippedEntry {
+        inode: u32,
+        name: String,
+        inode_type: InodeType,
+    },
+    /// Size that is inconsistent with the rest of the image.
+    SuspiciousSize {
+        inode: u32,
+        size: u64,
+        reason: &'static str,
+    },
+}
+impl std::fmt::Display for ParseEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedInode { inode, inode_type } => {
+                write!(
+                    f,
+                    "Skipping unsupported inode {} of type {:?}",
+                    inode, inode_type
+                )
+            }
+            Self::SkippedEntry {
+                inode,
+                name,
+                inode_type,
+            } => write!(
+                f,
+                "Directory entry {:?} points to inode {} of unsupported type {:?}",
+                name, inode, inode_type
+            ),
+            Self::SuspiciousSize {
+                inode,
+                size,
+                reason,
+            } => write!(
+                f,
+                "Suspicious size {} for inode {}: {}",
+                size, inode, reason
+            ),
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+struct Events {
+    events: Vec<ParseEvent>,
+    dropped: usize,
+}
+
+/// Sink for [`ParseEvent`]s, shared between the parsers (including those running in the
+/// background) and the [`crate::SquashFs`].
+///
+/// The default sink does not log the events.
+#[derive(Default, Debug, Clone)]
+pub struct Warnings {
+    events: Arc<Mutex<Events>>,
+    log: bool,
+}
+impl Warnings {
+    /// Create a sink, logging the events with [`warn!`] if `log` is set.
+    pub fn new(log: bool) -> Self {
+        Self {
+            log,
+            ..Default::default()
+        }
+    }
+    pub fn push(&self, event: ParseEvent) {
+        if self.log {
+            warn!("{}", event);
+        }
+        let mut events = self.events.lock().unwrap();
+        if events.events.len() < MAX_EVENTS {
+            events.events.push(event);
+        } else {
+            events.dropped += 1;
+        }
+    }
+    /// Events recorded so far.
+    pub fn events(&self) -> Vec<ParseEvent> {
+        self.events.lock().unwrap().events.clone()
+    }
+    /// Number of events that were not kept, beyond the first 1000.
+    pub fn dropped(&self) -> usize {
+        self.events.lock().unwrap().dropped
+    }
+}
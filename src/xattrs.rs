@@ -0,0 +1,304 @@
+//! Extended attributes, stored in the xattr table and referenced by the extended inodes.
+//!
+//! The table is made of the key/value pairs, in metadata blocks, followed by the xattr id table,
+//! which gives the location of the attributes of each extended inode (several inodes with the
+//! same attributes share their entry).
+//!
+//! See <https://dr-emann.github.io/squashfs/squashfs.html#_xattr_table>
+use futures::TryStreamExt;
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::error::XattrError;
+use super::metadata::{MetadataBlock, MetadataIndex, METADATA_BLOCK_SIZE};
+use super::superblock::SuperBlock;
+use crate::{acl, capability, Error, SquashFs};
+
+/// Index stored in the extended inodes without extended attributes.
+pub(crate) const NO_XATTRS: u32 = u32::MAX;
+/// Flag of the keys whose value is stored out of line, as a reference to another value.
+const OUT_OF_LINE: u16 = 0x0100;
+const ID_ENTRY_SIZE: usize = 16;
+
+/// Extended attribute of an inode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    /// Full name, with its namespace, e.g. `user.comment` or `security.capability`
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    kv_start: u64,
+    count: u32,
+    _unused: u32,
+}
+
+/// Attributes of an inode, in the xattr id table.
+#[derive(Debug, Clone, Copy)]
+struct IdEntry {
+    /// Reference to the first key, relative to the start of the key/value pairs
+    xattr: u64,
+    /// Number of key/value pairs
+    count: u32,
+}
+
+/// Xattr table, with the key/value pairs kept uncompressed in memory.
+#[derive(Default, Debug)]
+pub struct XattrTable {
+    ids: Vec<IdEntry>,
+    /// Uncompressed key/value pairs
+    kv: Vec<u8>,
+    /// Metadata blocks of the key/value pairs, relative to their start
+    index: MetadataIndex,
+}
+impl std::fmt::Display for XattrTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Xattr table with {} entries ({} bytes of key/value pairs)",
+            self.ids.len(),
+            self.kv.len()
+        )
+    }
+}
+
+impl XattrTable {
+    /// Estimate of the memory held by the table, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.ids.capacity() * std::mem::size_of::<IdEntry>()
+            + self.kv.capacity()
+            + self.index.memory_usage()
+    }
+    /// Number of entries, i.e. of distinct sets of attributes.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+    /// Read the xattr table, which is empty if the image has none (`mksquashfs -no-xattrs`).
+    pub async fn from_reader(
+        superblock: &SuperBlock,
+        mut r: impl crate::AsyncSeekBufRead,
+    ) -> Result<Self, XattrError> {
+        let Some(start) = superblock.xattr_id_table_start() else {
+            return Ok(Self::default());
+        };
+        r.seek(std::io::SeekFrom::Start(start))
+            .await
+            .map_err(XattrError::ReadFailure)?;
+        let header: Header =
+            crate::deser::bincode_deser_from(&mut r, 16, superblock.limits.structure)
+                .await
+                .map_err(XattrError::InvalidHeader)?;
+        // Entries take 16 bytes of the image
+        let ids_size = header.count as usize * ID_ENTRY_SIZE;
+        if ids_size as u64 > superblock.bytes_used {
+            return Err(XattrError::InvalidEntry);
+        }
+        let n = ids_size.div_ceil(METADATA_BLOCK_SIZE as usize);
+        let mut locations = Vec::<u64>::with_capacity(n);
+        for _ in 0..n {
+            locations.push(r.read_u64_le().await.map_err(XattrError::ReadFailure)?)
+        }
+        // The key/value pairs precede the metadata blocks of the id table
+        let kv_end = locations.first().copied().unwrap_or(start);
+        if header.kv_start > kv_end || kv_end > superblock.bytes_used {
+            return Err(XattrError::InvalidLocation);
+        }
+        let mut table = Self::default();
+        r.seek(std::io::SeekFrom::Start(header.kv_start))
+            .await
+            .map_err(XattrError::ReadFailure)?;
+        {
+            let mut blocks = std::pin::pin!(MetadataBlock::from_reader_stream(
+                &mut r,
+                kv_end,
+                superblock.codec()
+            ));
+            while let Some((position, block)) = blocks.try_next().await? {
+                table
+                    .index
+                    .push(position - header.kv_start, table.kv.len() as u64);
+                table.kv.extend(block.data);
+            }
+        }
+        let mut ids = Vec::<u8>::with_capacity(ids_size);
+        for l in locations {
+            r.seek(std::io::SeekFrom::Start(l))
+                .await
+                .map_err(XattrError::ReadFailure)?;
+            let block = MetadataBlock::from_reader(&mut r, &superblock.codec()).await?;
+            ids.extend(block.data);
+        }
+        if ids.len() < ids_size {
+            return Err(XattrError::InvalidEntry);
+        }
+        table.ids = ids[..ids_size]
+            .chunks_exact(ID_ENTRY_SIZE)
+            .map(|c| IdEntry {
+                xattr: u64::from_le_bytes(c[..8].try_into().unwrap()),
+                count: u32::from_le_bytes(c[8..12].try_into().unwrap()),
+            })
+            .collect();
+        Ok(table)
+    }
+    /// Attributes of the entry `index`, stored in an extended inode.
+    pub fn get(&self, index: u32) -> Result<Vec<Xattr>, XattrError> {
+        let entry = self
+            .ids
+            .get(index as usize)
+            .ok_or(XattrError::InvalidIndex(index))?;
+        let mut pos = self.locate(entry.xattr)?;
+        (0..entry.count)
+            .map(|_| {
+                let kind = u16::from_le_bytes(self.bytes(&mut pos, 2)?.try_into().unwrap());
+                let name_size = u16::from_le_bytes(self.bytes(&mut pos, 2)?.try_into().unwrap());
+                let prefix = match kind & !OUT_OF_LINE {
+                    0 => "user.",
+                    1 => "trusted.",
+                    2 => "security.",
+                    _ => return Err(XattrError::InvalidEntry),
+                };
+                let name = self.bytes(&mut pos, name_size as usize)?;
+                let name = format!("{}{}", prefix, String::from_utf8_lossy(name));
+                let mut value = self.value(&mut pos)?;
+                if kind & OUT_OF_LINE != 0 {
+                    let reference = value.try_into().map_err(|_| XattrError::InvalidEntry)?;
+                    value = self.value(&mut self.locate(u64::from_le_bytes(reference))?)?;
+                }
+                Ok(Xattr {
+                    name,
+                    value: value.to_vec(),
+                })
+            })
+            .collect()
+    }
+    /// Offset in the key/value pairs of a reference, made of the position of a metadata block
+    /// (relative to the first one) and of the offset in that block.
+    fn locate(&self, reference: u64) -> Result<usize, XattrError> {
+        self.index
+            .offset(reference >> 16, reference & 0xFFFF)
+            .map(|offset| offset as usize)
+            .ok_or(XattrError::InvalidLocation)
+    }
+    /// The `len` bytes at `pos` in the key/value pairs, advancing `pos` past them.
+    fn bytes(&self, pos: &mut usize, len: usize) -> Result<&[u8], XattrError> {
+        let bytes = self
+            .kv
+            .get(*pos..pos.saturating_add(len))
+            .ok_or(XattrError::InvalidEntry)?;
+        *pos += len;
+        Ok(bytes)
+    }
+    /// Value at `pos`, prefixed with its size.
+    fn value(&self, pos: &mut usize) -> Result<&[u8], XattrError> {
+        let size = u32::from_le_bytes(self.bytes(pos, 4)?.try_into().unwrap());
+        self.bytes(pos, size as usize)
+    }
+}
+
+impl<R: deadpool::managed::Manager> SquashFs<R> {
+    /// Extended attributes of a file, directory or symlink inode, empty if it has none.
+    pub async fn xattrs(&self, inode: u32) -> Result<Vec<Xattr>, Error> {
+        let inode_table = self.inode_table.wait().await?;
+        if !inode_table.headers.contains_key(&inode) {
+            return Err(Error::InvalidInode);
+        }
+        match inode_table.xattr_index(inode) {
+            Some(index) => Ok(self.xattr_table.wait().await?.get(index)?),
+            None => Ok(vec![]),
+        }
+    }
+    /// Value of the extended attribute `name` of `inode`, if it has it.
+    pub async fn xattr(&self, inode: u32, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self
+            .xattrs(inode)
+            .await?
+            .into_iter()
+            .find(|x| x.name == name)
+            .map(|x| x.value))
+    }
+    /// Access ACL of `inode`, if it has one.
+    pub async fn acl(&self, inode: u32) -> Result<Option<acl::Acl>, Error> {
+        self.xattr(inode, acl::ACCESS_XATTR)
+            .await?
+            .map(|value| acl::Acl::parse(&value))
+            .transpose()
+    }
+    /// Capabilities of the file `inode`, if it has some.
+    pub async fn file_capabilities(
+        &self,
+        inode: u32,
+    ) -> Result<Option<capability::Capabilities>, Error> {
+        self.xattr(inode, capability::CAPABILITY_XATTR)
+            .await?
+            .map(|value| capability::Capabilities::parse(&value))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(kind: u16, name: &str) -> Vec<u8> {
+        let mut data = kind.to_le_bytes().to_vec();
+        data.extend((name.len() as u16).to_le_bytes());
+        data.extend(name.as_bytes());
+        data
+    }
+    fn value(value: &[u8]) -> Vec<u8> {
+        let mut data = (value.len() as u32).to_le_bytes().to_vec();
+        data.extend(value);
+        data
+    }
+    #[test]
+    fn xattr_table_test() {
+        // Two metadata blocks, the second at position 100 of the table
+        let mut kv = [key(0, "comment"), value(b"first")].concat();
+        let second = kv.len();
+        kv.extend([key(2, "capability"), value(&[1, 2, 3])].concat());
+        let out_of_line = kv.len() - second;
+        kv.extend([key(1 | OUT_OF_LINE, "shared"), value(&11u64.to_le_bytes())].concat());
+        let mut index = MetadataIndex::default();
+        index.push(0, 0);
+        index.push(100, second as u64);
+        let table = XattrTable {
+            ids: vec![
+                IdEntry { xattr: 0, count: 2 },
+                IdEntry {
+                    xattr: (100 << 16) | out_of_line as u64,
+                    count: 1,
+                },
+                IdEntry {
+                    xattr: 1000 << 16,
+                    count: 1,
+                },
+            ],
+            kv,
+            index,
+        };
+        assert_eq!(
+            table.get(0).unwrap(),
+            [
+                Xattr {
+                    name: "user.comment".into(),
+                    value: b"first".to_vec()
+                },
+                Xattr {
+                    name: "security.capability".into(),
+                    value: vec![1, 2, 3]
+                }
+            ]
+        );
+        // Value of the first attribute, after its 11 bytes key
+        let shared = table.get(1).unwrap();
+        assert_eq!(shared[0].name, "trusted.shared");
+        assert_eq!(shared[0].value, b"first");
+        assert!(matches!(table.get(2), Err(XattrError::InvalidLocation)));
+        assert!(matches!(table.get(3), Err(XattrError::InvalidIndex(3))));
+    }
+}
@@ -0,0 +1,73 @@
+//! Fixtures shared by the integration tests.
+use std::path::Path;
+
+use squashfs_async::testing::{ImageSpec, TempImage};
+use squashfs_async::{pools, Error, Options, SquashFs};
+
+pub const BLOCK_SIZE: usize = 131072;
+
+/// Sizes around the block boundaries, by file name.
+pub const SIZES: [(&str, usize); 6] = [
+    ("empty", 0),
+    ("fragment", 100),
+    ("block", BLOCK_SIZE),
+    ("blocks", 3 * BLOCK_SIZE),
+    ("block_plus_one", BLOCK_SIZE + 1),
+    ("block_minus_one", BLOCK_SIZE - 1),
+];
+
+pub fn contents(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8).collect()
+}
+
+/// Write the files of [`SIZES`] to `dir`.
+pub fn write_sizes(dir: &Path) -> std::io::Result<()> {
+    for (name, size) in SIZES {
+        std::fs::write(dir.join(name), contents(size))?;
+    }
+    Ok(())
+}
+
+/// Check that the files of [`SIZES`] are read back in full.
+pub async fn read_sizes(fs: &SquashFs<pools::LocalReadersPoolTokio>) -> Result<(), Error> {
+    for (name, size) in SIZES {
+        let inode = fs.resolve(Path::new(name)).await?;
+        let data = fs
+            .read_file(inode, 0, size, 0, fs.superblock.compression)
+            .await?;
+        assert_eq!(data, contents(size), "{}", name);
+    }
+    Ok(())
+}
+
+/// Image built from the files of a temporary directory, opened with the default options.
+pub struct Fixture {
+    /// Contents of the image
+    pub dir: tempfile::TempDir,
+    pub image: TempImage,
+    pub fs: SquashFs<pools::LocalReadersPoolTokio>,
+}
+impl Fixture {
+    /// Image of the files of [`SIZES`], with blocks of [`BLOCK_SIZE`] and the options of `spec`.
+    pub async fn sizes(spec: ImageSpec) -> anyhow::Result<Self> {
+        Self::new(write_sizes, spec.block_size(BLOCK_SIZE as u32)).await
+    }
+    /// Image of the files created by `write` in a temporary directory.
+    pub async fn new(
+        write: impl FnOnce(&Path) -> std::io::Result<()>,
+        spec: ImageSpec,
+    ) -> anyhow::Result<Self> {
+        let dir = tempfile::tempdir()?;
+        write(dir.path())?;
+        let image = TempImage::new(dir.path(), spec.mksquashfs_options())?;
+        let fs = SquashFs::open(&image.path, &Options::default()).await?;
+        Ok(Self { dir, image, fs })
+    }
+    /// Open the image again, with `options`.
+    pub async fn open(
+        &self,
+        options: &Options,
+    ) -> Result<SquashFs<pools::LocalReadersPoolTokio>, Error> {
+        SquashFs::open(&self.image.path, options).await
+    }
+}
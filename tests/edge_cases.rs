@@ -0,0 +1,1566 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use fuser_async::cache::{DataBlockCache, LRUCache};
+use fuser_async::Filesystem;
+use futures::TryStreamExt;
+
+use squashfs_async::cache::{BlockCache, CacheBuilder, CacheSizes};
+use squashfs_async::content_cache::ContentCache;
+use squashfs_async::events::ErrorKind;
+use squashfs_async::export::TreeFormat;
+use squashfs_async::hooks::{FileHooks, HookFile};
+use squashfs_async::notify::Notifier;
+use squashfs_async::pools::BackendCapabilities;
+use squashfs_async::testing::{Builder, ImageSpec, MountHarness, TempImage};
+use squashfs_async::{
+    pools, Compression, CompressionOptions, Error, Options, ReadHints, SquashFs, XzFilters,
+};
+
+mod common;
+use common::{contents, read_sizes, write_sizes, Fixture, BLOCK_SIZE, SIZES};
+
+#[tokio::test]
+async fn content_cache() -> anyhow::Result<()> {
+    // Two images with the same blocks, sharing a cache, with the tail ends of the files in
+    // fragments or in data blocks shorter than the block size
+    let cache = Arc::new(ContentCache::new(10, BLOCK_SIZE as u32)?);
+    let mut images = vec![];
+    for fragments in [true, true, false, false] {
+        let spec = ImageSpec::new()
+            .compression(Some(Compression::Gzip))
+            .fragments(fragments);
+        let Fixture { image, fs, .. } = Fixture::sizes(spec).await?;
+        images.push((image, fs.with_content_cache(cache.clone())?));
+    }
+    for _ in 0..2 {
+        for (_, fs) in &images {
+            read_sizes(fs).await?;
+        }
+    }
+    assert!(ContentCache::new(10, BLOCK_SIZE as u32 * 2)
+        .map(|cache| images.pop().unwrap().1.with_content_cache(Arc::new(cache)))?
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn custom_caches() -> anyhow::Result<()> {
+    let fixture = Fixture::sizes(ImageSpec::new()).await?;
+    let built = Arc::new(Mutex::new(vec![]));
+    let caches = CacheBuilder::new().blocks_with({
+        let built = built.clone();
+        move |sizes: CacheSizes| {
+            built.lock().unwrap().push(sizes.block_size);
+            Ok(Arc::new(<LRUCache as DataBlockCache<Box<Error>>>::new(
+                sizes.capacity_mb,
+                sizes.block_size,
+                sizes.total_size,
+            )?) as Arc<dyn BlockCache>)
+        }
+    });
+    let options = Options {
+        caches,
+        ..Default::default()
+    };
+    let fs = fixture.open(&options).await?;
+    assert_eq!(*built.lock().unwrap(), [BLOCK_SIZE as u64]);
+    for _ in 0..2 {
+        let inode = fs.resolve(Path::new("blocks")).await?;
+        let data = fs
+            .read_file(inode, 0, 3 * BLOCK_SIZE, 0, fs.superblock.compression)
+            .await?;
+        assert_eq!(data, contents(3 * BLOCK_SIZE));
+    }
+    // Blocks recompressed in the cache
+    let options = Options {
+        compressed_cache: true,
+        ..Default::default()
+    };
+    let fs = fixture.open(&options).await?;
+    for _ in 0..2 {
+        read_sizes(&fs).await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn http() -> anyhow::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let Fixture {
+        fs, image: _image, ..
+    } = Fixture::sizes(ImageSpec::new()).await?;
+    let serve = |fs| async move {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(squashfs_async::http::serve(Arc::new(fs), listener));
+        anyhow::Ok(addr)
+    };
+    let addr = serve(fs).await?;
+    let get = |addr, request: String| async move {
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await?;
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let body = response.split_off(end + 4);
+        anyhow::Ok((String::from_utf8(response)?, body))
+    };
+    let (head, body) = get(addr, "GET /blocks HTTP/1.1\r\n\r\n".into()).await?;
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, contents(3 * BLOCK_SIZE));
+    let (head, body) = get(
+        addr,
+        "GET /blocks HTTP/1.1\r\nRange: bytes=10-19\r\n\r\n".into(),
+    )
+    .await?;
+    assert!(head.starts_with("HTTP/1.1 206"));
+    assert_eq!(body, contents(20)[10..]);
+    let (head, body) = get(addr, "GET / HTTP/1.1\r\n\r\n".into()).await?;
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(String::from_utf8(body)?.lines().count(), SIZES.len());
+    let (head, _) = get(addr, "GET /missing HTTP/1.1\r\n\r\n".into()).await?;
+    assert!(head.starts_with("HTTP/1.1 404"));
+    let (head, _) = get(addr, "PUT /blocks HTTP/1.1\r\n\r\n".into()).await?;
+    assert!(head.starts_with("HTTP/1.1 405"));
+    // Symlinks redirect to their target, when it is in the image
+    let Fixture {
+        fs, image: _image, ..
+    } = Fixture::new(
+        |dir| {
+            std::fs::create_dir(dir.join("dir"))?;
+            std::fs::write(dir.join("dir/a file"), contents(10))?;
+            std::os::unix::fs::symlink("a file", dir.join("dir/link"))?;
+            std::os::unix::fs::symlink("/etc/hostname", dir.join("absolute"))
+        },
+        ImageSpec::new(),
+    )
+    .await?;
+    let addr = serve(fs).await?;
+    let (head, _) = get(addr, "GET /dir/link HTTP/1.1\r\n\r\n".into()).await?;
+    assert!(head.starts_with("HTTP/1.1 302"));
+    assert!(head.contains("Location: /dir/a%20file\r\n"));
+    let (head, _) = get(addr, "GET /absolute HTTP/1.1\r\n\r\n".into()).await?;
+    assert!(head.starts_with("HTTP/1.1 404"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn grep() -> anyhow::Result<()> {
+    // Lines spanning several chunks
+    let long = format!("{}hello\n", "x".repeat(40 * BLOCK_SIZE));
+    let write = |dir: &Path| {
+        std::fs::create_dir(dir.join("logs"))?;
+        std::fs::write(dir.join("a.txt"), "hello\r\nworld\nhello again")?;
+        std::fs::write(dir.join("logs/b.log"), "hello")?;
+        std::fs::write(dir.join("binary"), "\0hello")?;
+        std::fs::write(dir.join("long.txt"), long.repeat(2))
+    };
+    let Fixture { fs, .. } = Fixture::new(write, ImageSpec::new()).await?;
+    let pattern = regex::Regex::new("hello")?;
+    let matches: Vec<_> = fs
+        .grep(pattern.clone(), None)
+        .map_ok(|m| (m.path.display().to_string(), m.line_number, m.line.len()))
+        .try_collect()
+        .await?;
+    assert_eq!(
+        matches,
+        [
+            ("a.txt".into(), 1, 5),
+            ("a.txt".into(), 3, 11),
+            ("long.txt".into(), 1, long.len() - 1),
+            ("long.txt".into(), 2, long.len() - 1),
+            ("logs/b.log".into(), 1, 5)
+        ]
+    );
+    let matches: Vec<_> = fs.grep(pattern, Some("logs/".into())).try_collect().await?;
+    assert_eq!(matches.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn export_file() -> anyhow::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    // Disk image with holes
+    let mut disk = contents(BLOCK_SIZE);
+    disk.extend(vec![0; 2 * BLOCK_SIZE]);
+    disk.extend(contents(BLOCK_SIZE + 100));
+    let write = |dir: &Path| std::fs::write(dir.join("disk.img"), &disk);
+    let Fixture { fs, .. } = Fixture::new(write, ImageSpec::new()).await?;
+    let inode = fs.resolve(Path::new("disk.img")).await?;
+    let output = tempfile::tempdir()?;
+    let dest = output.path().join("disk.img");
+    let written = fs.export_file(inode, &dest, false).await?;
+    assert_eq!(written, 2 * BLOCK_SIZE as u64 + 100);
+    assert_eq!(std::fs::read(&dest)?, disk);
+    assert!(std::fs::metadata(&dest)?.blocks() * 512 < disk.len() as u64);
+    Ok(())
+}
+
+#[tokio::test]
+async fn extract_to() -> anyhow::Result<()> {
+    use squashfs_async::export::{DuplicatePolicy, ExtractOptions};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    // Duplicates, hard links and a read-only directory
+    let write = |dir: &Path| {
+        std::fs::create_dir_all(dir.join("a/b"))?;
+        std::fs::write(dir.join("a/original"), contents(BLOCK_SIZE + 100))?;
+        std::fs::write(dir.join("a/b/copy"), contents(BLOCK_SIZE + 100))?;
+        std::fs::write(dir.join("other"), contents(100))?;
+        std::fs::hard_link(dir.join("other"), dir.join("link"))?;
+        std::fs::set_permissions(dir.join("a/b"), std::fs::Permissions::from_mode(0o555))
+    };
+    let Fixture { fs, .. } = Fixture::new(write, ImageSpec::new()).await?;
+    for duplicates in [DuplicatePolicy::Copy, DuplicatePolicy::HardLink] {
+        let output = tempfile::tempdir()?;
+        let options = ExtractOptions {
+            duplicates,
+            ..Default::default()
+        };
+        let stats = fs.extract_to(output.path(), options).await?;
+        let dest = output.path();
+        assert_eq!(stats.directories, 2);
+        assert_eq!(
+            std::fs::read(dest.join("a/b/copy"))?,
+            contents(BLOCK_SIZE + 100)
+        );
+        assert_eq!(std::fs::read(dest.join("link"))?, contents(100));
+        let ino = |path: &str| std::fs::metadata(dest.join(path)).map(|m| m.ino());
+        assert_eq!(ino("link")?, ino("other")?);
+        assert_eq!(
+            ino("a/original")? == ino("a/b/copy")?,
+            duplicates == DuplicatePolicy::HardLink
+        );
+        assert_eq!(stats.files, 4 - stats.hard_links);
+        let mode = std::fs::metadata(dest.join("a/b"))?.mode();
+        assert_eq!(mode & 0o777, 0o555);
+        // Writable again, for the cleanup
+        std::fs::set_permissions(dest.join("a/b"), std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    // Resuming, with `other` written and the first block of `a/original`
+    let output = tempfile::tempdir()?;
+    let dest = output.path();
+    std::fs::create_dir(dest.join("a"))?;
+    std::fs::write(dest.join("other"), b"resumed")?;
+    std::fs::write(dest.join("a/original"), contents(BLOCK_SIZE))?;
+    let state = dest.join("state.jsonl");
+    let superblock = &fs.superblock;
+    let image = serde_json::json!({"image": {
+        "bytes_used": superblock.bytes_used,
+        "inode_count": superblock.inode_count,
+        "mkfs_time": superblock.creation_time().duration_since(std::time::UNIX_EPOCH)?.as_secs(),
+    }});
+    let other = fs.resolve(Path::new("other")).await?;
+    let original = fs.resolve(Path::new("a/original")).await?;
+    std::fs::write(
+        &state,
+        format!(
+            "{}\n{}\n{}\n{{\"done\"",
+            image,
+            serde_json::json!({"done": {"inode": other}}),
+            serde_json::json!({"progress": {"inode": original, "offset": BLOCK_SIZE + 10}}),
+        ),
+    )?;
+    let options = ExtractOptions {
+        state: Some(state.clone()),
+        ..Default::default()
+    };
+    let stats = fs.extract_to(dest, options).await?;
+    assert_eq!(stats.resumed, 1);
+    assert_eq!(stats.bytes, (BLOCK_SIZE + 100) as u64 + 100);
+    assert_eq!(std::fs::read(dest.join("link"))?, b"resumed");
+    assert_eq!(
+        std::fs::read(dest.join("a/original"))?,
+        contents(BLOCK_SIZE + 100)
+    );
+    assert!(!state.exists());
+    std::fs::set_permissions(dest.join("a/b"), std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn symlinks() -> anyhow::Result<()> {
+    let write = |dir: &Path| {
+        std::fs::write(dir.join("file"), contents(100))?;
+        std::os::unix::fs::symlink("file", dir.join("relative"))?;
+        std::os::unix::fs::symlink("/nowhere/target", dir.join("dangling"))
+    };
+    // Along devices, a fifo and a socket, which are not supported
+    let Fixture { fs, .. } = Fixture::new(write, ImageSpec::new().devices(true)).await?;
+    let root = fuser::FUSE_ROOT_ID;
+    for (name, target) in [("relative", "file"), ("dangling", "/nowhere/target")] {
+        let attr = fs.lookup(root, OsStr::new(name)).await?;
+        assert_eq!(attr.kind, fuser::FileType::Symlink);
+        assert_eq!(attr.size, target.len() as u64);
+        assert_eq!(fs.readlink(attr.ino).await?, Path::new(target));
+    }
+    let file = fs.lookup(root, OsStr::new("file")).await?;
+    assert!(fs.readlink(file.ino).await.is_err());
+    let links = fs
+        .readdir(root, 0)
+        .await?
+        .filter(|e| e.file_type == fuser::FileType::Symlink)
+        .count();
+    assert_eq!(links, 2);
+    // Targets kept in the inode table
+    let inode_table = fs.inode_table.wait().await?;
+    let mut targets: Vec<_> = inode_table.symlinks.values().map(|l| l.target()).collect();
+    targets.sort();
+    assert_eq!(targets, ["/nowhere/target", "file"]);
+    assert_eq!(inode_table.ids().count(), inode_table.headers.len());
+    // Targets in the exported tree
+    let mut listing = vec![];
+    fs.export_tree(&mut listing, TreeFormat::Ndjson).await?;
+    let entries: Vec<serde_json::Value> = listing
+        .split(|c| *c == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(serde_json::from_slice)
+        .collect::<Result<_, _>>()?;
+    let dangling = entries.iter().find(|e| e["path"] == "/dangling").unwrap();
+    assert_eq!(dangling["type"], "symlink");
+    assert_eq!(dangling["target"], "/nowhere/target");
+    assert_eq!(dangling["size"], "/nowhere/target".len());
+    let file = entries.iter().find(|e| e["path"] == "/file").unwrap();
+    assert!(file["target"].is_null());
+    // Links recreated by the extraction, dangling or not
+    let output = tempfile::tempdir()?;
+    let stats = fs.extract_to(output.path(), Default::default()).await?;
+    assert_eq!((stats.files, stats.symlinks, stats.skipped), (1, 2, 4));
+    assert_eq!(
+        std::fs::read_link(output.path().join("dangling"))?,
+        Path::new("/nowhere/target")
+    );
+    assert_eq!(
+        std::fs::read_link(output.path().join("relative"))?,
+        Path::new("file")
+    );
+    assert_eq!(
+        std::fs::read(output.path().join("relative"))?,
+        contents(100)
+    );
+    // Extracting again replaces the links
+    fs.extract_to(output.path(), Default::default()).await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn symlinks_mount() -> anyhow::Result<()> {
+    let write = |dir: &Path| {
+        std::fs::write(dir.join("file"), contents(100))?;
+        std::os::unix::fs::symlink("file", dir.join("relative"))?;
+        std::os::unix::fs::symlink("/nowhere/target", dir.join("dangling"))
+    };
+    let Fixture {
+        fs, image: _image, ..
+    } = Fixture::new(write, ImageSpec::new()).await?;
+    let mount = MountHarness::mount_temp(fs).await?;
+    let path = |name| mount.mountpoint().join(name);
+    assert!(tokio::fs::symlink_metadata(path("relative"))
+        .await?
+        .file_type()
+        .is_symlink());
+    assert_eq!(
+        tokio::fs::read_link(path("relative")).await?,
+        Path::new("file")
+    );
+    assert_eq!(
+        tokio::fs::read_link(path("dangling")).await?,
+        Path::new("/nowhere/target")
+    );
+    // Followed by the kernel
+    assert_eq!(tokio::fs::read(path("relative")).await?, contents(100));
+    assert!(tokio::fs::metadata(path("dangling")).await.is_err());
+    let error = tokio::fs::read_link(path("file")).await.unwrap_err();
+    assert_eq!(error.raw_os_error(), Some(libc::EINVAL));
+    mount.unmount().await
+}
+
+#[tokio::test]
+async fn xattrs() -> anyhow::Result<()> {
+    let value = b"squashfs";
+    let write = |dir: &Path| {
+        std::fs::write(dir.join("tagged"), contents(100))?;
+        std::fs::write(dir.join("plain"), contents(100))?;
+        let path =
+            std::ffi::CString::new(dir.join("tagged").into_os_string().into_encoded_bytes())?;
+        let set = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                c"user.comment".as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        match set {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+    let Fixture { fs, .. } = Fixture::new(write, ImageSpec::new()).await?;
+    assert_eq!(fs.xattr_table.get()?.len(), 1);
+    let tagged = fs.resolve(Path::new("tagged")).await?;
+    let xattrs = fs.xattrs(tagged).await?;
+    assert_eq!(xattrs.len(), 1);
+    assert_eq!(xattrs[0].name, "user.comment");
+    assert_eq!(
+        fs.xattr(tagged, "user.comment").await?.as_deref(),
+        Some(&value[..])
+    );
+    assert_eq!(fs.acl(tagged).await?, None);
+    let plain = fs.resolve(Path::new("plain")).await?;
+    assert!(fs.xattrs(plain).await?.is_empty());
+    assert!(fs.xattrs(u32::MAX).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn gensquashfs() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    write_sizes(dir.path())?;
+    std::fs::create_dir(dir.path().join("empty_dir"))?;
+    let spec = ImageSpec::new().block_size(BLOCK_SIZE as u32);
+    for builder in [Builder::Mksquashfs, Builder::Gensquashfs] {
+        println!("Builder {:?}", builder);
+        let options = match builder {
+            Builder::Mksquashfs => spec.mksquashfs_options(),
+            Builder::Gensquashfs => spec.gensquashfs_options()?,
+        };
+        let image = TempImage::with_builder(builder, dir.path(), options)?;
+        let fs = SquashFs::<pools::LocalReadersPoolTokio>::open(&image.path, &Options::default())
+            .await?;
+        read_sizes(&fs).await?;
+        let inode = fs.resolve(Path::new("empty_dir")).await?;
+        assert!(fs.directory_table(inode).await?.entries.is_empty());
+        let verification = fs.compare_with_dir(dir.path()).await?;
+        assert!(verification.divergence.is_none(), "{}", verification);
+        assert_eq!(verification.files, SIZES.len());
+    }
+    assert!(ImageSpec::new()
+        .compression(None)
+        .gensquashfs_options()
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn block_checksums() -> anyhow::Result<()> {
+    let fixture = Fixture::sizes(ImageSpec::new()).await?;
+    let checksums = fixture.fs.block_checksums().await?;
+    // Five full blocks, and the fragment blocks
+    assert!(checksums.len() > 5);
+    let sidecar = squashfs_async::checksums::sidecar_path(&fixture.image.path);
+    checksums.write_to(std::fs::File::create(&sidecar)?)?;
+    let options = Options {
+        block_checksums: Some(sidecar.clone()),
+        ..Default::default()
+    };
+    read_sizes(&fixture.open(&options).await?).await?;
+    // Corrupted digest
+    let mut corrupted = std::fs::read(&sidecar)?;
+    *corrupted.last_mut().unwrap() ^= 1;
+    std::fs::write(&sidecar, corrupted)?;
+    assert!(matches!(
+        read_sizes(&fixture.open(&options).await?).await,
+        Err(Error::BlockChecksum { .. })
+    ));
+    // Sidecar of another image
+    std::fs::write(fixture.dir.path().join("other"), contents(10))?;
+    let spec = ImageSpec::new().block_size(BLOCK_SIZE as u32);
+    let other = TempImage::new(fixture.dir.path(), spec.mksquashfs_options())?;
+    assert!(matches!(
+        SquashFs::<pools::LocalReadersPoolTokio>::open(&other.path, &options).await,
+        Err(Error::InvalidChecksums(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn supervisor() -> anyhow::Result<()> {
+    // Two images of the same files
+    let images = [
+        Fixture::sizes(ImageSpec::new()).await?,
+        Fixture::sizes(ImageSpec::new()).await?,
+    ];
+    let dir = tempfile::tempdir()?;
+    for (fixture, name) in images.iter().zip(["a.squashfs", "b.sqfs"]) {
+        std::fs::copy(&fixture.image.path, dir.path().join(name))?;
+    }
+    let supervisor = squashfs_async::supervisor::Supervisor::<pools::LocalReadersPoolTokio>::new(
+        dir.path(),
+        &Options::default(),
+        std::time::Duration::ZERO,
+    )
+    .await?;
+    let root = fuser::FUSE_ROOT_ID;
+    let closed = vec![("a".to_string(), false), ("b".to_string(), false)];
+    assert_eq!(supervisor.images().await, closed);
+    // Opened on first access, concurrently
+    let (a, b) = futures::try_join!(
+        supervisor.lookup(root, OsStr::new("a")),
+        supervisor.lookup(root, OsStr::new("b"))
+    )?;
+    let file = |dir: u64| {
+        let supervisor = &supervisor;
+        async move {
+            let attr = supervisor.lookup(dir, OsStr::new("blocks")).await?;
+            anyhow::Ok(attr.ino)
+        }
+    };
+    let (file_a, file_b) = (file(a.ino).await?, file(b.ino).await?);
+    assert_ne!(file_a, file_b);
+    assert!(supervisor.images().await.iter().all(|(_, open)| *open));
+    // Only the image without open handles is closed
+    let fh = supervisor.open(file_a, libc::O_RDONLY).await?;
+    assert_eq!(supervisor.unmount_idle().await, 1);
+    assert_eq!(
+        supervisor.images().await,
+        [("a".to_string(), true), ("b".to_string(), false)]
+    );
+    let data = supervisor.read(file_a, fh, 0, 100).await?;
+    assert_eq!(data, contents(100));
+    supervisor.release(file_a, fh).await?;
+    assert_eq!(supervisor.unmount_idle().await, 1);
+    assert_eq!(supervisor.images().await, closed);
+    // Inodes remain valid across reopenings
+    let fh = supervisor.open(file_b, libc::O_RDONLY).await?;
+    let data = supervisor.read(file_b, fh, 0, 100).await?;
+    assert_eq!(data, contents(100));
+    supervisor.release(file_b, fh).await?;
+    assert_eq!(file(b.ino).await?, file_b);
+    Ok(())
+}
+
+#[tokio::test]
+async fn limits() -> anyhow::Result<()> {
+    let name = "a".repeat(100);
+    let fixture = Fixture::new(
+        |dir| {
+            std::fs::write(dir.join(&name), contents(10))?;
+            std::os::unix::fs::symlink("b".repeat(200), dir.join("link"))
+        },
+        ImageSpec::new(),
+    )
+    .await?;
+    fixture.fs.resolve(Path::new(&name)).await?;
+    // Images with names or link targets beyond lowered limits are rejected
+    for options in [
+        Options {
+            max_name_size: 50,
+            ..Default::default()
+        },
+        Options {
+            max_symlink_size: 100,
+            ..Default::default()
+        },
+    ] {
+        assert!(fixture.open(&options).await.is_err());
+    }
+    for options in [
+        Options {
+            max_structure_size: 10,
+            ..Default::default()
+        },
+        Options {
+            max_name_size: 1000,
+            ..Default::default()
+        },
+    ] {
+        assert!(matches!(
+            fixture.open(&options).await,
+            Err(Error::InvalidOptions(_))
+        ));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn xz_bcj() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    write_sizes(dir.path())?;
+    let spec = ImageSpec::new()
+        .compression(Some(Compression::Xz))
+        .block_size(BLOCK_SIZE as u32);
+    let mut mksquashfs_options = spec.mksquashfs_options();
+    mksquashfs_options.extend(["-Xbcj".into(), "x86".into()]);
+    let image = TempImage::new(dir.path(), mksquashfs_options)?;
+    let fs =
+        SquashFs::<pools::LocalReadersPoolTokio>::open(&image.path, &Options::default()).await?;
+    assert!(matches!(
+        fs.superblock.compression_options,
+        Some(CompressionOptions::Xz { filters, .. }) if XzFilters::from_bits_truncate(filters).contains(XzFilters::X86)
+    ));
+    read_sizes(&fs).await?;
+    // A memory limit too low for the image, which only applies to the image opened with it
+    let options = Options {
+        xz_memlimit_mb: Some(0),
+        xz_ignore_compressor_options: true,
+        ..Default::default()
+    };
+    let limited = SquashFs::<pools::LocalReadersPoolTokio>::open(&image.path, &options).await;
+    assert!(match limited {
+        Ok(limited) => read_sizes(&limited).await.is_err(),
+        Err(_) => true,
+    });
+    read_sizes(&fs).await?;
+    read_sizes(&SquashFs::open(&image.path, &Options::default()).await?).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn scheduler_single_reader() -> anyhow::Result<()> {
+    let fixture = Fixture::sizes(ImageSpec::new()).await?;
+    // Reads hold the only pooled reader while waiting on the scheduler
+    let options = Options {
+        readers: 1,
+        scheduler_window: Some(std::time::Duration::from_millis(1)),
+        ..Default::default()
+    };
+    let fs = fixture.open(&options).await?;
+    let reads = futures::future::try_join_all(SIZES.map(|(name, size)| {
+        let fs = &fs;
+        async move {
+            let inode = fs.resolve(Path::new(name)).await?;
+            let data = fs
+                .read_file(inode, 0, size, 0, fs.superblock.compression)
+                .await?;
+            assert_eq!(data, contents(size), "{}", name);
+            Ok::<_, Error>(())
+        }
+    }));
+    tokio::time::timeout(std::time::Duration::from_secs(10), reads).await??;
+    Ok(())
+}
+
+#[derive(Default)]
+struct RecordingNotifier {
+    inodes: Mutex<usize>,
+    entries: Mutex<usize>,
+}
+impl Notifier for RecordingNotifier {
+    fn inval_inode(&self, _ino: u64, _offset: i64, _len: i64) -> std::io::Result<()> {
+        *self.inodes.lock().unwrap() += 1;
+        Ok(())
+    }
+    fn inval_entry(&self, _parent: u64, _name: &std::ffi::OsStr) -> std::io::Result<()> {
+        *self.entries.lock().unwrap() += 1;
+        Ok(())
+    }
+}
+
+/// Operations seen by the hooks, as (operation, inode, paths).
+#[derive(Default)]
+struct RecordingHooks(Mutex<Vec<(&'static str, u32, usize)>>);
+impl FileHooks for RecordingHooks {
+    fn on_open(&self, file: &HookFile, _fh: u64) {
+        let mut calls = self.0.lock().unwrap();
+        calls.push(("open", file.inode, file.paths.len()));
+    }
+    fn on_release(&self, file: &HookFile, _fh: u64) {
+        let mut calls = self.0.lock().unwrap();
+        calls.push(("release", file.inode, file.paths.len()));
+    }
+    fn on_read_error(&self, file: &HookFile, _offset: u64, _size: u64, _error: &Error) {
+        let mut calls = self.0.lock().unwrap();
+        calls.push(("read_error", file.inode, file.paths.len()));
+    }
+}
+
+/// Images of [`SIZES`] with and without fragments and compression, with whether they have
+/// fragments and store their data uncompressed.
+async fn variants() -> anyhow::Result<Vec<(bool, bool, Fixture)>> {
+    let mut variants = vec![];
+    for fragments in [true, false] {
+        for compression in [None, Some(Compression::Gzip)] {
+            println!("Fragments {}, compression {:?}", fragments, compression);
+            let spec = ImageSpec::new()
+                .fragments(fragments)
+                .compression(compression);
+            let fixture = Fixture::sizes(spec).await?;
+            variants.push((fragments, compression.is_none(), fixture));
+        }
+    }
+    Ok(variants)
+}
+
+#[tokio::test]
+async fn reads() -> anyhow::Result<()> {
+    for (_, uncompressed, Fixture { fs, dir, .. }) in variants().await? {
+        // `-noD -noF` images are read without decoders
+        assert_eq!(fs.superblock.uncompressed_data(), uncompressed);
+        let verification = fs.compare_with_dir(dir.path()).await?;
+        assert!(verification.divergence.is_none(), "{}", verification);
+        for (name, size) in SIZES {
+            let expected = contents(size);
+            let inode = fs.resolve(Path::new(name)).await?;
+            assert_eq!(fs.attr(inode)?.size, size as u64, "{}", name);
+            let read = |offset: usize, len: usize| {
+                fs.read_file(inode, offset, len, 0, fs.superblock.compression)
+            };
+            // Whole file
+            assert_eq!(read(0, size + 1).await?, expected, "{}", name);
+            // Copies, past the end of the file
+            for offset in [0, 1, size, size + 1] {
+                let mut copy = vec![];
+                let copied = fs
+                    .copy_file(inode, offset as u64, BLOCK_SIZE as u64 * 2, 0, &mut copy)
+                    .await?;
+                assert_eq!(copied as usize, copy.len());
+                assert_eq!(
+                    copy,
+                    expected[offset.min(size)..(offset + 2 * BLOCK_SIZE).min(size)],
+                    "{}",
+                    name
+                );
+            }
+            // Reads around the block boundaries, up to the end of the file
+            for offset in [0, 1, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE + 1, size] {
+                if offset > size {
+                    assert!(read(offset, 10).await.is_err(), "{}", name);
+                    continue;
+                }
+                let data = read(offset, 10).await?;
+                assert_eq!(data, expected[offset..(offset + 10).min(size)], "{}", name);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn fragments_table() -> anyhow::Result<()> {
+    for (fragments, uncompressed, Fixture { fs, image, .. }) in variants().await? {
+        // Images created with `-no-fragments` have no fragments table to read
+        assert_eq!(fs.superblock.has_fragments(), fragments);
+        assert_eq!(
+            fs.fragments_table.wait().await?.locations.is_empty(),
+            !fragments
+        );
+        let reader = tokio::io::BufReader::new(tokio::fs::File::open(&image.path).await?);
+        let entries: Vec<_> = fs
+            .fragments_table
+            .wait()
+            .await?
+            .entries(reader)
+            .try_collect()
+            .await?;
+        assert_eq!(entries.len(), fs.superblock.fragment_entry_count as usize);
+        for (i, (index, entry)) in entries.iter().enumerate() {
+            assert_eq!(*index, i as u32);
+            assert!(entry.data_range().end <= fs.superblock.bytes_used);
+            assert_eq!(entry.compressed(), !uncompressed);
+        }
+        let analysis = fs.analyze(2).await?;
+        assert_eq!(analysis.fragments.count, if fragments { 3 } else { 0 });
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn digest() -> anyhow::Result<()> {
+    let fixture = Fixture::sizes(ImageSpec::new()).await?;
+    let digest = squashfs_async::verify::image_digest(tokio::io::BufReader::new(
+        tokio::fs::File::open(&fixture.image.path).await?,
+    ))
+    .await?;
+    let options = |digest: &str| Options {
+        expected_digest: Some(digest.into()),
+        ..Default::default()
+    };
+    fixture.open(&options(&digest)).await?;
+    assert!(matches!(
+        fixture.open(&options(&"0".repeat(64))).await,
+        Err(Error::DigestMismatch { .. })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn inode_table_index() -> anyhow::Result<()> {
+    let Fixture { fs, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    let inode_table = fs.inode_table.wait().await?;
+    let root = fs.superblock.root_inode;
+    let offset = inode_table
+        .index
+        .offset(root.block_start(), root.block_offset())
+        .unwrap();
+    assert_eq!(inode_table.inode_ref(offset), Some(root));
+    assert_eq!(
+        inode_table.inode_ref(0),
+        Some(squashfs_async::inodes::InodeRef::new(0, 0))
+    );
+    Ok(())
+}
+
+/// Open the image made of `data`.
+async fn open_bytes(
+    data: &[u8],
+) -> anyhow::Result<Result<SquashFs<pools::LocalReadersPoolTokio>, Error>> {
+    let file = tempfile::NamedTempFile::new()?;
+    std::fs::write(&file, data)?;
+    Ok(SquashFs::open(file.path(), &Options::default()).await)
+}
+
+#[tokio::test]
+async fn truncated() -> anyhow::Result<()> {
+    let Fixture { fs, image, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    let data = std::fs::read(&image.path)?;
+    // Truncated images are rejected when opened
+    assert!(matches!(
+        open_bytes(&data[..fs.superblock.bytes_used as usize / 2]).await?,
+        Err(Error::Truncated { .. })
+    ));
+    // Tables beyond the bytes used are rejected
+    let mut bounded = data.clone();
+    let bytes_used = fs.superblock.inode_table_start - 1;
+    bounded[40..48].copy_from_slice(&bytes_used.to_le_bytes());
+    assert!(matches!(
+        open_bytes(&bounded).await?,
+        Err(Error::OutOfBounds { .. })
+    ));
+    // Including the xattr table, with the `NO_XATTRS` flag cleared
+    let mut bounded = data.clone();
+    let flags = u16::from_le_bytes([bounded[24], bounded[25]]) & !0x0200;
+    bounded[24..26].copy_from_slice(&flags.to_le_bytes());
+    bounded[56..64].copy_from_slice(&(fs.superblock.bytes_used + 1).to_le_bytes());
+    assert!(matches!(
+        open_bytes(&bounded).await?,
+        Err(Error::OutOfBounds { .. })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn trailer() -> anyhow::Result<()> {
+    let Fixture { fs, image, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    // Trailing bytes are ignored, and can be retrieved
+    let signed = tempfile::NamedTempFile::new()?;
+    let mut data = std::fs::read(&image.path)?;
+    data.truncate(fs.superblock.bytes_used as usize);
+    data.extend(b"SIGNATURE");
+    std::fs::write(&signed, &data)?;
+    let fs_signed =
+        SquashFs::<pools::LocalReadersPoolTokio>::open(signed.path(), &Options::default()).await?;
+    assert_eq!(fs_signed.trailer(1024).await?, b"SIGNATURE");
+    assert_eq!(fs_signed.trailer(3).await?, b"SIG");
+    let blocks = fs_signed.resolve(Path::new("blocks")).await?;
+    assert_eq!(
+        fs_signed
+            .read_file(blocks, 0, 10, 0, fs_signed.superblock.compression)
+            .await?,
+        contents(10)
+    );
+    // The padding of the image itself
+    assert!(fs.trailer(u64::MAX).await?.iter().all(|b| *b == 0));
+    Ok(())
+}
+
+/// Image whose `blocks` file has a corrupted first block, and the inode of that file.
+async fn corrupted() -> anyhow::Result<(tempfile::NamedTempFile, u32)> {
+    let Fixture { fs, image, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    let inode = fs.resolve(Path::new("blocks")).await?;
+    let start = fs.inode_table.wait().await?.files[&inode].blocks_start() as usize;
+    let corrupted = tempfile::NamedTempFile::new()?;
+    let mut data = std::fs::read(&image.path)?;
+    data[start..start + 16].fill(0xff);
+    std::fs::write(&corrupted, &data)?;
+    Ok((corrupted, inode))
+}
+
+#[tokio::test]
+async fn read_errors() -> anyhow::Result<()> {
+    let (image, inode) = corrupted().await?;
+    let fs =
+        SquashFs::<pools::LocalReadersPoolTokio>::open(image.path(), &Options::default()).await?;
+    // Failed reads are published, with their context
+    let mut errors = fs.subscribe_errors();
+    assert!(fs
+        .read_file(inode, 0, 10, 0, fs.superblock.compression)
+        .await
+        .is_err());
+    let event = errors.try_recv()?;
+    assert_eq!(event.kind, ErrorKind::Decompress);
+    assert_eq!((event.inode, event.offset, event.size), (inode, 0, 10));
+    assert_eq!(event.request_id, None);
+    assert!(event.paths.is_empty());
+    // With the paths, once the index is built
+    assert_eq!(fs.paths_of(inode).await?, [Path::new("blocks")]);
+    assert!(fs
+        .read_file(inode, 0, 10, 0, fs.superblock.compression)
+        .await
+        .is_err());
+    assert_eq!(errors.try_recv()?.paths, [Path::new("blocks")]);
+    // FUSE reads have a request id
+    let ino_fuse = fs.ino_to_fuse(inode);
+    let fh = Filesystem::open(&fs, ino_fuse, libc::O_RDONLY).await?;
+    assert!(Filesystem::read(&fs, ino_fuse, fh, 0, 10).await.is_err());
+    assert!(errors.try_recv()?.request_id.is_some());
+    Filesystem::release(&fs, ino_fuse, fh).await?;
+    // Invalid requests are not reported
+    assert!(fs
+        .read_file(inode, 4 * BLOCK_SIZE, 10, 0, fs.superblock.compression)
+        .await
+        .is_err());
+    assert!(errors.try_recv().is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn hooks() -> anyhow::Result<()> {
+    let (image, inode) = corrupted().await?;
+    let fs =
+        SquashFs::<pools::LocalReadersPoolTokio>::open(image.path(), &Options::default()).await?;
+    // With the paths from the index
+    fs.paths_of(inode).await?;
+    let hooks = Arc::new(RecordingHooks::default());
+    let fs = fs.with_hooks(hooks.clone());
+    let ino_fuse = fs.ino_to_fuse(inode);
+    let fh = Filesystem::open(&fs, ino_fuse, libc::O_RDONLY).await?;
+    assert!(Filesystem::read(&fs, ino_fuse, fh, 0, 10).await.is_err());
+    Filesystem::release(&fs, ino_fuse, fh).await?;
+    assert_eq!(
+        *hooks.0.lock().unwrap(),
+        [
+            ("open", inode, 1),
+            ("read_error", inode, 1),
+            ("release", inode, 1)
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalidation() -> anyhow::Result<()> {
+    let Fixture { fs, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    // Invalidation of every entry and inode
+    let notifier = Arc::new(RecordingNotifier::default());
+    fs.set_notifier(notifier.clone());
+    fs.invalidate_all().await?;
+    assert_eq!(*notifier.inodes.lock().unwrap(), SIZES.len() + 1);
+    assert_eq!(*notifier.entries.lock().unwrap(), SIZES.len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn handles() -> anyhow::Result<()> {
+    let Fixture { fs, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    // Handles, with the bytes read through them
+    let inode = fs.ino_to_fuse(fs.resolve(Path::new("blocks")).await?);
+    let fh = Filesystem::open(&fs, inode, libc::O_RDONLY).await?;
+    Filesystem::read(&fs, inode, fh, 1, 100).await?;
+    let handles = fs.open_handles().await;
+    assert_eq!(handles.len(), 1);
+    assert_eq!((handles[0].fh, handles[0].inode), (fh, inode));
+    assert_eq!(handles[0].bytes_served, 100);
+    // Latencies recorded by the FUSE operations
+    let stats = fs.stats().await;
+    assert_eq!(stats.read_ms.count, 1);
+    assert!(stats.read_ms.min >= 0.0);
+    Filesystem::release(&fs, inode, fh).await?;
+    assert!(!fs.has_handles().await);
+    Ok(())
+}
+
+#[tokio::test]
+async fn readers() -> anyhow::Result<()> {
+    let fixture = Fixture::sizes(ImageSpec::new()).await?;
+    // Readers opened upfront
+    let options = Options {
+        prewarm: true,
+        ..Default::default()
+    };
+    let fs_warm = fixture.open(&options).await?;
+    let pools = fs_warm.stats().await.pools;
+    assert_eq!(pools.len(), 1);
+    assert_eq!((pools[0].flags, pools[0].readers), (0, options.readers));
+    assert!(pools[0].warmup_ms.is_some());
+    assert_eq!(fs_warm.capabilities(), BackendCapabilities::LOCAL);
+    // Backend without O_DIRECT semantics and with few connections: a single, smaller pool
+    let options = Options {
+        prewarm: true,
+        direct_limit: 1000,
+        capabilities: Some(BackendCapabilities {
+            direct_io: false,
+            max_readers: Some(2),
+            ..BackendCapabilities::LOCAL
+        }),
+        ..Default::default()
+    };
+    let fs_capped = fixture.open(&options).await?;
+    let small = fs_capped.resolve(Path::new("fragment")).await?;
+    fs_capped
+        .read_file(
+            small,
+            0,
+            100,
+            libc::O_DIRECT,
+            fs_capped.superblock.compression,
+        )
+        .await?;
+    let pools = fs_capped.stats().await.pools;
+    assert_eq!(pools.len(), 1);
+    assert_eq!((pools[0].flags, pools[0].readers), (0, 2));
+    // Idle readers are closed, and reopened when needed
+    let options = Options {
+        reader_ttl_secs: Some(0),
+        ..Default::default()
+    };
+    let fs_ttl = fixture.open(&options).await?;
+    let blocks = fs_ttl.resolve(Path::new("blocks")).await?;
+    let read = || fs_ttl.read_file(blocks, 0, 10, libc::O_DIRECT, fs_ttl.superblock.compression);
+    read().await?;
+    assert!(fs_ttl.close_idle_readers().await > 0);
+    assert_eq!(read().await?, contents(10));
+    Ok(())
+}
+
+/// Gate holding the reads of [`GatedPool`] while closed.
+#[derive(Clone, Default)]
+struct Gate(Arc<Mutex<Option<Vec<std::task::Waker>>>>);
+impl Gate {
+    fn close(&self) {
+        *self.0.lock().unwrap() = Some(vec![]);
+    }
+    fn open(&self) {
+        for waker in self.0.lock().unwrap().take().into_iter().flatten() {
+            waker.wake();
+        }
+    }
+    fn poll(&self, cx: &mut std::task::Context<'_>) -> std::task::Poll<()> {
+        match &mut *self.0.lock().unwrap() {
+            Some(wakers) => {
+                wakers.push(cx.waker().clone());
+                std::task::Poll::Pending
+            }
+            None => std::task::Poll::Ready(()),
+        }
+    }
+}
+
+/// Readers of an image, whose reads wait for the gate to be open.
+struct GatedPool {
+    path: std::path::PathBuf,
+    gate: Gate,
+}
+struct GatedReader {
+    reader: tokio::io::BufReader<tokio::fs::File>,
+    gate: Gate,
+}
+#[async_trait::async_trait]
+impl deadpool::managed::Manager for GatedPool {
+    type Type = GatedReader;
+    type Error = std::io::Error;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        Ok(GatedReader {
+            reader: tokio::io::BufReader::new(tokio::fs::File::open(&self.path).await?),
+            gate: self.gate.clone(),
+        })
+    }
+    async fn recycle(&self, _r: &mut Self::Type) -> deadpool::managed::RecycleResult<Self::Error> {
+        Ok(())
+    }
+}
+impl tokio::io::AsyncRead for GatedReader {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        futures::ready!(self.gate.poll(cx));
+        std::pin::Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+impl tokio::io::AsyncBufRead for GatedReader {
+    fn poll_fill_buf(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        futures::ready!(this.gate.poll(cx));
+        std::pin::Pin::new(&mut this.reader).poll_fill_buf(cx)
+    }
+    fn consume(mut self: std::pin::Pin<&mut Self>, amt: usize) {
+        std::pin::Pin::new(&mut self.reader).consume(amt)
+    }
+}
+impl tokio::io::AsyncSeek for GatedReader {
+    fn start_seek(
+        mut self: std::pin::Pin<&mut Self>,
+        position: std::io::SeekFrom,
+    ) -> std::io::Result<()> {
+        std::pin::Pin::new(&mut self.reader).start_seek(position)
+    }
+    fn poll_complete(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<u64>> {
+        std::pin::Pin::new(&mut self.reader).poll_complete(cx)
+    }
+}
+
+#[tokio::test]
+async fn cancelled_reads() -> anyhow::Result<()> {
+    let fixture = Fixture::sizes(ImageSpec::new()).await?;
+    let gate = Gate::default();
+    let options = Options {
+        direct_limit: BLOCK_SIZE,
+        ..Default::default()
+    };
+    let fs = Arc::new(
+        SquashFs::from_reader(&options, {
+            let (path, gate) = (fixture.image.path.clone(), gate.clone());
+            move |_| {
+                Ok(GatedPool {
+                    path: path.clone(),
+                    gate: gate.clone(),
+                })
+            }
+        })
+        .await?,
+    );
+    let inodes =
+        futures::future::try_join_all(SIZES.map(|(name, _)| fs.resolve(Path::new(name)))).await?;
+    let read = |(inode, (_, size)): (u32, (&str, usize)), flags| {
+        let fs = fs.clone();
+        async move {
+            fs.read_file(inode, 0, size, flags, fs.superblock.compression)
+                .await
+        }
+    };
+    let timeout = std::time::Duration::from_secs(10);
+    gate.close();
+    for flags in [0, libc::O_DIRECT] {
+        // Reads dropped while waiting on the backend, with the cache of blocks or of small files
+        for file in inodes.iter().copied().zip(SIZES).skip(1) {
+            let dropped =
+                tokio::time::timeout(std::time::Duration::from_millis(10), read(file, flags));
+            assert!(dropped.await.is_err());
+        }
+        // Read tasks aborted while waiting on the backend
+        let tasks: Vec<_> = inodes
+            .iter()
+            .copied()
+            .zip(SIZES)
+            .skip(1)
+            .map(|file| tokio::spawn(read(file, flags)))
+            .collect();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        for task in tasks {
+            task.abort();
+            assert!(task.await.unwrap_err().is_cancelled());
+        }
+    }
+    // The readers are back in their pools, and the caches serve the files in full
+    let pools = fs.stats().await.pools;
+    assert!(pools.iter().all(|pool| pool.idle == pool.readers));
+    gate.open();
+    for flags in [0, libc::O_DIRECT] {
+        let reads = inodes
+            .iter()
+            .copied()
+            .zip(SIZES)
+            .map(|file| read(file, flags));
+        let data = tokio::time::timeout(timeout, futures::future::try_join_all(reads)).await??;
+        for (data, (name, size)) in data.iter().zip(SIZES) {
+            assert_eq!(*data, contents(size), "{}", name);
+        }
+    }
+    let pools = fs.stats().await.pools;
+    assert!(pools.iter().all(|pool| pool.idle == pool.readers));
+    Ok(())
+}
+
+#[tokio::test]
+async fn open_flags() -> anyhow::Result<()> {
+    let fixture = Fixture::sizes(ImageSpec::new()).await?;
+    // Open flags, dropped where not supported
+    let options = Options {
+        image_direct: true,
+        image_noatime: true,
+        ..Default::default()
+    };
+    let fs_flags = fixture.open(&options).await?;
+    let blocks = fs_flags.resolve(Path::new("blocks")).await?;
+    let read = fs_flags.read_file(blocks, 0, 10, 0, fs_flags.superblock.compression);
+    assert_eq!(read.await?, contents(10));
+    // Tuned memory mapping
+    #[cfg(feature = "memmap")]
+    {
+        let options = Options {
+            mmap_advice: Some(pools::MemMapAdvice::Random),
+            mmap_lock_tables: true,
+            mmap_hugepages: true,
+            ..Default::default()
+        };
+        let fs_mmap =
+            SquashFs::<pools::LocalReadersPoolMemMap>::open(&fixture.image.path, &options).await?;
+        let blocks = fs_mmap.resolve(Path::new("blocks")).await?;
+        let read = fs_mmap.read_file(blocks, 0, 10, 0, fs_mmap.superblock.compression);
+        assert_eq!(read.await?, contents(10));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_handle_reads() -> anyhow::Result<()> {
+    let fixture = Fixture::sizes(ImageSpec::new()).await?;
+    // Concurrent reads on a handle beyond its limit wait for each other
+    let options = Options {
+        readers: 1,
+        max_handle_reads: Some(1),
+        ..Default::default()
+    };
+    let fs_limited = fixture.open(&options).await?;
+    let inode = fs_limited.ino_to_fuse(fs_limited.resolve(Path::new("blocks")).await?);
+    let fh = Filesystem::open(&fs_limited, inode, libc::O_RDONLY).await?;
+    let reads = (0..8).map(|i| Filesystem::read(&fs_limited, inode, fh, i * 10, 10));
+    for data in futures::future::try_join_all(reads).await? {
+        assert_eq!(data.len(), 10);
+    }
+    let options = Options {
+        max_handle_reads: Some(0),
+        ..Default::default()
+    };
+    assert!(fixture.open(&options).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn nonblocking_reads() -> anyhow::Result<()> {
+    let fixture = Fixture::sizes(ImageSpec::new()).await?;
+    // Non-blocking reads fail until the blocks are loaded in the background
+    let options = Options {
+        nonblock_eagain: true,
+        ..Default::default()
+    };
+    let fs_nonblock = fixture.open(&options).await?;
+    let blocks = fs_nonblock.resolve(Path::new("blocks")).await?;
+    let inode = fs_nonblock.ino_to_fuse(blocks);
+    let fh = Filesystem::open(&fs_nonblock, inode, libc::O_RDONLY | libc::O_NONBLOCK).await?;
+    let read = || Filesystem::read(&fs_nonblock, inode, fh, BLOCK_SIZE as i64, 100);
+    assert!(matches!(read().await, Err(Error::WouldBlock)));
+    let mut polls = 0;
+    let data = loop {
+        match read().await {
+            Err(Error::WouldBlock) if polls < 100 => {
+                polls += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            data => break data?,
+        }
+    };
+    assert_eq!(data, contents(BLOCK_SIZE + 100)[BLOCK_SIZE..]);
+    let fh = Filesystem::open(&fs_nonblock, inode, libc::O_RDONLY).await?;
+    Filesystem::read(&fs_nonblock, inode, fh, 0, 100).await?;
+    // Prefetched blocks are served from the cache
+    let read = |offset| {
+        let compression = fs_nonblock.superblock.compression;
+        fs_nonblock.read_file_with(blocks, offset, 100, 0, compression, cached_only())
+    };
+    let offset = 2 * BLOCK_SIZE;
+    assert!(matches!(read(offset).await, Err(Error::WouldBlock)));
+    fs_nonblock
+        .prefetch(blocks, offset as u64..offset as u64 + 100)
+        .await?;
+    assert_eq!(read(offset).await?, contents(offset + 100)[offset..]);
+    Ok(())
+}
+
+fn cached_only() -> ReadHints {
+    ReadHints {
+        cached_only: true,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn prefetch_tree() -> anyhow::Result<()> {
+    let Fixture { fs, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    // Prefetch of subtrees and patterns
+    let pattern = glob::Pattern::new("block_*")?;
+    assert_eq!(fs.prefetch_matching(&pattern).await?, 2 * BLOCK_SIZE as u64);
+    let total: usize = SIZES.iter().map(|(_, size)| size).sum();
+    assert_eq!(fs.prefetch_tree(Path::new("")).await?, total as u64);
+    let blocks = fs.resolve(Path::new("blocks")).await?;
+    let compression = fs.superblock.compression;
+    let read = fs.read_file_with(blocks, BLOCK_SIZE, 100, 0, compression, cached_only());
+    assert_eq!(read.await?, contents(BLOCK_SIZE + 100)[BLOCK_SIZE..]);
+    assert!(fs.prefetch_tree(Path::new("missing")).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn readdir_cookies() -> anyhow::Result<()> {
+    let Fixture { fs, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    // Directory listings resume from the cookies of their entries
+    let root = fuser::FUSE_ROOT_ID;
+    let dh = fs.opendir(root).await?;
+    let entries: Vec<_> = fs.readdir_handle(dh, 0).await?.collect();
+    assert_eq!(entries.len(), SIZES.len());
+    let names = |entries: &[(u64, fuser_async::DirEntry)]| -> Vec<String> {
+        entries.iter().map(|(_, e)| e.name.clone()).collect()
+    };
+    let rest: Vec<_> = fs.readdir_handle(dh, entries[1].0).await?.collect();
+    assert_eq!(names(&rest), names(&entries[2..]));
+    // Plain indices, as computed by `fuser_async`
+    assert_eq!(
+        Filesystem::readdir(&fs, root, 2).await?.count(),
+        SIZES.len() - 2
+    );
+    // Cookies of another version of the directory
+    let other = (((entries[0].0 >> 32) + 1) << 32) | 1;
+    assert!(matches!(
+        fs.readdir_handle(dh, other).await.map(|_| ()),
+        Err(Error::InvalidOffset)
+    ));
+    fs.releasedir(dh).await?;
+    Ok(())
+}
+
+/// Entries of a directory stream, with their offsets, until its end.
+fn read_dir_offsets(dir: *mut libc::DIR) -> Vec<(String, i64)> {
+    let mut entries = vec![];
+    loop {
+        let entry = unsafe { libc::readdir(dir) };
+        if entry.is_null() {
+            return entries;
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr((*entry).d_name.as_ptr()) };
+        entries.push((name.to_string_lossy().into_owned(), unsafe {
+            (*entry).d_off
+        }));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn readdir_cookies_mount() -> anyhow::Result<()> {
+    // Enough entries for several `readdir` requests
+    let write =
+        |dir: &Path| (0..1000).try_for_each(|i| std::fs::write(dir.join(i.to_string()), ""));
+    let Fixture {
+        fs, image: _image, ..
+    } = Fixture::new(write, ImageSpec::new()).await?;
+    let mount = MountHarness::mount_temp(fs).await?;
+    let path = std::ffi::CString::new(mount.mountpoint().as_os_str().as_encoded_bytes())?;
+    tokio::task::spawn_blocking(move || {
+        let open = || unsafe { libc::opendir(path.as_ptr()) };
+        let (first, second) = (open(), open());
+        assert!(!first.is_null() && !second.is_null());
+        let entries = read_dir_offsets(first);
+        assert_eq!(entries.len(), 1000);
+        // The offsets are the cookies of the entries, with the generation of the directory
+        assert!(entries.iter().all(|(_, offset)| offset >> 32 != 0));
+        // Moving the stream between calls, while another one lists the directory
+        unsafe { libc::seekdir(first, entries[499].1) };
+        assert_eq!(read_dir_offsets(second), entries);
+        assert_eq!(read_dir_offsets(first), entries[500..]);
+        unsafe { libc::rewinddir(first) };
+        assert_eq!(read_dir_offsets(first), entries);
+        unsafe {
+            libc::closedir(first);
+            libc::closedir(second);
+        }
+    })
+    .await?;
+    mount.unmount().await
+}
+
+#[tokio::test]
+async fn dir_stats() -> anyhow::Result<()> {
+    let fixture = Fixture::sizes(ImageSpec::new()).await?;
+    let fs = &fixture.fs;
+    let root = fuser::FUSE_ROOT_ID;
+    // Directory statistics, and directory sizes in the attributes
+    let total: usize = SIZES.iter().map(|(_, size)| size).sum();
+    let stats = fs.dir_stats(fs.resolve(Path::new("/")).await?).await?;
+    assert_eq!(
+        (stats.entries, stats.size, stats.depth),
+        (SIZES.len(), total as u64, 0)
+    );
+    assert_eq!(Filesystem::getattr(fs, root).await?.size, 0);
+    let options = Options {
+        dir_sizes: true,
+        ..Default::default()
+    };
+    let fs_sizes = fixture.open(&options).await?;
+    assert_eq!(
+        Filesystem::getattr(&fs_sizes, root).await?.size,
+        total as u64
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn disk_usage() -> anyhow::Result<()> {
+    let total: usize = SIZES.iter().map(|(_, size)| size).sum();
+    for (_, uncompressed, Fixture { fs, .. }) in variants().await? {
+        let usage = fs.disk_usage(Path::new("/")).await?;
+        assert_eq!(
+            (usage.apparent, usage.files, usage.directories),
+            (total as u64, SIZES.len() as u64, 1)
+        );
+        if uncompressed {
+            assert_eq!(usage.on_disk, usage.apparent);
+        }
+        let usage = fs.disk_usage(Path::new("fragment")).await?;
+        assert_eq!(
+            (usage.apparent, usage.files, usage.directories),
+            (100, 1, 0)
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn analyze() -> anyhow::Result<()> {
+    let Fixture { fs, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    let total: usize = SIZES.iter().map(|(_, size)| size).sum();
+    // Statistics from the tables
+    let analysis = fs.analyze(2).await?;
+    let sizes: Vec<_> = analysis.largest.iter().map(|(_, size)| *size).collect();
+    assert_eq!(sizes, [3 * BLOCK_SIZE as u64, BLOCK_SIZE as u64 + 1]);
+    assert_eq!(analysis.extensions[""].count, SIZES.len() as u64);
+    assert_eq!(analysis.directories[Path::new("")].apparent, total as u64);
+    // Simulation with the options of the image, and with larger blocks
+    let block_sizes = [BLOCK_SIZE as u32, 4 * BLOCK_SIZE as u32];
+    let advice = fs
+        .advise(&block_sizes, &[Compression::Zstd], 1_000_000)
+        .await?;
+    let simulation = &advice.simulations[0];
+    assert_eq!(
+        (
+            simulation.blocks,
+            simulation.tail_ends,
+            simulation.fragment_blocks
+        ),
+        (
+            advice.image.blocks,
+            advice.image.tail_ends,
+            advice.image.fragment_blocks
+        )
+    );
+    assert_eq!(advice.sample, total as u64);
+    assert!(advice.simulations.iter().all(|s| s.data.is_some()));
+    assert!(advice.simulations[1].blocks < simulation.blocks);
+    Ok(())
+}
+
+#[tokio::test]
+async fn find_names() -> anyhow::Result<()> {
+    let Fixture { fs, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    // Search by name
+    let found: Vec<_> = fs.find_names("block").try_collect().await?;
+    assert_eq!(found.len(), 4);
+    let found: Vec<_> = fs
+        .find_names(regex::Regex::new("^block$")?)
+        .try_collect()
+        .await?;
+    assert_eq!(found, [Path::new("block")]);
+    // Reverse lookup
+    let block = fs.resolve(Path::new("block")).await?;
+    assert_eq!(fs.paths_of(block).await?, [Path::new("block")]);
+    let root_inode = fs.resolve(Path::new("/")).await?;
+    assert_eq!(fs.paths_of(root_inode).await?, [Path::new("")]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn export_tree() -> anyhow::Result<()> {
+    let Fixture { fs, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    let mut listing = vec![];
+    fs.export_tree(&mut listing, TreeFormat::Text).await?;
+    assert_eq!(String::from_utf8(listing)?.lines().count(), SIZES.len());
+    let mut listing = vec![];
+    fs.export_tree(&mut listing, TreeFormat::Ndjson).await?;
+    let entries: Vec<serde_json::Value> = listing
+        .split(|c| *c == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(serde_json::from_slice)
+        .collect::<Result<_, _>>()?;
+    assert_eq!(entries.len(), SIZES.len());
+    assert_eq!(entries[0]["path"], "/block");
+    assert_eq!(entries[0]["type"], "file");
+    assert_eq!(entries[0]["size"], BLOCK_SIZE);
+    let mut listing = vec![];
+    fs.export_tree(&mut listing, TreeFormat::Json).await?;
+    let array: Vec<serde_json::Value> = serde_json::from_slice(&listing)?;
+    assert_eq!(array, entries);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn statfs_mount() -> anyhow::Result<()> {
+    let Fixture {
+        fs, image: _image, ..
+    } = Fixture::sizes(ImageSpec::new()).await?;
+    let expected = fs.statfs();
+    let mount = MountHarness::mount_temp(fs).await?;
+    let path = std::ffi::CString::new(mount.mountpoint().as_os_str().as_encoded_bytes())?;
+    // As `df`
+    let stat = tokio::task::spawn_blocking(move || {
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::statvfs(path.as_ptr(), &mut stat) }, 0);
+        stat
+    })
+    .await?;
+    assert_eq!(stat.f_blocks, expected.blocks);
+    assert_eq!(stat.f_files, SIZES.len() as u64 + 1);
+    assert_eq!((stat.f_bfree, stat.f_bavail, stat.f_ffree), (0, 0, 0));
+    assert_eq!(stat.f_frsize, BLOCK_SIZE as u64);
+    mount.unmount().await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_only_mount() -> anyhow::Result<()> {
+    let write = |dir: &Path| {
+        std::fs::write(dir.join("file"), contents(100))?;
+        std::fs::create_dir(dir.join("dir"))
+    };
+    let Fixture {
+        fs, image: _image, ..
+    } = Fixture::new(write, ImageSpec::new()).await?;
+    // Without the `ro` mount option, the modifications reach the adapter
+    let mount = MountHarness::mount_temp_writable(fs).await?;
+    let path = |name| mount.mountpoint().join(name);
+    let read_only =
+        |r: std::io::Result<()>| assert_eq!(r.unwrap_err().raw_os_error(), Some(libc::EROFS));
+    read_only(tokio::fs::create_dir(path("new_dir")).await);
+    read_only(tokio::fs::write(path("new_file"), b"data").await);
+    let permissions = std::os::unix::fs::PermissionsExt::from_mode(0o777);
+    read_only(tokio::fs::set_permissions(path("file"), permissions).await);
+    read_only(tokio::fs::remove_file(path("file")).await);
+    read_only(tokio::fs::remove_dir(path("dir")).await);
+    read_only(tokio::fs::rename(path("file"), path("renamed")).await);
+    read_only(tokio::fs::symlink("file", path("link")).await);
+    let writable = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(path("file"))
+        .await;
+    read_only(writable.map(|_| ()));
+    let file = std::ffi::CString::new(path("file").into_os_string().into_encoded_bytes())?;
+    assert_eq!(unsafe { libc::access(file.as_ptr(), libc::W_OK) }, -1);
+    assert_eq!(
+        std::io::Error::last_os_error().raw_os_error(),
+        Some(libc::EROFS)
+    );
+    // Nothing was modified
+    assert_eq!(tokio::fs::read(path("file")).await?, contents(100));
+    assert!(tokio::fs::metadata(path("dir")).await?.is_dir());
+    mount.unmount().await
+}
+
+#[tokio::test]
+async fn statfs() -> anyhow::Result<()> {
+    let Fixture { fs, .. } = Fixture::sizes(ImageSpec::new()).await?;
+    // Nothing is free in the read-only image
+    let statfs = fs.statfs();
+    assert_eq!(statfs.files, SIZES.len() as u64 + 1);
+    assert_eq!((statfs.bfree, statfs.bavail, statfs.ffree), (0, 0, 0));
+    Ok(())
+}
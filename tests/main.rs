@@ -6,7 +6,7 @@ use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 
 use squashfs_async::pools::LocalBackend;
-use squashfs_async::utils::MeanStd;
+use squashfs_async::stats::MeanStd;
 
 // See https://stackoverflow.com/a/48534068
 struct HashWriter<T: Hasher>(T);
@@ -53,6 +53,13 @@ fn process_folder(folder: &Path, n_chunks: i32, hash: bool) -> anyhow::Result<u6
     Ok(hasher.finish())
 }
 
+fn wait_mounted(mountpoint: &Path) -> anyhow::Result<()> {
+    tokio::runtime::Runtime::new()?.block_on(squashfs_async::testing::wait_mounted(
+        mountpoint,
+        squashfs_async::testing::MOUNT_TIMEOUT,
+    ))
+}
+
 fn test_one(
     suffix: &str,
     mut mount: Box<dyn mount::Mount>,
@@ -73,7 +80,7 @@ fn test_one(
         std::thread::sleep(std::time::Duration::from_secs(2));
 
         mount.mount(&filename, mountpoint)?;
-        std::thread::sleep(std::time::Duration::from_secs(2));
+        wait_mounted(mountpoint)?;
 
         let start = std::time::Instant::now();
         process_folder(mountpoint, n_chunks, false)?;
@@ -128,11 +135,11 @@ fn test() -> anyhow::Result<()> {
             let mounts: Vec<Box<dyn mount::Mount>> = vec![
                 Box::new(mount::Squashfuse::new("squashfuse")),
                 // Box::new(mount::Squashfuse::new("squashfuse_ll")),
-                Box::new(mount::SquashfuseRs::from(LocalBackend::Tokio)),
+                Box::new(mount::squashfuse_rs(LocalBackend::Tokio)),
                 #[cfg(feature = "asyncfs")]
-                Box::new(mount::SquashfuseRs::from(LocalBackend::AsyncFs)),
+                Box::new(mount::squashfuse_rs(LocalBackend::AsyncFs)),
                 #[cfg(feature = "memmap")]
-                Box::new(mount::SquashfuseRs::from(LocalBackend::MemMap)),
+                Box::new(mount::squashfuse_rs(LocalBackend::MemMap)),
             ];
             for mount in mounts {
                 let mount_name = mount.name();
@@ -0,0 +1,38 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use squashfs_async::testing::{ImageSpec, TempImage};
+use squashfs_async::{pools, Compression, Options, SquashFs};
+
+#[tokio::test]
+async fn nested() -> anyhow::Result<()> {
+    let contents = tempfile::tempdir()?;
+    let inner = ImageSpec::new()
+        .seed(1)
+        .tree(2, 2, 4)
+        .file_sizes(0..300_000)
+        .compression(Some(Compression::Zstd));
+    inner.write_contents(contents.path())?;
+    let outer_contents = tempfile::tempdir()?;
+    inner.build_to(&outer_contents.path().join("inner.squashfs"))?;
+    let outer = TempImage::new(
+        outer_contents.path(),
+        ImageSpec::new()
+            .compression(Some(Compression::Gzip))
+            .mksquashfs_options(),
+    )?;
+
+    let options = Options::default();
+    let outer =
+        Arc::new(SquashFs::<pools::LocalReadersPoolTokio>::open(&outer.path, &options).await?);
+    assert!(
+        SquashFs::open_nested(&outer, Path::new("missing.squashfs"), &options)
+            .await
+            .is_err()
+    );
+    let nested = SquashFs::open_nested(&outer, Path::new("inner.squashfs"), &options).await?;
+    let verification = nested.compare_with_dir(contents.path()).await?;
+    assert!(verification.divergence.is_none(), "{}", verification);
+    assert!(verification.files > 0);
+    Ok(())
+}
@@ -1,12 +1,13 @@
 use std::path::{Path, PathBuf};
 use std::sync::Once;
 
-use rand::{Rng, SeedableRng};
+use squashfs_async::testing::ImageSpec;
+use squashfs_async::Compression;
 
-pub const SPECS: [(&str, &[&str]); 3] = [
-    ("nocomp", &["-noI", "-noId", "-noD", "-noF", "-noX"]),
-    ("gzip", &["-comp", "gzip", "-Xcompression-level", "1"]),
-    ("zstd", &["-comp", "zstd", "-Xcompression-level", "1"]),
+pub const SPECS: [(&str, Option<Compression>); 3] = [
+    ("nocomp", None),
+    ("gzip", Some(Compression::Gzip)),
+    ("zstd", Some(Compression::Zstd)),
 ];
 
 pub fn tempdir() -> &'static Path {
@@ -29,7 +30,10 @@ const FOLDERS: usize = 1;
 const FILES_PER_FOLDER: usize = 8;
 
 fn setup_impl() -> anyhow::Result<()> {
-    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let spec = ImageSpec::new()
+        .seed(42)
+        .tree(1, FOLDERS, FILES_PER_FOLDER)
+        .file_sizes(FILE_SIZE..FILE_SIZE + 1);
 
     let contents = tempdir().join("contents");
     if SPECS
@@ -40,20 +44,15 @@ fn setup_impl() -> anyhow::Result<()> {
         if contents.exists() {
             std::fs::remove_dir_all(&contents)?;
         }
-        std::fs::create_dir_all(&contents)?;
         println!("Creating random files");
-        random_files(FILES_PER_FOLDER, &contents, FILE_SIZE, &mut rng)?;
-        for i in 0..FOLDERS {
-            let dir = contents.join(i.to_string());
-            std::fs::create_dir_all(&dir)?;
-            random_files(FILES_PER_FOLDER, &dir, FILE_SIZE, &mut rng)?;
-        }
+        spec.write_contents(&contents)?;
 
         println!("Creating test squashfs");
-        for (suffix, options) in SPECS {
+        for (suffix, compression) in SPECS {
             let filename = &filename(suffix);
             if !filename.exists() {
-                mksquashfs(&contents, filename, options)?;
+                let options = spec.clone().compression(compression).mksquashfs_options();
+                squashfs_async::testing::mksquashfs(&contents, filename, options)?;
             }
         }
         println!("Cleaning up random files");
@@ -63,39 +62,3 @@ fn setup_impl() -> anyhow::Result<()> {
     }
     Ok(())
 }
-
-fn random_file(path: &Path, size: usize, rng: &mut impl Rng) -> anyhow::Result<()> {
-    let f = std::fs::File::create(path)?;
-    let mut f = std::io::BufWriter::new(f);
-    let reader = rng
-        .sample_iter::<u8, _>(rand::distributions::Standard)
-        .take(size);
-    let mut reader = iter_read::IterRead::new(reader);
-    std::io::copy(&mut reader, &mut f)?;
-    Ok(())
-}
-
-fn random_files(n: usize, path: &Path, size: usize, rng: &mut impl Rng) -> anyhow::Result<()> {
-    for i in 0..n {
-        random_file(&path.join(format!("file-{}.random", i)), size, rng)?;
-    }
-    Ok(())
-}
-fn mksquashfs<I, S>(input: &Path, dest: &Path, options: I) -> anyhow::Result<()>
-where
-    I: IntoIterator<Item = S>,
-    S: AsRef<std::ffi::OsStr>,
-{
-    let cmd = std::process::Command::new("mksquashfs")
-        .args([input, dest])
-        .args(["-mkfs-time", "0", "-reproducible"])
-        .args(options)
-        .output()?;
-    if !cmd.status.success() {
-        anyhow::bail!(
-            "Failed to run mksquashfs: {}",
-            std::str::from_utf8(&cmd.stderr)?
-        );
-    }
-    Ok(())
-}